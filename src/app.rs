@@ -12,6 +12,7 @@ use crate::path_cache::PathCache;
 use crate::pipeline::{Event, Pipeline};
 use crate::playback_speed::PlaybackSpeed;
 use crate::random_files::random_file_with_timeout;
+use crate::remote::{Command, PlayerState, RemoteClient};
 use crate::ui::{self, PipelineRecv};
 
 const MAX_QUEUE_SIZE: usize = 20;
@@ -42,6 +43,9 @@ pub struct App {
     playback_speed: PlaybackSpeed,
     keep_awake: Option<KeepAwake>,
     pipeline_receivers: Vec<PipelineRecv>,
+    /// Server to join as a remote controllable player, from `Z_PLAY_REMOTE_URL`.
+    remote_url: Option<String>,
+    remote: Option<RemoteClient>,
 }
 
 impl App {
@@ -60,6 +64,8 @@ impl App {
             playback_speed: PlaybackSpeed::default(),
             keep_awake: None,
             pipeline_receivers: Vec::with_capacity(2),
+            remote_url: std::env::var("Z_PLAY_REMOTE_URL").ok(),
+            remote: None,
         }
     }
 }
@@ -71,6 +77,14 @@ impl eframe::App for App {
             start_file_feeder(ctx, queue, self.root_paths.clone());
         }
 
+        if let Some(url) = self.remote_url.take() {
+            let id = std::env::var("Z_PLAY_REMOTE_ID").unwrap_or_else(|_| "desktop".to_owned());
+            let ctx = ctx.clone();
+            let on_command = move || ctx.request_repaint();
+            let name = Some("Z-Play desktop".to_owned());
+            self.remote = Some(RemoteClient::connect(url, id, name, on_command));
+        }
+
         self.pipeline_receivers.retain(|rx| {
             if let Some(Err(error)) = rx.try_recv() {
                 self.error = Some(error);
@@ -80,6 +94,40 @@ impl eframe::App for App {
         });
 
         let mut load_next_file = self.player.pipeline().is_none();
+        let mut remote_toggle_fullscreen = false;
+
+        while let Some(command) = self.remote.as_ref().and_then(RemoteClient::try_recv) {
+            match command {
+                Command::Next => load_next_file = true,
+                Command::Pause { paused } => {
+                    let Some(pipeline) = self.player.pipeline() else { continue };
+                    let is_playing = pipeline.state() == gstreamer::State::Playing;
+                    let state = if paused.unwrap_or(is_playing) {
+                        gstreamer::State::Paused
+                    } else {
+                        gstreamer::State::Playing
+                    };
+                    self.pipeline_receivers.push(PipelineRecv::SetState(pipeline.set_state(state)));
+                }
+                Command::Seek { position } => {
+                    let Some(pipeline) = self.player.pipeline() else { continue };
+                    let time = gstreamer::ClockTime::from_nseconds((position * 1e9) as u64);
+                    let result_rx = pipeline.seek(time, Some(self.player.rate()));
+                    self.pipeline_receivers.push(PipelineRecv::Seek(result_rx));
+                }
+                Command::Fullscreen { enabled } => {
+                    remote_toggle_fullscreen =
+                        enabled.unwrap_or(!self.fullscreen) != self.fullscreen;
+                }
+                // There's no history and files come straight from the roots.
+                Command::Previous | Command::SetFilter { .. } => {
+                    tracing::warn!(
+                        ?command,
+                        "Remote command isn't supported by the desktop player"
+                    );
+                }
+            }
+        }
 
         egui::CentralPanel::default().show(ctx, |ui| {
             if !self.fullscreen {
@@ -110,6 +158,7 @@ impl eframe::App for App {
             } else {
                 ctx.input(|i| i.key_released(egui::Key::F11))
             };
+            toggle_fullscreen_button |= remote_toggle_fullscreen;
 
             ui.horizontal(|ui| {
                 let next_button = ui.button("Next");
@@ -164,6 +213,22 @@ impl eframe::App for App {
             }
         }
 
+        if let Some(remote) = &self.remote {
+            let state = match self.player.pipeline() {
+                Some(pipeline) => PlayerState {
+                    path: Some(pipeline.path().display().to_string()),
+                    paused: pipeline.state() != gstreamer::State::Playing,
+                    // Whole seconds, so a playing video isn't reported on every frame.
+                    position: Some(pipeline.position().seconds() as f64),
+                    duration: Some(pipeline.duration().seconds() as f64),
+                    fullscreen: self.fullscreen,
+                    kinds: Vec::new(),
+                },
+                None => PlayerState { fullscreen: self.fullscreen, ..PlayerState::default() },
+            };
+            remote.set_state(state);
+        }
+
         let playing = self.player.is_playing();
         if playing && self.keep_awake.is_none() {
             let keep_awake_result = keepawake::Builder::default()
@@ -176,7 +241,7 @@ impl eframe::App for App {
             match keep_awake_result {
                 Ok(keep_awake) => self.keep_awake = Some(keep_awake),
                 Err(error) => {
-                    log::error!("Failed to create keep awake: {error}");
+                    tracing::error!(%error, "Failed to create keep awake");
                 }
             }
         } else if !playing {
@@ -193,12 +258,12 @@ fn pre_roll_loop(
     let mut queue = VecDeque::<Pipeline>::with_capacity(MAX_PRE_ROLL_QUEUE_SIZE);
     loop {
         if pipeline_rx.is_disconnected() {
-            log::info!("Pipeline rx disconnected, stopping pre-roll loop");
+            tracing::info!("Pipeline rx disconnected, stopping pre-roll loop");
             break;
         }
 
         let Some(out_queue) = out_queue.upgrade() else {
-            log::info!("Queue dropped, stopping pre_roll loop");
+            tracing::info!("Queue dropped, stopping pre-roll loop");
             break;
         };
 
@@ -206,7 +271,7 @@ fn pre_roll_loop(
         if queue_len != 0 {
             'pre_roll: for _ in 0..queue_len {
                 let pipeline = queue.pop_front().unwrap();
-                let path = pipeline.path();
+                let _span = pipeline.span().clone().entered();
 
                 let mut is_paused = pipeline.state() == gstreamer::State::Paused;
 
@@ -217,20 +282,19 @@ fn pre_roll_loop(
                 for event in iter {
                     match event {
                         Event::Error(error) => {
-                            log::error!("Error on player for {path:?}: {error}");
+                            tracing::error!(%error, "Error on player");
                             continue 'pre_roll;
                         }
                         Event::StateChanged { from: _, to: gstreamer::State::Ready } => (),
                         Event::StateChanged { from: _, to: gstreamer::State::Paused } => {
                             is_paused = true;
                         }
-                        event => log::info!("Unhandled event on player for {path:?} - {event:?}"),
+                        event => tracing::info!(?event, "Unhandled event on player"),
                     }
                 }
 
                 if is_paused && out_queue.lock().len() < MAX_QUEUE_SIZE {
-                    let path = pipeline.path();
-                    log::info!("Queueing {}", path.display());
+                    tracing::info!("Queueing");
 
                     out_queue.lock().push_back(pipeline);
                     ctx.request_repaint();
@@ -249,7 +313,7 @@ fn pre_roll_loop(
             Ok(pipeline) => pipeline,
             Err(flume::RecvTimeoutError::Timeout) => continue,
             Err(flume::RecvTimeoutError::Disconnected) => {
-                log::info!("Pipeline rx disconnected, stopping pre-roll loop");
+                tracing::info!("Pipeline rx disconnected, stopping pre-roll loop");
                 break;
             }
         };
@@ -276,17 +340,17 @@ fn pipeline_loop(
 
     loop {
         if pipeline_tx.is_disconnected() {
-            log::info!("Pipeline tx disconnected, stopping pipeline loop");
+            tracing::info!("Pipeline tx disconnected, stopping pipeline loop");
             break;
         }
 
-        log::info!("Starting pipeline loop");
+        tracing::debug!("Starting pipeline loop");
         let roots = root_paths.lock().clone();
 
         let queue_len = match queue.upgrade() {
             Some(queue) => queue.lock().len(),
             None => {
-                log::info!("Queue dropped, stopping pipeline loop");
+                tracing::info!("Queue dropped, stopping pipeline loop");
                 break;
             }
         };
@@ -299,7 +363,7 @@ fn pipeline_loop(
         let path = match random_file_with_timeout(&roots, scan_timeout, busy_timeout) {
             Some(path) => path,
             None => {
-                log::info!("No files found, stopping pipeline loop");
+                tracing::info!("No files found, stopping pipeline loop");
                 break;
             }
         };
@@ -308,25 +372,25 @@ fn pipeline_loop(
             continue;
         }
 
-        println!("Loading {path:?}");
+        tracing::info!(path = %path.display(), "Loading");
         let ctx_clone = ctx.clone();
         let on_sample = move || ctx_clone.request_repaint();
         let pipeline = match Pipeline::new(path.clone(), on_sample) {
             Ok(pipeline) => pipeline,
             Err(error) => {
-                log::error!("Failed to set path for {}: {error}", path.display());
+                tracing::error!(path = %path.display(), %error, "Failed to create pipeline");
                 continue;
             }
         };
 
         let result_rx = pipeline.set_state(gstreamer::State::Paused);
         if let Err(error) = result_rx.recv().unwrap() {
-            log::error!("Failed to set state for {}: {error}", path.display());
+            tracing::error!(path = %path.display(), %error, "Failed to pause pipeline");
             continue;
         }
 
         if pipeline_tx.send(pipeline).is_err() {
-            log::info!("Pipeline tx disconnected, stopping pipeline loop");
+            tracing::info!("Pipeline tx disconnected, stopping pipeline loop");
             break;
         }
     }
@@ -337,7 +401,7 @@ fn start_file_feeder(
     queue: Weak<Mutex<VecDeque<Pipeline>>>,
     root_paths: Arc<Mutex<Vec<PathBuf>>>,
 ) {
-    log::info!("Starting file feeder");
+    tracing::info!("Starting file feeder");
 
     let (pipeline_tx, pipeline_rx) = flume::bounded(1);
     let ctx_clone = ctx.clone();
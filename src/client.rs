@@ -0,0 +1,349 @@
+//! Typed access to a running server's HTTP API, for scripts and the app. `/openapi.json` on the
+//! server describes everything this covers and more.
+
+use std::path::{Path, PathBuf};
+
+use http::{HeaderMap, Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::remote::{Command, PlayerInfo};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Http(#[from] cyper::Error),
+    #[error("Server responded with {status}: {message}")]
+    Status { status: StatusCode, message: String },
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    Video,
+    Audio,
+    Image,
+}
+
+impl FileKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Video => "video",
+            Self::Audio => "audio",
+            Self::Image => "image",
+        }
+    }
+}
+
+/// What the user has told the server about a file.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Deserialize)]
+pub struct PathEntry {
+    #[serde(default)]
+    pub rating: Option<u8>,
+    #[serde(default)]
+    pub favorite: bool,
+    #[serde(default)]
+    pub hidden: bool,
+}
+
+/// A file picked by `/random` or `/play`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Item {
+    /// What to fetch with [`Client::file_url`], an HLS playlist when the file is transcoded.
+    pub path: String,
+    /// The source file when `path` is a playlist.
+    #[serde(default)]
+    pub display_path: Option<String>,
+    pub kind: FileKind,
+    #[serde(flatten)]
+    pub entry: PathEntry,
+    /// Seconds.
+    #[serde(default)]
+    pub duration: Option<f64>,
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+}
+
+impl Item {
+    /// The file on disk, what feedback and tags are recorded against.
+    pub fn source_path(&self) -> &str {
+        self.display_path.as_deref().unwrap_or(&self.path)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Root {
+    pub path: String,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SearchResult {
+    pub path: PathBuf,
+    pub kind: FileKind,
+}
+
+/// The `x-queue-*` headers.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct QueueInfo {
+    pub count: usize,
+    /// Files queued per kind when the queue is full.
+    pub size: usize,
+    pub video_count: usize,
+    pub image_count: usize,
+    pub audio_count: usize,
+}
+
+impl QueueInfo {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+                .unwrap_or_default()
+        };
+        Self {
+            count: header("x-queue-count"),
+            size: header("x-queue-size"),
+            video_count: header("x-queue-video-count"),
+            image_count: header("x-queue-image-count"),
+            audio_count: header("x-queue-audio-count"),
+        }
+    }
+}
+
+/// The filters `/random` accepts, the defaults match anything.
+#[derive(Debug, Default, Clone)]
+pub struct RandomFilter {
+    pub kinds: Vec<FileKind>,
+    pub roots: Vec<String>,
+    /// Any directory within an enabled root.
+    pub dir: Option<PathBuf>,
+    pub tags: Vec<String>,
+    pub not_tags: Vec<String>,
+    pub favorites_only: bool,
+    /// 1 to 5.
+    pub min_rating: Option<u8>,
+    /// Name of a saved playlist to play from.
+    pub playlist: Option<String>,
+}
+
+impl RandomFilter {
+    fn query(&self) -> Vec<(&'static str, String)> {
+        let mut query = Vec::new();
+        query.extend(self.kinds.iter().map(|kind| ("kind", kind.as_str().to_owned())));
+        query.extend(self.roots.iter().map(|root| ("root", root.clone())));
+        if let Some(dir) = &self.dir {
+            query.push(("dir", dir.to_string_lossy().into_owned()));
+        }
+        query.extend(self.tags.iter().map(|tag| ("tag", tag.clone())));
+        query.extend(self.not_tags.iter().map(|tag| ("not_tag", tag.clone())));
+        if self.favorites_only {
+            query.push(("favorites_only", "true".to_owned()));
+        }
+        if let Some(min_rating) = self.min_rating {
+            query.push(("min_rating", min_rating.to_string()));
+        }
+        if let Some(playlist) = &self.playlist {
+            query.push(("playlist", playlist.clone()));
+        }
+        query
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    client: cyper::Client,
+    base_url: String,
+    session: Option<String>,
+}
+
+impl Client {
+    /// `base_url` is where the server listens, e.g. `http://tv.local:8080`.
+    pub fn new(base_url: impl Into<String>) -> Self {
+        let mut base_url = base_url.into();
+        if base_url.ends_with('/') {
+            base_url.pop();
+        }
+        Self { client: cyper::Client::new(), base_url, session: None }
+    }
+
+    /// Works on the session's own queue and history instead of the shared queue.
+    pub fn with_session(mut self, session: impl Into<String>) -> Self {
+        self.session = Some(session.into());
+        self
+    }
+
+    /// Where a player fetches `path`, e.g. [`Item::path`].
+    pub fn file_url(&self, path: &str) -> String {
+        self.url(&encode_path("/files", Path::new(path)), &[])
+    }
+
+    pub async fn random(&self, filter: &RandomFilter) -> Result<Item, Error> {
+        self.json(Method::GET, "/random", &filter.query()).await
+    }
+
+    /// Plays a file of the caller's choosing, e.g. from [`Client::search`].
+    pub async fn play(&self, path: &Path) -> Result<Item, Error> {
+        let query = [("path", path.to_string_lossy().into_owned())];
+        self.json(Method::GET, "/play", &query).await
+    }
+
+    pub async fn search(
+        &self,
+        text: &str,
+        kinds: &[FileKind],
+        limit: Option<usize>,
+    ) -> Result<Vec<SearchResult>, Error> {
+        let mut query = vec![("q", text.to_owned())];
+        query.extend(kinds.iter().map(|kind| ("kind", kind.as_str().to_owned())));
+        if let Some(limit) = limit {
+            query.push(("limit", limit.to_string()));
+        }
+        self.json(Method::GET, "/search", &query).await
+    }
+
+    pub async fn queue_info(&self) -> Result<QueueInfo, Error> {
+        let response = self.send(Method::GET, "/queue", &[], None::<&()>).await?;
+        Ok(QueueInfo::from_headers(response.headers()))
+    }
+
+    pub async fn roots(&self) -> Result<Vec<Root>, Error> {
+        self.json(Method::GET, "/roots", &[]).await
+    }
+
+    /// Enables or disables the given roots, the others keep their state.
+    pub async fn set_roots(&self, roots: &[Root]) -> Result<(), Error> {
+        self.send(Method::PATCH, "/roots", &[], Some(roots)).await?;
+        Ok(())
+    }
+
+    pub async fn reset(&self) -> Result<(), Error> {
+        self.send(Method::GET, "/reset", &[], None::<&()>).await?;
+        Ok(())
+    }
+
+    pub async fn shuffle(&self) -> Result<(), Error> {
+        self.send(Method::GET, "/shuffle", &[], None::<&()>).await?;
+        Ok(())
+    }
+
+    /// Tells the server a player is done with `path`, `watched` seconds of `duration` is what
+    /// it learns from.
+    pub async fn close(
+        &self,
+        path: &str,
+        watched: Option<f64>,
+        duration: Option<f64>,
+    ) -> Result<(), Error> {
+        let mut query = Vec::new();
+        if let Some(watched) = watched {
+            query.push(("watched", watched.to_string()));
+        }
+        if let Some(duration) = duration {
+            query.push(("duration", duration.to_string()));
+        }
+        let path = encode_path("/close", Path::new(path));
+        self.send(Method::POST, &path, &query, None::<&()>).await?;
+        Ok(())
+    }
+
+    /// 1 to 5 stars, 0 clears the rating.
+    pub async fn rate(&self, path: &Path, rating: u8) -> Result<PathEntry, Error> {
+        let query = [("rating", rating.to_string())];
+        self.json(Method::POST, &encode_path("/rate", path), &query).await
+    }
+
+    pub async fn set_favorite(&self, path: &Path, favorite: bool) -> Result<PathEntry, Error> {
+        let query = [("value", favorite.to_string())];
+        self.json(Method::POST, &encode_path("/favorite", path), &query).await
+    }
+
+    /// Hidden files are never picked again until unhidden.
+    pub async fn set_hidden(&self, path: &Path, hidden: bool) -> Result<PathEntry, Error> {
+        let query = [("value", hidden.to_string())];
+        self.json(Method::POST, &encode_path("/hide", path), &query).await
+    }
+
+    /// What the session played last, most recent first, always empty without a session.
+    pub async fn history(&self) -> Result<Vec<PathBuf>, Error> {
+        self.json(Method::GET, "/history", &[]).await
+    }
+
+    pub async fn players(&self) -> Result<Vec<PlayerInfo>, Error> {
+        self.json(Method::GET, "/remote/players", &[]).await
+    }
+
+    pub async fn command(&self, player: &str, command: &Command) -> Result<(), Error> {
+        let path = format!("/remote/players/{}", urlencoding::encode(player));
+        self.send(Method::POST, &path, &[], Some(command)).await?;
+        Ok(())
+    }
+
+    fn url(&self, path: &str, query: &[(&str, String)]) -> String {
+        let session = self.session.as_ref().map(|session| ("session", session));
+        let pairs = query
+            .iter()
+            .map(|(key, value)| (*key, value))
+            .chain(session)
+            .map(|(key, value)| format!("{key}={}", urlencoding::encode(value)))
+            .collect::<Vec<_>>();
+
+        if pairs.is_empty() {
+            format!("{}{path}", self.base_url)
+        } else {
+            format!("{}{path}?{}", self.base_url, pairs.join("&"))
+        }
+    }
+
+    async fn send<B>(
+        &self,
+        method: Method,
+        path: &str,
+        query: &[(&str, String)],
+        body: Option<&B>,
+    ) -> Result<cyper::Response, Error>
+    where
+        B: Serialize + ?Sized,
+    {
+        let mut request = self.client.request(method, self.url(path, query))?;
+        if let Some(body) = body {
+            request = request.json(body)?;
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_client_error() || status.is_server_error() {
+            let message = response.text().await.unwrap_or_default();
+            return Err(Error::Status { status, message });
+        }
+        Ok(response)
+    }
+
+    async fn json<T>(
+        &self,
+        method: Method,
+        path: &str,
+        query: &[(&str, String)],
+    ) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        let response = self.send(method, path, query, None::<&()>).await?;
+        Ok(response.json().await?)
+    }
+}
+
+/// `path` appended to `prefix`, with each component percent-encoded.
+fn encode_path(prefix: &str, path: &Path) -> String {
+    let path = path.to_string_lossy();
+    let components = path
+        .split('/')
+        .filter(|component| !component.is_empty())
+        .map(urlencoding::encode)
+        .collect::<Vec<_>>();
+    format!("{prefix}/{}", components.join("/"))
+}
@@ -0,0 +1,565 @@
+use std::fmt;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::raw_preview::{RAF_MAGIC, raf_preview_range};
+use crate::tiff::{Ifd, Tiff};
+
+const TAG_MAKE: u16 = 0x010F;
+const TAG_MODEL: u16 = 0x0110;
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_DATE_TIME: u16 = 0x0132;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_GPS_IFD: u16 = 0x8825;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+const TAG_DATE_TIME_DIGITIZED: u16 = 0x9004;
+const TAG_LENS_MODEL: u16 = 0xA434;
+
+const TAG_GPS_LATITUDE_REF: u16 = 1;
+const TAG_GPS_LATITUDE: u16 = 2;
+const TAG_GPS_LONGITUDE_REF: u16 = 3;
+const TAG_GPS_LONGITUDE: u16 = 4;
+const TAG_GPS_ALTITUDE_REF: u16 = 5;
+const TAG_GPS_ALTITUDE: u16 = 6;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+/// How much of a file [`read_file`] looks at, metadata lives near the start in every format we
+/// support.
+pub const HEAD_SIZE: usize = 1024 * 1024;
+/// The EXIF segment opens a RAF preview, after at most a small JFIF segment.
+const RAF_EXIF_SIZE: usize = 128 * 1024;
+
+/// The EXIF orientation, describing how the stored pixels must be transformed for display.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
+#[repr(u8)]
+pub enum Orientation {
+    #[default]
+    Normal = 1,
+    FlipHorizontal = 2,
+    Rotate180 = 3,
+    FlipVertical = 4,
+    /// Mirrored along the top-left to bottom-right diagonal.
+    Transpose = 5,
+    /// Rotate 90° clockwise.
+    Rotate90 = 6,
+    /// Mirrored along the top-right to bottom-left diagonal.
+    Transverse = 7,
+    /// Rotate 90° counter-clockwise.
+    Rotate270 = 8,
+}
+
+impl Orientation {
+    pub fn from_exif(value: u32) -> Option<Self> {
+        Some(match value {
+            1 => Self::Normal,
+            2 => Self::FlipHorizontal,
+            3 => Self::Rotate180,
+            4 => Self::FlipVertical,
+            5 => Self::Transpose,
+            6 => Self::Rotate90,
+            7 => Self::Transverse,
+            8 => Self::Rotate270,
+            _ => return None,
+        })
+    }
+
+    /// True when the displayed image is taller than it is stored, or vice versa.
+    pub fn swaps_dimensions(self) -> bool {
+        matches!(self, Self::Transpose | Self::Rotate90 | Self::Transverse | Self::Rotate270)
+    }
+}
+
+/// A local date and time without a time zone, as recorded by cameras.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Parses EXIF (`2024:05:17 13:45:00`) and ISO 8601 style (`2024-05-17T13:45`) dates.
+    ///
+    /// Trailing components may be omitted, `2024-05` is the start of May 2024.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.split(['-', ':', ' ', 'T', 't']).filter(|part| !part.is_empty());
+
+        let mut next = |default: u16| -> Option<u16> {
+            match parts.next() {
+                Some(part) => {
+                    // Ignore fractional seconds and time zones.
+                    let end = part.find(|c: char| !c.is_ascii_digit()).unwrap_or(part.len());
+                    part[..end].parse().ok()
+                }
+                None => Some(default),
+            }
+        };
+
+        let date_time = Self {
+            year: next(0)?,
+            month: u8::try_from(next(1)?).ok()?,
+            day: u8::try_from(next(1)?).ok()?,
+            hour: u8::try_from(next(0)?).ok()?,
+            minute: u8::try_from(next(0)?).ok()?,
+            second: u8::try_from(next(0)?).ok()?,
+        };
+
+        // Cameras without a clock write zeros.
+        let is_valid = date_time.year > 0
+            && (1..=12).contains(&date_time.month)
+            && (1..=31).contains(&date_time.day)
+            && date_time.hour < 24
+            && date_time.minute < 60
+            && date_time.second < 61;
+        is_valid.then_some(date_time)
+    }
+}
+
+impl FromStr for DateTime {
+    type Err = InvalidDateTime;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Self::parse(value).ok_or(InvalidDateTime)
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self { year, month, day, hour, minute, second } = self;
+        write!(f, "{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}")
+    }
+}
+
+#[derive(Debug, Copy, Clone, thiserror::Error)]
+#[error("invalid date, expected YYYY-MM-DD with an optional HH:MM:SS time")]
+pub struct InvalidDateTime;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GpsPosition {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Metres above sea level.
+    pub altitude: Option<f64>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Exif {
+    pub orientation: Orientation,
+    pub taken_at: Option<DateTime>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub lens: Option<String>,
+    pub gps: Option<GpsPosition>,
+}
+
+impl Exif {
+    /// Reads EXIF metadata from JPEG, PNG, WebP, TIFF, camera RAW and HEIF data.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.get(4..12) == Some(&b"ftypcrx "[..]) {
+            return Self::from_cr3(data);
+        }
+
+        let tiff = Tiff::parse(find_tiff(data)?)?;
+        let ifd0 = tiff.first_ifd()?;
+        let mut exif = Self::default();
+        exif.read_ifd0(&tiff, &ifd0);
+
+        if let Some(ifd) = sub_ifd(&tiff, &ifd0, TAG_EXIF_IFD) {
+            exif.read_exif_ifd(&tiff, &ifd);
+        }
+        if let Some(ifd) = sub_ifd(&tiff, &ifd0, TAG_GPS_IFD) {
+            exif.read_gps_ifd(&tiff, &ifd);
+        }
+
+        Some(exif)
+    }
+
+    // Canon CR3 splits the EXIF data over separate TIFF structures in `CMT1` to `CMT4` boxes.
+    fn from_cr3(data: &[u8]) -> Option<Self> {
+        let ifd0 = cr3_box(data, b"CMT1").and_then(Tiff::parse)?;
+        let mut exif = Self::default();
+        exif.read_ifd0(&ifd0, &ifd0.first_ifd()?);
+
+        if let Some(tiff) = cr3_box(data, b"CMT2").and_then(Tiff::parse)
+            && let Some(ifd) = tiff.first_ifd()
+        {
+            exif.read_exif_ifd(&tiff, &ifd);
+        }
+        if let Some(tiff) = cr3_box(data, b"CMT4").and_then(Tiff::parse)
+            && let Some(ifd) = tiff.first_ifd()
+        {
+            exif.read_gps_ifd(&tiff, &ifd);
+        }
+
+        Some(exif)
+    }
+
+    fn read_ifd0(&mut self, tiff: &Tiff<'_>, ifd: &Ifd) {
+        self.orientation = ifd
+            .get(TAG_ORIENTATION)
+            .and_then(|entry| tiff.entry_u32(entry, 0))
+            .and_then(Orientation::from_exif)
+            .unwrap_or_default();
+        self.make = ascii(tiff, ifd, TAG_MAKE);
+        self.model = ascii(tiff, ifd, TAG_MODEL);
+        self.taken_at = ascii(tiff, ifd, TAG_DATE_TIME).and_then(|value| DateTime::parse(&value));
+    }
+
+    fn read_exif_ifd(&mut self, tiff: &Tiff<'_>, ifd: &Ifd) {
+        // Prefer when the photo was taken over when the file was last written.
+        let taken_at = [TAG_DATE_TIME_ORIGINAL, TAG_DATE_TIME_DIGITIZED]
+            .into_iter()
+            .find_map(|tag| ascii(tiff, ifd, tag).and_then(|value| DateTime::parse(&value)));
+        if taken_at.is_some() {
+            self.taken_at = taken_at;
+        }
+        self.lens = ascii(tiff, ifd, TAG_LENS_MODEL);
+    }
+
+    fn read_gps_ifd(&mut self, tiff: &Tiff<'_>, ifd: &Ifd) {
+        let coordinate = |value_tag: u16, ref_tag: u16, negative: &str| -> Option<f64> {
+            let entry = ifd.get(value_tag)?;
+            let degrees = tiff.entry_rational(entry, 0)?;
+            let minutes = tiff.entry_rational(entry, 1).unwrap_or(0.0);
+            let seconds = tiff.entry_rational(entry, 2).unwrap_or(0.0);
+            let value = degrees + minutes / 60.0 + seconds / 3600.0;
+
+            let is_negative = ascii(tiff, ifd, ref_tag).is_some_and(|r| r == negative);
+            Some(if is_negative { -value } else { value })
+        };
+
+        let Some(latitude) = coordinate(TAG_GPS_LATITUDE, TAG_GPS_LATITUDE_REF, "S") else {
+            return;
+        };
+        let Some(longitude) = coordinate(TAG_GPS_LONGITUDE, TAG_GPS_LONGITUDE_REF, "W") else {
+            return;
+        };
+        // Cameras without a fix often write zeros rather than leaving the tags out.
+        if latitude == 0.0 && longitude == 0.0 {
+            return;
+        }
+
+        let altitude = ifd.get(TAG_GPS_ALTITUDE).and_then(|entry| tiff.entry_rational(entry, 0));
+        let below_sea_level = ifd
+            .get(TAG_GPS_ALTITUDE_REF)
+            .and_then(|entry| tiff.entry_u32(entry, 0))
+            .is_some_and(|value| value == 1);
+        let altitude = altitude.map(|altitude| if below_sea_level { -altitude } else { altitude });
+
+        self.gps = Some(GpsPosition { latitude, longitude, altitude });
+    }
+}
+
+/// Reads the EXIF metadata from the start of a file.
+pub fn read_file(path: &Path) -> std::io::Result<Option<Exif>> {
+    let mut file = std::fs::File::open(path)?;
+    let mut data = Vec::with_capacity(HEAD_SIZE);
+    (&file).take(HEAD_SIZE as u64).read_to_end(&mut data)?;
+
+    if let Some(range) = raf_exif_range(&data) {
+        data.clear();
+        file.seek(SeekFrom::Start(range.start))?;
+        file.take(range.end - range.start).read_to_end(&mut data)?;
+    }
+    Ok(Exif::parse(&data))
+}
+
+/// Fujifilm RAF keeps its EXIF in the embedded preview, which large files put past
+/// [`HEAD_SIZE`]. Returns the range to read and pass to [`Exif::parse`] instead of `head`, or
+/// `None` when `head` already holds it.
+pub fn raf_exif_range(head: &[u8]) -> Option<Range<u64>> {
+    let preview = raf_preview_range(head)?;
+    let end = preview.end.min(preview.start.saturating_add(RAF_EXIF_SIZE));
+    (end > head.len()).then_some(preview.start as u64..end as u64)
+}
+
+fn ascii(tiff: &Tiff<'_>, ifd: &Ifd, tag: u16) -> Option<String> {
+    ifd.get(tag).and_then(|entry| tiff.entry_ascii(entry)).map(str::to_owned)
+}
+
+fn sub_ifd(tiff: &Tiff<'_>, ifd: &Ifd, tag: u16) -> Option<Ifd> {
+    let offset = ifd.get(tag).and_then(|entry| tiff.entry_u32(entry, 0))?;
+    tiff.ifd_at(offset as usize)
+}
+
+/// Finds the TIFF structure holding the EXIF data.
+fn find_tiff(data: &[u8]) -> Option<&[u8]> {
+    if data.starts_with(&[0xFF, 0xD8]) {
+        return jpeg_exif(data);
+    }
+    if data.starts_with(PNG_SIGNATURE) {
+        return png_exif(data);
+    }
+    if data.starts_with(b"RIFF") && data.get(8..12) == Some(&b"WEBP"[..]) {
+        return webp_exif(data);
+    }
+    if data.starts_with(RAF_MAGIC) {
+        // Only the start of the preview is needed, it may be cut off by the read.
+        let preview = raf_preview_range(data)?;
+        return jpeg_exif(data.get(preview.start..preview.end.min(data.len()))?);
+    }
+    if Tiff::parse(data).is_some() {
+        return Some(data);
+    }
+
+    // HEIF stores EXIF as an item somewhere in `mdat`, prefixed with the usual header.
+    let index = memchr::memmem::find(data, EXIF_HEADER)?;
+    let tiff = &data[index + EXIF_HEADER.len()..];
+    Tiff::parse(tiff).is_some().then_some(tiff)
+}
+
+fn jpeg_exif(data: &[u8]) -> Option<&[u8]> {
+    let mut offset = 2;
+    loop {
+        if *data.get(offset)? != 0xFF {
+            return None;
+        }
+        let marker = *data.get(offset + 1)?;
+        match marker {
+            // Fill byte.
+            0xFF => {
+                offset += 1;
+                continue;
+            }
+            // Start of scan or end of image, metadata always comes before.
+            0xD9 | 0xDA => return None,
+            // Markers without a length.
+            0x01 | 0xD0..=0xD7 => {
+                offset += 2;
+                continue;
+            }
+            _ => {}
+        }
+
+        let length = u16::from_be_bytes(data.get(offset + 2..offset + 4)?.try_into().ok()?);
+        let segment = data.get(offset + 4..offset + 2 + length as usize)?;
+        if marker == 0xE1
+            && let Some(tiff) = segment.strip_prefix(EXIF_HEADER)
+        {
+            return Some(tiff);
+        }
+
+        offset += 2 + length as usize;
+    }
+}
+
+fn png_exif(data: &[u8]) -> Option<&[u8]> {
+    let mut offset = PNG_SIGNATURE.len();
+    loop {
+        let length = u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?) as usize;
+        let chunk_type = data.get(offset + 4..offset + 8)?;
+        let chunk = data.get(offset + 8..(offset + 8).checked_add(length)?)?;
+
+        match chunk_type {
+            b"eXIf" => return Some(chunk),
+            b"IEND" => return None,
+            _ => {}
+        }
+
+        // Length, type, data and CRC.
+        offset += 12 + length;
+    }
+}
+
+fn webp_exif(data: &[u8]) -> Option<&[u8]> {
+    let mut offset = 12;
+    loop {
+        let chunk_type = data.get(offset..offset + 4)?;
+        let length =
+            u32::from_le_bytes(data.get(offset + 4..offset + 8)?.try_into().ok()?) as usize;
+        let chunk = data.get(offset + 8..(offset + 8).checked_add(length)?)?;
+
+        if chunk_type == b"EXIF" {
+            // Some encoders keep the JPEG style header.
+            return Some(chunk.strip_prefix(EXIF_HEADER).unwrap_or(chunk));
+        }
+
+        // Chunks are padded to an even length.
+        offset += 8 + length + (length & 1);
+    }
+}
+
+fn cr3_box<'a>(data: &'a [u8], box_type: &[u8; 4]) -> Option<&'a [u8]> {
+    let box_type_index = memchr::memmem::find(data, box_type)?;
+    let box_start = box_type_index.checked_sub(4)?;
+    let box_size = u32::from_be_bytes(data.get(box_start..box_type_index)?.try_into().ok()?);
+    let box_end = box_start.checked_add(box_size as usize)?.min(data.len());
+    data.get(box_type_index + 4..box_end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiff::tests::IfdBuilder;
+
+    fn sample_tiff(big_endian: bool) -> Vec<u8> {
+        IfdBuilder::default()
+            .ascii(TAG_MAKE, "FUJIFILM")
+            .ascii(TAG_MODEL, "X-T5")
+            .short(TAG_ORIENTATION, &[6])
+            .ascii(TAG_DATE_TIME, "2024:06:01 09:00:00")
+            .ifd(
+                TAG_EXIF_IFD,
+                IfdBuilder::default()
+                    .ascii(TAG_DATE_TIME_ORIGINAL, "2024:05:17 13:45:00")
+                    .ascii(TAG_LENS_MODEL, "XF23mmF1.4 R LM WR"),
+            )
+            .ifd(
+                TAG_GPS_IFD,
+                IfdBuilder::default()
+                    .ascii(TAG_GPS_LATITUDE_REF, "S")
+                    .rational(TAG_GPS_LATITUDE, &[(33, 1), (51, 1), (36, 1)])
+                    .ascii(TAG_GPS_LONGITUDE_REF, "E")
+                    .rational(TAG_GPS_LONGITUDE, &[(151, 1), (12, 1), (0, 1)])
+                    .rational(TAG_GPS_ALTITUDE, &[(25, 2)]),
+            )
+            .build(big_endian)
+    }
+
+    fn jpeg(tiff: &[u8]) -> Vec<u8> {
+        let mut data = vec![0xFF, 0xD8];
+        // A JFIF segment first, as most encoders write.
+        data.extend([0xFF, 0xE0, 0, 4, 0, 0]);
+        data.extend([0xFF, 0xE1]);
+        data.extend(((2 + EXIF_HEADER.len() + tiff.len()) as u16).to_be_bytes());
+        data.extend(EXIF_HEADER);
+        data.extend(tiff);
+        data.extend([0xFF, 0xDA, 0, 2]);
+        data
+    }
+
+    fn png(tiff: &[u8]) -> Vec<u8> {
+        let mut data = PNG_SIGNATURE.to_vec();
+        for (chunk_type, chunk) in [(b"IHDR", &[0; 13][..]), (b"eXIf", tiff), (b"IEND", &[])] {
+            data.extend((chunk.len() as u32).to_be_bytes());
+            data.extend(chunk_type);
+            data.extend(chunk);
+            data.extend([0; 4]);
+        }
+        data
+    }
+
+    fn assert_sample(exif: Option<Exif>) {
+        let exif = exif.unwrap();
+        assert_eq!(exif.orientation, Orientation::Rotate90);
+        assert_eq!(exif.make.as_deref(), Some("FUJIFILM"));
+        assert_eq!(exif.model.as_deref(), Some("X-T5"));
+        assert_eq!(exif.lens.as_deref(), Some("XF23mmF1.4 R LM WR"));
+        // The original date wins over the modification date.
+        assert_eq!(exif.taken_at, DateTime::parse("2024-05-17T13:45:00"));
+
+        let gps = exif.gps.unwrap();
+        assert!((gps.latitude + 33.86).abs() < 1e-9);
+        assert!((gps.longitude - 151.2).abs() < 1e-9);
+        assert_eq!(gps.altitude, Some(12.5));
+    }
+
+    #[test]
+    fn reads_tiff_in_both_byte_orders() {
+        assert_sample(Exif::parse(&sample_tiff(false)));
+        assert_sample(Exif::parse(&sample_tiff(true)));
+    }
+
+    #[test]
+    fn reads_jpeg() {
+        assert_sample(Exif::parse(&jpeg(&sample_tiff(true))));
+    }
+
+    #[test]
+    fn reads_png() {
+        assert_sample(Exif::parse(&png(&sample_tiff(false))));
+    }
+
+    #[test]
+    fn reads_raf_preview() {
+        let preview = jpeg(&sample_tiff(true));
+        let mut data = RAF_MAGIC.to_vec();
+        data.resize(84, 0);
+        data.extend(256u32.to_be_bytes());
+        data.extend((preview.len() as u32).to_be_bytes());
+        data.resize(256, 0);
+        data.extend(&preview);
+
+        assert_eq!(raf_exif_range(&data), None);
+        assert_sample(Exif::parse(&data));
+
+        // A head that stops before the preview, the range covers only its start.
+        let head = &data[..200];
+        assert_eq!(raf_exif_range(head), Some(256..256 + preview.len() as u64));
+        assert_eq!(Exif::parse(head), None);
+        assert_sample(Exif::parse(&preview));
+    }
+
+    #[test]
+    fn caps_raf_exif_range() {
+        let mut head = RAF_MAGIC.to_vec();
+        head.resize(84, 0);
+        head.extend((8u32 << 20).to_be_bytes());
+        head.extend((4u32 << 20).to_be_bytes());
+        let start = 8u64 << 20;
+        assert_eq!(raf_exif_range(&head), Some(start..start + RAF_EXIF_SIZE as u64));
+    }
+
+    #[test]
+    fn handles_truncated_input() {
+        let data = jpeg(&sample_tiff(false));
+        for end in [0, 1, 2, 5, 8, 12, 20, data.len() - 10] {
+            // Every cut leaves the APP1 segment incomplete.
+            let exif = Exif::parse(&data[..end]);
+            assert!(exif.is_none(), "parsed {end} bytes");
+        }
+
+        let data = png(&sample_tiff(false));
+        assert_eq!(Exif::parse(&data[..data.len() - 40]), None);
+    }
+
+    #[test]
+    fn rejects_malformed_lengths() {
+        // An APP1 length shorter than its own header.
+        let mut data = jpeg(&sample_tiff(false));
+        data[10..12].copy_from_slice(&1u16.to_be_bytes());
+        assert_eq!(Exif::parse(&data), None);
+
+        // A chunk length that overflows.
+        let mut data = PNG_SIGNATURE.to_vec();
+        data.extend(u32::MAX.to_be_bytes());
+        data.extend(b"eXIf");
+        assert_eq!(Exif::parse(&data), None);
+    }
+
+    #[test]
+    fn ignores_zero_gps() {
+        let data = IfdBuilder::default()
+            .ifd(
+                TAG_GPS_IFD,
+                IfdBuilder::default()
+                    .rational(TAG_GPS_LATITUDE, &[(0, 1)])
+                    .rational(TAG_GPS_LONGITUDE, &[(0, 1)]),
+            )
+            .build(false);
+        let exif = Exif::parse(&data).unwrap();
+        assert_eq!(exif.gps, None);
+        assert_eq!(exif.orientation, Orientation::Normal);
+    }
+
+    #[test]
+    fn parses_dates() {
+        let date = DateTime::parse("2024:05:17 13:45:07").unwrap();
+        assert_eq!(date.to_string(), "2024-05-17T13:45:07");
+        assert_eq!(DateTime::parse("2024-05").unwrap().to_string(), "2024-05-01T00:00:00");
+        assert_eq!(
+            DateTime::parse("2024-05-17T13:45:07.123+02:00").unwrap().to_string(),
+            "2024-05-17T13:45:07"
+        );
+        assert_eq!(DateTime::parse("0000:00:00 00:00:00"), None);
+        assert_eq!(DateTime::parse("2024-13-01"), None);
+        assert_eq!(DateTime::parse("garbage"), None);
+    }
+}
@@ -0,0 +1,130 @@
+use std::path::PathBuf;
+
+use axum::response::{Html, IntoResponse, Json};
+use serde::Serialize;
+use z_play::storage_class::StorageClass;
+use z_play::walkdir;
+
+use super::file_cache::FileCacheStatus;
+use super::playlist::PlaylistStatus;
+use super::queue::QueueStats;
+use super::transcode::{self, DetectedGpu};
+use super::{DIR_COUNTS, FILE_CACHE, PLAYLISTS, QUEUE, browse};
+
+#[derive(Debug, Serialize)]
+struct StatusJson {
+    roots: Vec<RootStatus>,
+    walks: Vec<WalkStatus>,
+    /// `None` until the watcher thread has started, or if inotify failed.
+    watched_dirs: Option<usize>,
+    devices: Vec<DeviceStatus>,
+    file_cache: FileCacheStatus,
+    playlists: Vec<PlaylistStatus>,
+    gpu: DetectedGpu,
+    vram_free_mb: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct RootStatus {
+    path: PathBuf,
+    enabled: bool,
+    counts: QueueStats,
+}
+
+#[derive(Debug, Serialize)]
+struct WalkStatus {
+    roots: Vec<PathBuf>,
+    running_secs: f64,
+    dirs: u64,
+    entries: u64,
+    pending: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct DeviceStatus {
+    /// `major:minor`.
+    device: String,
+    #[serde(flatten)]
+    class: ClassJson,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "class", rename_all = "snake_case")]
+enum ClassJson {
+    Hdd {
+        /// The whole disk the device is a partition of, walks share one reader per disk.
+        disk: String,
+    },
+    SsdOrUnknown,
+}
+
+impl From<StorageClass> for ClassJson {
+    fn from(class: StorageClass) -> Self {
+        match class {
+            StorageClass::Hdd { major, minor } => Self::Hdd { disk: format!("{major}:{minor}") },
+            StorageClass::SsdOrUnknown => Self::SsdOrUnknown,
+        }
+    }
+}
+
+/// Roots, walks, caches and transcodes in one place, for working out why the server is slow.
+pub async fn status_handler() -> impl IntoResponse {
+    let queue = QUEUE.get().unwrap();
+    let enabled_roots = queue.enabled_roots().read_async().await.clone();
+    let disabled_roots = queue.disabled_roots().read_async().await.clone();
+
+    let all_roots = enabled_roots.iter().chain(&disabled_roots).cloned().collect::<Vec<_>>();
+    let counts = browse::recursive_counts(&all_roots);
+    let roots = enabled_roots
+        .into_iter()
+        .map(|path| (path, true))
+        .chain(disabled_roots.into_iter().map(|path| (path, false)))
+        .map(|(path, enabled)| {
+            let counts = counts.get(&path).copied().unwrap_or_default();
+            RootStatus { path, enabled, counts }
+        })
+        .collect();
+
+    let walks = walkdir::walks()
+        .into_iter()
+        .map(|walk| WalkStatus {
+            roots: walk.roots,
+            running_secs: walk.started.elapsed().as_secs_f64(),
+            dirs: walk.dirs,
+            entries: walk.entries,
+            pending: walk.pending,
+        })
+        .collect();
+
+    let devices = walkdir::device_classes()
+        .into_iter()
+        .map(|(device, class)| DeviceStatus {
+            device: format!("{}:{}", rustix::fs::major(device), rustix::fs::minor(device)),
+            class: class.into(),
+        })
+        .collect();
+
+    // The cache, playlists and GPU monitor belong to the server thread and aren't Send.
+    let (file_cache, playlists, gpu, vram_free_mb) = compio::runtime::spawn(async {
+        let file_cache = FILE_CACHE.status();
+        let playlists = PLAYLISTS.get().unwrap().status();
+        (file_cache, playlists, transcode::detected_gpu(), transcode::available_vram_mb().await)
+    })
+    .await
+    .unwrap();
+
+    Json(StatusJson {
+        roots,
+        walks,
+        watched_dirs: DIR_COUNTS.inotify.get().map(|inotify| inotify.watch_count()),
+        devices,
+        file_cache,
+        playlists,
+        gpu,
+        vram_free_mb,
+    })
+}
+
+pub async fn status_page() -> Html<&'static str> {
+    Html(include_str!("admin.html"))
+}
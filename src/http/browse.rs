@@ -0,0 +1,286 @@
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use axum::extract;
+use axum::response::{IntoResponse, Json, Response};
+use axum_extra::extract::Query;
+use camino::Utf8Path;
+use http::StatusCode;
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use super::queue::QueueStats;
+use super::session::{SessionParams, SessionQueue};
+use super::{DIR_COUNTS, FileKind, STORE};
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum BrowseSort {
+    #[default]
+    Name,
+    /// Bytes for files, number of playable files for directories.
+    Size,
+    Modified,
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct BrowseQuery {
+    #[serde(default)]
+    sort: BrowseSort,
+    #[serde(default)]
+    desc: bool,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+impl BrowseQuery {
+    const DEFAULT_LIMIT: usize = 200;
+    const MAX_LIMIT: usize = 1000;
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BrowseEntry {
+    Dir {
+        name: String,
+        #[schema(value_type = String)]
+        path: PathBuf,
+        /// Everything below it, not just its direct children.
+        counts: QueueStats,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        modified: Option<u64>,
+    },
+    File {
+        name: String,
+        #[schema(value_type = String)]
+        path: PathBuf,
+        kind: FileKind,
+        size: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        modified: Option<u64>,
+    },
+}
+
+impl BrowseEntry {
+    fn name(&self) -> &str {
+        match self {
+            Self::Dir { name, .. } | Self::File { name, .. } => name,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match self {
+            Self::Dir { counts, .. } => counts.total() as u64,
+            Self::File { size, .. } => *size,
+        }
+    }
+
+    fn modified(&self) -> Option<u64> {
+        match self {
+            Self::Dir { modified, .. } | Self::File { modified, .. } => *modified,
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        matches!(self, Self::Dir { .. })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+struct BrowseResponse {
+    /// Empty when listing the roots.
+    #[schema(value_type = String)]
+    path: PathBuf,
+    counts: QueueStats,
+    total: usize,
+    offset: usize,
+    entries: Vec<BrowseEntry>,
+}
+
+/// Lists the enabled roots.
+#[utoipa::path(
+    get,
+    path = "/browse",
+    tag = "library",
+    params(SessionParams, BrowseQuery),
+    responses((status = OK, body = BrowseResponse)),
+)]
+pub async fn browse_roots(queue: SessionQueue, Query(query): Query<BrowseQuery>) -> Response {
+    let roots = queue.enabled_roots().read_async().await.clone();
+    let counts = recursive_counts(&roots);
+
+    let mut total_counts = QueueStats::default();
+    let entries = roots
+        .into_iter()
+        .map(|root| {
+            let root_counts = counts.get(&root).copied().unwrap_or_default();
+            total_counts.merge(&root_counts);
+            BrowseEntry::Dir {
+                name: root.display().to_string(),
+                modified: modified(&root),
+                path: root,
+                counts: root_counts,
+            }
+        })
+        .collect();
+
+    Json(paginate(PathBuf::new(), total_counts, entries, &query)).into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/browse/{path}",
+    tag = "library",
+    params(("path" = String, Path, description = "Absolute path without the leading slash"), BrowseQuery),
+    responses(
+        (status = OK, body = BrowseResponse),
+        (status = NOT_FOUND, description = "Not a directory in an enabled root"),
+    ),
+)]
+pub async fn browse_dir(
+    extract::Path(path): extract::Path<String>,
+    Query(query): Query<BrowseQuery>,
+) -> Response {
+    let path = Utf8Path::new("/").join(path).into_std_path_buf();
+    // Listed paths are compared with the canonical walked ones.
+    let Ok(path) = path.canonicalize() else { return StatusCode::NOT_FOUND.into_response() };
+
+    let path_clone = path.clone();
+    let read = compio::runtime::spawn(async move {
+        compio::runtime::spawn_blocking(move || read_entries(&path_clone))
+            .await
+            .unwrap()
+    })
+    .await
+    .unwrap();
+    let (dirs, mut entries) = match read {
+        Ok(read) => read,
+        Err(error)
+            if matches!(
+                error.kind(),
+                std::io::ErrorKind::NotFound | std::io::ErrorKind::NotADirectory
+            ) =>
+        {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+        Err(error) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()).into_response();
+        }
+    };
+
+    let mut scanned = dirs.iter().map(|(dir, _)| dir.clone()).collect::<Vec<_>>();
+    scanned.push(path.clone());
+    let counts = recursive_counts(&scanned);
+
+    entries.extend(dirs.into_iter().map(|(dir, modified)| BrowseEntry::Dir {
+        name: file_name(&dir),
+        counts: counts.get(&dir).copied().unwrap_or_default(),
+        path: dir,
+        modified,
+    }));
+
+    let dir_counts = counts.get(&path).copied().unwrap_or_default();
+    Json(paginate(path, dir_counts, entries, &query)).into_response()
+}
+
+/// Playable files and subdirectories of `dir`, directories are returned separately so their
+/// counts can be filled in afterwards.
+pub fn read_entries(
+    dir: &Path,
+) -> Result<(Vec<(PathBuf, Option<u64>)>, Vec<BrowseEntry>), std::io::Error> {
+    let store = STORE.get().unwrap();
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let Ok(entry) = entry else { continue };
+        let path = entry.path();
+        // Follows symlinks, the walk does too.
+        let Ok(metadata) = std::fs::metadata(&path) else { continue };
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_secs());
+
+        if metadata.is_dir() {
+            dirs.push((path, modified));
+        } else if let Some(kind) = FileKind::from_path(&path)
+            && !store.is_hidden(&path)
+        {
+            files.push(BrowseEntry::File {
+                name: file_name(&path),
+                path,
+                kind,
+                size: metadata.len(),
+                modified,
+            });
+        }
+    }
+
+    Ok((dirs, files))
+}
+
+/// Sums the direct counts of every directory below each of `dirs`, in one pass over the
+/// counted directories that looks each of their ancestors up.
+pub fn recursive_counts(dirs: &[PathBuf]) -> FxHashMap<PathBuf, QueueStats> {
+    let mut counts = dirs
+        .iter()
+        .map(|dir| (dir.as_path(), QueueStats::default()))
+        .collect::<FxHashMap<_, _>>();
+    {
+        let dir_counts = DIR_COUNTS.dir_counts.read();
+        for (path, path_counts) in dir_counts.iter() {
+            for ancestor in path.ancestors() {
+                if let Some(total) = counts.get_mut(ancestor) {
+                    total.merge(path_counts);
+                }
+            }
+        }
+    }
+    counts.into_iter().map(|(dir, counts)| (dir.to_owned(), counts)).collect()
+}
+
+fn paginate(
+    path: PathBuf,
+    counts: QueueStats,
+    mut entries: Vec<BrowseEntry>,
+    query: &BrowseQuery,
+) -> BrowseResponse {
+    entries.sort_unstable_by(|a, b| {
+        let ordering = match query.sort {
+            BrowseSort::Name => compare_names(a.name(), b.name()),
+            BrowseSort::Size => a.size().cmp(&b.size()),
+            BrowseSort::Modified => a.modified().cmp(&b.modified()),
+        }
+        .then_with(|| compare_names(a.name(), b.name()));
+        let ordering = if query.desc { ordering.reverse() } else { ordering };
+        // Directories always come first.
+        b.is_dir().cmp(&a.is_dir()).then(ordering)
+    });
+
+    let total = entries.len();
+    let limit = query.limit.unwrap_or(BrowseQuery::DEFAULT_LIMIT).min(BrowseQuery::MAX_LIMIT);
+    let entries = entries.into_iter().skip(query.offset).take(limit).collect();
+
+    BrowseResponse { path, counts, total, offset: query.offset, entries }
+}
+
+pub fn compare_names(a: &str, b: &str) -> Ordering {
+    a.to_lowercase().cmp(&b.to_lowercase()).then_with(|| a.cmp(b))
+}
+
+fn modified(path: &Path) -> Option<u64> {
+    let modified = std::fs::metadata(path).ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+pub fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
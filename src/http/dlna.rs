@@ -0,0 +1,642 @@
+//! UPnP MediaServer, so smart TVs and other DLNA renderers can browse the roots and play from the
+//! shared queue without the web UI.
+//!
+//! Object ids are the absolute paths of directories and files, apart from `0`, the root
+//! container, and [`RANDOM_ID`], whose children are popped from the [`SESSION_ID`] session's queue
+//! on every browse.
+
+mod ssdp;
+
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use axum::Router;
+use axum::extract::{self, Request};
+use axum::http::header::{CONTENT_TYPE, HOST};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{any, get, post};
+use rand::RngExt;
+
+use self::ssdp::Ssdp;
+use super::browse::{self, BrowseEntry};
+use super::image::needs_conversion;
+use super::{DLNA, FileKind, METADATA, QUEUE, SESSIONS, SHUTDOWN, STORE};
+
+const ROOT_ID: &str = "0";
+const RANDOM_ID: &str = "random";
+/// Children of the random container per browse, renderers page through it like any other.
+const RANDOM_COUNT: usize = 50;
+// A narrow root selection can run the queue dry, return what was found by then.
+const RANDOM_ITEM_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_BROWSE_COUNT: usize = 200;
+/// Renderers pop from their own session, so browsing doesn't drain the web UI's shared queue.
+const SESSION_ID: &str = "dlna";
+
+const DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:MediaServer:1";
+const CONTENT_DIRECTORY: &str = "urn:schemas-upnp-org:service:ContentDirectory:1";
+const CONNECTION_MANAGER: &str = "urn:schemas-upnp-org:service:ConnectionManager:1";
+
+const XML_CONTENT_TYPE: &str = "text/xml; charset=\"utf-8\"";
+
+pub struct Dlna {
+    uuid: String,
+    name: String,
+    ssdp: Ssdp,
+}
+
+impl Dlna {
+    const UUID_FILE_NAME: &'static str = "dlna_uuid";
+
+    /// The device uuid is kept in `data_dir`, renderers remember servers by it.
+    pub fn new(data_dir: &Path, port: u16) -> Self {
+        let file_path = data_dir.join(Self::UUID_FILE_NAME);
+        let uuid = match std::fs::read_to_string(&file_path) {
+            Ok(uuid) => uuid.trim().to_owned(),
+            Err(error) => {
+                if error.kind() != std::io::ErrorKind::NotFound {
+                    tracing::error!(
+                        path = %file_path.display(),
+                        %error,
+                        "Failed to read DLNA uuid"
+                    );
+                }
+                let uuid = random_uuid();
+                let written = std::fs::create_dir_all(data_dir)
+                    .and_then(|()| std::fs::write(&file_path, &uuid));
+                if let Err(error) = written {
+                    tracing::error!(
+                        path = %file_path.display(),
+                        %error,
+                        "Failed to write DLNA uuid"
+                    );
+                }
+                uuid
+            }
+        };
+        let name = std::env::var("Z_PLAY_DLNA_NAME").unwrap_or_else(|_| "Z-Play".to_owned());
+
+        let ssdp = Ssdp::new(uuid.clone(), port);
+        Self { uuid, name, ssdp }
+    }
+
+    /// Answers searches and announces the server until shutdown or the socket fails.
+    pub fn run_ssdp(&self) -> std::io::Result<()> {
+        self.ssdp.run(|| SHUTDOWN.is_requested())
+    }
+}
+
+// Version 4, from random bytes.
+fn random_uuid() -> String {
+    let mut bytes: [u8; 16] = rand::rng().random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex = bytes.iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+pub fn routes() -> Router {
+    Router::new()
+        .route("/dlna/description.xml", get(description_handler))
+        .route(
+            "/dlna/content_directory.xml",
+            get(|| async { xml_response(include_str!("dlna/content_directory.xml").to_owned()) }),
+        )
+        .route(
+            "/dlna/connection_manager.xml",
+            get(|| async { xml_response(include_str!("dlna/connection_manager.xml").to_owned()) }),
+        )
+        .route("/dlna/control/{service}", post(control_handler))
+        .route("/dlna/event/{service}", any(event_handler))
+}
+
+fn xml_response(xml: String) -> Response {
+    ([(CONTENT_TYPE, XML_CONTENT_TYPE)], xml).into_response()
+}
+
+fn escape(text: &str) -> Cow<'_, str> {
+    if !text.contains(['&', '<', '>', '"', '\'']) {
+        return Cow::Borrowed(text);
+    }
+
+    let mut escaped = String::with_capacity(text.len() + 16);
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+fn unescape(text: &str) -> Cow<'_, str> {
+    if !text.contains('&') {
+        return Cow::Borrowed(text);
+    }
+    Cow::Owned(
+        text.replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&amp;", "&"),
+    )
+}
+
+async fn description_handler() -> Response {
+    let Dlna { uuid, name, .. } = DLNA.get().unwrap();
+    let name = escape(name);
+    let version = env!("CARGO_PKG_VERSION");
+
+    xml_response(format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0" xmlns:dlna="urn:schemas-dlna-org:device-1-0">
+  <specVersion><major>1</major><minor>0</minor></specVersion>
+  <device>
+    <deviceType>{DEVICE_TYPE}</deviceType>
+    <friendlyName>{name}</friendlyName>
+    <manufacturer>Z-Play</manufacturer>
+    <modelName>Z-Play</modelName>
+    <modelNumber>{version}</modelNumber>
+    <UDN>uuid:{uuid}</UDN>
+    <dlna:X_DLNADOC>DMS-1.50</dlna:X_DLNADOC>
+    <serviceList>
+      <service>
+        <serviceType>{CONTENT_DIRECTORY}</serviceType>
+        <serviceId>urn:upnp-org:serviceId:ContentDirectory</serviceId>
+        <SCPDURL>/dlna/content_directory.xml</SCPDURL>
+        <controlURL>/dlna/control/content_directory</controlURL>
+        <eventSubURL>/dlna/event/content_directory</eventSubURL>
+      </service>
+      <service>
+        <serviceType>{CONNECTION_MANAGER}</serviceType>
+        <serviceId>urn:upnp-org:serviceId:ConnectionManager</serviceId>
+        <SCPDURL>/dlna/connection_manager.xml</SCPDURL>
+        <controlURL>/dlna/control/connection_manager</controlURL>
+        <eventSubURL>/dlna/event/connection_manager</eventSubURL>
+      </service>
+    </serviceList>
+  </device>
+</root>
+"#
+    ))
+}
+
+/// UPnP error codes, see the UPnP Device Architecture and ContentDirectory specs.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum UpnpError {
+    InvalidAction,
+    InvalidArgs,
+    NoSuchObject,
+}
+
+impl UpnpError {
+    fn code(self) -> u16 {
+        match self {
+            Self::InvalidAction => 401,
+            Self::InvalidArgs => 402,
+            Self::NoSuchObject => 701,
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Self::InvalidAction => "Invalid Action",
+            Self::InvalidArgs => "Invalid Args",
+            Self::NoSuchObject => "No such object",
+        }
+    }
+}
+
+impl IntoResponse for UpnpError {
+    fn into_response(self) -> Response {
+        let body = format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body><s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring><detail><UPnPError xmlns="urn:schemas-upnp-org:control-1-0"><errorCode>{}</errorCode><errorDescription>{}</errorDescription></UPnPError></detail></s:Fault></s:Body></s:Envelope>
+"#,
+            self.code(),
+            self.description()
+        );
+        (StatusCode::INTERNAL_SERVER_ERROR, [(CONTENT_TYPE, XML_CONTENT_TYPE)], body)
+            .into_response()
+    }
+}
+
+/// The text of the first `<name>` element, whatever its namespace prefix, arguments are plain
+/// enough that this beats pulling in an XML parser.
+fn argument<'a>(body: &'a str, name: &str) -> Option<Cow<'a, str>> {
+    let mut rest = body;
+    loop {
+        let start = rest.find('<')?;
+        rest = &rest[start + 1..];
+        let end = rest.find('>')?;
+        let tag = &rest[..end];
+        rest = &rest[end + 1..];
+
+        let tag_name = tag.split_ascii_whitespace().next().unwrap_or_default();
+        let local_name = tag_name.rsplit(':').next().unwrap_or_default();
+        if local_name != name {
+            continue;
+        }
+        if tag.ends_with('/') {
+            return Some(Cow::Borrowed(""));
+        }
+        let close = rest.find(&format!("</{tag_name}>"))?;
+        return Some(unescape(&rest[..close]));
+    }
+}
+
+fn soap_response(service: &str, action: &str, arguments: &[(&str, &str)]) -> Response {
+    let mut body = String::from(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body>"#,
+    );
+    _ = write!(body, r#"<u:{action}Response xmlns:u="{service}">"#);
+    for (name, value) in arguments {
+        _ = write!(body, "<{name}>{}</{name}>", escape(value));
+    }
+    _ = writeln!(body, "</u:{action}Response></s:Body></s:Envelope>");
+    xml_response(body)
+}
+
+async fn control_handler(
+    extract::Path(service): extract::Path<String>,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    // `"urn:schemas-upnp-org:service:ContentDirectory:1#Browse"`, quotes included.
+    let Some(action) = headers
+        .get("soapaction")
+        .and_then(|action| action.to_str().ok())
+        .and_then(|action| action.trim_matches('"').split_once('#'))
+        .map(|(_, action)| action.to_owned())
+    else {
+        return UpnpError::InvalidAction.into_response();
+    };
+
+    match (service.as_str(), action.as_str()) {
+        ("content_directory", "Browse") => {
+            let Some(host) = headers.get(HOST).and_then(|host| host.to_str().ok()) else {
+                return (StatusCode::BAD_REQUEST, "Missing Host header").into_response();
+            };
+            let Some(request) = BrowseRequest::parse(&body) else {
+                return UpnpError::InvalidArgs.into_response();
+            };
+            let base_url = format!("http://{host}");
+            let result =
+                compio::runtime::spawn(async move { request.browse(&base_url).await }).await;
+            match result.unwrap() {
+                Ok(result) => soap_response(
+                    CONTENT_DIRECTORY,
+                    "Browse",
+                    &[
+                        ("Result", &result.didl),
+                        ("NumberReturned", &result.returned.to_string()),
+                        ("TotalMatches", &result.total.to_string()),
+                        ("UpdateID", "0"),
+                    ],
+                ),
+                Err(error) => error.into_response(),
+            }
+        }
+        ("content_directory", "GetSearchCapabilities") => {
+            soap_response(CONTENT_DIRECTORY, &action, &[("SearchCaps", "")])
+        }
+        ("content_directory", "GetSortCapabilities") => {
+            soap_response(CONTENT_DIRECTORY, &action, &[("SortCaps", "")])
+        }
+        ("content_directory", "GetSystemUpdateID") => {
+            soap_response(CONTENT_DIRECTORY, &action, &[("Id", "0")])
+        }
+        ("connection_manager", "GetProtocolInfo") => {
+            let source = ["video/*", "audio/*", "image/*"]
+                .map(|mime| format!("http-get:*:{mime}:*"))
+                .join(",");
+            soap_response(CONNECTION_MANAGER, &action, &[("Source", &source), ("Sink", "")])
+        }
+        ("connection_manager", "GetCurrentConnectionIDs") => {
+            soap_response(CONNECTION_MANAGER, &action, &[("ConnectionIDs", "0")])
+        }
+        ("connection_manager", "GetCurrentConnectionInfo") => soap_response(
+            CONNECTION_MANAGER,
+            &action,
+            &[
+                ("RcsID", "-1"),
+                ("AVTransportID", "-1"),
+                ("ProtocolInfo", ""),
+                ("PeerConnectionManager", ""),
+                ("PeerConnectionID", "-1"),
+                ("Direction", "Output"),
+                ("Status", "OK"),
+            ],
+        ),
+        ("content_directory" | "connection_manager", _) => UpnpError::InvalidAction.into_response(),
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Subscriptions are accepted so renderers don't give up on the server, but no events are ever
+/// sent, nothing they'd care about changes.
+async fn event_handler(request: Request) -> Response {
+    match request.method().as_str() {
+        "SUBSCRIBE" => {
+            let sid = format!("uuid:{}", random_uuid());
+            (StatusCode::OK, [("sid", sid.as_str()), ("timeout", "Second-1800")]).into_response()
+        }
+        "UNSUBSCRIBE" => StatusCode::OK.into_response(),
+        _ => {
+            (StatusCode::METHOD_NOT_ALLOWED, [("allow", "SUBSCRIBE, UNSUBSCRIBE")]).into_response()
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum BrowseFlag {
+    Metadata,
+    DirectChildren,
+}
+
+struct BrowseRequest {
+    object_id: String,
+    flag: BrowseFlag,
+    start: usize,
+    count: usize,
+}
+
+struct BrowseResult {
+    didl: String,
+    returned: usize,
+    total: usize,
+}
+
+/// An object's place in the tree.
+enum Object {
+    Root,
+    Random,
+    Dir(PathBuf),
+    File(PathBuf),
+}
+
+impl BrowseRequest {
+    fn parse(body: &str) -> Option<Self> {
+        let object_id = argument(body, "ObjectID")?.into_owned();
+        let flag = match argument(body, "BrowseFlag")?.as_ref() {
+            "BrowseMetadata" => BrowseFlag::Metadata,
+            "BrowseDirectChildren" => BrowseFlag::DirectChildren,
+            _ => return None,
+        };
+        let start = argument(body, "StartingIndex").map_or(Some(0), |start| start.parse().ok())?;
+        // 0 asks for everything.
+        let count = argument(body, "RequestedCount").map_or(Some(0), |count| count.parse().ok())?;
+        let count = if count == 0 { DEFAULT_BROWSE_COUNT } else { count };
+
+        Some(Self { object_id, flag, start, count })
+    }
+
+    async fn object(&self) -> Result<Object, UpnpError> {
+        match self.object_id.as_str() {
+            ROOT_ID => return Ok(Object::Root),
+            RANDOM_ID => return Ok(Object::Random),
+            _ => (),
+        }
+
+        let path = PathBuf::from(&self.object_id);
+        let is_normal = path.is_absolute()
+            && path.components().all(|component| !matches!(component, Component::ParentDir));
+        if !is_normal {
+            return Err(UpnpError::NoSuchObject);
+        }
+        // Roots are canonical, so symlinks out of them have to be resolved for the prefix check.
+        let resolved = compio::runtime::spawn_blocking(move || {
+            let path = path.canonicalize()?;
+            let metadata = std::fs::metadata(&path)?;
+            std::io::Result::Ok((path, metadata))
+        })
+        .await
+        .unwrap();
+        let Ok((path, metadata)) = resolved else { return Err(UpnpError::NoSuchObject) };
+
+        let in_root = {
+            let roots = QUEUE.get().unwrap().enabled_roots().read_async().await;
+            roots.iter().any(|root| path.starts_with(root))
+        };
+        if !in_root || STORE.get().unwrap().is_hidden(&path) {
+            return Err(UpnpError::NoSuchObject);
+        }
+
+        if metadata.is_dir() {
+            Ok(Object::Dir(path))
+        } else if metadata.is_file() && FileKind::from_path(&path).is_some() {
+            Ok(Object::File(path))
+        } else {
+            Err(UpnpError::NoSuchObject)
+        }
+    }
+
+    async fn browse(&self, base_url: &str) -> Result<BrowseResult, UpnpError> {
+        let object = self.object().await?;
+        let mut didl = Didl::new(base_url);
+
+        if self.flag == BrowseFlag::Metadata {
+            match object {
+                Object::Root => didl.container(ROOT_ID, "-1", "Z-Play"),
+                Object::Random => didl.container(RANDOM_ID, ROOT_ID, "Random"),
+                Object::Dir(dir) => {
+                    let parent = self.parent_id(&dir).await;
+                    didl.container(&id(&dir), &parent, &title(&dir));
+                }
+                Object::File(path) => {
+                    let parent = self.parent_id(&path).await;
+                    didl.item(&path, &parent);
+                }
+            }
+            return Ok(didl.finish(1));
+        }
+
+        match object {
+            Object::Root => {
+                let roots = QUEUE.get().unwrap().enabled_roots().read_async().await.clone();
+                let mut containers = vec![(RANDOM_ID.to_owned(), "Random".to_owned())];
+                containers.extend(roots.iter().map(|root| (id(root), title(root))));
+
+                let total = containers.len();
+                for (id, title) in containers.iter().skip(self.start).take(self.count) {
+                    didl.container(id, ROOT_ID, title);
+                }
+                Ok(didl.finish(total))
+            }
+            Object::Random => {
+                let session = SESSIONS.get_or_create(SESSION_ID);
+                // Sharing beats showing nothing when every session is in use.
+                let queue = session.as_ref().map_or(QUEUE.get().unwrap(), |session| &session.queue);
+                let count = self.count.min(RANDOM_COUNT.saturating_sub(self.start));
+                for _ in 0..count {
+                    let Ok((path, _)) =
+                        compio::time::timeout(RANDOM_ITEM_TIMEOUT, queue.pop_async(None)).await
+                    else {
+                        break;
+                    };
+                    didl.item(&path, RANDOM_ID);
+                }
+                Ok(didl.finish(RANDOM_COUNT))
+            }
+            Object::Dir(dir) => {
+                let dir_clone = dir.clone();
+                let read =
+                    compio::runtime::spawn_blocking(move || browse::read_entries(&dir_clone))
+                        .await
+                        .unwrap();
+                let Ok((mut dirs, mut files)) = read else {
+                    return Err(UpnpError::NoSuchObject);
+                };
+                dirs.sort_unstable_by(|(a, _), (b, _)| browse::compare_names(&title(a), &title(b)));
+                files.sort_unstable_by(|a, b| browse::compare_names(entry_name(a), entry_name(b)));
+
+                let parent = id(&dir);
+                let total = dirs.len() + files.len();
+                let dirs = dirs.into_iter().map(|(dir, _)| (dir, true));
+                let files = files.into_iter().filter_map(|entry| match entry {
+                    BrowseEntry::File { path, .. } => Some((path, false)),
+                    BrowseEntry::Dir { .. } => None,
+                });
+                for (path, is_dir) in dirs.chain(files).skip(self.start).take(self.count) {
+                    if is_dir {
+                        didl.container(&id(&path), &parent, &title(&path));
+                    } else {
+                        didl.item(&path, &parent);
+                    }
+                }
+                Ok(didl.finish(total))
+            }
+            Object::File(_) => Err(UpnpError::InvalidArgs),
+        }
+    }
+
+    /// Roots hang off the root container, files only know their directory.
+    async fn parent_id(&self, path: &Path) -> String {
+        let roots = QUEUE.get().unwrap().enabled_roots().read_async().await;
+        match path.parent() {
+            Some(parent) if roots.iter().any(|root| parent.starts_with(root)) => id(parent),
+            _ => ROOT_ID.to_owned(),
+        }
+    }
+}
+
+fn id(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+fn title(path: &Path) -> String {
+    let name = browse::file_name(path);
+    if name.is_empty() { id(path) } else { name }
+}
+
+fn entry_name(entry: &BrowseEntry) -> &str {
+    match entry {
+        BrowseEntry::Dir { name, .. } | BrowseEntry::File { name, .. } => name,
+    }
+}
+
+/// DIDL-Lite, what `Browse` returns its objects as.
+struct Didl<'a> {
+    base_url: &'a str,
+    xml: String,
+    returned: usize,
+}
+
+impl<'a> Didl<'a> {
+    fn new(base_url: &'a str) -> Self {
+        let xml = String::from(
+            r#"<DIDL-Lite xmlns="urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/" xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:upnp="urn:schemas-upnp-org:metadata-1-0/upnp/" xmlns:dlna="urn:schemas-dlna-org:metadata-1-0/">"#,
+        );
+        Self { base_url, xml, returned: 0 }
+    }
+
+    fn container(&mut self, id: &str, parent: &str, title: &str) {
+        _ = write!(
+            self.xml,
+            r#"<container id="{}" parentID="{}" restricted="1" searchable="0"><dc:title>{}</dc:title><upnp:class>object.container.storageFolder</upnp:class></container>"#,
+            escape(id),
+            escape(parent),
+            escape(title)
+        );
+        self.returned += 1;
+    }
+
+    fn item(&mut self, path: &Path, parent: &str) {
+        let Some(kind) = FileKind::from_path(path) else { return };
+        let metadata = METADATA.get(path);
+        if metadata.is_none() {
+            // Probed for next time.
+            METADATA.request(path.to_owned());
+        }
+        let tags = metadata.as_ref().and_then(|metadata| metadata.tags.as_ref());
+
+        let id = id(path);
+        let name = tags.and_then(|tags| tags.title.clone()).unwrap_or_else(|| title(path));
+        let class = match kind {
+            FileKind::Video => "object.item.videoItem",
+            FileKind::Audio => "object.item.audioItem.musicTrack",
+            FileKind::Image => "object.item.imageItem.photo",
+        };
+
+        // Renderers can't decode the formats the web UI has converted either.
+        let convert = kind == FileKind::Image && needs_conversion(path);
+        let url_path = id.split('/').map(urlencoding::encode).collect::<Vec<_>>();
+        let mut url = format!("{}/files{}", self.base_url, url_path.join("/"));
+        let mime = if convert {
+            url.push_str("?format=jpeg");
+            Cow::Borrowed("image/jpeg")
+        } else {
+            Cow::Owned(mime_guess::from_path(path).first_or_octet_stream().to_string())
+        };
+        // Byte seeking, served by `serve_dir` with ranges.
+        let operations = if convert { "00" } else { "01" };
+
+        let mut res = format!(
+            r#" protocolInfo="http-get:*:{mime}:DLNA.ORG_OP={operations};DLNA.ORG_CI={}""#,
+            u8::from(convert)
+        );
+        if !convert && let Ok(file_metadata) = std::fs::metadata(path) {
+            _ = write!(res, r#" size="{}""#, file_metadata.len());
+        }
+        if let Some(duration) = metadata.as_ref().and_then(|metadata| metadata.duration) {
+            let millis = (duration * 1000.0).round() as u64;
+            let (hours, minutes) = (millis / 3_600_000, millis / 60_000 % 60);
+            let (seconds, millis) = (millis / 1000 % 60, millis % 1000);
+            _ = write!(res, r#" duration="{hours}:{minutes:02}:{seconds:02}.{millis:03}""#);
+        }
+        if let Some(metadata) = &metadata
+            && let (Some(width), Some(height)) = (metadata.width, metadata.height)
+        {
+            _ = write!(res, r#" resolution="{width}x{height}""#);
+        }
+
+        _ = write!(
+            self.xml,
+            r#"<item id="{}" parentID="{}" restricted="1"><dc:title>{}</dc:title><upnp:class>{class}</upnp:class>"#,
+            escape(&id),
+            escape(parent),
+            escape(&name)
+        );
+        if let Some(artist) = tags.and_then(|tags| tags.artist.as_deref()) {
+            _ = write!(self.xml, "<upnp:artist>{}</upnp:artist>", escape(artist));
+        }
+        if let Some(album) = tags.and_then(|tags| tags.album.as_deref()) {
+            _ = write!(self.xml, "<upnp:album>{}</upnp:album>", escape(album));
+        }
+        _ = write!(self.xml, "<res{res}>{}</res></item>", escape(&url));
+        self.returned += 1;
+    }
+
+    fn finish(mut self, total: usize) -> BrowseResult {
+        self.xml.push_str("</DIDL-Lite>");
+        BrowseResult { didl: self.xml, returned: self.returned, total }
+    }
+}
@@ -0,0 +1,223 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+use rustix::net::{AddressFamily, SocketType};
+
+use super::{CONNECTION_MANAGER, CONTENT_DIRECTORY, DEVICE_TYPE};
+
+const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const PORT: u16 = 1900;
+const MAX_AGE: Duration = Duration::from_secs(1800);
+// Announced well within `MAX_AGE`, a dropped datagram or two mustn't make the server vanish.
+const NOTIFY_INTERVAL: Duration = Duration::from_secs(600);
+/// How often the socket loop checks for shutdown between searches.
+const STOP_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const SERVER: &str = concat!("Linux UPnP/1.0 Z-Play/", env!("CARGO_PKG_VERSION"));
+
+/// Simple Service Discovery Protocol, how renderers find the server on the LAN.
+///
+/// Searches sent straight to port 1900 on loopback are answered too, which is the easiest way
+/// to check it's up: `printf 'M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN:
+/// "ssdp:discover"\r\nMX: 1\r\nST: ssdp:all\r\n\r\n' | nc -u -w1 127.0.0.1 1900`.
+pub struct Ssdp {
+    uuid: String,
+    port: u16,
+}
+
+impl Ssdp {
+    pub fn new(uuid: String, port: u16) -> Self {
+        Self { uuid, port }
+    }
+
+    /// Everything announced: the root device, the device itself and its services.
+    fn targets(&self) -> [String; 5] {
+        [
+            "upnp:rootdevice".to_owned(),
+            format!("uuid:{}", self.uuid),
+            DEVICE_TYPE.to_owned(),
+            CONTENT_DIRECTORY.to_owned(),
+            CONNECTION_MANAGER.to_owned(),
+        ]
+    }
+
+    fn usn(&self, target: &str) -> String {
+        if target.starts_with("uuid:") {
+            target.to_owned()
+        } else {
+            format!("uuid:{}::{target}", self.uuid)
+        }
+    }
+
+    fn location(&self, ip: IpAddr) -> String {
+        format!("http://{}/dlna/description.xml", SocketAddr::new(ip, self.port))
+    }
+
+    /// Returns once `stopped` does, after telling control points we're leaving.
+    pub fn run(&self, stopped: impl Fn() -> bool) -> std::io::Result<()> {
+        let socket = bind()?;
+        tracing::info!(uuid = %self.uuid, "Announcing DLNA server");
+
+        let mut buf = [0; 2048];
+        let mut next_notify = Instant::now();
+        loop {
+            if stopped() {
+                self.notify(&socket, "ssdp:byebye");
+                return Ok(());
+            }
+
+            let now = Instant::now();
+            if now >= next_notify {
+                self.notify(&socket, "ssdp:alive");
+                next_notify = now + NOTIFY_INTERVAL;
+            }
+            socket.set_read_timeout(Some((next_notify - now).min(STOP_POLL_INTERVAL)))?;
+
+            let (len, peer) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue;
+                }
+                Err(error) => return Err(error),
+            };
+            let Ok(message) = std::str::from_utf8(&buf[..len]) else { continue };
+            if let Some(target) = search_target(message) {
+                self.answer(&socket, peer, target);
+            }
+        }
+    }
+
+    // Answered right away rather than after a random delay up to MX, there's only one of us.
+    fn answer(&self, socket: &UdpSocket, peer: SocketAddr, target: &str) {
+        let Some(ip) = local_ip(peer) else { return };
+        let location = self.location(ip);
+
+        let targets = self.targets();
+        let matching =
+            targets.iter().filter(|candidate| target == "ssdp:all" || *candidate == target);
+        for target in matching {
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age={}\r\nEXT:\r\nLOCATION: {location}\r\nSERVER: {SERVER}\r\nST: {target}\r\nUSN: {}\r\n\r\n",
+                MAX_AGE.as_secs(),
+                self.usn(target)
+            );
+            if let Err(error) = socket.send_to(response.as_bytes(), peer) {
+                tracing::warn!(%peer, %error, "Failed to answer SSDP search");
+            }
+        }
+    }
+
+    /// Multicasts `nts`, `ssdp:alive` or `ssdp:byebye`, for every target.
+    fn notify(&self, socket: &UdpSocket, nts: &str) {
+        let group = SocketAddr::V4(SocketAddrV4::new(MULTICAST_ADDR, PORT));
+        let Some(ip) = local_ip(group) else {
+            tracing::warn!("No route to the SSDP multicast group, not announcing");
+            return;
+        };
+        let location = self.location(ip);
+
+        for target in self.targets() {
+            let message = format!(
+                "NOTIFY * HTTP/1.1\r\nHOST: {group}\r\nCACHE-CONTROL: max-age={}\r\nLOCATION: {location}\r\nNT: {target}\r\nNTS: {nts}\r\nSERVER: {SERVER}\r\nUSN: {}\r\n\r\n",
+                MAX_AGE.as_secs(),
+                self.usn(&target)
+            );
+            if let Err(error) = socket.send_to(message.as_bytes(), group) {
+                tracing::warn!(nts, %error, "Failed to send SSDP notify");
+                return;
+            }
+        }
+    }
+}
+
+/// Shares the port with any other SSDP daemon on the machine.
+fn bind() -> std::io::Result<UdpSocket> {
+    let fd = rustix::net::socket(AddressFamily::INET, SocketType::DGRAM, None)?;
+    rustix::net::sockopt::set_socket_reuseaddr(&fd, true)?;
+    rustix::net::bind(&fd, &SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, PORT))?;
+
+    let socket = UdpSocket::from(fd);
+    socket.join_multicast_v4(&MULTICAST_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+    Ok(socket)
+}
+
+/// The `ST` of an `M-SEARCH` for devices, `None` for anything else.
+fn search_target(message: &str) -> Option<&str> {
+    let mut lines = message.lines();
+    if !lines.next()?.starts_with("M-SEARCH * ") {
+        return None;
+    }
+
+    let mut is_discover = false;
+    let mut target = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else { continue };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("man") {
+            is_discover = value == "\"ssdp:discover\"";
+        } else if name.eq_ignore_ascii_case("st") {
+            target = Some(value);
+        }
+    }
+    target.filter(|_| is_discover)
+}
+
+/// The address `peer` reaches us on, what goes in `LOCATION`. Connecting a UDP socket sends
+/// nothing, it only picks the route.
+fn local_ip(peer: SocketAddr) -> Option<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect(peer).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_search_on_loopback() {
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let search = concat!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\n",
+            "MX: 1\r\nST: upnp:rootdevice\r\n\r\n",
+        );
+        client.send_to(search.as_bytes(), server.local_addr().unwrap()).unwrap();
+
+        let mut buf = [0; 2048];
+        let (len, peer) = server.recv_from(&mut buf).unwrap();
+        let message = std::str::from_utf8(&buf[..len]).unwrap();
+        let target = search_target(message).unwrap();
+        assert_eq!(target, "upnp:rootdevice");
+
+        let ssdp = Ssdp::new("test-uuid".to_owned(), 8080);
+        ssdp.answer(&server, peer, target);
+
+        let (len, _) = client.recv_from(&mut buf).unwrap();
+        let response = std::str::from_utf8(&buf[..len]).unwrap();
+        let header = |name: &str| {
+            response.lines().find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                key.eq_ignore_ascii_case(name).then(|| value.trim())
+            })
+        };
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(header("ST"), Some("upnp:rootdevice"));
+        assert_eq!(header("USN"), Some("uuid:test-uuid::upnp:rootdevice"));
+        assert_eq!(header("LOCATION"), Some("http://127.0.0.1:8080/dlna/description.xml"));
+    }
+
+    #[test]
+    fn ignores_non_discover_searches() {
+        let message = "M-SEARCH * HTTP/1.1\r\nMAN: \"ssdp:other\"\r\nST: ssdp:all\r\n\r\n";
+        assert_eq!(search_target(message), None);
+        assert_eq!(search_target("NOTIFY * HTTP/1.1\r\nNT: ssdp:all\r\n\r\n"), None);
+    }
+}
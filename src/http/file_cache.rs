@@ -9,8 +9,29 @@ use compio::bytes::{Bytes, BytesMut};
 use compio::io::AsyncReadAt;
 use lru::LruCache;
 use rustc_hash::{FxBuildHasher, FxHashMap};
+use serde::Serialize;
 use z_sync::Notify64;
 
+use super::METRICS;
+
+/// What's cached, for `/admin/status`.
+#[derive(Debug, Serialize)]
+pub struct FileCacheStatus {
+    pub bytes: usize,
+    /// 0 when there's no limit.
+    pub max_bytes: usize,
+    pub chunk_size: u64,
+    pub files: Vec<CachedFileStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CachedFileStatus {
+    pub path: PathBuf,
+    pub size: u64,
+    /// Indexes of the chunks in memory, ones still being read aren't included.
+    pub chunks: Vec<u64>,
+}
+
 #[derive(Clone)]
 pub struct FileCache {
     inner: Rc<FileCacheInner>,
@@ -90,6 +111,34 @@ impl FileCache {
         Ok(new_entry)
     }
 
+    pub fn status(&self) -> FileCacheStatus {
+        let mut files = self
+            .inner
+            .files
+            .borrow()
+            .iter()
+            .map(|(path, file)| {
+                let mut chunks = file
+                    .chunks
+                    .borrow()
+                    .iter()
+                    .filter(|(_, state)| matches!(state, ChunkState::Loaded(_)))
+                    .map(|(index, _)| *index)
+                    .collect::<Vec<_>>();
+                chunks.sort_unstable();
+                CachedFileStatus { path: path.clone(), size: file.size, chunks }
+            })
+            .collect::<Vec<_>>();
+        files.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+
+        FileCacheStatus {
+            bytes: *self.inner.current_cache_size.borrow(),
+            max_bytes: self.inner.max_cache_size,
+            chunk_size: CachedFile::CHUNK_SIZE,
+            files,
+        }
+    }
+
     pub fn close<P>(&self, path: P)
     where
         P: AsRef<Path>,
@@ -192,11 +241,13 @@ impl CachedFile {
                 let mut chunks = self.chunks.borrow_mut();
                 match chunks.get(&chunk_index) {
                     Some(ChunkState::Loaded(chunk)) => {
+                        METRICS.file_cache_hit();
                         self.file_cache.lru_chunks.borrow_mut().push((self.id, chunk_index), ());
                         return Ok(chunk.clone());
                     }
                     Some(ChunkState::Loading(notify)) => Notify64::rc_listener(notify),
                     None => {
+                        METRICS.file_cache_miss();
                         let notify = Rc::new(Notify64::new());
                         chunks.insert(chunk_index, ChunkState::Loading(notify));
                         break;
@@ -244,10 +295,13 @@ impl CachedFile {
                                 file.chunks.borrow_mut().remove(&chunk_index)
                         {
                             *current_cache_size = current_cache_size.saturating_sub(chunk.len());
+                            METRICS.file_cache_freed(chunk.len());
+                            METRICS.file_cache_evicted();
                         }
                     }
 
                     *current_cache_size += buffer_len;
+                    METRICS.file_cache_stored(buffer_len);
                 }
 
                 // Update state and notify parked waiters
@@ -290,6 +344,7 @@ impl Drop for CachedFile {
         for state in self.chunks.borrow().values() {
             if let ChunkState::Loaded(chunk) = state {
                 *current_cache_size = current_cache_size.saturating_sub(chunk.len());
+                METRICS.file_cache_freed(chunk.len());
             }
         }
     }
@@ -0,0 +1,540 @@
+use std::cell::{Cell, RefCell};
+use std::hash::{Hash, Hasher};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::LazyLock;
+
+use compio::BufResult;
+use lru::LruCache;
+use rustc_hash::{FxBuildHasher, FxHashMap, FxHasher};
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+use utoipa::{IntoParams, ToSchema};
+use z_play::exif::Orientation;
+use z_play::raw_preview::find_embedded_preview;
+use z_sync::Notify16;
+
+use super::metrics::FfmpegJob;
+use super::{FileKind, METRICS};
+
+const CONVERT_EXTENSIONS: &[&str] = &["heic", "heif", "tif", "tiff"];
+// HEIF stores its own rotation and mirroring, which ffmpeg applies and which takes precedence
+// over the EXIF orientation.
+const HEIF_EXTENSIONS: &[&str] = &["heic", "heif"];
+const RAW_EXTENSIONS: &[&str] =
+    &["cr2", "cr3", "nef", "nrw", "arw", "srf", "sr2", "dng", "orf", "rw2", "raf", "pef", "srw"];
+// Animated and vector images are always served as they are.
+const PASSTHROUGH_EXTENSIONS: &[&str] = &["gif", "svg", "ico", "apng"];
+
+// Requested sizes are rounded up to one of these so clients don't fill the cache with one
+// rendition per pixel of window width.
+const SIZE_BUCKETS: &[u32] = &[320, 640, 960, 1280, 1920, 2560, 3840];
+
+// AVIF encoding is slow enough that it's opt-in.
+static AVIF_ENABLED: LazyLock<bool> =
+    LazyLock::new(|| std::env::var_os("Z_PLAY_IMAGE_AVIF").is_some_and(|value| value != "0"));
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extensions.iter().any(|e| extension.eq_ignore_ascii_case(e)))
+}
+
+fn is_raw(path: &Path) -> bool {
+    has_extension(path, RAW_EXTENSIONS)
+}
+
+/// Returns true for images browsers can't display, which are served as renditions instead.
+pub fn needs_conversion(path: &Path) -> bool {
+    is_raw(path) || has_extension(path, CONVERT_EXTENSIONS)
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RenditionFormat {
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Webp,
+    Avif,
+}
+
+impl RenditionFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Webp => "webp",
+            Self::Avif => "avif",
+        }
+    }
+
+    fn codec_args(self) -> &'static [&'static str] {
+        match self {
+            Self::Jpeg => &["-c:v", "mjpeg", "-q:v", "3", "-update", "1"],
+            Self::Webp => &["-c:v", "libwebp", "-quality", "80"],
+            Self::Avif => &[
+                "-c:v",
+                "libaom-av1",
+                "-still-picture",
+                "1",
+                "-crf",
+                "30",
+                "-cpu-used",
+                "6",
+                "-pix_fmt",
+                "yuv420p",
+            ],
+        }
+    }
+
+    /// Picks the best format the client advertises in its `Accept` header.
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        let Some(accept) = accept else { return Self::Jpeg };
+        if *AVIF_ENABLED && accepts(accept, "image/avif") {
+            Self::Avif
+        } else if accepts(accept, "image/webp") {
+            Self::Webp
+        } else {
+            Self::Jpeg
+        }
+    }
+}
+
+fn accepts(accept: &str, mime: &str) -> bool {
+    accept.split(',').any(|range| {
+        let mut params = range.split(';').map(str::trim);
+        let matches = params.next().is_some_and(|media_type| media_type.eq_ignore_ascii_case(mime));
+        // `q=0` means the type is explicitly refused.
+        matches
+            && !params.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q <= 0.0)
+            })
+    })
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Hash, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RenditionFit {
+    /// Fit inside the box, keeping the aspect ratio.
+    #[default]
+    Contain,
+    /// Fill the box, keeping the aspect ratio and cropping the overflow.
+    Cover,
+    /// Stretch to exactly the box.
+    Fill,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct RenditionSpec {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub fit: RenditionFit,
+    pub format: RenditionFormat,
+}
+
+impl RenditionSpec {
+    /// Used when serving images browsers can't display.
+    pub const CONVERTED: Self = Self {
+        max_width: 3840,
+        max_height: 3840,
+        fit: RenditionFit::Contain,
+        format: RenditionFormat::Jpeg,
+    };
+
+    fn scale_filter(&self) -> String {
+        let Self { max_width, max_height, fit, .. } = self;
+        match fit {
+            // Only ever scale down.
+            RenditionFit::Contain => format!(
+                "scale=w='min(iw,{max_width})':h='min(ih,{max_height})':force_original_aspect_ratio=decrease"
+            ),
+            RenditionFit::Cover => format!(
+                "scale=w={max_width}:h={max_height}:force_original_aspect_ratio=increase,crop={max_width}:{max_height}"
+            ),
+            RenditionFit::Fill => format!("scale=w={max_width}:h={max_height}"),
+        }
+    }
+}
+
+/// The `?w=&h=&fit=&format=` query accepted by `/files`.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RenditionQuery {
+    pub w: Option<u32>,
+    pub h: Option<u32>,
+    #[serde(default)]
+    pub fit: RenditionFit,
+    pub format: Option<RenditionFormat>,
+}
+
+impl RenditionQuery {
+    /// Returns the rendition to serve for `path`, or `None` to serve the original file.
+    pub fn spec(&self, path: &Path, accept: Option<&str>) -> Option<RenditionSpec> {
+        // Videos and audio would come back as a single frame or a conversion error.
+        if FileKind::from_path(path) != Some(FileKind::Image) {
+            return None;
+        }
+
+        let convert = needs_conversion(path);
+        let resize = self.w.is_some() || self.h.is_some();
+        if !convert && (!resize || has_extension(path, PASSTHROUGH_EXTENSIONS)) {
+            return None;
+        }
+
+        let max_size = RenditionSpec::CONVERTED.max_width;
+        let (max_width, max_height) = match (self.w, self.h) {
+            (Some(w), Some(h)) => bucket_box(w, h),
+            (w, h) => (w.map_or(max_size, bucket_size), h.map_or(max_size, bucket_size)),
+        };
+
+        // Cropping or stretching needs both dimensions.
+        let fit = if self.w.is_some() && self.h.is_some() {
+            self.fit
+        } else {
+            RenditionFit::Contain
+        };
+        let format = self.format.unwrap_or_else(|| RenditionFormat::negotiate(accept));
+
+        Some(RenditionSpec { max_width, max_height, fit, format })
+    }
+}
+
+fn bucket_size(size: u32) -> u32 {
+    SIZE_BUCKETS
+        .iter()
+        .copied()
+        .find(|&bucket| bucket >= size)
+        .unwrap_or(*SIZE_BUCKETS.last().unwrap())
+}
+
+/// Buckets the larger side and scales the other by as much, so covers and fills keep the
+/// requested aspect ratio.
+fn bucket_box(width: u32, height: u32) -> (u32, u32) {
+    let larger = width.max(height).max(1);
+    let scale = f64::from(bucket_size(larger)) / f64::from(larger);
+    let scaled = |size: u32| ((f64::from(size) * scale).round() as u32).max(1);
+    (scaled(width), scaled(height))
+}
+
+pub struct ImageRenditions {
+    cache_dir: PathBuf,
+    // rendition path -> notified when the conversion finishes
+    in_flight: RefCell<FxHashMap<PathBuf, Rc<Notify16>>>,
+    permits: Semaphore,
+    // rendition path -> file size
+    renditions: RefCell<LruCache<PathBuf, u64, FxBuildHasher>>,
+    cache_size: Cell<u64>,
+    max_cache_size: u64,
+}
+
+impl ImageRenditions {
+    const MAX_CONCURRENT_CONVERSIONS: usize = 2;
+
+    pub async fn new(cache_dir: PathBuf, max_cache_size: u64) -> Self {
+        if let Err(error) = compio::fs::create_dir_all(&cache_dir).await {
+            tracing::error!(dir = %cache_dir.display(), %error, "Failed to create image cache dir");
+        }
+
+        let dir = cache_dir.clone();
+        let existing = compio::runtime::spawn_blocking(move || scan_cache_dir(&dir))
+            .await
+            .unwrap()
+            .unwrap_or_else(|error| {
+                tracing::error!(
+                    dir = %cache_dir.display(),
+                    %error,
+                    "Failed to scan image cache dir"
+                );
+                Vec::new()
+            });
+
+        let mut renditions = LruCache::unbounded_with_hasher(FxBuildHasher);
+        let mut cache_size = 0;
+        for (path, size) in existing {
+            cache_size += size;
+            renditions.push(path, size);
+        }
+
+        let images = Self {
+            cache_dir,
+            in_flight: RefCell::new(FxHashMap::default()),
+            permits: Semaphore::new(Self::MAX_CONCURRENT_CONVERSIONS),
+            renditions: RefCell::new(renditions),
+            cache_size: Cell::new(cache_size),
+            max_cache_size,
+        };
+        images.evict();
+        images
+    }
+
+    fn touch(&self, path: &Path, size: u64) {
+        let mut renditions = self.renditions.borrow_mut();
+        if renditions.get(path).is_none() {
+            renditions.push(path.to_owned(), size);
+            self.cache_size.set(self.cache_size.get() + size);
+        }
+    }
+
+    fn evict(&self) {
+        let mut renditions = self.renditions.borrow_mut();
+        while self.cache_size.get() > self.max_cache_size {
+            let Some((path, size)) = renditions.pop_lru() else { break };
+            self.cache_size.set(self.cache_size.get().saturating_sub(size));
+            // Responses that already opened the file keep reading it after it's unlinked.
+            if let Err(error) = std::fs::remove_file(&path) {
+                tracing::warn!(path = %path.display(), %error, "Failed to evict image rendition");
+            }
+        }
+    }
+
+    fn rendition_path(
+        &self,
+        path: &Path,
+        metadata: &compio::fs::Metadata,
+        spec: &RenditionSpec,
+    ) -> PathBuf {
+        // Include the modification time so edited files don't serve stale renditions.
+        let mut hasher = FxHasher::default();
+        path.hash(&mut hasher);
+        metadata.len().hash(&mut hasher);
+        metadata.mtime().hash(&mut hasher);
+        metadata.mtime_nsec().hash(&mut hasher);
+        spec.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        self.cache_dir.join(format!("{hash:016x}.{}", spec.format.extension()))
+    }
+
+    /// Returns the path to the rendition, converting the image if it isn't cached yet.
+    pub async fn get(&self, path: &Path, spec: &RenditionSpec) -> Result<PathBuf, std::io::Error> {
+        let metadata = compio::fs::metadata(path).await?;
+        let output_path = self.rendition_path(path, &metadata, spec);
+
+        loop {
+            if let Some(size) = file_size(&output_path).await {
+                self.touch(&output_path, size);
+                return Ok(output_path);
+            }
+
+            let listener = {
+                let mut in_flight = self.in_flight.borrow_mut();
+                match in_flight.get(&output_path) {
+                    Some(notify) => Notify16::rc_listener(notify),
+                    None => {
+                        in_flight.insert(output_path.clone(), Rc::new(Notify16::new()));
+                        break;
+                    }
+                }
+            };
+
+            // Wait for the other conversion, then check whether it succeeded.
+            listener.await;
+        }
+
+        // Wake waiters even if this future is cancelled.
+        let _guard = scopeguard::guard(&output_path, |output_path| {
+            let notify = self.in_flight.borrow_mut().remove(output_path);
+            if let Some(notify) = notify {
+                notify.notify(usize::MAX);
+            }
+        });
+
+        let _permit = self.permits.acquire().await.expect("Semaphore closed");
+        convert(path, &output_path, spec).await?;
+
+        let size = compio::fs::metadata(&output_path).await?.len();
+        self.touch(&output_path, size);
+        self.evict();
+
+        Ok(output_path)
+    }
+}
+
+async fn convert(
+    source: &Path,
+    output_path: &Path,
+    spec: &RenditionSpec,
+) -> Result<(), std::io::Error> {
+    let mut input = source.to_owned();
+    let mut preview_path = None;
+
+    // Decoding RAW data is slow and rarely supported, the embedded preview is good enough.
+    if is_raw(source) {
+        let data = compio::fs::read(source).await?;
+        if let Some(preview) = find_embedded_preview(&data) {
+            let path = output_path.with_extension("preview.jpg");
+            let BufResult(result, _) = compio::fs::write(&path, preview.to_vec()).await;
+            result?;
+            input = path.clone();
+            preview_path = Some(path);
+        }
+    }
+
+    let _cleanup = scopeguard::guard(preview_path, |preview_path| {
+        if let Some(preview_path) = preview_path {
+            _ = std::fs::remove_file(preview_path);
+        }
+    });
+
+    // Write to a temporary file so a partial rendition is never served.
+    let temp_path = output_path.with_extension(format!("tmp.{}", spec.format.extension()));
+
+    let mut command = compio::process::Command::new("ffmpeg");
+    command.args(["-v", "error", "-y"]);
+
+    let mut filters = Vec::new();
+    if !has_extension(source, HEIF_EXTENSIONS) {
+        // Read from the source, RAW previews don't carry the orientation themselves.
+        let metadata = super::METADATA.load(source).await;
+        let orientation =
+            metadata.exif.as_ref().map_or(Orientation::Normal, |exif| exif.orientation);
+        command.arg("-noautorotate");
+        filters.extend(orientation_filter(orientation));
+    }
+    // Rotate first so the size applies to the image as displayed.
+    filters.push(spec.scale_filter());
+
+    let _ffmpeg = METRICS.track_ffmpeg(FfmpegJob::Image);
+    let output = command
+        .arg("-i")
+        .arg(&input)
+        .args(["-frames:v", "1", "-vf", &filters.join(",")])
+        .args(spec.format.codec_args())
+        .arg(&temp_path)
+        .stdout(std::process::Stdio::null())
+        .unwrap()
+        .stderr(std::process::Stdio::inherit())
+        .unwrap()
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        _ = compio::fs::remove_file(&temp_path).await;
+        return Err(std::io::Error::other(format!(
+            "ffmpeg failed to convert {}: {}",
+            source.display(),
+            output.status
+        )));
+    }
+
+    let output_path = output_path.to_owned();
+    compio::runtime::spawn_blocking(move || std::fs::rename(temp_path, output_path))
+        .await
+        .unwrap()?;
+
+    Ok(())
+}
+
+fn orientation_filter(orientation: Orientation) -> Option<String> {
+    let filter = match orientation {
+        Orientation::Normal => return None,
+        Orientation::FlipHorizontal => "hflip",
+        Orientation::Rotate180 => "hflip,vflip",
+        Orientation::FlipVertical => "vflip",
+        Orientation::Transpose => "transpose=cclock_flip",
+        Orientation::Rotate90 => "transpose=clock",
+        Orientation::Transverse => "transpose=clock_flip",
+        Orientation::Rotate270 => "transpose=cclock",
+    };
+    Some(filter.to_owned())
+}
+
+async fn file_size(path: &Path) -> Option<u64> {
+    let metadata = compio::fs::metadata(path).await.ok()?;
+    (metadata.is_file() && metadata.len() > 0).then_some(metadata.len())
+}
+
+/// Lists the renditions left by previous runs, oldest first, and removes unfinished ones.
+fn scan_cache_dir(cache_dir: &Path) -> std::io::Result<Vec<(PathBuf, u64)>> {
+    let mut renditions = Vec::new();
+    for entry in std::fs::read_dir(cache_dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+
+        let path = entry.path();
+        let is_partial = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.contains(".tmp.") || name.contains(".preview."));
+        if is_partial {
+            _ = std::fs::remove_file(&path);
+            continue;
+        }
+
+        let used = metadata.accessed().or_else(|_| metadata.modified())?;
+        renditions.push((used, path, metadata.len()));
+    }
+
+    renditions.sort_unstable_by_key(|(used, ..)| *used);
+    Ok(renditions.into_iter().map(|(_, path, size)| (path, size)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(w: Option<u32>, h: Option<u32>, fit: RenditionFit) -> RenditionQuery {
+        RenditionQuery { w, h, fit, format: None }
+    }
+
+    #[test]
+    fn spec_keeps_aspect_ratio() {
+        let spec = query(Some(400), Some(300), RenditionFit::Cover)
+            .spec(Path::new("/photos/a.jpg"), Some("image/webp"))
+            .unwrap();
+        assert_eq!(
+            spec,
+            RenditionSpec {
+                max_width: 640,
+                max_height: 480,
+                fit: RenditionFit::Cover,
+                format: RenditionFormat::Webp,
+            }
+        );
+
+        let spec = query(Some(8000), Some(4000), RenditionFit::Fill)
+            .spec(Path::new("/photos/a.png"), None)
+            .unwrap();
+        assert_eq!((spec.max_width, spec.max_height), (3840, 1920));
+    }
+
+    #[test]
+    fn spec_with_one_dimension_contains() {
+        let spec = query(Some(500), None, RenditionFit::Cover)
+            .spec(Path::new("/photos/a.jpg"), None)
+            .unwrap();
+        assert_eq!((spec.max_width, spec.max_height), (640, 3840));
+        assert_eq!(spec.fit, RenditionFit::Contain);
+    }
+
+    #[test]
+    fn spec_serves_originals() {
+        let fit = RenditionFit::Contain;
+        assert_eq!(query(None, None, fit).spec(Path::new("/photos/a.jpg"), None), None);
+        assert_eq!(query(Some(320), None, fit).spec(Path::new("/photos/a.gif"), None), None);
+        assert_eq!(query(Some(320), None, fit).spec(Path::new("/videos/a.mp4"), None), None);
+        // Browsers can't show these, so they're converted even without a size.
+        assert_eq!(
+            query(None, None, fit).spec(Path::new("/photos/a.HEIC"), None),
+            Some(RenditionSpec::CONVERTED)
+        );
+    }
+
+    #[test]
+    fn accept_header() {
+        assert!(accepts("text/html,image/webp,*/*;q=0.8", "image/webp"));
+        assert!(accepts("IMAGE/WEBP", "image/webp"));
+        assert!(!accepts("image/webp;q=0", "image/webp"));
+        assert!(!accepts("image/*", "image/webp"));
+
+        assert_eq!(RenditionFormat::negotiate(None), RenditionFormat::Jpeg);
+        assert_eq!(RenditionFormat::negotiate(Some("image/webp,*/*")), RenditionFormat::Webp);
+        assert_eq!(RenditionFormat::negotiate(Some("text/html")), RenditionFormat::Jpeg);
+    }
+}
@@ -0,0 +1,199 @@
+use std::cmp::Reverse;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use axum::response::Json;
+use axum_extra::extract::Query;
+use parking_lot::RwLock;
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use super::session::{SessionParams, SessionQueue};
+use super::{FileKind, LIBRARY, STORE};
+
+/// Every playable file found by the directory walk, kept up to date by inotify.
+#[derive(Debug, Default)]
+pub struct Library {
+    // Lowercased once up front, searches compare against every entry. Shared so searches can
+    // take a snapshot and score it without holding the lock.
+    paths: RwLock<FxHashMap<Arc<Path>, Arc<str>>>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SearchResult {
+    #[schema(value_type = String)]
+    pub path: PathBuf,
+    pub kind: FileKind,
+}
+
+impl Library {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, path: &Path) {
+        let Some(path_str) = path.to_str() else { return };
+        let lower = Arc::from(path_str.to_lowercase());
+        self.paths.write().insert(Arc::from(path), lower);
+    }
+
+    pub fn remove(&self, path: &Path) {
+        self.paths.write().remove(path);
+    }
+
+    pub fn remove_dir(&self, dir: &Path) {
+        self.paths.write().retain(|path, _| !path.starts_with(dir));
+    }
+
+    /// Every indexed file `filter` accepts, in no particular order.
+    pub fn files<F>(&self, filter: F) -> Vec<PathBuf>
+    where
+        F: Fn(&Path) -> bool,
+    {
+        self.paths
+            .read()
+            .keys()
+            .filter(|path| filter(path))
+            .map(|path| path.to_path_buf())
+            .collect()
+    }
+
+    /// Files matching every whitespace separated term of `query`, best matches first.
+    ///
+    /// A term matches when its characters appear in order, matches within the file name and
+    /// contiguous matches rank higher. Scores every indexed file, so call it off the runtime
+    /// thread.
+    pub fn search<F>(
+        &self,
+        query: &str,
+        kinds: &FxHashSet<FileKind>,
+        limit: usize,
+        filter: F,
+    ) -> Vec<SearchResult>
+    where
+        F: Fn(&Path) -> bool,
+    {
+        let query = query.to_lowercase();
+        let terms = query.split_whitespace().collect::<Vec<_>>();
+        if terms.is_empty() {
+            return Vec::new();
+        }
+
+        let snapshot = self
+            .paths
+            .read()
+            .iter()
+            .map(|(path, lower)| (path.clone(), lower.clone()))
+            .collect::<Vec<_>>();
+        let mut results = snapshot
+            .iter()
+            .filter_map(|(path, lower)| {
+                let kind = FileKind::from_path(path)?;
+                if !kinds.is_empty() && !kinds.contains(&kind) {
+                    return None;
+                }
+
+                let name_start = lower.rfind('/').map_or(0, |index| index + 1);
+                let score = terms
+                    .iter()
+                    .map(|term| score_term(lower, name_start, term))
+                    .sum::<Option<i64>>()?;
+                Some((score, path, kind))
+            })
+            .filter(|(_, path, _)| filter(path))
+            .map(|(score, path, kind)| (score, path.clone(), kind))
+            .collect::<Vec<_>>();
+
+        results.sort_unstable_by(|(a_score, a_path, _), (b_score, b_path, _)| {
+            (Reverse(*a_score), a_path.as_os_str().len(), a_path).cmp(&(
+                Reverse(*b_score),
+                b_path.as_os_str().len(),
+                b_path,
+            ))
+        });
+        results.truncate(limit);
+        results
+            .into_iter()
+            .map(|(_, path, kind)| SearchResult { path: path.to_path_buf(), kind })
+            .collect()
+    }
+}
+
+fn score_term(haystack: &str, name_start: usize, term: &str) -> Option<i64> {
+    let name = &haystack[name_start..];
+    if let Some(index) = name.find(term) {
+        let at_word_start = index == 0 || !name.as_bytes()[index - 1].is_ascii_alphanumeric();
+        return Some(if at_word_start { 400 } else { 300 });
+    }
+    if haystack.contains(term) {
+        return Some(200);
+    }
+
+    if let Some(spread) = subsequence_spread(name, term) {
+        return Some(100 - spread.min(90) as i64);
+    }
+    subsequence_spread(haystack, term).map(|spread| 10 - spread.min(10) as i64)
+}
+
+/// Number of skipped characters between the first and last matched character, if all of
+/// `needle` appears in order within `haystack`.
+fn subsequence_spread(haystack: &str, needle: &str) -> Option<usize> {
+    let mut needle_chars = needle.chars().peekable();
+    let mut started = false;
+    let mut skipped = 0;
+
+    for c in haystack.chars() {
+        let Some(&wanted) = needle_chars.peek() else { break };
+        if c == wanted {
+            started = true;
+            needle_chars.next();
+        } else if started {
+            skipped += 1;
+        }
+    }
+
+    needle_chars.peek().is_none().then_some(skipped)
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchQuery {
+    q: String,
+    #[serde(default, rename = "kind")]
+    #[param(value_type = Option<Vec<FileKind>>)]
+    kinds: FxHashSet<FileKind>,
+    limit: Option<usize>,
+}
+
+impl SearchQuery {
+    const DEFAULT_LIMIT: usize = 50;
+    const MAX_LIMIT: usize = 500;
+}
+
+#[utoipa::path(
+    get,
+    path = "/search",
+    tag = "library",
+    params(SessionParams, SearchQuery),
+    responses((status = OK, description = "Best matches first", body = Vec<SearchResult>)),
+)]
+pub async fn search_handler(
+    queue: SessionQueue,
+    Query(query): Query<SearchQuery>,
+) -> Json<Vec<SearchResult>> {
+    let limit = query.limit.unwrap_or(SearchQuery::DEFAULT_LIMIT).min(SearchQuery::MAX_LIMIT);
+    let roots = queue.enabled_roots().read_async().await.clone();
+    let store = STORE.get().unwrap();
+
+    // Disabled roots stay indexed so re-enabling them is instant.
+    let filter = move |path: &Path| {
+        roots.iter().any(|root| path.starts_with(root)) && !store.is_hidden(path)
+    };
+    let results = compio::runtime::spawn_blocking(move || {
+        LIBRARY.search(&query.q, &query.kinds, limit, filter)
+    })
+    .await
+    .unwrap();
+    Json(results)
+}
@@ -0,0 +1,80 @@
+use axum::extract::Path;
+use axum::response::{IntoResponse, Json, Response};
+use camino::Utf8Path;
+use http::{StatusCode, header};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use super::metadata::{self, MetadataJson};
+use super::{FileKind, METADATA};
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+struct MetaResponse {
+    path: String,
+    kind: FileKind,
+    #[serde(flatten)]
+    metadata: MetadataJson,
+}
+
+#[utoipa::path(
+    get,
+    path = "/meta/{path}",
+    tag = "files",
+    params(("path" = String, Path, description = "Absolute path without the leading slash")),
+    responses(
+        (status = OK, body = MetaResponse),
+        (status = NOT_FOUND, description = "Not a playable file in an enabled root"),
+    ),
+)]
+pub async fn meta_handler(Path(path): Path<String>) -> Response {
+    let path = Utf8Path::new("/").join(path);
+    let Some(kind) = FileKind::from_path(path.as_std_path()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let path_clone = path.clone();
+    let metadata =
+        compio::runtime::spawn(async move { METADATA.load(path_clone.as_std_path()).await })
+            .await
+            .unwrap();
+
+    let response = MetaResponse {
+        path: path.into_string(),
+        kind,
+        metadata: MetadataJson::from(&*metadata),
+    };
+    Json(response).into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/cover/{path}",
+    tag = "files",
+    params(("path" = String, Path, description = "Absolute path without the leading slash")),
+    responses(
+        (status = OK, description = "Embedded cover art, in whatever format it was stored"),
+        (status = NOT_FOUND, description = "No cover art"),
+    ),
+)]
+pub async fn cover_handler(Path(path): Path<String>) -> Response {
+    let path = Utf8Path::new("/").join(path);
+
+    let path_clone = path.clone();
+    let tags =
+        compio::runtime::spawn(async move { metadata::read_tags(path_clone.as_std_path()).await })
+            .await
+            .unwrap();
+
+    let Some(cover) = tags.and_then(|tags| tags.cover) else {
+        return (StatusCode::NOT_FOUND, "No cover art").into_response();
+    };
+
+    (
+        [
+            (header::CONTENT_TYPE, cover.mime_type),
+            (header::CACHE_CONTROL, "public, max-age=86400".to_owned()),
+        ],
+        cover.data,
+    )
+        .into_response()
+}
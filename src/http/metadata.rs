@@ -0,0 +1,399 @@
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+use compio::BufResult;
+use compio::io::AsyncReadAt;
+use futures_util::StreamExt;
+use lru::LruCache;
+use parking_lot::Mutex;
+use rustc_hash::FxBuildHasher;
+use serde::{Deserialize, Serialize};
+use triomphe::Arc;
+use utoipa::ToSchema;
+use z_play::exif::{self, DateTime, Exif};
+use z_play::tags::{self, Tags};
+
+use crate::http::FileKind;
+use crate::http::transcode::VideoProbe;
+
+#[derive(Debug, Default, Clone)]
+pub struct MediaMetadata {
+    pub exif: Option<Exif>,
+    /// Seconds, only set for video and audio.
+    pub duration: Option<f64>,
+    /// Width as displayed, after rotation.
+    pub width: Option<u32>,
+    /// Height as displayed, after rotation.
+    pub height: Option<u32>,
+    /// Audio tags, without the cover art.
+    pub tags: Option<Tags>,
+}
+
+impl MediaMetadata {
+    pub fn orientation(&self) -> Option<FrameOrientation> {
+        let (width, height) = (self.width?, self.height?);
+        // Allow a couple of percent either way, crops are rarely exact.
+        let tolerance = width.max(height) / 50;
+        Some(if width.abs_diff(height) <= tolerance {
+            FrameOrientation::Square
+        } else if width > height {
+            FrameOrientation::Landscape
+        } else {
+            FrameOrientation::Portrait
+        })
+    }
+}
+
+/// Metadata of recently queued files, so queue filters don't have to touch the disk.
+///
+/// Files are probed in the background by [`MetadataCache::run_worker`] after being queued.
+pub struct MetadataCache {
+    entries: Mutex<LruCache<PathBuf, Arc<MediaMetadata>, FxBuildHasher>>,
+    pending_tx: z_queue::defaults::UnboundedSender<PathBuf>,
+    // Taken by the worker.
+    pending_rx: Mutex<Option<z_queue::defaults::UnboundedReceiver<PathBuf>>>,
+}
+
+impl MetadataCache {
+    // Comfortably more than the queue holds.
+    const CAPACITY: usize = 4096;
+    const MAX_CONCURRENT_PROBES: usize = 4;
+
+    pub fn new() -> Self {
+        let capacity = NonZeroUsize::new(Self::CAPACITY).unwrap();
+        let (pending_tx, pending_rx) = z_queue::unbounded();
+        Self {
+            entries: Mutex::new(LruCache::with_hasher(capacity, FxBuildHasher)),
+            pending_tx,
+            pending_rx: Mutex::new(Some(pending_rx)),
+        }
+    }
+
+    /// Returns the cached metadata, `None` if the file hasn't been probed yet.
+    pub fn get(&self, path: &Path) -> Option<Arc<MediaMetadata>> {
+        self.entries.lock().get(path).cloned()
+    }
+
+    /// Queues the file to be probed by the worker.
+    pub fn request(&self, path: PathBuf) {
+        if self.entries.lock().contains(&path) {
+            return;
+        }
+        _ = self.pending_tx.send(path);
+    }
+
+    pub async fn load(&self, path: &Path) -> Arc<MediaMetadata> {
+        if let Some(metadata) = self.get(path) {
+            return metadata;
+        }
+
+        let metadata = Arc::new(probe(path).await);
+        self.entries.lock().push(path.to_owned(), metadata.clone());
+        metadata
+    }
+
+    pub async fn run_worker(&self) {
+        let Some(pending_rx) = self.pending_rx.lock().take() else {
+            tracing::warn!("Metadata worker is already running");
+            return;
+        };
+
+        pending_rx
+            .into_stream()
+            .for_each_concurrent(Self::MAX_CONCURRENT_PROBES, |path| async move {
+                self.load(&path).await;
+            })
+            .await;
+    }
+}
+
+async fn probe(path: &Path) -> MediaMetadata {
+    let kind = FileKind::from_path(path);
+
+    let exif = if kind == Some(FileKind::Image) {
+        match read_exif(path).await {
+            Ok(exif) => exif,
+            Err(error) => {
+                tracing::warn!(path = %path.display(), %error, "Failed to read EXIF");
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let (probe, duration) = match probe_stream(path).await {
+        Ok(result) => result,
+        Err(error) => {
+            tracing::warn!(path = %path.display(), %error, "Failed to probe");
+            (None, None)
+        }
+    };
+
+    let mut metadata = MediaMetadata { exif, ..MediaMetadata::default() };
+
+    match kind {
+        Some(FileKind::Image) => {
+            if let Some(probe) = probe {
+                // The EXIF orientation is applied on display, ignore what ffprobe makes of it.
+                let swap = metadata.exif.as_ref().is_some_and(|e| e.orientation.swaps_dimensions());
+                let (width, height) =
+                    if swap { (probe.height, probe.width) } else { (probe.width, probe.height) };
+                metadata.width = u32::try_from(width).ok();
+                metadata.height = u32::try_from(height).ok();
+            }
+        }
+        Some(FileKind::Video) => {
+            if let Some(probe) = probe {
+                let (width, height) = probe.display_size();
+                metadata.width = u32::try_from(width).ok();
+                metadata.height = u32::try_from(height).ok();
+            }
+            metadata.duration = duration;
+        }
+        // The video stream of an audio file is cover art.
+        Some(FileKind::Audio) => {
+            metadata.duration = duration;
+            metadata.tags = read_tags(path).await.map(|mut tags| {
+                // Served separately, see `/cover`.
+                tags.cover = None;
+                tags
+            });
+        }
+        None => {}
+    }
+
+    metadata
+}
+
+async fn probe_stream(path: &Path) -> Result<(Option<VideoProbe>, Option<f64>), std::io::Error> {
+    let output = compio::process::Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-select_streams",
+            "v:0",
+            "-show_entries",
+            "format=duration:stream=width,height,field_order:stream_side_data=rotation:stream_tags=rotate",
+            "-of",
+            "json",
+        ])
+        .arg(path)
+        .stdout(std::process::Stdio::piped())
+        .unwrap()
+        .stderr(std::process::Stdio::null())
+        .unwrap()
+        .output()
+        .await?;
+
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+
+    let probe = json
+        .get("streams")
+        .and_then(|streams| streams.get(0))
+        .and_then(VideoProbe::from_stream_json);
+    let duration = json
+        .get("format")
+        .and_then(|format| format.get("duration"))
+        .and_then(serde_json::Value::as_str)
+        .and_then(|duration| duration.parse::<f64>().ok())
+        .filter(|duration| duration.is_finite() && *duration > 0.0);
+
+    Ok((probe, duration))
+}
+
+pub async fn read_tags(path: &Path) -> Option<Tags> {
+    let path_clone = path.to_owned();
+    match compio::runtime::spawn_blocking(move || tags::read_file(&path_clone))
+        .await
+        .unwrap()
+    {
+        Ok(tags) => tags,
+        Err(error) => {
+            tracing::warn!(path = %path.display(), %error, "Failed to read tags");
+            None
+        }
+    }
+}
+
+pub async fn read_exif(path: &Path) -> Result<Option<Exif>, std::io::Error> {
+    let file = compio::fs::File::open(path).await?;
+    let BufResult(result, mut data) = file.read_at(Vec::with_capacity(exif::HEAD_SIZE), 0).await;
+    result?;
+
+    if let Some(range) = exif::raf_exif_range(&data) {
+        let size = (range.end - range.start) as usize;
+        let BufResult(result, preview) = file.read_at(Vec::with_capacity(size), range.start).await;
+        result?;
+        data = preview;
+    }
+    Ok(Exif::parse(&data))
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameOrientation {
+    Portrait,
+    Landscape,
+    Square,
+}
+
+/// Filters on probed metadata, files missing the relevant metadata never match.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct MetadataFilter {
+    /// Inclusive.
+    pub taken_after: Option<DateTime>,
+    /// Exclusive.
+    pub taken_before: Option<DateTime>,
+    pub min_duration: Option<f64>,
+    pub max_duration: Option<f64>,
+    pub min_width: Option<u32>,
+    pub max_width: Option<u32>,
+    pub min_height: Option<u32>,
+    pub max_height: Option<u32>,
+    pub orientation: Option<FrameOrientation>,
+}
+
+impl MetadataFilter {
+    pub fn is_empty(&self) -> bool {
+        !self.filters_taken()
+            && self.min_duration.is_none()
+            && self.max_duration.is_none()
+            && self.min_width.is_none()
+            && self.max_width.is_none()
+            && self.min_height.is_none()
+            && self.max_height.is_none()
+            && self.orientation.is_none()
+    }
+
+    /// Only images carry a date taken.
+    pub fn filters_taken(&self) -> bool {
+        self.taken_after.is_some() || self.taken_before.is_some()
+    }
+
+    pub fn matches(&self, metadata: &MediaMetadata) -> bool {
+        fn in_range<T: PartialOrd>(value: Option<T>, min: Option<T>, max: Option<T>) -> bool {
+            if min.is_none() && max.is_none() {
+                return true;
+            }
+            let Some(value) = value else { return false };
+            min.is_none_or(|min| value >= min) && max.is_none_or(|max| value <= max)
+        }
+
+        if self.filters_taken() {
+            let Some(taken_at) = metadata.exif.as_ref().and_then(|exif| exif.taken_at) else {
+                return false;
+            };
+            if self.taken_after.is_some_and(|after| taken_at < after)
+                || self.taken_before.is_some_and(|before| taken_at >= before)
+            {
+                return false;
+            }
+        }
+
+        in_range(metadata.duration, self.min_duration, self.max_duration)
+            && in_range(metadata.width, self.min_width, self.max_width)
+            && in_range(metadata.height, self.min_height, self.max_height)
+            && self
+                .orientation
+                .is_none_or(|orientation| metadata.orientation() == Some(orientation))
+    }
+}
+
+/// The probed metadata as returned by `/random` and `/meta`.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct MetadataJson {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exif: Option<ExifJson>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<TagsJson>,
+}
+
+impl From<&MediaMetadata> for MetadataJson {
+    fn from(metadata: &MediaMetadata) -> Self {
+        Self {
+            duration: metadata.duration,
+            width: metadata.width,
+            height: metadata.height,
+            exif: metadata.exif.as_ref().map(ExifJson::from),
+            tags: metadata.tags.as_ref().map(TagsJson::from),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ExifJson {
+    orientation: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    taken_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    make: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lens: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gps: Option<GpsJson>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+struct GpsJson {
+    latitude: f64,
+    longitude: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    altitude: Option<f64>,
+}
+
+impl From<&Exif> for ExifJson {
+    fn from(exif: &Exif) -> Self {
+        Self {
+            orientation: exif.orientation as u8,
+            taken_at: exif.taken_at.map(|taken_at| taken_at.to_string()),
+            make: exif.make.clone(),
+            model: exif.model.clone(),
+            lens: exif.lens.clone(),
+            gps: exif.gps.map(|gps| GpsJson {
+                latitude: gps.latitude,
+                longitude: gps.longitude,
+                altitude: gps.altitude,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TagsJson {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    artist: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    album: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    album_artist: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    track: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    track_total: Option<u32>,
+    has_cover: bool,
+}
+
+impl From<&Tags> for TagsJson {
+    fn from(tags: &Tags) -> Self {
+        Self {
+            title: tags.title.clone(),
+            artist: tags.artist.clone(),
+            album: tags.album.clone(),
+            album_artist: tags.album_artist.clone(),
+            track: tags.track,
+            track_total: tags.track_total,
+            has_cover: tags.has_cover,
+        }
+    }
+}
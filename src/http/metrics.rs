@@ -0,0 +1,289 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use axum::extract::{MatchedPath, Request};
+use axum::http::Method;
+use axum::http::header::CONTENT_TYPE;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+use z_play::storage_class::StorageClass;
+use z_play::walkdir;
+
+use super::queue::Queue;
+use super::{FileKind, METRICS, PLAYLISTS, QUEUE, transcode};
+
+// Seconds. Most requests are answered from memory, the slow ones wait on a disk or ffprobe.
+const LATENCY_BUCKETS: [f64; 12] =
+    [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Cumulative, each counts every observation up to its bound.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if secs <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+/// Why an ffmpeg outside of a playlist is running, playlists are counted from their children.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FfmpegJob {
+    /// A continuous MP3 stream.
+    Stream,
+    /// An image rendition.
+    Image,
+}
+
+/// Counts a running ffmpeg until dropped.
+pub struct FfmpegGuard(&'static AtomicUsize);
+
+impl Drop for FfmpegGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// What `/metrics` reports that can't be read from the state it describes when scraped.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Files walked but not yet queued, in the order of [`FileKind::ALL`].
+    feeder_pools: [AtomicUsize; FileKind::NUM_VARIANTS],
+    file_cache_hits: AtomicU64,
+    file_cache_misses: AtomicU64,
+    file_cache_bytes: AtomicU64,
+    file_cache_evictions: AtomicU64,
+    transcode_restarts: AtomicU64,
+    ffmpeg_streams: AtomicUsize,
+    ffmpeg_images: AtomicUsize,
+    latencies: Mutex<FxHashMap<(Method, String), Histogram>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_feeder_pools(&self, video: usize, image: usize, audio: usize) {
+        for (kind, len) in
+            [(FileKind::Video, video), (FileKind::Image, image), (FileKind::Audio, audio)]
+        {
+            self.feeder_pools[kind_index(kind)].store(len, Ordering::Relaxed);
+        }
+    }
+
+    pub fn file_cache_hit(&self) {
+        self.file_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn file_cache_miss(&self) {
+        self.file_cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn file_cache_stored(&self, bytes: usize) {
+        self.file_cache_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn file_cache_freed(&self, bytes: usize) {
+        self.file_cache_bytes.fetch_sub(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn file_cache_evicted(&self) {
+        self.file_cache_evictions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A client seeked outside of what ffmpeg had transcoded so far.
+    pub fn transcode_restarted(&self) {
+        self.transcode_restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn track_ffmpeg(&'static self, job: FfmpegJob) -> FfmpegGuard {
+        let running = match job {
+            FfmpegJob::Stream => &self.ffmpeg_streams,
+            FfmpegJob::Image => &self.ffmpeg_images,
+        };
+        running.fetch_add(1, Ordering::Relaxed);
+        FfmpegGuard(running)
+    }
+
+    pub fn ffmpeg_running(&self, job: FfmpegJob) -> usize {
+        match job {
+            FfmpegJob::Stream => self.ffmpeg_streams.load(Ordering::Relaxed),
+            FfmpegJob::Image => self.ffmpeg_images.load(Ordering::Relaxed),
+        }
+    }
+
+    fn observe_latency(&self, method: Method, route: String, elapsed: Duration) {
+        let mut latencies = self.latencies.lock();
+        latencies.entry((method, route)).or_default().observe(elapsed.as_secs_f64());
+    }
+}
+
+fn kind_index(kind: FileKind) -> usize {
+    FileKind::ALL.iter().position(|&other| other == kind).unwrap()
+}
+
+fn kind_label(kind: FileKind) -> &'static str {
+    match kind {
+        FileKind::Video => "video",
+        FileKind::Audio => "audio",
+        FileKind::Image => "image",
+    }
+}
+
+/// Times every routed request, unmatched ones would only add noise.
+pub async fn latency_middleware(request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let method = request.method().clone();
+    let route = request.extensions().get::<MatchedPath>().map(|path| path.as_str().to_owned());
+
+    let response = next.run(request).await;
+    if let Some(route) = route {
+        METRICS.observe_latency(method, route, started.elapsed());
+    }
+    response
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    _ = writeln!(out, "# HELP {name} {help}");
+    _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Everything in the Prometheus text format.
+pub async fn metrics_handler() -> Response {
+    let mut out = String::with_capacity(8 * 1024);
+
+    let queue = QUEUE.get().unwrap();
+    let stats = queue.stats();
+    family(&mut out, "z_play_queue_files", "gauge", "Files in the shared queue.");
+    for (kind, count) in [
+        (FileKind::Video, stats.video_count),
+        (FileKind::Image, stats.image_count),
+        (FileKind::Audio, stats.audio_count),
+    ] {
+        _ = writeln!(out, "z_play_queue_files{{kind=\"{}\"}} {count}", kind_label(kind));
+    }
+    family(&mut out, "z_play_queue_capacity", "gauge", "Files queued per kind when it's full.");
+    _ = writeln!(out, "z_play_queue_capacity {}", Queue::QUEUE_SIZE);
+
+    family(&mut out, "z_play_feeder_pool_files", "gauge", "Files walked but not yet queued.");
+    for kind in FileKind::ALL {
+        let len = METRICS.feeder_pools[kind_index(kind)].load(Ordering::Relaxed);
+        _ = writeln!(out, "z_play_feeder_pool_files{{kind=\"{}\"}} {len}", kind_label(kind));
+    }
+
+    let throughput = walkdir::throughput();
+    let labels = |class: StorageClass| match class {
+        StorageClass::Hdd { major, minor } => format!("class=\"hdd\",device=\"{major}:{minor}\""),
+        StorageClass::SsdOrUnknown => "class=\"ssd_or_unknown\",device=\"\"".to_owned(),
+    };
+    family(&mut out, "z_play_walk_dirs_total", "counter", "Directories read by walks.");
+    for (class, throughput) in &throughput {
+        _ = writeln!(out, "z_play_walk_dirs_total{{{}}} {}", labels(*class), throughput.dirs);
+    }
+    family(&mut out, "z_play_walk_entries_total", "counter", "Directory entries read by walks.");
+    for (class, throughput) in &throughput {
+        _ = writeln!(out, "z_play_walk_entries_total{{{}}} {}", labels(*class), throughput.entries);
+    }
+    family(&mut out, "z_play_walk_seconds_total", "counter", "Time spent reading directories.");
+    for (class, throughput) in &throughput {
+        let secs = throughput.busy.as_secs_f64();
+        _ = writeln!(out, "z_play_walk_seconds_total{{{}}} {secs}", labels(*class));
+    }
+
+    let counters = [
+        (
+            "z_play_file_cache_hits_total",
+            "Chunks read from the file cache.",
+            &METRICS.file_cache_hits,
+        ),
+        ("z_play_file_cache_misses_total", "Chunks read from disk.", &METRICS.file_cache_misses),
+        (
+            "z_play_file_cache_evictions_total",
+            "Chunks evicted to stay under the limit.",
+            &METRICS.file_cache_evictions,
+        ),
+        (
+            "z_play_transcode_restarts_total",
+            "Transcodes restarted after a seek.",
+            &METRICS.transcode_restarts,
+        ),
+    ];
+    for (name, help, counter) in counters {
+        family(&mut out, name, "counter", help);
+        _ = writeln!(out, "{name} {}", counter.load(Ordering::Relaxed));
+    }
+    family(&mut out, "z_play_file_cache_bytes", "gauge", "Bytes held by the file cache.");
+    _ = writeln!(
+        out,
+        "z_play_file_cache_bytes {}",
+        METRICS.file_cache_bytes.load(Ordering::Relaxed)
+    );
+
+    let (playlists, transcoding) = PLAYLISTS.get().unwrap().counts();
+    family(&mut out, "z_play_playlists", "gauge", "HLS playlists being served.");
+    _ = writeln!(out, "z_play_playlists {playlists}");
+    family(&mut out, "z_play_ffmpeg_processes", "gauge", "Running ffmpeg processes.");
+    for (job, count) in [
+        ("hls", transcoding),
+        ("stream", METRICS.ffmpeg_running(FfmpegJob::Stream)),
+        ("image", METRICS.ffmpeg_running(FfmpegJob::Image)),
+    ] {
+        _ = writeln!(out, "z_play_ffmpeg_processes{{job=\"{job}\"}} {count}");
+    }
+
+    // The GPU monitor is per thread and its futures aren't Send.
+    let vram_mb = compio::runtime::spawn(transcode::available_vram_mb()).await.unwrap();
+    if let Some(vram_mb) = vram_mb {
+        family(&mut out, "z_play_gpu_vram_free_bytes", "gauge", "VRAM left for transcoding.");
+        _ = writeln!(out, "z_play_gpu_vram_free_bytes {}", vram_mb * 1024 * 1024);
+    }
+
+    family(
+        &mut out,
+        "z_play_http_request_duration_seconds",
+        "histogram",
+        "Time to the response headers, streamed bodies aren't included.",
+    );
+    let latencies = METRICS.latencies.lock();
+    let mut routes = latencies.iter().collect::<Vec<_>>();
+    routes.sort_unstable_by(|(a, _), (b, _)| {
+        (a.1.as_str(), a.0.as_str()).cmp(&(b.1.as_str(), b.0.as_str()))
+    });
+    for ((method, route), histogram) in routes {
+        let labels = format!("method=\"{method}\",route=\"{route}\"");
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+            _ = writeln!(
+                out,
+                "z_play_http_request_duration_seconds_bucket{{{labels},le=\"{bound}\"}} {count}"
+            );
+        }
+        _ = writeln!(
+            out,
+            "z_play_http_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {}",
+            histogram.count
+        );
+        _ = writeln!(out, "z_play_http_request_duration_seconds_sum{{{labels}}} {}", histogram.sum);
+        _ = writeln!(
+            out,
+            "z_play_http_request_duration_seconds_count{{{labels}}} {}",
+            histogram.count
+        );
+    }
+    drop(latencies);
+
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")], out).into_response()
+}
@@ -1,7 +1,28 @@
+mod admin;
+mod browse;
+#[cfg(feature = "dlna")]
+mod dlna;
 mod file_cache;
+mod image;
+mod library;
+mod meta;
+mod metadata;
+mod metrics;
+mod openapi;
+mod ordered;
 mod playlist;
 mod queue;
+mod random;
+mod remote;
+mod saved_playlist;
 mod serve_dir;
+mod session;
+mod shutdown;
+mod store;
+#[cfg(feature = "subsonic")]
+mod subsonic;
+mod sync;
+mod tags;
 mod transcode;
 
 use std::borrow::Cow;
@@ -9,14 +30,14 @@ use std::cell::{LazyCell, OnceCell};
 use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::num::NonZeroUsize;
-use std::path::{Path, PathBuf};
-use std::pin::Pin;
+use std::path::{Component, Path, PathBuf};
+use std::pin::{Pin, pin};
 use std::sync::{LazyLock, OnceLock};
 use std::task::{Context, Poll};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use axum::extract::Request;
-use axum::http::header::{CACHE_CONTROL, EXPIRES, PRAGMA};
+use axum::http::header::CACHE_CONTROL;
 use axum::http::{HeaderName, StatusCode};
 use axum::middleware::Next;
 use axum::response::sse::Event;
@@ -26,16 +47,28 @@ use axum::{Router, middleware};
 use axum_extra::extract::Query;
 use camino::{Utf8Path, Utf8PathBuf};
 use futures_util::Stream;
+use futures_util::future::{Either, select};
 use rand::RngExt;
 use rustc_hash::{FxHashMap, FxHashSet};
 use serde::{Deserialize, Serialize};
+use tracing::Instrument;
 use triomphe::Arc;
+use utoipa::{IntoParams, ToSchema};
 use z_play::inotify::{self, INotify};
 #[cfg(feature = "immich")]
 use z_play::random_files_immich::{self, ImmichClient};
 use z_play::walkdir::walk_roots_filter;
 
+use self::library::Library;
+use self::metadata::{MetadataCache, MetadataJson};
+use self::metrics::{FfmpegJob, Metrics};
+use self::ordered::Cursors;
 use self::queue::{Queue, QueueStats};
+use self::remote::Remote;
+use self::session::{SessionParams, SessionQueue, Sessions, StartSession};
+use self::shutdown::{Shutdown, Worker};
+use self::store::{Feedback, PathEntry, Store};
+use self::sync::SyncGroups;
 use self::transcode::should_transcode;
 
 const QUEUE_COUNT_HEADER: HeaderName = HeaderName::from_static("x-queue-count");
@@ -46,6 +79,35 @@ const QUEUE_AUDIO_COUNT_HEADER: HeaderName = HeaderName::from_static("x-queue-au
 
 static QUEUE: OnceLock<Queue> = OnceLock::new();
 
+static METADATA: LazyLock<MetadataCache> = LazyLock::new(MetadataCache::new);
+
+static STORE: OnceLock<Store> = OnceLock::new();
+
+static LIBRARY: LazyLock<Library> = LazyLock::new(Library::new);
+
+static CURSORS: LazyLock<Cursors> = LazyLock::new(Cursors::new);
+
+static SESSIONS: LazyLock<Sessions> = LazyLock::new(Sessions::new);
+
+static SYNC_GROUPS: LazyLock<SyncGroups> = LazyLock::new(SyncGroups::new);
+
+static REMOTE: LazyLock<Remote> = LazyLock::new(Remote::new);
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+static SHUTDOWN: LazyLock<Shutdown> = LazyLock::new(Shutdown::new);
+
+/// How long open responses get to finish once a signal arrives, SSE never does on its own.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long background threads and stream transcodes get to stop after the drain.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[cfg(feature = "dlna")]
+static DLNA: OnceLock<dlna::Dlna> = OnceLock::new();
+
+#[cfg(feature = "subsonic")]
+static SUBSONIC: OnceLock<subsonic::Subsonic> = OnceLock::new();
+
 // 5 GiB
 const FILE_CACHE_LIMIT: usize = 5 * 1024 * 1024 * 1024;
 #[thread_local]
@@ -55,38 +117,158 @@ static FILE_CACHE: LazyCell<file_cache::FileCache> =
 #[thread_local]
 static PLAYLISTS: OnceCell<playlist::PlaylistManager> = OnceCell::new();
 
-pub fn start_server(port: u16, roots: Vec<PathBuf>, hls_dir: PathBuf) {
-    compio::runtime::Runtime::new().unwrap().block_on(async {
-        start_server_inner(port, roots, hls_dir).await;
-    });
+// 2 GiB
+const IMAGE_CACHE_LIMIT: u64 = 2 * 1024 * 1024 * 1024;
+#[thread_local]
+static IMAGES: OnceCell<image::ImageRenditions> = OnceCell::new();
+
+/// Serves until SIGINT or SIGTERM, then stops every thread and ffmpeg child it started.
+pub fn start_server(port: u16, roots: Vec<PathBuf>, hls_dir: PathBuf, data_dir: PathBuf) {
+    let runtime = compio::runtime::Runtime::new().unwrap();
+    let workers = runtime.block_on(start_server_inner(port, roots, hls_dir, data_dir));
+    // Drops the connections that outlived the drain, which kills their Subsonic streams and
+    // any playlist they were still holding.
+    drop(runtime);
+
+    let deadline = Instant::now() + STOP_TIMEOUT;
+    shutdown::join(workers, deadline);
+    if !shutdown::wait_until(deadline, || METRICS.ffmpeg_running(FfmpegJob::Stream) == 0) {
+        tracing::warn!("Stream transcodes didn't stop in time");
+    }
+
+    // The saver has stopped, so this can't race it.
+    if let Err(error) = STORE.get().unwrap().save() {
+        tracing::error!(%error, "Failed to save the store");
+    }
+    tracing::info!("Shut down");
 }
 
-async fn start_server_inner(port: u16, mut roots: Vec<PathBuf>, hls_dir: PathBuf) {
+async fn start_server_inner(
+    port: u16,
+    mut roots: Vec<PathBuf>,
+    hls_dir: PathBuf,
+    data_dir: PathBuf,
+) -> Vec<Worker> {
     let app = Router::new()
         .route("/", get(root_handler))
+        .route("/openapi.json", get(openapi::openapi_handler))
+        .route("/metrics", get(metrics::metrics_handler))
+        .route("/admin", get(admin::status_page))
+        .route("/admin/status", get(admin::status_handler))
         .route("/roots", get(get_roots))
         .route("/roots", patch(patch_roots))
-        .route("/random", get(random_path_handler))
+        .route("/random", get(random::random_path_handler))
+        .route("/random.m3u8", get(random::random_m3u8_handler))
+        .route("/search", get(library::search_handler))
+        .route("/play", get(play_handler))
+        .route("/playlists", get(saved_playlist::list_playlists))
+        .route(
+            "/playlists/{name}",
+            get(saved_playlist::get_playlist)
+                .put(saved_playlist::put_playlist)
+                .patch(saved_playlist::patch_playlist)
+                .delete(saved_playlist::delete_playlist),
+        )
         .route("/queue", get(queue_info_handler))
         .route("/reset", get(reset_queue_handler))
         .route("/shuffle", get(shuffle_queue_handler))
+        .route("/history", get(session::history_handler))
         .route("/sse", get(sse_handler))
-        .route("/close/{*path}", post(close_file))
+        .route("/sync/clock", get(sync::clock_handler))
+        .route("/sync/{group}", get(sync::get_state))
+        .route("/sync/{group}/lead", post(sync::lead_handler))
+        .route("/sync/{group}/playback", post(sync::playback_handler))
+        .route("/remote", get(remote::remote_handler))
+        .route("/remote/players", get(remote::list_players))
+        .route("/remote/players/{id}", post(remote::command_player))
+        .nest(
+            "/close",
+            Router::new()
+                .route("/{*path}", post(close_file))
+                .route_layer(middleware::from_fn(validate_path_middleware)),
+        )
+        .nest(
+            "/rate",
+            Router::new()
+                .route("/{*path}", post(rate_handler))
+                .route_layer(middleware::from_fn(validate_path_middleware)),
+        )
+        .nest(
+            "/favorite",
+            Router::new()
+                .route("/{*path}", post(favorite_handler))
+                .route_layer(middleware::from_fn(validate_path_middleware)),
+        )
+        .nest(
+            "/hide",
+            Router::new()
+                .route("/{*path}", post(hide_handler))
+                .route_layer(middleware::from_fn(validate_path_middleware)),
+        )
+        .nest(
+            "/browse",
+            Router::new()
+                .route("/{*path}", get(browse::browse_dir))
+                .route_layer(middleware::from_fn(validate_path_middleware))
+                .route("/", get(browse::browse_roots)),
+        )
+        .nest(
+            "/tags",
+            Router::new()
+                .route(
+                    "/{*path}",
+                    get(tags::get_tags)
+                        .put(tags::put_tags)
+                        .post(tags::add_tags)
+                        .delete(tags::delete_tags),
+                )
+                .route_layer(middleware::from_fn(validate_path_middleware))
+                // Added after the layer, the listing isn't a path.
+                .route("/", get(tags::list_tags)),
+        )
+        .nest(
+            "/meta",
+            Router::new()
+                .route("/{*path}", get(meta::meta_handler))
+                .route_layer(middleware::from_fn(validate_path_middleware)),
+        )
+        .nest(
+            "/cover",
+            Router::new()
+                .route("/{*path}", get(meta::cover_handler))
+                .route_layer(middleware::from_fn(validate_path_middleware)),
+        )
         .nest(
             "/files",
             Router::new()
                 .route("/{*path}", get(serve_dir::serve_dir))
                 .route_layer(middleware::from_fn(validate_path_middleware)),
         );
+    #[cfg(feature = "dlna")]
+    let app = app.merge(dlna::routes());
+    #[cfg(feature = "subsonic")]
+    let app = match subsonic::Subsonic::from_env() {
+        Some(subsonic) => {
+            SUBSONIC.get_or_init(|| subsonic);
+            app.merge(subsonic::routes())
+        }
+        None => {
+            tracing::info!("Subsonic API disabled, set Z_PLAY_SUBSONIC_PASSWORD to enable it");
+            app
+        }
+    };
+    let app = app
+        .route_layer(middleware::from_fn(metrics::latency_middleware))
+        .layer(middleware::from_fn(trace_middleware));
 
     roots.retain_mut(|root| match root.canonicalize() {
         Ok(path) => {
-            println!("Adding root: {}", path.display());
+            tracing::info!(root = %path.display(), "Adding root");
             *root = path;
             true
         }
         Err(error) => {
-            println!("Removing invalid root \"{}\": {error}", root.display());
+            tracing::warn!(root = %root.display(), %error, "Removing invalid root");
             false
         }
     });
@@ -94,29 +276,80 @@ async fn start_server_inner(port: u16, mut roots: Vec<PathBuf>, hls_dir: PathBuf
 
     QUEUE.get_or_init(move || Queue::new(roots));
 
+    tracing::info!(dir = %data_dir.display(), "Using data dir");
+    STORE.get_or_init(|| Store::load(&data_dir));
+    let mut workers = vec![Worker::spawn("store_saver", || {
+        let saver = STORE.get().unwrap().run_saver();
+        compio::runtime::Runtime::new().unwrap().block_on(SHUTDOWN.or_shutdown(saver));
+    })];
+
+    let images = image::ImageRenditions::new(hls_dir.join("images"), IMAGE_CACHE_LIMIT).await;
+    IMAGES.get_or_init(move || images);
+
     let playlists = playlist::PlaylistManager::new(hls_dir).await;
     PLAYLISTS.get_or_init(move || playlists);
 
     #[cfg(feature = "immich")]
-    std::thread::spawn(|| {
+    workers.push(Worker::spawn("immich_feeder", || {
         let queue = QUEUE.get().unwrap();
-        compio::runtime::Runtime::new().unwrap().block_on(immich_queue_feeder(queue));
-    });
+        let feeder = immich_queue_feeder(queue).instrument(tracing::info_span!("immich_feeder"));
+        compio::runtime::Runtime::new().unwrap().block_on(SHUTDOWN.or_shutdown(feeder));
+    }));
 
-    std::thread::spawn(|| {
+    workers.push(Worker::spawn("queue_feeder", || {
         let queue = QUEUE.get().unwrap();
-        compio::runtime::Runtime::new().unwrap().block_on(queue_feeder(queue, None));
-    });
+        let feeder = queue_feeder(queue, None).instrument(tracing::info_span!("queue_feeder"));
+        compio::runtime::Runtime::new().unwrap().block_on(SHUTDOWN.or_shutdown(feeder));
+    }));
+
+    workers.push(Worker::spawn("sessions_feeder", || {
+        let feeder = SESSIONS.run_feeder();
+        compio::runtime::Runtime::new().unwrap().block_on(SHUTDOWN.or_shutdown(feeder));
+    }));
 
-    std::thread::spawn(|| directory_counts());
+    workers.push(Worker::spawn("directory_counts", directory_counts));
+
+    workers.push(Worker::spawn("metadata_worker", || {
+        let worker = METADATA.run_worker();
+        compio::runtime::Runtime::new().unwrap().block_on(SHUTDOWN.or_shutdown(worker));
+    }));
+
+    #[cfg(feature = "dlna")]
+    {
+        DLNA.get_or_init(|| dlna::Dlna::new(&data_dir, port));
+        workers.push(Worker::spawn("ssdp", || {
+            if let Err(error) = DLNA.get().unwrap().run_ssdp() {
+                tracing::error!(%error, "SSDP stopped, TVs won't find the DLNA server");
+            }
+        }));
+    }
+
+    compio::runtime::spawn(async {
+        shutdown::signal().await;
+        tracing::info!("Shutting down");
+        SHUTDOWN.request();
+    })
+    .detach();
 
     let address = SocketAddr::from(([0, 0, 0, 0], port));
-    println!("Listening on http://{address}");
+    tracing::info!(%address, "Listening");
     let listener = compio::net::TcpListener::bind(address).await.unwrap();
-    cyper_axum::serve(listener, app).await.unwrap();
+    let server = cyper_axum::serve(listener, app).with_graceful_shutdown(SHUTDOWN.wait());
+    let drain_timeout = async {
+        SHUTDOWN.wait().await;
+        compio::time::sleep(DRAIN_TIMEOUT).await;
+    };
+    match select(pin!(server.into_future()), pin!(drain_timeout)).await {
+        Either::Left((result, _)) => result.unwrap(),
+        Either::Right(_) => tracing::warn!("Connections still open after the drain timeout"),
+    }
+
+    PLAYLISTS.get().unwrap().close_all().await;
+
+    workers
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, ToSchema)]
 #[serde(rename_all = "lowercase")]
 enum FileKind {
     Video,
@@ -154,37 +387,40 @@ impl FileKind {
             "jpg" | "jpeg" | "png" | "gif" | "bmp" | "webp" | "svg" | "avif" | "ico" | "apng" => {
                 Some(Self::Image)
             }
+            // Converted on demand, see `image::needs_conversion`.
+            "heic" | "heif" | "tif" | "tiff" | "cr2" | "cr3" | "nef" | "nrw" | "arw" | "srf"
+            | "sr2" | "dng" | "orf" | "rw2" | "raf" | "pef" | "srw" => Some(Self::Image),
             "mp4" | "mkv" | "webm" | "avi" | "mov" | "qt" | "wmv" | "flv" | "mpeg" | "mpg"
             | "ogv" | "ts" | "m2ts" | "vob" | "3gp" | "rmvb" => Some(Self::Video),
-            "mp3" | "wav" | "ogg" | "m4a" | "flac" | "aac" | "mpga" | "opus" | "weba" | "oga" => {
-                Some(Self::Audio)
-            }
+            "mp3" | "wav" | "ogg" | "m4a" | "flac" | "aac" | "mpga" | "opus" | "weba" | "oga"
+            | "wma" | "ape" | "alac" | "dsf" | "dff" | "mka" | "wv" | "tta" | "aif" | "aiff"
+            | "ac3" | "dts" | "amr" | "mpc" => Some(Self::Audio),
             _ => None,
         }
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
-struct RandomQuery {
-    #[serde(default, rename = "kind")]
-    kinds: FxHashSet<FileKind>,
-    #[serde(default, rename = "root")]
-    roots: FxHashSet<String>,
-}
-
 async fn root_handler() -> Html<&'static str> {
     Html(include_str!("index.html"))
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 struct RootJson {
     path: String,
     enabled: bool,
 }
 
-async fn get_roots() -> impl IntoResponse {
-    let queue = QUEUE.get().unwrap();
-
+#[utoipa::path(
+    get,
+    path = "/roots",
+    tag = "queue",
+    params(SessionParams),
+    responses(
+        (status = OK, description = "Enabled roots first", body = Vec<RootJson>),
+        (status = SERVICE_UNAVAILABLE, description = "Too many sessions in use to start another"),
+    ),
+)]
+async fn get_roots(StartSession(queue): StartSession) -> impl IntoResponse {
     let (enabled_roots, disabled_roots) = futures_util::join!(
         queue.enabled_roots().read_async(),
         queue.disabled_roots().read_async()
@@ -197,12 +433,24 @@ async fn get_roots() -> impl IntoResponse {
         .map(|(path, enabled)| RootJson { path: path.to_string_lossy().into_owned(), enabled })
         .collect::<Vec<_>>();
 
-    (queue_info(), Json(roots))
+    (queue_info(&queue), Json(roots))
 }
 
-async fn patch_roots(body: Json<Vec<RootJson>>) -> impl IntoResponse {
-    let queue = QUEUE.get().unwrap();
-
+#[utoipa::path(
+    patch,
+    path = "/roots",
+    tag = "queue",
+    params(SessionParams),
+    request_body(content = Vec<RootJson>, description = "Roots left out keep their state"),
+    responses(
+        (status = NO_CONTENT, description = "The queue is refilled from the new roots"),
+        (status = SERVICE_UNAVAILABLE, description = "Too many sessions in use to start another"),
+    ),
+)]
+async fn patch_roots(
+    StartSession(queue): StartSession,
+    body: Json<Vec<RootJson>>,
+) -> impl IntoResponse {
     let (mut enabled_roots, mut disabled_roots) = futures_util::join!(
         queue.enabled_roots().write_async(),
         queue.disabled_roots().write_async()
@@ -227,86 +475,197 @@ async fn patch_roots(body: Json<Vec<RootJson>>) -> impl IntoResponse {
 
     queue.refresh_roots().await;
 
-    (queue_info(), StatusCode::NO_CONTENT)
+    (queue_info(&queue), StatusCode::NO_CONTENT)
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
 struct PathResponse {
     path: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     display_path: Option<String>,
     kind: FileKind,
+    #[serde(flatten)]
+    entry: PathEntry,
+    #[serde(flatten)]
+    metadata: MetadataJson,
 }
 
-#[axum::debug_handler]
-async fn random_path_handler(query: Query<RandomQuery>) -> impl IntoResponse {
-    let RandomQuery { kinds, roots } = query.0;
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct PlayQuery {
+    path: String,
+    /// Sync group to play it in, only its leader may.
+    group: Option<String>,
+    client: Option<String>,
+}
 
-    let queue = QUEUE.get().unwrap();
+/// Like `/random`, but for a file the client picked, e.g. from `/search`.
+#[utoipa::path(
+    get,
+    path = "/play",
+    tag = "files",
+    params(SessionParams, PlayQuery),
+    responses(
+        (status = OK, body = PathResponse),
+        (status = BAD_REQUEST, description = "Unsupported file type"),
+        (status = NOT_FOUND, description = "Not a file in an enabled root"),
+        (status = CONFLICT, description = "Only the group's leader may play"),
+    ),
+)]
+async fn play_handler(
+    queue: SessionQueue,
+    Query(PlayQuery { path, group, client }): Query<PlayQuery>,
+) -> Response {
+    let group = match group.map(|group| sync::leader_group(&group, client.as_deref())) {
+        Some(Ok(group)) => Some(group),
+        Some(Err(response)) => return response,
+        None => None,
+    };
 
-    let (path, display_path, file_kind) = loop {
-        let filter_kinds = if kinds.is_empty() { None } else { Some(&kinds) };
-        let filter_roots = if roots.is_empty() { None } else { Some(&roots) };
-        let (path, file_kind) = queue.find_pop_async(filter_kinds, filter_roots).await;
-
-        let path = Utf8PathBuf::from_path_buf(path).expect("Only UTF-8 paths are supported");
-
-        // No point pre-caching if we're going to transcode.
-        let should_transcode =
-            compio::runtime::spawn(should_transcode(path.clone())).await.unwrap();
-        if should_transcode {
-            let path_clone = path.clone();
-            let playlist = compio::runtime::spawn(async move {
-                PLAYLISTS.get().unwrap().get(path_clone.as_ref()).await
-            })
-            .await
-            .unwrap();
-
-            match playlist {
-                // Gifs are transcoded to video.
-                Ok(playlist) => {
-                    let playlist = Utf8PathBuf::from_path_buf(playlist).unwrap();
-                    break (playlist, Some(path), FileKind::Video);
-                }
-                Err(error) => {
-                    println!("Failed to get playlist for {path}: {error}");
-                }
-            }
+    let path = Utf8Path::new("/").join(path);
+    let Some(file_kind) = FileKind::from_path(path.as_std_path()) else {
+        return (StatusCode::BAD_REQUEST, "Unsupported file type").into_response();
+    };
+    // Resolves `..`, which would otherwise pass the prefix check below.
+    let Ok(path) = path.canonicalize_utf8() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let in_root = {
+        let roots = queue.enabled_roots().read_async().await;
+        roots.iter().any(|root| path.as_std_path().starts_with(root))
+    };
+    if !in_root || !path.is_file() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let (path, display_path, file_kind) = match prepare_file(path, file_kind).await {
+        Prepared::Ready(path, display_path, file_kind) => (path, display_path, file_kind),
+        Prepared::Failed => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to open file").into_response();
         }
+        // Serve it anyway, the client asked for this one.
+        Prepared::TimedOut(path) => (path, None, file_kind),
+    };
+    queue.record_played(display_path.as_ref().unwrap_or(&path).as_std_path());
 
-        let future = compio::time::timeout(Duration::from_secs(1), precache_file(path.clone()));
-        // The future isn't Send unless we spawn this, and axum was designed for tokio.
-        let result = compio::runtime::spawn(future).await.unwrap();
-        match result {
-            Ok(Ok(_)) => break (path, None, file_kind),
-            Ok(Err(_)) => continue,
-            Err(_) => {
-                // Timeout.
-                compio::runtime::spawn(queue.push_async(path.into())).detach();
-                continue;
+    let response = PathResponse::new(path, display_path, file_kind).await;
+    if let Some(group) = group {
+        group.publish_item(response.clone());
+    }
+    ([(CACHE_CONTROL, "no-cache")], Json(response)).into_response()
+}
+
+impl PathResponse {
+    async fn new(path: Utf8PathBuf, display_path: Option<Utf8PathBuf>, kind: FileKind) -> Self {
+        // Playlists are probed by their source file.
+        let source_path = display_path.as_ref().unwrap_or(&path).clone();
+        let entry = STORE.get().unwrap().get(source_path.as_std_path()).unwrap_or_default();
+        let metadata =
+            compio::runtime::spawn(async move { METADATA.load(source_path.as_std_path()).await })
+                .await
+                .unwrap();
+
+        Self {
+            path: path.into_string(),
+            display_path: display_path.map(Utf8PathBuf::into_string),
+            kind,
+            entry,
+            metadata: MetadataJson::from(&*metadata),
+        }
+    }
+}
+
+enum Prepared {
+    /// The path to serve, the source file when it's a playlist, and the kind to play it as.
+    Ready(Utf8PathBuf, Option<Utf8PathBuf>, FileKind),
+    Failed,
+    /// Pre-caching took too long, the file is probably on a slow or sleeping disk.
+    TimedOut(Utf8PathBuf),
+}
+
+/// Gets a file ready for a client, starting a transcode or pre-caching it as needed.
+async fn prepare_file(path: Utf8PathBuf, file_kind: FileKind) -> Prepared {
+    // No point pre-caching if we're going to transcode.
+    let should_transcode = compio::runtime::spawn(should_transcode(path.clone())).await.unwrap();
+    if should_transcode {
+        let path_clone = path.clone();
+        let playlist = compio::runtime::spawn(async move {
+            PLAYLISTS.get().unwrap().get(path_clone.as_ref()).await
+        })
+        .await
+        .unwrap();
+
+        match playlist {
+            Ok(playlist) => {
+                let playlist = Utf8PathBuf::from_path_buf(playlist).unwrap();
+                // Gifs are transcoded to video.
+                let kind =
+                    if file_kind == FileKind::Audio { FileKind::Audio } else { FileKind::Video };
+                return Prepared::Ready(playlist, Some(path), kind);
+            }
+            Err(error) => {
+                tracing::error!(%path, %error, "Failed to get playlist");
             }
         }
-    };
+    }
 
-    let display_path = display_path.map(Utf8PathBuf::into_string);
+    if image::needs_conversion(path.as_std_path()) {
+        // The original is never served and the rendition depends on the client's screen.
+        return Prepared::Ready(path, None, file_kind);
+    }
 
-    (
-        [
-            (CACHE_CONTROL, "no-cache, no-store, must-revalidate"),
-            (PRAGMA, "no-cache"),
-            (EXPIRES, "0"),
-        ],
-        queue_info(),
-        Json(PathResponse { path: path.into_string(), display_path, kind: file_kind }),
-    )
+    let future = compio::time::timeout(Duration::from_secs(1), precache_file(path.clone()));
+    // The future isn't Send unless we spawn this, and axum was designed for tokio.
+    let result = compio::runtime::spawn(future).await.unwrap();
+    match result {
+        Ok(Ok(_)) => Prepared::Ready(path, None, file_kind),
+        Ok(Err(_)) => Prepared::Failed,
+        Err(_) => Prepared::TimedOut(path),
+    }
 }
 
-async fn queue_info_handler() -> [(HeaderName, String); 5] {
-    queue_info()
+/// Like [`prepare_file`], but only works out where the file will be served. Transcodes start
+/// when their playlist is first fetched.
+async fn locate_file(path: Utf8PathBuf, file_kind: FileKind) -> Prepared {
+    let should_transcode = compio::runtime::spawn(should_transcode(path.clone())).await.unwrap();
+    if !should_transcode {
+        return Prepared::Ready(path, None, file_kind);
+    }
+
+    let path_clone = path.clone();
+    let playlist = compio::runtime::spawn(async move {
+        PLAYLISTS.get().unwrap().playlist_file_for(path_clone.as_ref())
+    })
+    .await
+    .unwrap();
+    let playlist = Utf8PathBuf::from_path_buf(playlist).unwrap();
+    let kind = if file_kind == FileKind::Audio { FileKind::Audio } else { FileKind::Video };
+    Prepared::Ready(playlist, Some(path), kind)
 }
 
-fn queue_info() -> [(HeaderName, String); 5] {
-    let queue = QUEUE.get().unwrap();
+#[utoipa::path(
+    get,
+    path = "/queue",
+    tag = "queue",
+    params(SessionParams),
+    responses((
+        status = OK,
+        description = "The same headers come with `/roots` and `/random`",
+        headers(
+            ("x-queue-count" = usize, description = "Files in the queue"),
+            ("x-queue-size" = usize, description = "Files queued per kind when it's full"),
+            ("x-queue-video-count" = usize),
+            ("x-queue-image-count" = usize),
+            ("x-queue-audio-count" = usize),
+        ),
+    )),
+)]
+async fn queue_info_handler(queue: SessionQueue) -> [(HeaderName, String); 5] {
+    queue_info(&queue)
+}
+
+fn queue_info(queue: &Queue) -> [(HeaderName, String); 5] {
     let stats = queue.stats();
     [
         (QUEUE_COUNT_HEADER, queue.len().to_string()),
@@ -317,50 +676,198 @@ fn queue_info() -> [(HeaderName, String); 5] {
     ]
 }
 
-async fn reset_queue_handler() -> impl IntoResponse {
-    let queue = QUEUE.get().unwrap();
-
+#[utoipa::path(
+    get,
+    path = "/reset",
+    tag = "queue",
+    params(SessionParams),
+    responses((status = NO_CONTENT)),
+)]
+async fn reset_queue_handler(queue: SessionQueue) -> impl IntoResponse {
     queue.reset().await;
     DIR_COUNTS.notify.notify(usize::MAX);
     StatusCode::NO_CONTENT
 }
 
-async fn shuffle_queue_handler() -> impl IntoResponse {
-    let queue = QUEUE.get().unwrap();
-
+#[utoipa::path(
+    get,
+    path = "/shuffle",
+    tag = "queue",
+    params(SessionParams),
+    responses((status = NO_CONTENT)),
+)]
+async fn shuffle_queue_handler(queue: SessionQueue) -> impl IntoResponse {
     queue.shuffle();
     StatusCode::NO_CONTENT
 }
 
-async fn close_file(axum::extract::Path(path): axum::extract::Path<String>) -> impl IntoResponse {
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CloseQuery {
+    /// Seconds the file was shown or played for, nothing is learned without it.
+    watched: Option<f64>,
+    duration: Option<f64>,
+    #[serde(default)]
+    looped: u32,
+}
+
+#[utoipa::path(
+    post,
+    path = "/close/{path}",
+    tag = "files",
+    params(("path" = String, Path, description = "Absolute path without the leading slash"), CloseQuery),
+    responses(
+        (status = NO_CONTENT),
+        (status = NOT_FOUND, description = "Not in an enabled root or a transcode"),
+    ),
+)]
+async fn close_file(
+    axum::extract::Path(path): axum::extract::Path<String>,
+    Query(CloseQuery { watched, duration, looped }): Query<CloseQuery>,
+) -> impl IntoResponse {
     let path = Utf8Path::new("/").join(path);
     FILE_CACHE.close(&path);
     PLAYLISTS.get().unwrap().close(path.as_ref());
+
+    if let Some(watched) = watched.filter(|watched| watched.is_finite()) {
+        let source_path = playlist::PlaylistManager::playlist_path_to_file_path(path.as_ref())
+            .unwrap_or_else(|| path.into_std_path_buf());
+        let feedback = Feedback::from_watch_time(watched, duration, looped);
+        STORE.get().unwrap().record_feedback(&source_path, feedback);
+    }
+
     StatusCode::NO_CONTENT
 }
 
-async fn validate_path_middleware(request: Request, next: Next) -> Result<Response, StatusCode> {
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct RateQuery {
+    /// 0 clears the rating.
+    rating: u8,
+}
+
+#[utoipa::path(
+    post,
+    path = "/rate/{path}",
+    tag = "files",
+    params(("path" = String, Path, description = "Absolute path without the leading slash"), RateQuery),
+    responses(
+        (status = OK, body = PathEntry),
+        (status = BAD_REQUEST, description = "Rating out of range"),
+        (status = NOT_FOUND, description = "Not in an enabled root"),
+    ),
+)]
+async fn rate_handler(
+    axum::extract::Path(path): axum::extract::Path<String>,
+    Query(RateQuery { rating }): Query<RateQuery>,
+) -> Response {
+    if rating > PathEntry::MAX_RATING {
+        let message = format!("Rating must be between 0 and {}", PathEntry::MAX_RATING);
+        return (StatusCode::BAD_REQUEST, message).into_response();
+    }
+
+    let path = Utf8Path::new("/").join(path);
+    let entry = STORE.get().unwrap().update(path.as_std_path(), |entry| {
+        entry.rating = (rating > 0).then_some(rating);
+    });
+    Json(entry).into_response()
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct FlagQuery {
+    /// Defaults to true, pass false to undo.
+    value: Option<bool>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/favorite/{path}",
+    tag = "files",
+    params(("path" = String, Path, description = "Absolute path without the leading slash"), FlagQuery),
+    responses(
+        (status = OK, body = PathEntry),
+        (status = NOT_FOUND, description = "Not in an enabled root"),
+    ),
+)]
+async fn favorite_handler(
+    axum::extract::Path(path): axum::extract::Path<String>,
+    Query(FlagQuery { value }): Query<FlagQuery>,
+) -> Json<PathEntry> {
+    let path = Utf8Path::new("/").join(path);
+    let entry = STORE.get().unwrap().update(path.as_std_path(), |entry| {
+        entry.favorite = value.unwrap_or(true);
+    });
+    Json(entry)
+}
+
+#[utoipa::path(
+    post,
+    path = "/hide/{path}",
+    tag = "files",
+    params(("path" = String, Path, description = "Absolute path without the leading slash"), FlagQuery),
+    responses(
+        (status = OK, body = PathEntry),
+        (status = NOT_FOUND, description = "Not in an enabled root"),
+    ),
+)]
+async fn hide_handler(
+    axum::extract::Path(path): axum::extract::Path<String>,
+    Query(FlagQuery { value }): Query<FlagQuery>,
+) -> Json<PathEntry> {
+    let path = Utf8Path::new("/").join(path);
+    let hidden = value.unwrap_or(true);
+    let entry = STORE.get().unwrap().update(path.as_std_path(), |entry| entry.hidden = hidden);
+    if hidden {
+        QUEUE.get().unwrap().remove(path.as_std_path());
+        SESSIONS.remove_path(path.as_std_path());
+    }
+    Json(entry)
+}
+
+/// Everything logged while handling a request is tagged with it, including the outcome.
+async fn trace_middleware(request: Request, next: Next) -> Response {
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        // Queries carry Subsonic passwords and session ids, keep them out of the logs.
+        path = %request.uri().path(),
+        status = tracing::field::Empty,
+    );
+    let response = next.run(request).instrument(span.clone()).await;
+    span.record("status", response.status().as_u16());
+    tracing::debug!(parent: &span, "Responded");
+    response
+}
+
+async fn validate_path_middleware(
+    queue: SessionQueue,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
     let path_query = request.uri().path();
     let decoded_path = urlencoding::decode(path_query).map_err(|_| StatusCode::BAD_REQUEST)?;
     let requested_path = Path::new(decoded_path.as_ref());
+    // Handlers use the path as given, so it has to name the file it resolves to.
+    if requested_path.components().any(|component| component == Component::ParentDir) {
+        return Err(StatusCode::NOT_FOUND);
+    }
 
-    let path_clone = requested_path.to_owned();
-    let is_hls_playlist =
-        compio::runtime::spawn(
-            async move { PLAYLISTS.get().unwrap().contains_file(&path_clone).await },
-        )
-        .await
-        .unwrap();
+    // Transcoded files stand in for their source file, which is what has to be in a root.
+    let source_path = playlist::PlaylistManager::playlist_path_to_file_path(requested_path);
+    let checked_path = source_path.as_deref().unwrap_or(requested_path);
+    // Roots are canonical, so symlinks out of them have to be resolved for the prefix check.
+    let checked_path = checked_path.canonicalize().map_err(|_| StatusCode::NOT_FOUND)?;
 
-    let is_valid = is_hls_playlist || {
-        let queue = QUEUE.get().unwrap();
+    let in_root = {
         let roots = queue.enabled_roots().read_async().await;
-        roots.iter().any(|root| requested_path.starts_with(root))
+        roots.iter().any(|root| checked_path.starts_with(root))
     };
-    if is_valid { Ok(next.run(request).await) } else { Err(StatusCode::NOT_FOUND) }
+    if in_root { Ok(next.run(request).await) } else { Err(StatusCode::NOT_FOUND) }
 }
 
-#[derive(Debug, Clone, Serialize)]
+/// Sent as `queue_info` events on `/sse`.
+#[derive(Debug, Clone, Serialize, ToSchema)]
 struct QueueStatsJson {
     queue_count: usize,
     queue_size: usize,
@@ -369,62 +876,112 @@ struct QueueStatsJson {
     audio_count: usize,
 }
 
-async fn sse_handler() -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SseQuery {
+    /// Also stream the state of this sync group.
+    group: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/sse",
+    tag = "queue",
+    params(SessionParams, SseQuery),
+    responses(
+        (
+            status = OK,
+            content_type = "text/event-stream",
+            description = "`queue_info` events carrying `QueueStatsJson`, and `sync` events carrying `SyncState` when following a group",
+        ),
+        (status = NOT_FOUND, description = "Unknown sync group"),
+    ),
+)]
+async fn sse_handler(
+    queue: SessionQueue,
+    Query(SseQuery { group }): Query<SseQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+    let group = match group {
+        Some(group) => Some(SYNC_GROUPS.get(&group).ok_or(StatusCode::NOT_FOUND)?),
+        None => None,
+    };
     let (tx, rx) = z_queue::defaults::bounded(NonZeroUsize::MIN);
 
-    let tx_clone = tx.clone();
-    compio::runtime::spawn(async move {
-        let tx = tx_clone;
-        let queue = QUEUE.get().unwrap();
+    if let Some(group) = group {
+        let tx = tx.clone();
+        compio::runtime::spawn(async move {
+            let mut prev_seq = None;
+            loop {
+                // Listen before reading so a change in between isn't missed.
+                let listener = group.observe();
+                let state = group.state();
+                let event = if prev_seq != Some(state.seq) {
+                    prev_seq = Some(state.seq);
+                    let json = serde_json::to_string(&state).unwrap();
+                    Event::default().event("sync").data(json)
+                } else {
+                    // Finds out whether the follower is still there.
+                    Event::default().comment("keep-alive")
+                };
+                if tx.send_async(Ok(event)).await.is_err() {
+                    break;
+                }
+
+                // A connected follower keeps the group around even while nothing changes.
+                group.touch();
+                _ = compio::time::timeout(Duration::from_secs(15), listener).await;
+            }
+        })
+        .detach();
+    }
 
+    compio::runtime::spawn(async move {
         let mut prev_len = 0;
         let mut prev_stats = queue::QueueStats::default();
+        let mut last_sent = Instant::now();
 
         loop {
             let stats = queue.stats();
             let len = queue.len();
-            if stats == prev_stats && len == prev_len {
+            let event = if stats != prev_stats || len != prev_len {
+                let json = QueueStatsJson {
+                    queue_count: len,
+                    queue_size: Queue::QUEUE_SIZE,
+                    video_count: stats.video_count,
+                    image_count: stats.image_count,
+                    audio_count: stats.audio_count,
+                };
+
+                prev_stats = stats;
+                prev_len = len;
+
+                let json = serde_json::to_string(&json).unwrap();
+                Event::default().event("queue_info").data(json)
+            } else if last_sent.elapsed() >= Duration::from_secs(15) {
+                // The browser ignores this, but it keeps the TCP socket warm and finds out when
+                // the client is gone, which unchanged stats never would.
+                Event::default().comment("keep-alive")
+            } else {
                 compio::time::sleep(Duration::from_millis(500)).await;
                 continue;
-            }
-
-            let json = QueueStatsJson {
-                queue_count: len,
-                queue_size: Queue::QUEUE_SIZE,
-                video_count: stats.video_count,
-                image_count: stats.image_count,
-                audio_count: stats.audio_count,
             };
-
-            prev_stats = stats;
-            prev_len = len;
-
-            let json = serde_json::to_string(&json).unwrap();
-            let event = Event::default().event("queue_info").data(json);
             if tx.send_async(Ok(event)).await.is_err() {
                 break;
             }
+            last_sent = Instant::now();
 
-            compio::time::sleep(Duration::from_millis(500)).await;
-        }
-    })
-    .detach();
-
-    compio::runtime::spawn(async move {
-        let mut interval = compio::time::interval(Duration::from_secs(15));
-        loop {
-            interval.tick().await;
-            // The browser ignores this, but it keeps the TCP socket warm.
-            let event = Event::default().comment("keep-alive");
-            if tx.send_async(Ok(event)).await.is_err() {
-                break;
+            // An open player keeps its session alive.
+            if let Some(session) = queue.session() {
+                session.touch();
             }
+
+            compio::time::sleep(Duration::from_millis(500)).await;
         }
     })
     .detach();
 
     let stream = rx.into_stream();
-    Sse::new(stream)
+    Ok(Sse::new(stream))
 }
 
 fn filter_path<P>(path: P) -> bool
@@ -432,7 +989,7 @@ where
     P: AsRef<Path>,
 {
     let queue = QUEUE.get().unwrap();
-    if queue.contains_path(path.as_ref()) {
+    if queue.contains_path(path.as_ref()) || STORE.get().unwrap().is_hidden(path.as_ref()) {
         return false;
     }
 
@@ -455,8 +1012,9 @@ async fn immich_queue_feeder(queue: &Queue) {
 
         ImmichClient::new(immich_url, &immich_api_key)
     };
+    let store = STORE.get().unwrap();
 
-    println!("Starting queue feeder");
+    tracing::info!("Starting queue feeder");
     'main: loop {
         let queue_len = queue.len();
         // Start at 100ms and scale up to 10s based on queue length.
@@ -466,7 +1024,7 @@ async fn immich_queue_feeder(queue: &Queue) {
             if queue.len() == Queue::MAX_QUEUE_SIZE {
                 let listener = queue.observe_pop();
                 if queue.len() == Queue::MAX_QUEUE_SIZE {
-                    println!("Queue is full, waiting for pop");
+                    tracing::debug!("Queue is full, waiting for pop");
                     listener.await;
                     continue 'main;
                 }
@@ -474,7 +1032,7 @@ async fn immich_queue_feeder(queue: &Queue) {
 
             let mut roots = queue.enabled_roots().read_async().await.clone();
             if roots.is_empty() {
-                println!("No enabled roots, sleeping for 1s");
+                tracing::debug!("No enabled roots, sleeping for 1s");
                 compio::time::sleep(Duration::from_secs(1)).await;
                 continue 'main;
             }
@@ -492,14 +1050,14 @@ async fn immich_queue_feeder(queue: &Queue) {
                 &immich,
                 &roots,
                 timeout,
-                |path| filter_path(path),
+                |path| filter_path(path) && store.accept(path.as_std_path()),
             )
             .await;
             match path {
                 Some(path) => break path,
                 None => {
                     timeout_ms += 1000;
-                    println!("[Immich] No files found, increasing timeout to {timeout_ms}ms");
+                    tracing::debug!(timeout_ms, "No files found, increasing timeout");
 
                     if queue.len() > 1 {
                         compio::time::sleep(Duration::from_millis(100)).await;
@@ -512,8 +1070,48 @@ async fn immich_queue_feeder(queue: &Queue) {
     }
 }
 
+// Entries replaced each time a filtered request starves.
+const STARVING_EVICT_COUNT: usize = 10;
+// Random candidates compared when picking by rating.
+const WEIGHTED_CANDIDATES: usize = 4;
+// Bounds the walk of large directories asked for by filtered requests.
+const WANTED_WALK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Pushes random files from `paths`, which may be files or directories, so requests filtered
+/// by tag or directory don't have to wait for the regular walk to come across them.
+async fn push_wanted<F>(queue: &Queue, paths: Vec<PathBuf>, filter: F)
+where
+    F: Fn(&Path, bool) -> bool + Send + Sync + Copy + 'static,
+{
+    let roots = queue.enabled_roots().read_async().await.clone();
+    let (dirs, files): (Vec<_>, Vec<_>) = paths
+        .into_iter()
+        .filter(|path| roots.iter().any(|root| path.starts_with(root)))
+        .partition(|path| path.is_dir());
+
+    let mut pool = files.into_iter().filter(|path| filter(path, false)).collect::<Vec<_>>();
+    if !dirs.is_empty() {
+        let deadline = std::time::Instant::now() + WANTED_WALK_TIMEOUT;
+        if let Ok(rx) = walk_roots_filter(&dirs, Some(deadline), filter).await {
+            while let Ok(path) = rx.recv_async().await {
+                pool.push(path);
+            }
+        }
+    }
+
+    {
+        use rand::seq::SliceRandom;
+        pool.shuffle(&mut rand::rng());
+    }
+
+    let paths = pool.into_iter().filter(|path| !queue.contains_path(path));
+    for path in paths.take(STARVING_EVICT_COUNT) {
+        queue.push_async(path).await;
+    }
+}
+
 async fn queue_feeder(queue: &Queue, max_count: Option<usize>) {
-    println!("Starting streaming cyclic queue feeder");
+    tracing::info!("Starting streaming cyclic queue feeder");
 
     let mut counter = 0;
 
@@ -525,14 +1123,31 @@ async fn queue_feeder(queue: &Queue, max_count: Option<usize>) {
     let mut current_walk_rx: Option<z_play::walkdir::PathReceiver> = None;
     let mut rng = rand::rng();
 
+    let store = STORE.get().unwrap();
     let filter = |path: &Path, is_dir: bool| {
         if is_dir {
             return true;
         }
-        FileKind::from_path(path).is_some()
+        FileKind::from_path(path).is_some() && !store.is_hidden(path)
     };
 
     'main: loop {
+        METRICS.set_feeder_pools(video_pool.len(), image_pool.len(), audio_pool.len());
+
+        // Narrow filters can leave a full queue without a single match, make room for new
+        // entries so waiting requests get to see more of the library.
+        for kind in queue.take_starving() {
+            queue.evict_oldest(kind, STARVING_EVICT_COUNT).await;
+        }
+        let mut wanted = Vec::from_iter(queue.take_starving_dirs());
+        let starving_tags = queue.take_starving_tags();
+        if !starving_tags.is_empty() {
+            wanted.extend(store.tagged_paths(&starving_tags));
+        }
+        if !wanted.is_empty() {
+            push_wanted(queue, wanted, filter).await;
+        }
+
         let stats = queue.stats();
         let total_counts = DIR_COUNTS.total_counts.read();
 
@@ -547,6 +1162,7 @@ async fn queue_feeder(queue: &Queue, max_count: Option<usize>) {
         if !need_videos && !need_images && !need_audios {
             tokio::select! {
                 _ = queue.observe_pop() => (),
+                _ = queue.observe_starving() => (),
                 _ = DIR_COUNTS.notify.listener() => (),
             }
             continue 'main;
@@ -586,15 +1202,41 @@ async fn queue_feeder(queue: &Queue, max_count: Option<usize>) {
 
         // Helper to pop a random, unqueued file from a pool.
         // swap_remove is O(1) and prevents shifting massive vectors.
+        // Draws a few candidates and picks one of them weighted by rating and affinity, so liked
+        // files come up more often without starving the rest of the library.
         let mut try_push_random = |pool: &mut Vec<PathBuf>| -> Option<PathBuf> {
-            while !pool.is_empty() {
+            let mut candidates = Vec::with_capacity(WEIGHTED_CANDIDATES);
+            while !pool.is_empty() && candidates.len() < WEIGHTED_CANDIDATES {
                 let index = rng.random_range(0..pool.len());
                 let path = pool.swap_remove(index);
-                if !queue.contains_path(&path) {
-                    return Some(path);
+                if !queue.contains_path(&path) && !store.is_hidden(&path) {
+                    let weight = store.weight(&path);
+                    candidates.push((path, weight));
                 }
             }
-            None
+
+            // Now and then ignore what we've learned so new material still gets a chance.
+            if rng.random_bool(*store::EXPLORATION) {
+                candidates.iter_mut().for_each(|(_, weight)| *weight = 1.0);
+            }
+
+            let total: f64 = candidates.iter().map(|(_, weight)| weight).sum();
+            let mut target = rng.random_range(0.0..total.max(f64::MIN_POSITIVE));
+            let index = candidates
+                .iter()
+                .position(|(_, weight)| {
+                    target -= weight;
+                    target < 0.0
+                })
+                .unwrap_or(candidates.len().saturating_sub(1));
+            if index >= candidates.len() {
+                return None;
+            }
+
+            // Put the others back for the next round.
+            let (path, _) = candidates.swap_remove(index);
+            pool.extend(candidates.into_iter().map(|(path, _)| path));
+            Some(path)
         };
 
         // 4. Safely push to the queues
@@ -646,6 +1288,8 @@ struct DirectoryCounts {
     total_counts: Arc<z_sync::Lock16<QueueStats>>,
     dir_counts: Arc<z_sync::Lock16<FxHashMap<PathBuf, QueueStats>>>,
     notify: z_sync::Notify16,
+    // Set once the watcher thread is up, only read for `/admin/status`.
+    inotify: OnceLock<Arc<INotify>>,
 }
 
 static DIR_COUNTS: LazyLock<DirectoryCounts> = LazyLock::new(|| {
@@ -660,6 +1304,7 @@ static DIR_COUNTS: LazyLock<DirectoryCounts> = LazyLock::new(|| {
         total_counts: Arc::new(z_sync::Lock16::new(total_counts)),
         dir_counts: Arc::new(z_sync::Lock16::new(FxHashMap::default())),
         notify: z_sync::Notify16::new(),
+        inotify: OnceLock::new(),
     }
 });
 
@@ -667,10 +1312,11 @@ fn directory_counts() {
     let inotify = match INotify::new() {
         Ok(inotify) => Arc::new(inotify),
         Err(error) => {
-            eprintln!("Failed to initialize inotify: {error}");
+            tracing::error!(%error, "Failed to initialize inotify");
             return;
         }
     };
+    DIR_COUNTS.inotify.get_or_init(|| inotify.clone());
 
     let roots = {
         let mut roots = Vec::new();
@@ -695,7 +1341,7 @@ fn directory_counts() {
                     | rustix::fs::inotify::WatchFlags::MOVE_SELF,
             );
             if let Err(error) = result {
-                eprintln!("Failed to add watch for {}: {error}", path.display());
+                tracing::warn!(path = %path.display(), %error, "Failed to add watch");
             }
             DIR_COUNTS.dir_counts.write().insert(path.to_path_buf(), QueueStats::default());
             return true;
@@ -703,6 +1349,7 @@ fn directory_counts() {
 
         let Some(kind) = FileKind::from_path(path) else { return false };
 
+        LIBRARY.insert(path);
         total_counts_clone.write().add(kind);
         let parent = path.parent().unwrap();
         let mut dir_counts = DIR_COUNTS.dir_counts.write();
@@ -716,17 +1363,20 @@ fn directory_counts() {
 
     let compio_rt = compio::runtime::Runtime::new().unwrap();
 
-    compio_rt.block_on(async move {
+    compio_rt.block_on(SHUTDOWN.or_shutdown(async move {
         let rx = match walk_roots_filter(&roots, None, filter).await {
             Ok(rx) => rx,
             Err(error) => {
-                eprintln!("Failed to walk roots: {error}");
+                tracing::error!(%error, "Failed to walk roots");
                 return;
             }
         };
 
         while let Ok(_) = rx.recv_async().await {}
-    });
+    }));
+    if SHUTDOWN.is_requested() {
+        return;
+    }
 
     {
         let mut total_counts = total_counts.write();
@@ -767,7 +1417,7 @@ fn directory_counts() {
                             | rustix::fs::inotify::WatchFlags::MOVE_SELF,
                     );
                     if let Err(error) = result {
-                        eprintln!("Failed to add watch for {}: {error}", path.display());
+                        tracing::warn!(path = %path.display(), %error, "Failed to add watch");
                     }
                     DIR_COUNTS.dir_counts.write().insert(path.clone(), QueueStats::default());
                     add_dirs.push(path);
@@ -783,7 +1433,9 @@ fn directory_counts() {
                 }
                 DIR_COUNTS.total_counts.write().remove(kind);
 
+                LIBRARY.remove(&path);
                 QUEUE.get().unwrap().remove(&path);
+                SESSIONS.remove_path(&path);
             } else if is_create || is_move_to {
                 {
                     let mut dir_counts = DIR_COUNTS.dir_counts.write();
@@ -794,13 +1446,16 @@ fn directory_counts() {
                 }
 
                 DIR_COUNTS.total_counts.write().add(kind);
+                LIBRARY.insert(&path);
 
                 DIR_COUNTS.notify.notify(1);
             }
         };
 
-        if let Err(error) = inotify.wait(visit) {
-            eprintln!("Failed to wait for inotify events: {error}");
+        match compio_rt.block_on(SHUTDOWN.or_shutdown(inotify.wait_async(visit))) {
+            Some(Ok(())) => {}
+            Some(Err(error)) => tracing::error!(%error, "Failed to wait for inotify events"),
+            None => return,
         }
 
         let queue = QUEUE.get().unwrap();
@@ -814,6 +1469,7 @@ fn directory_counts() {
                     let mut enabled_roots = queue.enabled_roots().write();
                     let mut disabled_roots = queue.disabled_roots().write();
                     for path in delete_dirs {
+                        LIBRARY.remove_dir(&path);
                         dir_counts.retain(|p, counts| {
                             if !p.starts_with(&path) {
                                 return true;
@@ -846,7 +1502,7 @@ fn directory_counts() {
                         | rustix::fs::inotify::WatchFlags::MOVE_SELF,
                 );
                 if let Err(error) = result {
-                    eprintln!("Failed to add watch for {}: {error}", path.display());
+                    tracing::warn!(path = %path.display(), %error, "Failed to add watch");
                 }
                 DIR_COUNTS.dir_counts.write().insert(path.to_path_buf(), QueueStats::default());
                 return true;
@@ -854,6 +1510,7 @@ fn directory_counts() {
 
             let Some(kind) = FileKind::from_path(path) else { return false };
 
+            LIBRARY.insert(path);
             DIR_COUNTS.total_counts.write().add(kind);
             let parent = path.parent().unwrap();
             let mut dir_counts = DIR_COUNTS.dir_counts.write();
@@ -868,17 +1525,20 @@ fn directory_counts() {
             true
         };
 
-        compio_rt.block_on(async move {
+        compio_rt.block_on(SHUTDOWN.or_shutdown(async move {
             let rx = match walk_roots_filter(&add_dirs, None, filter).await {
                 Ok(rx) => rx,
                 Err(error) => {
-                    eprintln!("Failed to walk roots: {error}");
+                    tracing::error!(%error, "Failed to walk roots");
                     return;
                 }
             };
 
             while let Ok(_) = rx.recv_async().await {}
-        });
+        }));
+        if SHUTDOWN.is_requested() {
+            return;
+        }
     }
 }
 
@@ -888,7 +1548,7 @@ where
 {
     let path = path.into();
 
-    println!("Pre-caching file: {path}");
+    tracing::debug!(%path, "Pre-caching file");
 
     let file = FILE_CACHE.open(path.as_ref()).await?;
     let mut size = if file.size() == 0 { 0 } else { file.size() / 2 };
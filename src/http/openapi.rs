@@ -0,0 +1,73 @@
+use std::sync::LazyLock;
+
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use utoipa::OpenApi;
+
+use super::{
+    QueueStatsJson, browse, library, meta, random, remote, saved_playlist, serve_dir, session,
+    sync, tags,
+};
+
+/// The JSON API, DLNA and Subsonic follow their own specs and are left out, as are the
+/// operator endpoints `/metrics` and `/admin`.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        super::get_roots,
+        super::patch_roots,
+        super::play_handler,
+        super::queue_info_handler,
+        super::reset_queue_handler,
+        super::shuffle_queue_handler,
+        super::close_file,
+        super::rate_handler,
+        super::favorite_handler,
+        super::hide_handler,
+        super::sse_handler,
+        random::random_path_handler,
+        random::random_m3u8_handler,
+        library::search_handler,
+        session::history_handler,
+        browse::browse_roots,
+        browse::browse_dir,
+        tags::list_tags,
+        tags::get_tags,
+        tags::put_tags,
+        tags::add_tags,
+        tags::delete_tags,
+        meta::meta_handler,
+        meta::cover_handler,
+        serve_dir::serve_dir,
+        saved_playlist::list_playlists,
+        saved_playlist::get_playlist,
+        saved_playlist::put_playlist,
+        saved_playlist::patch_playlist,
+        saved_playlist::delete_playlist,
+        sync::clock_handler,
+        sync::get_state,
+        sync::lead_handler,
+        sync::playback_handler,
+        remote::remote_handler,
+        remote::list_players,
+        remote::command_player,
+    ),
+    // Only sent over `/sse`, so no handler pulls them in.
+    components(schemas(QueueStatsJson, sync::SyncState)),
+    tags(
+        (name = "queue", description = "Picking files, per session when `?session=` or the `z_play_session` cookie is set"),
+        (name = "files", description = "Serving files and recording feedback on them"),
+        (name = "library", description = "Browsing, searching and tagging everything under the roots"),
+        (name = "playlists", description = "Saved playlists"),
+        (name = "sync", description = "Watch parties"),
+        (name = "remote", description = "Controlling players from elsewhere"),
+    ),
+)]
+struct ApiDoc;
+
+// The handlers can't change at runtime, build it once.
+static DOCUMENT: LazyLock<String> = LazyLock::new(|| ApiDoc::openapi().to_pretty_json().unwrap());
+
+pub async fn openapi_handler() -> impl IntoResponse {
+    ([(CONTENT_TYPE, "application/json")], DOCUMENT.as_str())
+}
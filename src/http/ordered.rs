@@ -0,0 +1,351 @@
+use std::cmp::Ordering;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+use lru::LruCache;
+use parking_lot::Mutex;
+use rand::RngExt;
+use rand::seq::{IndexedRandom, SliceRandom};
+use rustc_hash::{FxBuildHasher, FxHashSet};
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+use super::{FileKind, LIBRARY, STORE};
+
+/// How `/random` picks the next file for a client.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PlayMode {
+    /// From the shared queue, or shuffled when playing a saved playlist.
+    #[default]
+    Random,
+    /// Every file in natural order, or in playlist order, starting over at the end.
+    Sequential,
+    /// A random directory, then all of its files in order.
+    RandomDir,
+    /// A random file, then the rest of its directory in order.
+    RandomStart,
+}
+
+/// What a cursor was built for, any change starts a new one.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Scope {
+    pub mode: PlayMode,
+    pub kinds: FxHashSet<FileKind>,
+    pub roots: FxHashSet<String>,
+    pub dir: Option<PathBuf>,
+    /// A saved playlist to play instead of the library.
+    pub playlist: Option<String>,
+}
+
+impl Scope {
+    fn contains(&self, path: &Path) -> bool {
+        FileKind::from_path(path)
+            .is_some_and(|kind| self.kinds.is_empty() || self.kinds.contains(&kind))
+            && (self.roots.is_empty() || self.roots.iter().any(|root| path.starts_with(root)))
+            && self.dir.as_ref().is_none_or(|dir| path.starts_with(dir))
+    }
+}
+
+#[derive(Debug)]
+struct Cursor {
+    scope: Scope,
+    /// The run currently being played, in natural order.
+    files: Vec<PathBuf>,
+    index: usize,
+}
+
+/// Per-client position for the ordered play modes.
+pub struct Cursors {
+    cursors: Mutex<LruCache<String, Cursor, FxBuildHasher>>,
+}
+
+impl Cursors {
+    // Clients that stop asking eventually fall out.
+    const MAX_CLIENTS: usize = 256;
+
+    pub fn new() -> Self {
+        let capacity = NonZeroUsize::new(Self::MAX_CLIENTS).unwrap();
+        Self { cursors: Mutex::new(LruCache::with_hasher(capacity, FxBuildHasher)) }
+    }
+
+    /// Advances the cursor of `client` and returns its next file, skipping files `filter`
+    /// rejects.
+    pub fn next<F>(&self, client: &str, scope: &Scope, filter: F) -> Option<PathBuf>
+    where
+        F: Fn(&Path) -> bool,
+    {
+        // A second pass only happens after refilling, an empty refill means nothing matches.
+        for _ in 0..2 {
+            while let Some(path) = self.advance(client, scope) {
+                if filter(&path) && path.is_file() {
+                    return Some(path);
+                }
+            }
+
+            let previous_dir = self
+                .cursors
+                .lock()
+                .peek(client)
+                .and_then(|cursor| cursor.files.last()?.parent().map(Path::to_owned));
+            // Scanning the library takes a while, other clients mustn't wait on the lock for it.
+            let in_scope = |path: &Path| scope.contains(path) && filter(path);
+            let (candidates, in_order) = match &scope.playlist {
+                Some(name) => {
+                    let mut paths = STORE.get().unwrap().playlist(name).unwrap_or_default();
+                    paths.retain(|path| in_scope(path));
+                    (paths, true)
+                }
+                None => (LIBRARY.files(in_scope), false),
+            };
+            let (files, index) =
+                next_run(scope.mode, candidates, in_order, previous_dir.as_deref());
+            if files.is_empty() {
+                return None;
+            }
+            self.cursors
+                .lock()
+                .put(client.to_owned(), Cursor { scope: scope.clone(), files, index });
+        }
+        None
+    }
+
+    /// Moves the cursor of `client` on by one, starting over when the scope changed. Returns
+    /// `None` at the end of the run.
+    fn advance(&self, client: &str, scope: &Scope) -> Option<PathBuf> {
+        let mut cursors = self.cursors.lock();
+        if cursors.peek(client).is_none_or(|cursor| cursor.scope != *scope) {
+            let cursor = Cursor { scope: scope.clone(), files: Vec::new(), index: 0 };
+            cursors.put(client.to_owned(), cursor);
+        }
+        let cursor = cursors.get_mut(client).unwrap();
+
+        let path = cursor.files.get(cursor.index)?.clone();
+        cursor.index += 1;
+        Some(path)
+    }
+}
+
+/// Picks the next run of files for `mode` and where to start in it.
+///
+/// Candidates that are `in_order` keep their order, otherwise runs are sorted naturally.
+fn next_run(
+    mode: PlayMode,
+    mut candidates: Vec<PathBuf>,
+    in_order: bool,
+    previous_dir: Option<&Path>,
+) -> (Vec<PathBuf>, usize) {
+    let mut rng = rand::rng();
+    let sort = |paths: &mut Vec<PathBuf>| {
+        if !in_order {
+            paths.sort_unstable_by(|a, b| natural_cmp_paths(a, b));
+        }
+    };
+
+    match mode {
+        PlayMode::Random => {
+            candidates.shuffle(&mut rng);
+            (candidates, 0)
+        }
+        PlayMode::Sequential => {
+            sort(&mut candidates);
+            (candidates, 0)
+        }
+        PlayMode::RandomDir => {
+            let mut dirs = candidates.iter().filter_map(|path| path.parent()).collect::<Vec<_>>();
+            dirs.sort_unstable();
+            dirs.dedup();
+            // Don't play the same directory twice in a row unless it's the only one.
+            if dirs.len() > 1 {
+                dirs.retain(|dir| Some(*dir) != previous_dir);
+            }
+            let Some(dir) = dirs.choose(&mut rng).map(|dir| dir.to_path_buf()) else {
+                return (Vec::new(), 0);
+            };
+
+            candidates.retain(|path| path.parent() == Some(dir.as_path()));
+            sort(&mut candidates);
+            (candidates, 0)
+        }
+        PlayMode::RandomStart if in_order => {
+            // A playlist carries on past the end of the directory.
+            let index =
+                if candidates.is_empty() { 0 } else { rng.random_range(0..candidates.len()) };
+            (candidates, index)
+        }
+        PlayMode::RandomStart => {
+            if candidates.is_empty() {
+                return (Vec::new(), 0);
+            }
+            let start = candidates.swap_remove(rng.random_range(0..candidates.len()));
+            candidates.retain(|path| path.parent() == start.parent());
+            candidates.push(start.clone());
+            sort(&mut candidates);
+            let index = candidates.iter().position(|path| *path == start).unwrap();
+            (candidates, index)
+        }
+    }
+}
+
+fn natural_cmp_paths(a: &Path, b: &Path) -> Ordering {
+    natural_cmp(&a.to_string_lossy(), &b.to_string_lossy())
+}
+
+/// Compares runs of digits by value and everything else case-insensitively, so "Episode 2"
+/// sorts before "Episode 10".
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a_chunks = Chunks(a);
+    let mut b_chunks = Chunks(b);
+    loop {
+        let ordering = match (a_chunks.next(), b_chunks.next()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(a), Some(b)) => compare_chunks(a, b),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+fn compare_chunks(a: &str, b: &str) -> Ordering {
+    let a_is_digits = a.as_bytes()[0].is_ascii_digit();
+    let b_is_digits = b.as_bytes()[0].is_ascii_digit();
+    match (a_is_digits, b_is_digits) {
+        (true, true) => {
+            let a_trimmed = a.trim_start_matches('0');
+            let b_trimmed = b.trim_start_matches('0');
+            a_trimmed.len().cmp(&b_trimmed.len()).then_with(|| a_trimmed.cmp(b_trimmed))
+        }
+        // Numbers before text, like most file managers.
+        (true, false) => Ordering::Less,
+        (false, true) => Ordering::Greater,
+        (false, false) => {
+            let a_lower = a.chars().flat_map(char::to_lowercase);
+            let b_lower = b.chars().flat_map(char::to_lowercase);
+            a_lower.cmp(b_lower)
+        }
+    }
+}
+
+/// Splits a string into alternating runs of ASCII digits and everything else.
+struct Chunks<'a>(&'a str);
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = *self.0.as_bytes().first()?;
+        let is_digit = first.is_ascii_digit();
+        let end = self
+            .0
+            .bytes()
+            .position(|byte| byte.is_ascii_digit() != is_digit)
+            .unwrap_or(self.0.len());
+        let (chunk, rest) = self.0.split_at(end);
+        self.0 = rest;
+        Some(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn natural_order() {
+        assert_eq!(natural_cmp("Episode 2", "Episode 10"), Ordering::Less);
+        assert_eq!(natural_cmp("apple", "Banana"), Ordering::Less);
+        assert_eq!(natural_cmp("2 intro", "intro"), Ordering::Less);
+        assert_eq!(natural_cmp("track", "track 1"), Ordering::Less);
+        // Equal values fall back to the raw strings, so the order stays total.
+        assert_eq!(natural_cmp("track 007", "track 7"), Ordering::Less);
+        assert_eq!(natural_cmp("track 7", "track 007"), Ordering::Greater);
+        assert_eq!(natural_cmp("track 7", "track 7"), Ordering::Equal);
+
+        let mut names = vec!["ep10", "Ep2", "ep1", "ep02"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        // "Ep2" and "ep02" tie on value and case, the raw strings decide.
+        assert_eq!(names, ["ep1", "Ep2", "ep02", "ep10"]);
+    }
+
+    #[test]
+    fn sequential_run() {
+        let candidates = paths(&["/m/b/2.mp3", "/m/a/10.mp3", "/m/a/9.mp3"]);
+        let (files, index) = next_run(PlayMode::Sequential, candidates.clone(), false, None);
+        assert_eq!(files, paths(&["/m/a/9.mp3", "/m/a/10.mp3", "/m/b/2.mp3"]));
+        assert_eq!(index, 0);
+
+        // Playlists keep their own order.
+        let (files, _) = next_run(PlayMode::Sequential, candidates.clone(), true, None);
+        assert_eq!(files, candidates);
+    }
+
+    #[test]
+    fn random_dir_run() {
+        let candidates = paths(&["/m/a/2.mp3", "/m/b/1.mp3", "/m/a/1.mp3", "/m/b/10.mp3"]);
+        for _ in 0..10 {
+            let previous = Some(Path::new("/m/a"));
+            let (files, index) = next_run(PlayMode::RandomDir, candidates.clone(), false, previous);
+            assert_eq!(files, paths(&["/m/b/1.mp3", "/m/b/10.mp3"]));
+            assert_eq!(index, 0);
+        }
+
+        // The only directory comes round again.
+        let candidates = paths(&["/m/a/1.mp3"]);
+        let previous = Some(Path::new("/m/a"));
+        let (files, _) = next_run(PlayMode::RandomDir, candidates.clone(), false, previous);
+        assert_eq!(files, candidates);
+
+        assert_eq!(next_run(PlayMode::RandomDir, Vec::new(), false, None), (Vec::new(), 0));
+    }
+
+    #[test]
+    fn random_start_run() {
+        let candidates = paths(&["/m/a/3.mp3", "/m/b/1.mp3", "/m/a/1.mp3", "/m/a/2.mp3"]);
+        for _ in 0..10 {
+            let (files, index) = next_run(PlayMode::RandomStart, candidates.clone(), false, None);
+            let dir = files[index].parent().unwrap();
+            assert!(files.iter().all(|path| path.parent() == Some(dir)));
+            let mut sorted = files.clone();
+            sorted.sort_by(|a, b| natural_cmp_paths(a, b));
+            assert_eq!(files, sorted);
+        }
+
+        assert_eq!(next_run(PlayMode::RandomStart, Vec::new(), false, None), (Vec::new(), 0));
+    }
+
+    #[test]
+    fn cursor_wraps_around() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = [dir.path().join("ep 10.mp3"), dir.path().join("ep 2.mp3")];
+        for file in &files {
+            std::fs::write(file, b"").unwrap();
+            LIBRARY.insert(file);
+        }
+
+        let scope = Scope {
+            mode: PlayMode::Sequential,
+            kinds: FxHashSet::default(),
+            roots: FxHashSet::default(),
+            dir: Some(dir.path().to_owned()),
+            playlist: None,
+        };
+        let cursors = Cursors::new();
+        let next = || cursors.next("client", &scope, |_| true);
+        // Starting over hands back the first file again, which is how callers notice they've
+        // been through every file.
+        assert_eq!(next().as_ref(), Some(&files[1]));
+        assert_eq!(next().as_ref(), Some(&files[0]));
+        assert_eq!(next().as_ref(), Some(&files[1]));
+
+        assert_eq!(cursors.next("other", &scope, |_| false), None);
+
+        LIBRARY.remove_dir(dir.path());
+    }
+}
@@ -8,17 +8,36 @@ use std::time::{Duration, Instant};
 
 use base64::Engine;
 use rustc_hash::{FxHashMap, FxHashSet};
+use serde::Serialize;
 use z_play::inotify::{self, INotify};
 use z_sync::Notify16;
 
-use crate::http::transcode::{create_vod_playlist, get_video_duration, spawn_transcode_hls};
+use crate::http::transcode::{
+    TranscodeKind, VideoProbe, create_vod_playlist, get_video_duration, probe_video,
+    spawn_transcode,
+};
+use crate::http::{FileKind, METRICS};
+
+/// A playlist being served, for `/admin/status`.
+#[derive(Debug, Serialize)]
+pub struct PlaylistStatus {
+    pub file_path: PathBuf,
+    pub dir: PathBuf,
+    /// Inclusive ranges of the segments known to be on disk.
+    pub segments: Vec<(u16, u16)>,
+    /// Only while ffmpeg is still running.
+    pub ffmpeg_pid: Option<u32>,
+    pub expires_in_secs: u64,
+}
 
 struct Playlist {
     dir: PathBuf,
     file_path: PathBuf,
+    kind: TranscodeKind,
     segments: RefCell<FxHashSet<u16>>,
     expires_at: Cell<Instant>,
     ffmpeg: RefCell<Option<std::process::Child>>,
+    span: tracing::Span,
 }
 
 impl Playlist {
@@ -29,13 +48,44 @@ impl Playlist {
             .await?
             .ok_or_else(|| std::io::Error::other("Failed to get duration"))?;
 
+        let kind = if matches!(FileKind::from_path(&file_path), Some(FileKind::Audio)) {
+            TranscodeKind::Audio
+        } else {
+            // Transcode as before rotation and deinterlacing existed rather than refuse the file.
+            let probe = match probe_video(&file_path).await {
+                Ok(Some(probe)) => probe,
+                Ok(None) => {
+                    tracing::warn!(
+                        path = %file_path.display(),
+                        "Failed to parse video stream, transcoding without rotation"
+                    );
+                    VideoProbe::default()
+                }
+                Err(error) => {
+                    tracing::warn!(
+                        path = %file_path.display(),
+                        %error,
+                        "Failed to probe video stream, transcoding without rotation"
+                    );
+                    VideoProbe::default()
+                }
+            };
+            TranscodeKind::Video(probe)
+        };
+
         let fake_playlist_path = output_dir.join("playlist.m3u8");
-        create_vod_playlist(&fake_playlist_path, duration).await?;
+        create_vod_playlist(&fake_playlist_path, duration, &kind).await?;
+
+        let span = tracing::info_span!("transcode", path = %file_path.display());
+        tracing::info!(parent: &span, ?kind, duration, "Starting transcode");
 
         let file_path_clone = file_path.clone();
         let output_dir_clone = output_dir.clone();
+        let span_clone = span.clone();
         let ffmpeg = compio::runtime::spawn_blocking(move || {
-            spawn_transcode_hls(file_path_clone, output_dir_clone, "_playlist.m3u8", None)
+            span_clone.in_scope(|| {
+                spawn_transcode(file_path_clone, output_dir_clone, "_playlist.m3u8", &kind, None)
+            })
         })
         .await
         .unwrap()?;
@@ -43,12 +93,49 @@ impl Playlist {
         Ok(Self {
             dir: output_dir,
             file_path,
+            kind,
             segments: RefCell::new(FxHashSet::default()),
             expires_at: Cell::new(Instant::now() + Self::EXPIRES_AFTER),
             ffmpeg: RefCell::new(Some(ffmpeg)),
+            span,
         })
     }
 
+    fn is_transcoding(&self) -> bool {
+        self.ffmpeg_pid().is_some()
+    }
+
+    fn ffmpeg_pid(&self) -> Option<u32> {
+        let mut ffmpeg = self.ffmpeg.borrow_mut();
+        let ffmpeg = ffmpeg.as_mut()?;
+        matches!(ffmpeg.try_wait(), Ok(None)).then(|| ffmpeg.id())
+    }
+
+    fn status(&self) -> PlaylistStatus {
+        let mut segments = self.segments.borrow().iter().copied().collect::<Vec<_>>();
+        segments.sort_unstable();
+
+        let mut ranges = Vec::<(u16, u16)>::new();
+        for segment in segments {
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == segment => *end = segment,
+                _ => ranges.push((segment, segment)),
+            }
+        }
+
+        PlaylistStatus {
+            file_path: self.file_path.clone(),
+            dir: self.dir.clone(),
+            segments: ranges,
+            ffmpeg_pid: self.ffmpeg_pid(),
+            expires_in_secs: self
+                .expires_at
+                .get()
+                .saturating_duration_since(Instant::now())
+                .as_secs(),
+        }
+    }
+
     fn playlist_file(&self) -> PathBuf {
         self.dir.join("playlist.m3u8")
     }
@@ -130,6 +217,8 @@ impl Playlist {
             && (segment_number < last_segment || segment_number > last_segment + 2)
         {
             // Restart ffmpeg at the request segment.
+            tracing::info!(parent: &self.span, segment = segment_number, "Restarting transcode");
+            METRICS.transcode_restarted();
             let ffmpeg = self.ffmpeg.borrow_mut().take();
             if let Some(mut ffmpeg) = ffmpeg {
                 compio::runtime::spawn_blocking(move || {
@@ -144,8 +233,18 @@ impl Playlist {
 
             let file_path = self.file_path.clone();
             let output_dir = self.dir.clone();
+            let kind = self.kind;
+            let span = self.span.clone();
             let ffmpeg = compio::runtime::spawn_blocking(move || {
-                spawn_transcode_hls(file_path, output_dir, "_playlist.m3u8", Some(segment_number))
+                span.in_scope(|| {
+                    spawn_transcode(
+                        file_path,
+                        output_dir,
+                        "_playlist.m3u8",
+                        &kind,
+                        Some(segment_number),
+                    )
+                })
             })
             .await
             .unwrap()?;
@@ -158,6 +257,7 @@ impl Playlist {
     }
 
     async fn close(mut self) {
+        tracing::info!(parent: &self.span, "Closing transcode");
         compio::runtime::spawn_blocking(move || {
             if let Some(mut ffmpeg) = self.ffmpeg.take() {
                 _ = ffmpeg.kill();
@@ -198,7 +298,14 @@ impl PlaylistManager {
         base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(bytes)
     }
 
-    fn playlist_path_to_file_path(playlist_path: &Path) -> Option<PathBuf> {
+    /// Where the playlist for a file lives, without starting a transcode.
+    pub fn playlist_file_for(&self, file_path: &Path) -> PathBuf {
+        self.root_dir
+            .join(Self::file_path_to_playlist_name(file_path))
+            .join("playlist.m3u8")
+    }
+
+    pub fn playlist_path_to_file_path(playlist_path: &Path) -> Option<PathBuf> {
         let file_name = playlist_path.file_name()?.to_str()?;
         let is_playlist_file = file_name == "playlist.m3u8"
             || file_name == "_playlist.m3u8"
@@ -347,6 +454,36 @@ impl PlaylistManager {
         }
     }
 
+    /// Playlists being served and how many of them ffmpeg is still transcoding.
+    pub fn counts(&self) -> (usize, usize) {
+        let playlists = self.playlists.borrow();
+        let transcoding = playlists.values().filter(|playlist| playlist.is_transcoding()).count();
+        (playlists.len(), transcoding)
+    }
+
+    /// Stops every transcode and removes its segments, for shutting down.
+    pub async fn close_all(&self) {
+        let playlists = std::mem::take(&mut *self.playlists.borrow_mut());
+        tracing::info!(count = playlists.len(), "Closing playlists");
+        for playlist in playlists.into_values() {
+            // Requests still holding one clean it up when they're dropped.
+            if let Ok(playlist) = Rc::try_unwrap(playlist) {
+                playlist.close().await;
+            }
+        }
+    }
+
+    pub fn status(&self) -> Vec<PlaylistStatus> {
+        let mut playlists = self
+            .playlists
+            .borrow()
+            .values()
+            .map(|playlist| playlist.status())
+            .collect::<Vec<_>>();
+        playlists.sort_unstable_by(|a, b| a.file_path.cmp(&b.file_path));
+        playlists
+    }
+
     pub async fn contains_file(&self, path: &Path) -> bool {
         self.get(path).await.is_ok()
     }
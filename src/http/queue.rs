@@ -1,22 +1,35 @@
 use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use rustc_hash::{FxBuildHasher, FxHashSet};
+use serde::Serialize;
+use utoipa::ToSchema;
 use z_queue::ZQueueMap;
 use z_queue::container::CrossbeamArrayQueue;
 
-use crate::http::FileKind;
+use crate::http::{FileKind, METADATA};
 
 pub struct Queue {
     enabled_roots: z_sync::Lock16<Vec<PathBuf>>,
     disabled_roots: z_sync::Lock16<Vec<PathBuf>>,
     queue: ZQueueMap<FileKind, CrossbeamArrayQueue<PathBuf>, FxBuildHasher>,
     queued_files: z_sync::Lock16<FxHashSet<PathBuf>>,
+    // Kinds a filtered pop has been waiting on, see `Queue::observe_starving`.
+    starving: z_sync::Lock16<FxHashSet<FileKind>>,
+    starving_notify: z_sync::Notify16,
+    // Tags and directories those pops were asking for, so the feeder can look there directly.
+    starving_tags: z_sync::Lock16<FxHashSet<String>>,
+    starving_dirs: z_sync::Lock16<FxHashSet<PathBuf>>,
 }
 
 impl Queue {
     pub const QUEUE_SIZE: usize = 100;
     pub const MAX_QUEUE_SIZE: usize = Self::QUEUE_SIZE * 3;
+    /// How long a filtered pop waits before asking the feeder to cycle the queue.
+    const STARVING_TIMEOUT: Duration = Duration::from_millis(500);
+    /// How long a filtered pop keeps cycling the queue before deciding nothing matches.
+    const FIND_TIMEOUT: Duration = Duration::from_secs(5);
 
     pub fn new(roots: Vec<PathBuf>) -> Self {
         let len = roots.len();
@@ -28,6 +41,10 @@ impl Queue {
             disabled_roots: z_sync::Lock::new(Vec::with_capacity(len)),
             queue: ZQueueMap::bounded(FileKind::NUM_VARIANTS, queue_size),
             queued_files: z_sync::Lock::new(queued_files),
+            starving: z_sync::Lock::new(FxHashSet::default()),
+            starving_notify: z_sync::Notify16::new(),
+            starving_tags: z_sync::Lock::new(FxHashSet::default()),
+            starving_dirs: z_sync::Lock::new(FxHashSet::default()),
         }
     }
 
@@ -55,6 +72,25 @@ impl Queue {
         self.queue.observe_pop()
     }
 
+    /// Notified when a filtered pop can't find a match, the feeder should replace old entries of
+    /// the kinds returned by [`Queue::take_starving`] with new ones, preferably carrying the tags
+    /// from [`Queue::take_starving_tags`] or from [`Queue::take_starving_dirs`].
+    pub fn observe_starving(&self) -> z_sync::notify::NotifyListener<'_> {
+        self.starving_notify.listener()
+    }
+
+    pub fn take_starving(&self) -> FxHashSet<FileKind> {
+        std::mem::take(&mut *self.starving.write())
+    }
+
+    pub fn take_starving_tags(&self) -> FxHashSet<String> {
+        std::mem::take(&mut *self.starving_tags.write())
+    }
+
+    pub fn take_starving_dirs(&self) -> FxHashSet<PathBuf> {
+        std::mem::take(&mut *self.starving_dirs.write())
+    }
+
     pub fn enabled_roots(&self) -> &z_sync::Lock16<Vec<PathBuf>> {
         &self.enabled_roots
     }
@@ -72,10 +108,13 @@ impl Queue {
             queued_files.insert(path.clone());
         }
         let Some(file_kind) = FileKind::from_path(&path) else {
-            eprintln!("Unknown file type: {}", path.display());
+            tracing::warn!(path = %path.display(), "Unknown file type");
             return;
         };
 
+        // Probed in the background so filters can match on it.
+        METADATA.request(path.clone());
+
         self.queue.push_async(file_kind, path).await;
     }
 
@@ -88,10 +127,12 @@ impl Queue {
             queued_files.insert(path.clone());
         }
         let Some(file_kind) = FileKind::from_path(&path) else {
-            eprintln!("Unknown file type: {}", path.display());
+            tracing::warn!(path = %path.display(), "Unknown file type");
             return;
         };
 
+        METADATA.request(path.clone());
+
         self.queue.push(file_kind, path);
     }
 
@@ -101,11 +142,11 @@ impl Queue {
         }
 
         let Some(file_kind) = FileKind::from_path(path) else {
-            eprintln!("Unknown file type: {}", path.display());
+            tracing::warn!(path = %path.display(), "Unknown file type");
             return;
         };
 
-        self.queue.retain(|k| *k == file_kind, |path| path != path);
+        self.queue.retain(|k| *k == file_kind, |queued| queued != path);
     }
 
     pub async fn reset(&self) {
@@ -142,22 +183,81 @@ impl Queue {
         (path, file_kind)
     }
 
+    /// Returns `None` when nothing matching turned up within [`Self::FIND_TIMEOUT`], there may
+    /// be no such file at all.
     pub async fn find_pop_async(
         &self,
         kinds: Option<&FxHashSet<FileKind>>,
         roots: Option<&FxHashSet<String>>,
-    ) -> (PathBuf, FileKind) {
-        let Some(roots) = roots else { return self.pop_async(kinds).await };
+        dir: Option<&Path>,
+        tags: Option<&FxHashSet<String>>,
+        filter: Option<&(dyn Fn(&Path) -> bool + Sync)>,
+    ) -> Option<(PathBuf, FileKind)> {
+        if roots.is_none() && dir.is_none() && filter.is_none() {
+            return Some(self.pop_async(kinds).await);
+        }
 
         let key_fn =
             |file_kind: &FileKind| -> bool { kinds.is_none_or(|kinds| kinds.contains(file_kind)) };
 
-        let find_fn = |path: &PathBuf| -> bool { roots.iter().any(|root| path.starts_with(root)) };
+        let find_fn = |path: &PathBuf| -> bool {
+            roots.is_none_or(|roots| roots.iter().any(|root| path.starts_with(root)))
+                && dir.is_none_or(|dir| path.starts_with(dir))
+                && filter.is_none_or(|filter| filter(path))
+        };
 
-        let (file_kind, path) = self.queue.find_async(key_fn, find_fn).await;
+        // Entries are matched as they're pushed, but metadata is probed afterwards, so keep
+        // checking the queue while waiting.
+        let deadline = Instant::now() + Self::FIND_TIMEOUT;
+        let (file_kind, path) = loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return None;
+            }
+
+            let find = self.queue.find_async(key_fn, find_fn);
+            match compio::time::timeout(Self::STARVING_TIMEOUT.min(remaining), find).await {
+                Ok(entry) => break entry,
+                Err(_) => {
+                    let mut starving = self.starving.write_async().await;
+                    match kinds {
+                        Some(kinds) => starving.extend(kinds.iter().copied()),
+                        None => starving.extend(FileKind::ALL),
+                    }
+                    drop(starving);
+                    if let Some(tags) = tags {
+                        self.starving_tags.write_async().await.extend(tags.iter().cloned());
+                    }
+                    if let Some(dir) = dir {
+                        self.starving_dirs.write_async().await.insert(dir.to_owned());
+                    }
+                    self.starving_notify.notify(usize::MAX);
+                }
+            }
+        };
         self.queued_files.write_async().await.remove(&path);
 
-        (path, file_kind)
+        Some((path, file_kind))
+    }
+
+    /// Drops the oldest `count` entries of `kind` so the feeder can replace them.
+    pub async fn evict_oldest(&self, kind: FileKind, count: usize) {
+        let mut queued_files = self.queued_files.write_async().await;
+        let mut evicted = 0;
+
+        self.queue
+            .retain_async(
+                |k| *k == kind,
+                |path| {
+                    if evicted >= count {
+                        return true;
+                    }
+                    evicted += 1;
+                    queued_files.remove(path);
+                    false
+                },
+            )
+            .await;
     }
 
     pub fn shuffle(&self) {
@@ -166,7 +266,7 @@ impl Queue {
     }
 }
 
-#[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Default, Debug, Copy, Clone, Eq, PartialEq, Serialize, ToSchema)]
 pub struct QueueStats {
     pub video_count: usize,
     pub image_count: usize,
@@ -189,4 +289,14 @@ impl QueueStats {
             FileKind::Audio => self.audio_count -= 1,
         }
     }
+
+    pub fn merge(&mut self, other: &Self) {
+        self.video_count += other.video_count;
+        self.image_count += other.image_count;
+        self.audio_count += other.audio_count;
+    }
+
+    pub fn total(&self) -> usize {
+        self.video_count + self.image_count + self.audio_count
+    }
 }
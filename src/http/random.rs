@@ -0,0 +1,454 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE, EXPIRES, HOST, PRAGMA};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Json, Response};
+use axum_extra::extract::Query;
+use camino::Utf8PathBuf;
+use rustc_hash::FxHashSet;
+use serde::Deserialize;
+use triomphe::Arc;
+use utoipa::IntoParams;
+use z_play::exif::DateTime;
+
+use super::metadata::{FrameOrientation, MetadataFilter};
+use super::ordered::{PlayMode, Scope};
+use super::session::{SessionParams, SessionQueue, StartSession};
+use super::sync::leader_group;
+use super::{
+    CURSORS, FileKind, METADATA, PathResponse, Prepared, STORE, locate_file, prepare_file,
+    queue_info,
+};
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RandomQuery {
+    #[serde(default, rename = "kind")]
+    #[param(value_type = Option<Vec<FileKind>>)]
+    kinds: FxHashSet<FileKind>,
+    #[serde(default, rename = "root")]
+    #[param(value_type = Option<Vec<String>>)]
+    roots: FxHashSet<String>,
+    /// Any directory within an enabled root.
+    #[param(value_type = Option<String>)]
+    dir: Option<PathBuf>,
+    /// Only photos taken on or after this date, see [`DateTime::parse`].
+    taken_after: Option<String>,
+    /// Only photos taken before this date.
+    taken_before: Option<String>,
+    /// Seconds.
+    min_duration: Option<f64>,
+    max_duration: Option<f64>,
+    min_width: Option<u32>,
+    max_width: Option<u32>,
+    min_height: Option<u32>,
+    max_height: Option<u32>,
+    orientation: Option<FrameOrientation>,
+    #[serde(default)]
+    favorites_only: bool,
+    /// 1 to 5, unrated files never match.
+    min_rating: Option<u8>,
+    /// Files carrying any of these tags, directly or through a directory.
+    #[serde(default, rename = "tag")]
+    #[param(value_type = Option<Vec<String>>)]
+    tags: FxHashSet<String>,
+    #[serde(default, rename = "not_tag")]
+    #[param(value_type = Option<Vec<String>>)]
+    not_tags: FxHashSet<String>,
+    #[serde(default)]
+    mode: PlayMode,
+    /// Identifies the player whose position the ordered modes keep.
+    client: Option<String>,
+    /// Name of a saved playlist to play from.
+    playlist: Option<String>,
+    /// Sync group to pick for, only its leader may.
+    group: Option<String>,
+    /// Number of entries, only used by `/random.m3u8`.
+    count: Option<usize>,
+}
+
+/// A validated `/random` request, picks files one at a time.
+struct RandomPicker {
+    queue: SessionQueue,
+    kinds: FxHashSet<FileKind>,
+    roots: FxHashSet<String>,
+    dir: Option<PathBuf>,
+    tags: FxHashSet<String>,
+    not_tags: FxHashSet<String>,
+    favorites_only: bool,
+    min_rating: Option<u8>,
+    metadata_filter: MetadataFilter,
+    /// Set for ordered play and playlists, which keep a cursor per client instead of popping
+    /// from the queue.
+    cursor: Option<(String, Scope)>,
+}
+
+impl RandomPicker {
+    async fn new(queue: SessionQueue, query: RandomQuery) -> Result<Self, Response> {
+        let RandomQuery {
+            mut kinds,
+            roots,
+            dir,
+            taken_after,
+            taken_before,
+            min_duration,
+            max_duration,
+            min_width,
+            max_width,
+            min_height,
+            max_height,
+            orientation,
+            favorites_only,
+            min_rating,
+            tags,
+            not_tags,
+            mode,
+            client,
+            playlist,
+            group: _,
+            count: _,
+        } = query;
+
+        let parse_date =
+            |value: Option<String>| value.as_deref().map(str::parse::<DateTime>).transpose();
+        let (taken_after, taken_before) = match (parse_date(taken_after), parse_date(taken_before))
+        {
+            (Ok(after), Ok(before)) => (after, before),
+            (Err(error), _) | (_, Err(error)) => {
+                return Err((StatusCode::BAD_REQUEST, error.to_string()).into_response());
+            }
+        };
+
+        let metadata_filter = MetadataFilter {
+            taken_after,
+            taken_before,
+            min_duration,
+            max_duration,
+            min_width,
+            max_width,
+            min_height,
+            max_height,
+            orientation,
+        };
+
+        let uses_cursor = mode != PlayMode::Random || playlist.is_some();
+        if uses_cursor {
+            if !metadata_filter.is_empty() {
+                let message = "Metadata filters only apply to random play from the library";
+                return Err((StatusCode::BAD_REQUEST, message).into_response());
+            }
+            if client.is_none() {
+                let message = "Ordered play and playlists need a client id";
+                return Err((StatusCode::BAD_REQUEST, message).into_response());
+            }
+        }
+        if let Some(playlist) = &playlist
+            && STORE.get().unwrap().playlist(playlist).is_none()
+        {
+            return Err((StatusCode::NOT_FOUND, "Playlist not found").into_response());
+        }
+
+        if metadata_filter.filters_taken() {
+            // Only images carry a date taken.
+            if !kinds.is_empty() && !kinds.contains(&FileKind::Image) {
+                let message = "Date filters only match images";
+                return Err((StatusCode::BAD_REQUEST, message).into_response());
+            }
+            kinds = FxHashSet::from_iter([FileKind::Image]);
+        }
+
+        // Roots are canonical, so this has to be too for the prefix checks to mean anything.
+        let not_found = || (StatusCode::NOT_FOUND, "Directory not found").into_response();
+        let dir = dir.map(|dir| dir.canonicalize()).transpose().map_err(|_| not_found())?;
+        if let Some(dir) = &dir {
+            let in_root = {
+                let roots = queue.enabled_roots().read_async().await;
+                roots.iter().any(|root| dir.starts_with(root))
+            };
+            if !in_root || !dir.is_dir() {
+                return Err(not_found());
+            }
+        }
+
+        let cursor = if uses_cursor {
+            let scope = Scope {
+                mode,
+                kinds: kinds.clone(),
+                roots: roots.clone(),
+                dir: dir.clone(),
+                playlist,
+            };
+            Some((client.unwrap(), scope))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            queue,
+            kinds,
+            roots,
+            dir,
+            tags,
+            not_tags,
+            favorites_only,
+            min_rating,
+            metadata_filter,
+            cursor,
+        })
+    }
+
+    fn filters_store(&self) -> bool {
+        self.favorites_only || self.min_rating.is_some()
+    }
+
+    fn filters_tags(&self) -> bool {
+        !self.tags.is_empty() || !self.not_tags.is_empty()
+    }
+
+    /// Filters beyond kind, root and directory, which the queue checks itself.
+    fn filters_entries(&self) -> bool {
+        self.filters_tags() || self.filters_store() || !self.metadata_filter.is_empty()
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        let store = STORE.get().unwrap();
+
+        let matches_tags = || {
+            (self.tags.is_empty() || store.has_any_tag(path, &self.tags))
+                && (self.not_tags.is_empty() || !store.has_any_tag(path, &self.not_tags))
+        };
+        let matches_store = || {
+            let Some(entry) = store.get(path) else { return false };
+            (!self.favorites_only || entry.favorite)
+                && self
+                    .min_rating
+                    .is_none_or(|min| entry.rating.is_some_and(|rating| rating >= min))
+        };
+        // Entries that haven't been probed yet don't match until they are.
+        let matches_metadata = || {
+            METADATA
+                .get(path)
+                .is_some_and(|metadata| self.metadata_filter.matches(&metadata))
+        };
+
+        (!self.filters_tags() || matches_tags())
+            && (!self.filters_store() || matches_store())
+            && (self.metadata_filter.is_empty() || matches_metadata())
+    }
+
+    /// The path to serve, the source file when it's a playlist, and the kind to play it as.
+    async fn next(&self) -> Result<(Utf8PathBuf, Option<Utf8PathBuf>, FileKind), Response> {
+        self.next_with(prepare_file).await
+    }
+
+    async fn next_with<F, Fut>(
+        &self,
+        prepare: F,
+    ) -> Result<(Utf8PathBuf, Option<Utf8PathBuf>, FileKind), Response>
+    where
+        F: Fn(Utf8PathBuf, FileKind) -> Fut,
+        Fut: Future<Output = Prepared>,
+    {
+        let queue = &self.queue;
+
+        if let Some((client, scope)) = &self.cursor {
+            let store = STORE.get().unwrap();
+            let enabled_roots = queue.enabled_roots().read_async().await.clone();
+            let filter = |path: &Path| {
+                enabled_roots.iter().any(|root| path.starts_with(root))
+                    && !store.is_hidden(path)
+                    && self.matches(path)
+            };
+
+            // Cursors cycle forever, so stop once a file that failed comes round again.
+            let mut failed = FxHashSet::default();
+            loop {
+                let Some(path) = CURSORS.next(client, scope, filter) else {
+                    return Err((StatusCode::NOT_FOUND, "No files to play").into_response());
+                };
+                if failed.contains(&path) {
+                    return Err((StatusCode::NOT_FOUND, "No playable files").into_response());
+                }
+                let file_kind = FileKind::from_path(&path).unwrap();
+                let path =
+                    Utf8PathBuf::from_path_buf(path).expect("Only UTF-8 paths are supported");
+
+                match prepare(path.clone(), file_kind).await {
+                    Prepared::Ready(path, display_path, file_kind) => {
+                        return Ok((path, display_path, file_kind));
+                    }
+                    Prepared::Failed => {
+                        failed.insert(path.into_std_path_buf());
+                    }
+                    // Skipping would break the order.
+                    Prepared::TimedOut(path) => return Ok((path, None, file_kind)),
+                }
+            }
+        }
+
+        let matches = |path: &Path| self.matches(path);
+        loop {
+            let filter_kinds = if self.kinds.is_empty() { None } else { Some(&self.kinds) };
+            let filter_roots = if self.roots.is_empty() { None } else { Some(&self.roots) };
+            let filter_tags = if self.tags.is_empty() { None } else { Some(&self.tags) };
+            let filter: Option<&(dyn Fn(&Path) -> bool + Sync)> =
+                if self.filters_entries() { Some(&matches) } else { None };
+            let found = queue
+                .find_pop_async(
+                    filter_kinds,
+                    filter_roots,
+                    self.dir.as_deref(),
+                    filter_tags,
+                    filter,
+                )
+                .await;
+            let Some((path, file_kind)) = found else {
+                return Err((StatusCode::NOT_FOUND, "No files match").into_response());
+            };
+
+            let path = Utf8PathBuf::from_path_buf(path).expect("Only UTF-8 paths are supported");
+
+            match prepare(path, file_kind).await {
+                Prepared::Ready(path, display_path, file_kind) => {
+                    return Ok((path, display_path, file_kind));
+                }
+                Prepared::Failed => continue,
+                Prepared::TimedOut(path) => {
+                    let queue = self.queue.clone();
+                    compio::runtime::spawn(async move { queue.push_async(path.into()).await })
+                        .detach();
+                    continue;
+                }
+            }
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/random",
+    tag = "queue",
+    params(SessionParams, RandomQuery),
+    responses(
+        (status = OK, description = "Also sends the `/queue` headers", body = PathResponse),
+        (status = BAD_REQUEST, description = "Invalid filter"),
+        (status = NOT_FOUND, description = "Unknown directory, playlist or sync group, or no file matches"),
+        (status = CONFLICT, description = "Only the group's leader may pick"),
+        (status = SERVICE_UNAVAILABLE, description = "Too many sessions in use to start another"),
+    ),
+)]
+#[axum::debug_handler]
+pub async fn random_path_handler(
+    StartSession(queue): StartSession,
+    Query(query): Query<RandomQuery>,
+) -> Response {
+    // Checked up front, followers mustn't pop anything.
+    let group =
+        match query.group.as_deref().map(|group| leader_group(group, query.client.as_deref())) {
+            Some(Ok(group)) => Some(group),
+            Some(Err(response)) => return response,
+            None => None,
+        };
+    let picker = match RandomPicker::new(queue, query).await {
+        Ok(picker) => picker,
+        Err(response) => return response,
+    };
+    let (path, display_path, file_kind) = match picker.next().await {
+        Ok(next) => next,
+        Err(response) => return response,
+    };
+    picker.queue.record_played(display_path.as_ref().unwrap_or(&path).as_std_path());
+
+    let response = PathResponse::new(path, display_path, file_kind).await;
+    if let Some(group) = group {
+        group.publish_item(response.clone());
+    }
+
+    (
+        [
+            (CACHE_CONTROL, "no-cache, no-store, must-revalidate"),
+            (PRAGMA, "no-cache"),
+            (EXPIRES, "0"),
+        ],
+        queue_info(&picker.queue),
+        Json(response),
+    )
+        .into_response()
+}
+
+const M3U8_DEFAULT_COUNT: usize = 50;
+const M3U8_MAX_COUNT: usize = 500;
+// Narrow filters can run the queue dry, stop there instead of waiting forever.
+const M3U8_ITEM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pops a batch of files into an extended M3U for external players, with absolute URLs.
+#[utoipa::path(
+    get,
+    path = "/random.m3u8",
+    tag = "queue",
+    params(SessionParams, RandomQuery),
+    responses(
+        (status = OK, content_type = "audio/x-mpegurl", body = String),
+        (status = BAD_REQUEST, description = "Invalid filter or missing Host header"),
+        (status = SERVICE_UNAVAILABLE, description = "Too many sessions in use to start another"),
+    ),
+)]
+pub async fn random_m3u8_handler(
+    StartSession(queue): StartSession,
+    headers: HeaderMap,
+    Query(query): Query<RandomQuery>,
+) -> Response {
+    let count = query.count.unwrap_or(M3U8_DEFAULT_COUNT).clamp(1, M3U8_MAX_COUNT);
+    let picker = match RandomPicker::new(queue, query).await {
+        Ok(picker) => Arc::new(picker),
+        Err(response) => return response,
+    };
+
+    let Some(host) = headers.get(HOST).and_then(|host| host.to_str().ok()) else {
+        return (StatusCode::BAD_REQUEST, "Missing Host header").into_response();
+    };
+    let scheme = headers
+        .get("x-forwarded-proto")
+        .and_then(|proto| proto.to_str().ok())
+        .filter(|proto| matches!(*proto, "http" | "https"))
+        .unwrap_or("http");
+    let base_url = format!("{scheme}://{host}/files");
+
+    let mut m3u8 = String::from("#EXTM3U\n");
+    for _ in 0..count {
+        let picker = picker.clone();
+        let next = compio::runtime::spawn(async move {
+            // External players can't wait on a transcode per entry, so they start on first fetch.
+            compio::time::timeout(M3U8_ITEM_TIMEOUT, picker.next_with(locate_file)).await
+        })
+        .await
+        .unwrap();
+
+        let (path, display_path, _) = match next {
+            Ok(Ok(next)) => next,
+            // Whatever was found so far is still useful.
+            Ok(Err(_)) | Err(_) => break,
+        };
+
+        let source_path = display_path.as_ref().unwrap_or(&path);
+        let metadata = METADATA.get(source_path.as_std_path());
+        let duration = metadata.as_ref().and_then(|metadata| metadata.duration);
+        let seconds = duration.map_or(-1, |duration| duration.round() as i64);
+        let title = source_path.file_name().unwrap_or_default();
+        let url_path = path.as_str().split('/').map(urlencoding::encode).collect::<Vec<_>>();
+
+        _ = writeln!(m3u8, "#EXTINF:{seconds},{title}");
+        _ = writeln!(m3u8, "{base_url}{}", url_path.join("/"));
+    }
+
+    (
+        [
+            (CONTENT_TYPE, "audio/x-mpegurl; charset=utf-8"),
+            (CACHE_CONTROL, "no-cache, no-store, must-revalidate"),
+        ],
+        m3u8,
+    )
+        .into_response()
+}
@@ -0,0 +1,301 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use axum::body::Body;
+use axum::extract::{self, Request};
+use axum::http::header::{CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE};
+use axum::http::{HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Json, Response};
+use hyper_util::rt::TokioIo;
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use triomphe::Arc;
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+use z_play::remote::{ClientMessage, Command, PlayerInfo, PlayerState, ServerMessage};
+
+use super::REMOTE;
+
+struct Player {
+    name: Option<String>,
+    state: PlayerState,
+    connection: u64,
+}
+
+/// Players that joined over `/remote` and every open connection, so controllers hear about
+/// state changes.
+pub struct Remote {
+    players: Mutex<FxHashMap<String, Player>>,
+    connections: Mutex<FxHashMap<u64, UnboundedSender<Arc<str>>>>,
+    next_connection: AtomicU64,
+}
+
+impl Remote {
+    pub fn new() -> Self {
+        Self {
+            players: Mutex::new(FxHashMap::default()),
+            connections: Mutex::new(FxHashMap::default()),
+            next_connection: AtomicU64::new(0),
+        }
+    }
+
+    fn connect(&self, tx: UnboundedSender<Arc<str>>) -> u64 {
+        let connection = self.next_connection.fetch_add(1, Ordering::Relaxed);
+        self.connections.lock().insert(connection, tx);
+        connection
+    }
+
+    fn disconnect(&self, connection: u64) {
+        self.connections.lock().remove(&connection);
+
+        let mut left = Vec::new();
+        self.players.lock().retain(|id, player| {
+            if player.connection != connection {
+                return true;
+            }
+            left.push(id.clone());
+            false
+        });
+        for id in left {
+            self.left(id);
+        }
+    }
+
+    fn left(&self, id: String) {
+        tracing::info!(id, "Remote player left");
+        self.broadcast(&ServerMessage::Left { id });
+    }
+
+    fn send_to(&self, connection: u64, message: &ServerMessage) {
+        if let Some(tx) = self.connections.lock().get(&connection) {
+            _ = tx.send(Arc::from(serde_json::to_string(message).unwrap()));
+        }
+    }
+
+    fn broadcast(&self, message: &ServerMessage) {
+        let json = Arc::<str>::from(serde_json::to_string(message).unwrap());
+        for tx in self.connections.lock().values() {
+            _ = tx.send(json.clone());
+        }
+    }
+
+    fn players(&self) -> Vec<PlayerInfo> {
+        let mut players = self
+            .players
+            .lock()
+            .iter()
+            .map(|(id, player)| PlayerInfo {
+                id: id.clone(),
+                name: player.name.clone(),
+                state: player.state.clone(),
+            })
+            .collect::<Vec<_>>();
+        players.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+        players
+    }
+
+    /// Returns whether `target` is connected.
+    fn command(&self, target: &str, command: Command) -> bool {
+        let Some(connection) = self.players.lock().get(target).map(|player| player.connection)
+        else {
+            return false;
+        };
+        self.send_to(connection, &ServerMessage::Command { id: target.to_owned(), command });
+        true
+    }
+
+    fn handle(&self, connection: u64, message: ClientMessage) {
+        match message {
+            ClientMessage::Register { id, name } => {
+                tracing::info!(id, "Remote player joined");
+                let player = Player { name, state: PlayerState::default(), connection };
+                self.players.lock().insert(id.clone(), player);
+                self.broadcast(&ServerMessage::State { id, state: PlayerState::default() });
+            }
+            ClientMessage::Leave { id } => {
+                let mut players = self.players.lock();
+                if players.get(&id).is_some_and(|player| player.connection == connection) {
+                    players.remove(&id);
+                    drop(players);
+                    self.left(id);
+                }
+            }
+            ClientMessage::State { id, state } => {
+                let updated = match self.players.lock().get_mut(&id) {
+                    Some(player) if player.connection == connection => {
+                        player.state = state.clone();
+                        true
+                    }
+                    _ => false,
+                };
+                if updated {
+                    self.broadcast(&ServerMessage::State { id, state });
+                } else {
+                    let message = format!("Register {id} before reporting its state");
+                    self.send_to(connection, &ServerMessage::Error { message });
+                }
+            }
+            ClientMessage::List => {
+                self.send_to(connection, &ServerMessage::Players { players: self.players() });
+            }
+            ClientMessage::Command { target, command } => {
+                if !self.command(&target, command) {
+                    let message = format!("No player {target}");
+                    self.send_to(connection, &ServerMessage::Error { message });
+                }
+            }
+        }
+    }
+}
+
+/// What tungstenite reads from and writes to, the socket itself is driven asynchronously.
+#[derive(Default)]
+struct Buffered {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+impl Read for Buffered {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.input.is_empty() {
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+        self.input.read(buf)
+    }
+}
+
+impl Write for Buffered {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Upgrades to a WebSocket speaking [`z_play::remote`].
+#[utoipa::path(
+    get,
+    path = "/remote",
+    tag = "remote",
+    responses(
+        (status = SWITCHING_PROTOCOLS, description = "WebSocket, see `z_play::remote` for the messages"),
+        (status = UPGRADE_REQUIRED),
+    ),
+)]
+pub async fn remote_handler(mut request: Request) -> Response {
+    let headers = request.headers();
+    let is_upgrade = headers
+        .get(UPGRADE)
+        .and_then(|upgrade| upgrade.to_str().ok())
+        .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+    let Some(key) = headers.get(SEC_WEBSOCKET_KEY).filter(|_| is_upgrade) else {
+        return (StatusCode::UPGRADE_REQUIRED, "Expected a WebSocket upgrade").into_response();
+    };
+    let accept = derive_accept_key(key.as_bytes());
+
+    let on_upgrade = hyper::upgrade::on(&mut request);
+    compio::runtime::spawn(async move {
+        match on_upgrade.await {
+            Ok(upgraded) => run_socket(TokioIo::new(upgraded)).await,
+            Err(error) => tracing::error!(%error, "Remote upgrade failed"),
+        }
+    })
+    .detach();
+
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(UPGRADE, HeaderValue::from_static("websocket"))
+        .header(CONNECTION, HeaderValue::from_static("Upgrade"))
+        .header(SEC_WEBSOCKET_ACCEPT, accept)
+        .body(Body::empty())
+        .unwrap()
+}
+
+async fn run_socket<S>(mut io: S)
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let connection = REMOTE.connect(tx);
+    let mut socket = WebSocket::from_raw_socket(Buffered::default(), Role::Server, None);
+    let mut buf = vec![0; 16 * 1024];
+
+    'main: loop {
+        // Handle every complete frame received so far, pings are answered along the way.
+        loop {
+            match socket.read() {
+                Ok(Message::Text(text)) => match serde_json::from_str(&text) {
+                    Ok(message) => REMOTE.handle(connection, message),
+                    Err(error) => {
+                        let message = format!("Invalid message: {error}");
+                        REMOTE.send_to(connection, &ServerMessage::Error { message });
+                    }
+                },
+                Ok(_) => (),
+                Err(tungstenite::Error::Io(error))
+                    if error.kind() == std::io::ErrorKind::WouldBlock =>
+                {
+                    break;
+                }
+                Err(_) => break 'main,
+            }
+        }
+
+        let output = std::mem::take(&mut socket.get_mut().output);
+        if !output.is_empty() && io.write_all(&output).await.is_err() {
+            break;
+        }
+
+        tokio::select! {
+            read = io.read(&mut buf) => match read {
+                Ok(0) | Err(_) => break,
+                Ok(len) => socket.get_mut().input.extend(&buf[..len]),
+            },
+            json = rx.recv() => {
+                let Some(json) = json else { break };
+                if socket.send(Message::text(json.to_string())).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    REMOTE.disconnect(connection);
+}
+
+#[utoipa::path(
+    get,
+    path = "/remote/players",
+    tag = "remote",
+    responses((status = OK, body = Vec<PlayerInfo>)),
+)]
+pub async fn list_players() -> Json<Vec<PlayerInfo>> {
+    Json(REMOTE.players())
+}
+
+/// The same commands as over the WebSocket, for controllers that only speak HTTP.
+#[utoipa::path(
+    post,
+    path = "/remote/players/{id}",
+    tag = "remote",
+    params(("id" = String, Path)),
+    request_body = Command,
+    responses((status = NO_CONTENT), (status = NOT_FOUND, description = "No such player")),
+)]
+pub async fn command_player(
+    extract::Path(id): extract::Path<String>,
+    Json(command): Json<Command>,
+) -> StatusCode {
+    if REMOTE.command(&id, command) {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
@@ -0,0 +1,441 @@
+use std::fmt::Write;
+use std::path::{Component, Path, PathBuf};
+
+use axum::body::Bytes;
+use axum::extract;
+use axum::response::{IntoResponse, Json, Response};
+use axum_extra::extract::Query;
+use http::{HeaderMap, StatusCode, header};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use super::{METADATA, QUEUE, STORE};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaylistFormat {
+    Json,
+    #[serde(alias = "m3u")]
+    M3u8,
+    Xspf,
+}
+
+impl PlaylistFormat {
+    fn from_content_type(headers: &HeaderMap) -> Self {
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        match mime {
+            "audio/x-mpegurl" | "audio/mpegurl" | "application/vnd.apple.mpegurl" => Self::M3u8,
+            "application/xspf+xml" => Self::Xspf,
+            _ => Self::Json,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::M3u8 => "audio/x-mpegurl; charset=utf-8",
+            Self::Xspf => "application/xspf+xml",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::M3u8 => "m3u8",
+            Self::Xspf => "xspf",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PlaylistQuery {
+    /// Defaults to JSON, or to the request's content type for imports.
+    format: Option<PlaylistFormat>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+struct PlaylistJson {
+    #[schema(value_type = Vec<String>)]
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+struct PlaylistSummary {
+    name: String,
+    count: usize,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+struct PlaylistResponse {
+    name: String,
+    #[schema(value_type = Vec<String>)]
+    paths: Vec<PathBuf>,
+    /// Entries of an import that didn't match a file in any root.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    unresolved: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, ToSchema)]
+pub struct PlaylistPatch {
+    #[serde(default)]
+    #[schema(value_type = Vec<String>)]
+    add: Vec<PathBuf>,
+    #[serde(default)]
+    #[schema(value_type = Vec<String>)]
+    remove: Vec<PathBuf>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/playlists",
+    tag = "playlists",
+    responses((status = OK, body = Vec<PlaylistSummary>)),
+)]
+pub async fn list_playlists() -> Response {
+    let playlists = STORE.get().unwrap().playlists();
+    let summaries = playlists
+        .into_iter()
+        .map(|(name, count)| PlaylistSummary { name, count })
+        .collect::<Vec<_>>();
+    Json(summaries).into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/playlists/{name}",
+    tag = "playlists",
+    params(("name" = String, Path), PlaylistQuery),
+    responses(
+        (
+            status = OK,
+            content(
+                (PlaylistResponse = "application/json"),
+                (String = "audio/x-mpegurl"),
+                (String = "application/xspf+xml"),
+            ),
+        ),
+        (status = NOT_FOUND),
+    ),
+)]
+pub async fn get_playlist(
+    extract::Path(name): extract::Path<String>,
+    Query(query): Query<PlaylistQuery>,
+) -> Response {
+    let name = normalize_name(&name);
+    let Some(paths) = STORE.get().unwrap().playlist(&name) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let format = query.format.unwrap_or(PlaylistFormat::Json);
+    let body = match format {
+        PlaylistFormat::Json => {
+            let response = PlaylistResponse { name, paths, unresolved: Vec::new() };
+            return Json(response).into_response();
+        }
+        PlaylistFormat::M3u8 => to_m3u8(&paths),
+        PlaylistFormat::Xspf => to_xspf(&name, &paths),
+    };
+
+    let disposition = format!(
+        "attachment; filename=\"{}.{}\"",
+        name.replace(['"', '\\'], "_"),
+        format.extension()
+    );
+    (
+        [
+            (header::CONTENT_TYPE, format.content_type().to_owned()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// Creates or replaces a playlist from JSON, M3U8 or XSPF.
+#[utoipa::path(
+    put,
+    path = "/playlists/{name}",
+    tag = "playlists",
+    params(("name" = String, Path), PlaylistQuery),
+    request_body(content(
+        (PlaylistJson = "application/json"),
+        (String = "audio/x-mpegurl"),
+        (String = "application/xspf+xml"),
+    )),
+    responses(
+        (status = OK, body = PlaylistResponse),
+        (status = BAD_REQUEST, description = "Empty name or invalid JSON"),
+    ),
+)]
+pub async fn put_playlist(
+    extract::Path(name): extract::Path<String>,
+    Query(query): Query<PlaylistQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let name = normalize_name(&name);
+    if name.is_empty() {
+        return (StatusCode::BAD_REQUEST, "Playlist name can't be empty").into_response();
+    }
+
+    let format = query.format.unwrap_or_else(|| PlaylistFormat::from_content_type(&headers));
+    let entries = match format {
+        PlaylistFormat::Json => match serde_json::from_slice::<PlaylistJson>(&body) {
+            Ok(playlist) => playlist
+                .paths
+                .into_iter()
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect(),
+            Err(error) => return (StatusCode::BAD_REQUEST, error.to_string()).into_response(),
+        },
+        PlaylistFormat::M3u8 => parse_m3u8(&String::from_utf8_lossy(&body)),
+        PlaylistFormat::Xspf => parse_xspf(&String::from_utf8_lossy(&body)),
+    };
+
+    let (paths, unresolved) = resolve_entries(entries).await;
+    STORE.get().unwrap().set_playlist(&name, paths.clone());
+    Json(PlaylistResponse { name, paths, unresolved }).into_response()
+}
+
+/// Adds paths to the end of a playlist and removes others.
+#[utoipa::path(
+    patch,
+    path = "/playlists/{name}",
+    tag = "playlists",
+    params(("name" = String, Path)),
+    request_body = PlaylistPatch,
+    responses((status = OK, body = PlaylistResponse), (status = NOT_FOUND)),
+)]
+pub async fn patch_playlist(
+    extract::Path(name): extract::Path<String>,
+    Json(patch): Json<PlaylistPatch>,
+) -> Response {
+    let name = normalize_name(&name);
+    let added = patch.add.into_iter().map(|path| path.to_string_lossy().into_owned()).collect();
+    let (added, unresolved) = resolve_entries(added).await;
+
+    let paths = STORE.get().unwrap().update_playlist(&name, |paths| {
+        paths.retain(|path| !patch.remove.contains(path));
+        paths.extend(added);
+    });
+    match paths {
+        Some(paths) => Json(PlaylistResponse { name, paths, unresolved }).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/playlists/{name}",
+    tag = "playlists",
+    params(("name" = String, Path)),
+    responses((status = NO_CONTENT), (status = NOT_FOUND)),
+)]
+pub async fn delete_playlist(extract::Path(name): extract::Path<String>) -> StatusCode {
+    if STORE.get().unwrap().remove_playlist(&normalize_name(&name)) {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+/// Every handler looks playlists up the way `PUT` stored them, without surrounding whitespace.
+fn normalize_name(name: &str) -> String {
+    name.trim().to_owned()
+}
+
+/// Maps playlist entries onto files in the roots, dropping the ones that aren't found.
+async fn resolve_entries(entries: Vec<String>) -> (Vec<PathBuf>, Vec<String>) {
+    let roots = {
+        let queue = QUEUE.get().unwrap();
+        let mut roots = queue.enabled_roots().read_async().await.clone();
+        roots.extend(queue.disabled_roots().read_async().await.iter().cloned());
+        roots
+    };
+
+    compio::runtime::spawn(async move {
+        compio::runtime::spawn_blocking(move || {
+            let mut paths = Vec::with_capacity(entries.len());
+            let mut unresolved = Vec::new();
+            for entry in entries {
+                match resolve(&entry, &roots) {
+                    Some(path) => paths.push(path),
+                    None => unresolved.push(entry),
+                }
+            }
+            (paths, unresolved)
+        })
+        .await
+        .unwrap()
+    })
+    .await
+    .unwrap()
+}
+
+/// Playlists made elsewhere rarely share our absolute paths, so besides the path itself this
+/// tries every trailing part of it under every root, longest first.
+fn resolve(entry: &str, roots: &[PathBuf]) -> Option<PathBuf> {
+    let entry = entry.strip_prefix("file://").map_or_else(
+        || entry.to_owned(),
+        |path| urlencoding::decode(path).map_or_else(|_| path.to_owned(), |path| path.into_owned()),
+    );
+    let path = Path::new(&entry);
+
+    let in_roots = |path: &Path| {
+        let path = path.canonicalize().ok()?;
+        (path.is_file() && roots.iter().any(|root| path.starts_with(root))).then_some(path)
+    };
+
+    if path.is_absolute()
+        && let Some(path) = in_roots(path)
+    {
+        return Some(path);
+    }
+
+    let components = path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    for start in 0..components.len() {
+        let suffix = components[start..].iter().collect::<PathBuf>();
+        for root in roots {
+            if let Some(path) = in_roots(&root.join(&suffix)) {
+                return Some(path);
+            }
+        }
+    }
+    None
+}
+
+fn parse_m3u8(data: &str) -> Vec<String> {
+    data.trim_start_matches('\u{feff}')
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Only `<location>` elements are read, the rest of the document is ignored.
+fn parse_xspf(data: &str) -> Vec<String> {
+    let mut entries = Vec::new();
+    let mut rest = data;
+    while let Some(start) = rest.find("<location>") {
+        rest = &rest[start + "<location>".len()..];
+        let Some(end) = rest.find("</location>") else { break };
+        entries.push(unescape_xml(rest[..end].trim()));
+        rest = &rest[end..];
+    }
+    entries
+}
+
+fn to_m3u8(paths: &[PathBuf]) -> String {
+    let mut m3u8 = String::from("#EXTM3U\n");
+    for path in paths {
+        let (title, duration) = title_and_duration(path);
+        let seconds = duration.map_or(-1, |duration| duration.round() as i64);
+        _ = writeln!(m3u8, "#EXTINF:{seconds},{title}");
+        _ = writeln!(m3u8, "{}", path.display());
+    }
+    m3u8
+}
+
+fn to_xspf(name: &str, paths: &[PathBuf]) -> String {
+    let mut xspf = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n",
+    );
+    _ = writeln!(xspf, "  <title>{}</title>", escape_xml(name));
+    xspf.push_str("  <trackList>\n");
+    for path in paths {
+        let (title, duration) = title_and_duration(path);
+        let location = path
+            .to_string_lossy()
+            .split('/')
+            .map(|segment| urlencoding::encode(segment).into_owned())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        xspf.push_str("    <track>\n");
+        _ = writeln!(xspf, "      <location>file://{}</location>", escape_xml(&location));
+        _ = writeln!(xspf, "      <title>{}</title>", escape_xml(&title));
+        if let Some(duration) = duration {
+            _ = writeln!(xspf, "      <duration>{}</duration>", (duration * 1000.0).round() as u64);
+        }
+        xspf.push_str("    </track>\n");
+    }
+    xspf.push_str("  </trackList>\n</playlist>\n");
+    xspf
+}
+
+/// Uses metadata that's already been probed, exporting shouldn't wait on ffprobe.
+fn title_and_duration(path: &Path) -> (String, Option<f64>) {
+    let metadata = METADATA.get(path);
+    let duration = metadata.as_ref().and_then(|metadata| metadata.duration);
+    let tags = metadata.as_ref().and_then(|metadata| metadata.tags.as_ref());
+    let title = match tags.and_then(|tags| Some((tags.artist.as_deref(), tags.title.as_deref()?))) {
+        Some((Some(artist), title)) => format!("{artist} - {title}"),
+        Some((None, title)) => title.to_owned(),
+        None => path.file_stem().unwrap_or_default().to_string_lossy().into_owned(),
+    };
+    (title, duration)
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape_xml(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find(';') else { break };
+        let entity = &rest[1..end];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse::<u32>))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        match c {
+            Some(c) => {
+                unescaped.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
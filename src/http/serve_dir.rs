@@ -3,15 +3,31 @@ use std::num::NonZeroUsize;
 use axum::body::Body;
 use axum::extract::Path;
 use axum::response::{IntoResponse, Response};
-use camino::Utf8Path;
+use axum_extra::extract::Query;
+use camino::{Utf8Path, Utf8PathBuf};
 use http::{StatusCode, header};
 
 use super::FILE_CACHE;
 use crate::http::file_cache::CachedFile;
-
+use crate::http::image::RenditionQuery;
+use crate::http::playlist::PlaylistManager;
+
+#[utoipa::path(
+    get,
+    path = "/files/{path}",
+    tag = "files",
+    params(("path" = String, Path, description = "Absolute path without the leading slash"), RenditionQuery),
+    responses(
+        (status = OK, description = "The file, or a rendition of an image"),
+        (status = PARTIAL_CONTENT, description = "The requested range"),
+        (status = NOT_FOUND, description = "Not in an enabled root"),
+        (status = RANGE_NOT_SATISFIABLE),
+    ),
+)]
 #[axum::debug_handler]
 pub async fn serve_dir(
     Path(path): Path<String>,
+    Query(query): Query<RenditionQuery>,
     request: axum::extract::Request,
 ) -> impl IntoResponse {
     let mut path = Utf8Path::new("/").join(path);
@@ -19,17 +35,28 @@ pub async fn serve_dir(
     let path_clone = path.to_owned();
     let mapped_path = compio::runtime::spawn(async move {
         let hls_map = super::PLAYLISTS.get().unwrap();
+        // Starts the transcode if it expired or was handed out without being started.
+        let is_hls_path =
+            PlaylistManager::playlist_path_to_file_path(path_clone.as_ref()).is_some();
+        if is_hls_path && !hls_map.contains_file(path_clone.as_ref()).await {
+            return Err(StatusCode::NOT_FOUND);
+        }
+
         match hls_map.pre_read(path_clone.as_ref()).await {
-            Ok(Some(path)) => Some(path.into_owned()),
-            Ok(None) => None,
+            Ok(Some(path)) => Ok(Some(path.into_owned())),
+            Ok(None) => Ok(None),
             Err(error) => {
-                eprintln!("Error pre-reading file: {error}");
-                None
+                tracing::error!(path = %path_clone, %error, "Error pre-reading file");
+                Ok(None)
             }
         }
     })
     .await
     .unwrap();
+    let mapped_path = match mapped_path {
+        Ok(mapped_path) => mapped_path,
+        Err(status) => return status.into_response(),
+    };
 
     if let Some(mapped_path) = &mapped_path {
         path = Utf8Path::from_path(mapped_path).unwrap().to_path_buf();
@@ -37,6 +64,32 @@ pub async fn serve_dir(
 
     let is_hls_file = mapped_path.is_some();
 
+    let accept = request.headers().get(header::ACCEPT).and_then(|value| value.to_str().ok());
+    let rendition_spec = if is_hls_file { None } else { query.spec(path.as_std_path(), accept) };
+
+    if let Some(spec) = rendition_spec {
+        // Otherwise ffmpeg's failure to open it would be reported as a conversion error.
+        if !compio::fs::metadata(&path).await.is_ok_and(|metadata| metadata.is_file()) {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+
+        let path_clone = path.clone();
+        let rendition = compio::runtime::spawn(async move {
+            let images = super::IMAGES.get().unwrap();
+            images.get(path_clone.as_std_path(), &spec).await
+        })
+        .await
+        .unwrap();
+
+        match rendition {
+            Ok(rendition) => path = Utf8PathBuf::from_path_buf(rendition).unwrap(),
+            Err(error) => {
+                return (StatusCode::INTERNAL_SERVER_ERROR, format!("Conversion Error: {error:?}"))
+                    .into_response();
+            }
+        }
+    }
+
     let mut _guard = None;
     if is_hls_file {
         _guard = Some(scopeguard::guard(path.clone(), move |path| {
@@ -130,7 +183,7 @@ pub async fn serve_dir(
                     }
                 }
                 Err(error) => {
-                    eprintln!("Error reading file at {current_offset}: {error:?}");
+                    tracing::error!(offset = current_offset, %error, "Error reading file");
                     _ = tx.send_async(Err(error)).await;
                     break;
                 }
@@ -166,6 +219,11 @@ pub async fn serve_dir(
         response = response.header(header::CACHE_CONTROL, "public, max-age=31536000");
     }
 
+    // The rendition format depends on what the client accepts.
+    if rendition_spec.is_some() {
+        response = response.header(header::VARY, header::ACCEPT.as_str());
+    }
+
     // If it's a range request, we MUST return a 206 Partial Content status
     if is_range {
         response = response
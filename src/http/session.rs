@@ -0,0 +1,340 @@
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use axum::extract::FromRequestParts;
+use axum::http::StatusCode;
+use axum::http::header::COOKIE;
+use axum::http::request::Parts;
+use axum::response::Json;
+use parking_lot::Mutex;
+use rand::seq::SliceRandom;
+use rustc_hash::FxHashMap;
+use triomphe::Arc;
+use utoipa::IntoParams;
+
+use super::queue::Queue;
+use super::{FileKind, LIBRARY, QUEUE, STARVING_EVICT_COUNT, STORE};
+
+/// Cookie holding the session id, `?session=` takes precedence.
+pub const SESSION_COOKIE: &str = "z_play_session";
+
+/// A client with its own queue, roots selection and history.
+pub struct Session {
+    pub queue: Queue,
+    last_seen: Mutex<Instant>,
+    history: Mutex<VecDeque<PathBuf>>,
+    // Queue length after the last refill, nothing to do until it changes.
+    fed_len: Mutex<Option<usize>>,
+    refilled_at: Mutex<Option<Instant>>,
+}
+
+impl Session {
+    const HISTORY_SIZE: usize = 100;
+    // Refilling walks the whole library, pops waiting on a tag or directory don't wait for it.
+    const REFILL_INTERVAL: Duration = Duration::from_secs(10);
+
+    fn new() -> Self {
+        // Starts out with the same roots selected as the shared queue.
+        let shared = QUEUE.get().unwrap();
+        let queue = Queue::new(shared.enabled_roots().read().clone());
+        queue
+            .disabled_roots()
+            .write()
+            .extend(shared.disabled_roots().read().iter().cloned());
+
+        Self {
+            queue,
+            last_seen: Mutex::new(Instant::now()),
+            history: Mutex::new(VecDeque::with_capacity(Self::HISTORY_SIZE)),
+            fed_len: Mutex::new(None),
+            refilled_at: Mutex::new(None),
+        }
+    }
+
+    pub fn touch(&self) {
+        *self.last_seen.lock() = Instant::now();
+    }
+
+    pub fn record_played(&self, path: PathBuf) {
+        let mut history = self.history.lock();
+        if history.len() == Self::HISTORY_SIZE {
+            history.pop_back();
+        }
+        history.push_front(path);
+    }
+
+    /// Most recently played first.
+    pub fn history(&self) -> Vec<PathBuf> {
+        self.history.lock().iter().cloned().collect()
+    }
+
+    /// Tops up the queue from the library index, starting with the tags and directories
+    /// filtered pops have been waiting on.
+    async fn feed(&self) {
+        let queue = &self.queue;
+        let store = STORE.get().unwrap();
+
+        let starving = queue.take_starving();
+        for &kind in &starving {
+            queue.evict_oldest(kind, STARVING_EVICT_COUNT).await;
+        }
+        let starving_dirs = queue.take_starving_dirs();
+        let starving_tags = queue.take_starving_tags();
+
+        let len = queue.len();
+        let is_fed = *self.fed_len.lock() == Some(len);
+        let refill = !is_fed
+            && self.refilled_at.lock().is_none_or(|at| at.elapsed() >= Self::REFILL_INTERVAL);
+        if !refill && starving.is_empty() && starving_dirs.is_empty() && starving_tags.is_empty() {
+            return;
+        }
+
+        let roots = queue.enabled_roots().read_async().await.clone();
+        let tagged = if starving_tags.is_empty() {
+            Vec::new()
+        } else {
+            store.tagged_paths(&starving_tags)
+        };
+        let is_wanted = |path: &Path| {
+            starving_dirs.iter().chain(&tagged).any(|wanted| path.starts_with(wanted))
+        };
+        let (mut wanted, mut rest): (Vec<_>, Vec<_>) = LIBRARY
+            .files(|path| {
+                roots.iter().any(|root| path.starts_with(root))
+                    && (refill || is_wanted(path))
+                    && !queue.contains_path(path)
+                    && !store.is_hidden(path)
+            })
+            .into_iter()
+            .partition(|path| is_wanted(path));
+
+        let mut rng = rand::rng();
+        wanted.shuffle(&mut rng);
+        rest.shuffle(&mut rng);
+
+        let stats = queue.stats();
+        let mut room = FxHashMap::from_iter([
+            (FileKind::Video, Queue::QUEUE_SIZE.saturating_sub(stats.video_count)),
+            (FileKind::Image, Queue::QUEUE_SIZE.saturating_sub(stats.image_count)),
+            (FileKind::Audio, Queue::QUEUE_SIZE.saturating_sub(stats.audio_count)),
+        ]);
+        let mut push = |path: PathBuf| {
+            let Some(kind) = FileKind::from_path(&path) else { return };
+            let room = room.get_mut(&kind).unwrap();
+            if *room > 0 {
+                *room -= 1;
+                queue.push(path);
+            }
+        };
+
+        wanted.into_iter().take(STARVING_EVICT_COUNT).for_each(&mut push);
+        // Same rejection sampling as the shared queue, so ratings and affinity still count.
+        rest.into_iter().filter(|path| store.accept(path)).for_each(push);
+
+        if refill {
+            *self.fed_len.lock() = Some(queue.len());
+            *self.refilled_at.lock() = Some(Instant::now());
+        }
+    }
+}
+
+/// Sessions by id, garbage collected once idle.
+pub struct Sessions {
+    sessions: Mutex<FxHashMap<String, Arc<Session>>>,
+}
+
+impl Sessions {
+    const MAX_SESSIONS: usize = 64;
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+    // Open players touch their session at least every 15s, so anything idle longer is unused.
+    const EVICTABLE_AFTER: Duration = Duration::from_secs(5 * 60);
+    const FEED_INTERVAL: Duration = Duration::from_millis(500);
+
+    pub fn new() -> Self {
+        Self { sessions: Mutex::new(FxHashMap::default()) }
+    }
+
+    fn get(&self, id: &str) -> Option<Arc<Session>> {
+        let session = self.sessions.lock().get(id).cloned()?;
+        session.touch();
+        Some(session)
+    }
+
+    /// `None` when full of sessions that are still in use, those are never evicted.
+    pub fn get_or_create(&self, id: &str) -> Option<Arc<Session>> {
+        let mut sessions = self.sessions.lock();
+        if let Some(session) = sessions.get(id) {
+            session.touch();
+            return Some(session.clone());
+        }
+
+        if sessions.len() >= Self::MAX_SESSIONS {
+            let now = Instant::now();
+            let oldest = sessions
+                .iter()
+                .map(|(id, session)| (id, *session.last_seen.lock()))
+                .filter(|(_, last_seen)| now.duration_since(*last_seen) > Self::EVICTABLE_AFTER)
+                .min_by_key(|(_, last_seen)| *last_seen)
+                .map(|(id, _)| id.clone());
+            let Some(oldest) = oldest else {
+                tracing::warn!(id, "Too many sessions in use, not starting another");
+                return None;
+            };
+            tracing::info!(id = oldest, "Evicting idle session");
+            sessions.remove(&oldest);
+        }
+
+        tracing::info!(id, "Starting session");
+        let session = Arc::new(Session::new());
+        sessions.insert(id.to_owned(), session.clone());
+        Some(session)
+    }
+
+    fn all(&self) -> Vec<Arc<Session>> {
+        self.sessions.lock().values().cloned().collect()
+    }
+
+    /// Drops `path` from every session queue, e.g. once hidden or deleted.
+    pub fn remove_path(&self, path: &Path) {
+        for session in self.all() {
+            session.queue.remove(path);
+        }
+    }
+
+    fn remove_idle(&self) {
+        let now = Instant::now();
+        self.sessions.lock().retain(|id, session| {
+            let idle = now.duration_since(*session.last_seen.lock()) > Self::IDLE_TIMEOUT;
+            if idle {
+                tracing::info!(id, "Dropping idle session");
+            }
+            !idle
+        });
+    }
+
+    /// Keeps every session queue topped up from the shared index and drops idle sessions.
+    pub async fn run_feeder(&self) {
+        loop {
+            compio::time::sleep(Self::FEED_INTERVAL).await;
+            self.remove_idle();
+            for session in self.all() {
+                session.feed().await;
+            }
+        }
+    }
+}
+
+/// How [`SessionQueue`] picks the session, only read to document it in `/openapi.json`.
+#[derive(IntoParams)]
+#[into_params(parameter_in = Query)]
+#[allow(dead_code)]
+pub struct SessionParams {
+    /// Session to use, overrides the `z_play_session` cookie. Without either, or before `/random`
+    /// or `/roots` started the session, the shared queue is used.
+    session: Option<String>,
+}
+
+/// The queue a request works on, its session's when it names a started one, the shared one
+/// otherwise.
+#[derive(Clone)]
+pub enum SessionQueue {
+    Shared,
+    Session(Arc<Session>),
+}
+
+impl SessionQueue {
+    pub fn session(&self) -> Option<&Session> {
+        match self {
+            Self::Shared => None,
+            Self::Session(session) => Some(session),
+        }
+    }
+
+    pub fn record_played(&self, path: &Path) {
+        if let Some(session) = self.session() {
+            session.record_played(path.to_owned());
+        }
+    }
+}
+
+impl Deref for SessionQueue {
+    type Target = Queue;
+
+    fn deref(&self) -> &Queue {
+        match self {
+            Self::Shared => QUEUE.get().unwrap(),
+            Self::Session(session) => &session.queue,
+        }
+    }
+}
+
+impl<S> FromRequestParts<S> for SessionQueue
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let session = session_id(parts).and_then(|id| super::SESSIONS.get(id));
+        Ok(session.map_or(Self::Shared, Self::Session))
+    }
+}
+
+/// A [`SessionQueue`] that starts the named session if needed. Only `/random` and `/roots` use
+/// it, so ids sent anywhere else can't evict sessions that are in use.
+pub struct StartSession(pub SessionQueue);
+
+impl<S> FromRequestParts<S> for StartSession
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(id) = session_id(parts) else { return Ok(Self(SessionQueue::Shared)) };
+        match super::SESSIONS.get_or_create(id) {
+            Some(session) => Ok(Self(SessionQueue::Session(session))),
+            None => Err((StatusCode::SERVICE_UNAVAILABLE, "Too many sessions in use")),
+        }
+    }
+}
+
+fn session_id(parts: &Parts) -> Option<&str> {
+    let from_query = parts.uri.query().and_then(|query| {
+        query.split('&').find_map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            (key == "session").then_some(value)
+        })
+    });
+    let from_cookie = || {
+        parts.headers.get_all(COOKIE).iter().find_map(|cookie| {
+            cookie.to_str().ok()?.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                (key == SESSION_COOKIE).then_some(value)
+            })
+        })
+    };
+
+    from_query.or_else(from_cookie).filter(|id| is_valid_id(id))
+}
+
+// Ids end up in logs, keep them short and boring.
+pub fn is_valid_id(id: &str) -> bool {
+    (1..=64).contains(&id.len())
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// What the session played last, most recent first.
+#[utoipa::path(
+    get,
+    path = "/history",
+    tag = "queue",
+    params(SessionParams),
+    responses((status = OK, description = "Empty without a session", body = Vec<String>)),
+)]
+pub async fn history_handler(queue: SessionQueue) -> Json<Vec<PathBuf>> {
+    Json(queue.session().map(Session::history).unwrap_or_default())
+}
@@ -0,0 +1,105 @@
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use futures_util::future::{Either, select};
+use rustix::process::Signal;
+use z_sync::Notify16;
+
+/// Set once on SIGINT or SIGTERM, every background thread races its work against it.
+#[derive(Debug)]
+pub struct Shutdown {
+    requested: AtomicBool,
+    notify: Notify16,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self { requested: AtomicBool::new(false), notify: Notify16::new() }
+    }
+
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::Release);
+        self.notify.notify(usize::MAX);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::Acquire)
+    }
+
+    pub async fn wait(&self) {
+        loop {
+            let listener = self.notify.listener();
+            if self.is_requested() {
+                return;
+            }
+            listener.await;
+        }
+    }
+
+    /// Runs `future` until it completes, or `None` if shutdown is requested first.
+    pub async fn or_shutdown<F>(&self, future: F) -> Option<F::Output>
+    where
+        F: Future,
+    {
+        match select(pin!(future), pin!(self.wait())).await {
+            Either::Left((output, _)) => Some(output),
+            Either::Right(_) => None,
+        }
+    }
+}
+
+/// Resolves on the first SIGINT or SIGTERM.
+pub async fn signal() {
+    let interrupt = pin!(compio::signal::ctrl_c());
+    let terminate = pin!(compio::signal::unix::signal(Signal::TERM.as_raw()));
+    let (result, _) = select(interrupt, terminate).await.factor_first();
+    if let Err(error) = result {
+        tracing::error!(%error, "Failed to listen for signals, shutting down");
+    }
+}
+
+/// A background thread that should stop on shutdown.
+pub struct Worker {
+    pub name: &'static str,
+    pub handle: JoinHandle<()>,
+}
+
+impl Worker {
+    pub fn spawn<F>(name: &'static str, f: F) -> Self
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        Self { name, handle: std::thread::spawn(f) }
+    }
+}
+
+/// Polls `done` until it's true or `deadline` passes, returns whether it finished in time.
+pub fn wait_until(deadline: Instant, mut done: impl FnMut() -> bool) -> bool {
+    const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+    loop {
+        if done() {
+            return true;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return false;
+        }
+        std::thread::sleep(POLL_INTERVAL.min(deadline - now));
+    }
+}
+
+/// Joins the workers that stop before `deadline`, the rest die with the process.
+pub fn join(workers: Vec<Worker>, deadline: Instant) {
+    wait_until(deadline, || workers.iter().all(|worker| worker.handle.is_finished()));
+
+    for worker in workers {
+        if !worker.handle.is_finished() {
+            tracing::warn!(thread = worker.name, "Thread didn't stop in time");
+        } else if worker.handle.join().is_err() {
+            tracing::error!(thread = worker.name, "Thread panicked");
+        }
+    }
+}
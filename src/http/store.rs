@@ -0,0 +1,386 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use parking_lot::RwLock;
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// What the user has told us about a file.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct PathEntry {
+    /// 1 to 5 stars.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rating: Option<u8>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub favorite: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub hidden: bool,
+}
+
+impl PathEntry {
+    pub const MAX_RATING: u8 = 5;
+
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Relative chance of being picked by the queue feeder, unrated files are 1.
+    pub fn weight(&self) -> f64 {
+        let rating_weight = match self.rating {
+            None | Some(3) => 1.0,
+            Some(1) => 0.25,
+            Some(2) => 0.5,
+            Some(4) => 2.0,
+            Some(_) => 4.0,
+        };
+        if self.favorite { rating_weight * 2.0 } else { rating_weight }
+    }
+}
+
+/// How a client left a file, reported through `/close`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Feedback {
+    Skipped,
+    Partial,
+    Finished,
+}
+
+impl Feedback {
+    const SKIP_SECONDS: f64 = 2.0;
+    const FINISHED_RATIO: f64 = 0.9;
+
+    pub fn from_watch_time(watched: f64, duration: Option<f64>, looped: u32) -> Self {
+        let duration = duration.filter(|duration| duration.is_finite() && *duration > 0.0);
+        if looped > 0 || duration.is_some_and(|duration| watched >= duration * Self::FINISHED_RATIO)
+        {
+            Self::Finished
+        } else if watched < Self::SKIP_SECONDS {
+            Self::Skipped
+        } else {
+            Self::Partial
+        }
+    }
+
+    fn delta(self) -> f32 {
+        match self {
+            Self::Skipped => -1.0,
+            Self::Partial => 0.0,
+            Self::Finished => 1.0,
+        }
+    }
+}
+
+/// Chance of ignoring learned affinity, so files that were skipped once still come back.
+///
+/// Set with `Z_PLAY_EXPLORATION`, between 0 and 1.
+pub static EXPLORATION: LazyLock<f64> = LazyLock::new(|| {
+    std::env::var("Z_PLAY_EXPLORATION")
+        .ok()
+        .and_then(|value| value.parse::<f64>().ok())
+        .map_or(0.1, |value| value.clamp(0.0, 1.0))
+});
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StoreFile {
+    #[serde(default)]
+    paths: FxHashMap<PathBuf, PathEntry>,
+    /// Learned from watch time, keyed by both files and their directories.
+    #[serde(default)]
+    affinity: FxHashMap<PathBuf, f32>,
+    /// Files and directories, files inherit the tags of every directory above them.
+    #[serde(default)]
+    tags: FxHashMap<PathBuf, BTreeSet<String>>,
+    #[serde(default)]
+    playlists: BTreeMap<String, Vec<PathBuf>>,
+}
+
+/// Per-path user data, persisted as JSON in the data directory.
+pub struct Store {
+    file_path: PathBuf,
+    paths: RwLock<FxHashMap<PathBuf, PathEntry>>,
+    affinity: RwLock<FxHashMap<PathBuf, f32>>,
+    tags: RwLock<FxHashMap<PathBuf, BTreeSet<String>>>,
+    playlists: RwLock<BTreeMap<String, Vec<PathBuf>>>,
+    dirty: AtomicBool,
+    notify_dirty: z_sync::Notify16,
+}
+
+impl Store {
+    const FILE_NAME: &'static str = "store.json";
+    // Batches bursts of changes into one write.
+    const SAVE_DELAY: Duration = Duration::from_secs(2);
+    // Affinity is a log2 weight, so this caps it between 1/8 and 8.
+    const MAX_AFFINITY: f32 = 3.0;
+    // Older feedback fades out as new feedback comes in.
+    const AFFINITY_DECAY: f32 = 0.8;
+
+    pub fn load(data_dir: &Path) -> Self {
+        let file_path = data_dir.join(Self::FILE_NAME);
+
+        let file = match std::fs::read(&file_path) {
+            Ok(data) => serde_json::from_slice::<StoreFile>(&data).unwrap_or_else(|error| {
+                // Keep the broken file around rather than overwriting it on the next save.
+                tracing::error!(path = %file_path.display(), %error, "Failed to parse store");
+                _ = std::fs::rename(&file_path, file_path.with_extension("json.broken"));
+                StoreFile::default()
+            }),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => StoreFile::default(),
+            Err(error) => {
+                tracing::error!(path = %file_path.display(), %error, "Failed to read store");
+                StoreFile::default()
+            }
+        };
+
+        Self {
+            file_path,
+            paths: RwLock::new(file.paths),
+            affinity: RwLock::new(file.affinity),
+            tags: RwLock::new(file.tags),
+            playlists: RwLock::new(file.playlists),
+            dirty: AtomicBool::new(false),
+            notify_dirty: z_sync::Notify16::new(),
+        }
+    }
+
+    pub fn get(&self, path: &Path) -> Option<PathEntry> {
+        self.paths.read().get(path).cloned()
+    }
+
+    pub fn is_hidden(&self, path: &Path) -> bool {
+        self.paths.read().get(path).is_some_and(|entry| entry.hidden)
+    }
+
+    /// Rating weight combined with the affinity of the file and its directory.
+    pub fn weight(&self, path: &Path) -> f64 {
+        let rating_weight = self.paths.read().get(path).map_or(1.0, PathEntry::weight);
+        rating_weight * self.affinity_weight(path)
+    }
+
+    fn affinity_weight(&self, path: &Path) -> f64 {
+        let affinity = self.affinity.read();
+        let file = affinity.get(path).copied().unwrap_or_default();
+        let dir = path.parent().and_then(|dir| affinity.get(dir)).copied().unwrap_or_default();
+        // A directory says less about one file than the file's own history.
+        f64::from(file + dir * 0.5).exp2()
+    }
+
+    /// Decides whether a single candidate should be used, for feeders that can't compare several.
+    ///
+    /// Disliked files are rejected in proportion to their weight, everything else is accepted.
+    pub fn accept(&self, path: &Path) -> bool {
+        let weight = self.weight(path);
+        weight >= 1.0 || rand::random_bool(EXPLORATION.max(weight))
+    }
+
+    pub fn record_feedback(&self, path: &Path, feedback: Feedback) {
+        let delta = feedback.delta();
+        {
+            let mut affinity = self.affinity.write();
+            for (key, delta) in [(Some(path), delta), (path.parent(), delta * 0.5)] {
+                let Some(key) = key else { continue };
+                let score = affinity.entry(key.to_owned()).or_default();
+                *score = (*score * Self::AFFINITY_DECAY + delta)
+                    .clamp(-Self::MAX_AFFINITY, Self::MAX_AFFINITY);
+                if score.abs() < 0.01 {
+                    affinity.remove(key);
+                }
+            }
+        }
+
+        self.mark_dirty();
+    }
+
+    /// Applies `f` to the entry for `path` and returns the updated entry.
+    pub fn update<F>(&self, path: &Path, f: F) -> PathEntry
+    where
+        F: FnOnce(&mut PathEntry),
+    {
+        let entry = {
+            let mut paths = self.paths.write();
+            let entry = paths.entry(path.to_owned()).or_default();
+            f(entry);
+            let entry = entry.clone();
+            if entry.is_empty() {
+                paths.remove(path);
+            }
+            entry
+        };
+
+        self.mark_dirty();
+        entry
+    }
+
+    /// Tags set on `path` itself.
+    pub fn tags(&self, path: &Path) -> BTreeSet<String> {
+        self.tags.read().get(path).cloned().unwrap_or_default()
+    }
+
+    /// Tags `path` gets from the directories above it.
+    pub fn inherited_tags(&self, path: &Path) -> BTreeSet<String> {
+        let tags = self.tags.read();
+        path.ancestors()
+            .skip(1)
+            .filter_map(|dir| tags.get(dir))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    pub fn has_any_tag(&self, path: &Path, wanted: &FxHashSet<String>) -> bool {
+        let tags = self.tags.read();
+        path.ancestors()
+            .filter_map(|path| tags.get(path))
+            .any(|tags| tags.iter().any(|tag| wanted.contains(tag)))
+    }
+
+    /// Every tag with the paths it was set on.
+    pub fn all_tags(&self) -> BTreeMap<String, Vec<PathBuf>> {
+        let mut all_tags = BTreeMap::<String, Vec<PathBuf>>::new();
+        for (path, tags) in self.tags.read().iter() {
+            for tag in tags {
+                all_tags.entry(tag.clone()).or_default().push(path.clone());
+            }
+        }
+        all_tags.values_mut().for_each(|paths| paths.sort_unstable());
+        all_tags
+    }
+
+    /// Paths tagged with any of `wanted`, directories are not expanded.
+    pub fn tagged_paths(&self, wanted: &FxHashSet<String>) -> Vec<PathBuf> {
+        self.tags
+            .read()
+            .iter()
+            .filter(|(_, tags)| tags.iter().any(|tag| wanted.contains(tag)))
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    /// Applies `f` to the tags set on `path` and returns the updated tags.
+    pub fn update_tags<F>(&self, path: &Path, f: F) -> BTreeSet<String>
+    where
+        F: FnOnce(&mut BTreeSet<String>),
+    {
+        let tags = {
+            let mut all_tags = self.tags.write();
+            let tags = all_tags.entry(path.to_owned()).or_default();
+            f(tags);
+            let tags = tags.clone();
+            if tags.is_empty() {
+                all_tags.remove(path);
+            }
+            tags
+        };
+
+        self.mark_dirty();
+        tags
+    }
+
+    /// Names of the saved playlists with their lengths.
+    pub fn playlists(&self) -> Vec<(String, usize)> {
+        self.playlists
+            .read()
+            .iter()
+            .map(|(name, paths)| (name.clone(), paths.len()))
+            .collect()
+    }
+
+    pub fn playlist(&self, name: &str) -> Option<Vec<PathBuf>> {
+        self.playlists.read().get(name).cloned()
+    }
+
+    /// Creates or replaces a playlist.
+    pub fn set_playlist(&self, name: &str, paths: Vec<PathBuf>) {
+        self.playlists.write().insert(name.to_owned(), paths);
+        self.mark_dirty();
+    }
+
+    /// Applies `f` to an existing playlist and returns the updated paths.
+    pub fn update_playlist<F>(&self, name: &str, f: F) -> Option<Vec<PathBuf>>
+    where
+        F: FnOnce(&mut Vec<PathBuf>),
+    {
+        let paths = {
+            let mut playlists = self.playlists.write();
+            let paths = playlists.get_mut(name)?;
+            f(paths);
+            paths.clone()
+        };
+        self.mark_dirty();
+        Some(paths)
+    }
+
+    pub fn remove_playlist(&self, name: &str) -> bool {
+        let removed = self.playlists.write().remove(name).is_some();
+        if removed {
+            self.mark_dirty();
+        }
+        removed
+    }
+
+    fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Release);
+        self.notify_dirty.notify(usize::MAX);
+    }
+
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+
+        let json = {
+            let paths = self.paths.read();
+            let affinity = self.affinity.read();
+            let tags = self.tags.read();
+            let playlists = self.playlists.read();
+            serde_json::to_vec(&StoreFileRef {
+                paths: &paths,
+                affinity: &affinity,
+                tags: &tags,
+                playlists: &playlists,
+            })?
+        };
+
+        let result = write_atomic(&self.file_path, &json);
+        if result.is_err() {
+            // Try again with the next change.
+            self.dirty.store(true, Ordering::Release);
+        }
+        result
+    }
+
+    pub async fn run_saver(&self) {
+        loop {
+            let listener = self.notify_dirty.listener();
+            if !self.dirty.load(Ordering::Acquire) {
+                listener.await;
+            }
+            compio::time::sleep(Self::SAVE_DELAY).await;
+
+            if let Err(error) = self.save() {
+                tracing::error!(path = %self.file_path.display(), %error, "Failed to save store");
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct StoreFileRef<'a> {
+    paths: &'a FxHashMap<PathBuf, PathEntry>,
+    affinity: &'a FxHashMap<PathBuf, f32>,
+    tags: &'a FxHashMap<PathBuf, BTreeSet<String>>,
+    playlists: &'a BTreeMap<String, Vec<PathBuf>>,
+}
+
+/// Writes to a temporary file first so a crash never leaves a truncated file behind.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<(), std::io::Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let temp_path = path.with_extension("tmp");
+    std::fs::write(&temp_path, data)?;
+    std::fs::rename(temp_path, path)
+}
@@ -0,0 +1,607 @@
+//! The part of the Subsonic API that folder based clients need to browse and play the audio in
+//! the roots, e.g. DSub, Symfonium or Feishin.
+//!
+//! Ids are the URL safe base64 of absolute paths, music folders are the enabled roots by
+//! position. Only `GET` is supported, so the OpenSubsonic `formPost` extension isn't advertised.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::Router;
+use axum::body::Body;
+use axum::extract::{self, Request};
+use axum::http::header::CONTENT_TYPE;
+use axum::response::{IntoResponse, Json, Response};
+use axum::routing::get;
+use axum_extra::extract::Query;
+use base64::Engine;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use rustc_hash::FxHashSet;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+
+use super::browse::{self, BrowseEntry};
+use super::image::RenditionQuery;
+use super::metrics::FfmpegJob;
+use super::transcode::{needs_audio_transcode, spawn_audio_stream};
+use super::{FileKind, LIBRARY, METADATA, METRICS, QUEUE, STORE, SUBSONIC, meta, serve_dir};
+
+const API_VERSION: &str = "1.16.1";
+const DEFAULT_RANDOM_SONGS: usize = 10;
+const MAX_SONGS: usize = 500;
+const DEFAULT_SEARCH_SONGS: usize = 20;
+// The queue can run out of audio in a narrow root selection, return what was found by then.
+const RANDOM_SONG_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_BIT_RATE: u32 = 192;
+const MAX_BIT_RATE: u32 = 320;
+
+/// The one account clients log in with.
+pub struct Subsonic {
+    user: String,
+    password: String,
+}
+
+impl Subsonic {
+    /// `None` unless `Z_PLAY_SUBSONIC_PASSWORD` is set, the API stays off without a password.
+    pub fn from_env() -> Option<Self> {
+        let password = std::env::var("Z_PLAY_SUBSONIC_PASSWORD")
+            .ok()
+            .filter(|password| !password.is_empty())?;
+        let user = std::env::var("Z_PLAY_SUBSONIC_USER").unwrap_or_else(|_| "z-play".to_owned());
+        Some(Self { user, password })
+    }
+
+    fn authenticate(&self, params: &Params) -> Result<(), SubsonicError> {
+        let Some(user) = &params.u else { return Err(SubsonicError::missing("u")) };
+        let is_valid = match (&params.t, &params.s, &params.p) {
+            (Some(token), Some(salt), _) => {
+                let expected = md5::compute(format!("{}{salt}", self.password));
+                token.eq_ignore_ascii_case(&format!("{expected:x}"))
+            }
+            // Older clients send the password itself, optionally hex encoded.
+            (_, _, Some(password)) => match password.strip_prefix("enc:") {
+                Some(hex) => decode_hex(hex).is_some_and(|password| password == self.password),
+                None => *password == self.password,
+            },
+            _ => return Err(SubsonicError::missing("t")),
+        };
+
+        if is_valid && *user == self.user {
+            Ok(())
+        } else {
+            Err(SubsonicError::WRONG_CREDENTIALS)
+        }
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<String> {
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+pub fn routes() -> Router {
+    Router::new().route("/rest/{method}", get(rest_handler))
+}
+
+#[derive(Debug, Clone)]
+struct SubsonicError {
+    code: u16,
+    message: Cow<'static, str>,
+}
+
+impl SubsonicError {
+    const WRONG_CREDENTIALS: Self =
+        Self { code: 40, message: Cow::Borrowed("Wrong username or password") };
+    const NOT_FOUND: Self =
+        Self { code: 70, message: Cow::Borrowed("The requested data was not found") };
+
+    fn generic(message: String) -> Self {
+        Self { code: 0, message: Cow::Owned(message) }
+    }
+
+    fn missing(name: &str) -> Self {
+        Self {
+            code: 10,
+            message: Cow::Owned(format!("Required parameter is missing: {name}")),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Params {
+    u: Option<String>,
+    /// `md5(password + salt)`, hex encoded.
+    t: Option<String>,
+    s: Option<String>,
+    p: Option<String>,
+    /// `json`, anything else gets XML.
+    f: Option<String>,
+    id: Option<String>,
+    size: Option<usize>,
+    music_folder_id: Option<usize>,
+    query: Option<String>,
+    song_count: Option<usize>,
+    song_offset: Option<usize>,
+    /// `raw` never transcodes, `mp3` always does.
+    format: Option<String>,
+    /// Kbps, 0 means no limit.
+    max_bit_rate: Option<u32>,
+    /// Seconds, only honoured when transcoding.
+    time_offset: Option<u32>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Format {
+    Xml,
+    Json,
+}
+
+impl Format {
+    fn respond(self, status: &str, body: Value) -> Response {
+        let mut response = Map::new();
+        response.insert("status".to_owned(), status.into());
+        response.insert("version".to_owned(), API_VERSION.into());
+        response.insert("type".to_owned(), "z-play".into());
+        response.insert("serverVersion".to_owned(), env!("CARGO_PKG_VERSION").into());
+        response.insert("openSubsonic".to_owned(), true.into());
+        if let Value::Object(body) = body {
+            response.extend(body);
+        }
+
+        match self {
+            Self::Json => Json(json!({ "subsonic-response": response })).into_response(),
+            Self::Xml => {
+                response.insert("xmlns".to_owned(), "http://subsonic.org/restapi".into());
+                let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+                write_xml(&mut xml, "subsonic-response", &Value::Object(response));
+                ([(CONTENT_TYPE, "text/xml; charset=utf-8")], xml).into_response()
+            }
+        }
+    }
+
+    fn error(self, error: SubsonicError) -> Response {
+        let body = json!({ "error": { "code": error.code, "message": error.message } });
+        self.respond("failed", body)
+    }
+}
+
+/// Scalars become attributes, objects and arrays child elements, the same mapping Subsonic
+/// uses between its XML and JSON responses.
+fn write_xml(xml: &mut String, name: &str, value: &Value) {
+    let Value::Object(fields) = value else {
+        _ = write!(xml, "<{name}>{}</{name}>", escape(&scalar(value)));
+        return;
+    };
+
+    _ = write!(xml, "<{name}");
+    for (key, value) in fields {
+        if !matches!(value, Value::Null | Value::Object(_) | Value::Array(_)) {
+            _ = write!(xml, " {key}=\"{}\"", escape(&scalar(value)));
+        }
+    }
+
+    let mut children = fields.iter().filter(|(_, value)| value.is_object() || value.is_array());
+    let Some(first) = children.next() else {
+        xml.push_str("/>");
+        return;
+    };
+    xml.push('>');
+    for (key, value) in std::iter::once(first).chain(children) {
+        match value {
+            Value::Array(items) => items.iter().for_each(|item| write_xml(xml, key, item)),
+            value => write_xml(xml, key, value),
+        }
+    }
+    _ = write!(xml, "</{name}>");
+}
+
+fn scalar(value: &Value) -> Cow<'_, str> {
+    match value {
+        Value::String(string) => Cow::Borrowed(string),
+        value => Cow::Owned(value.to_string()),
+    }
+}
+
+fn escape(text: &str) -> Cow<'_, str> {
+    if !text.contains(['&', '<', '>', '"']) {
+        return Cow::Borrowed(text);
+    }
+    Cow::Owned(
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;"),
+    )
+}
+
+/// Every method, `.view` suffix or not, as older clients still add it.
+async fn rest_handler(
+    extract::Path(method): extract::Path<String>,
+    Query(params): Query<Params>,
+    request: Request,
+) -> Response {
+    let format = if params.f.as_deref() == Some("json") { Format::Json } else { Format::Xml };
+    if let Err(error) = SUBSONIC.get().unwrap().authenticate(&params) {
+        return format.error(error);
+    }
+
+    let method = method.strip_suffix(".view").unwrap_or(&method).to_owned();
+    let result = compio::runtime::spawn(async move {
+        let body = match method.as_str() {
+            "ping" => Ok(json!({})),
+            "getLicense" => Ok(json!({ "license": { "valid": true } })),
+            "getOpenSubsonicExtensions" => Ok(json!({ "openSubsonicExtensions": [] })),
+            "getMusicFolders" => Ok(music_folders().await),
+            "getIndexes" => indexes(&params).await,
+            "getMusicDirectory" => music_directory(&params).await,
+            "getRandomSongs" => random_songs(&params).await,
+            "search3" => search3(&params).await,
+            "stream" | "download" => return stream(&params, request).await,
+            "getCoverArt" => return cover_art(&params).await,
+            _ => Err(SubsonicError::generic(format!("Unsupported method {method}"))),
+        };
+        body.map(|body| format.respond("ok", body))
+    })
+    .await
+    .unwrap();
+
+    result.unwrap_or_else(|error| format.error(error))
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Child {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent: Option<String>,
+    is_dir: bool,
+    title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    album: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    artist: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    track: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cover_art: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suffix: Option<String>,
+    /// Seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration: Option<u64>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    media_type: Option<&'static str>,
+}
+
+impl Child {
+    fn dir(path: &Path, parent: Option<String>) -> Self {
+        Self {
+            id: id(path),
+            parent,
+            is_dir: true,
+            title: browse::file_name(path),
+            album: None,
+            artist: None,
+            track: None,
+            cover_art: None,
+            size: None,
+            content_type: None,
+            suffix: None,
+            duration: None,
+            media_type: None,
+        }
+    }
+
+    /// `size` comes from [`songs`], which reads it off the runtime thread.
+    fn song(path: &Path, size: Option<u64>) -> Self {
+        let metadata = METADATA.get(path);
+        if metadata.is_none() {
+            // Tags and duration show up the next time it's listed.
+            METADATA.request(path.to_owned());
+        }
+        let tags = metadata.as_ref().and_then(|metadata| metadata.tags.as_ref());
+
+        let id = id(path);
+        Self {
+            parent: path.parent().map(self::id),
+            is_dir: false,
+            title: tags
+                .and_then(|tags| tags.title.clone())
+                .unwrap_or_else(|| browse::file_name(path)),
+            album: tags.and_then(|tags| tags.album.clone()),
+            artist: tags.and_then(|tags| tags.artist.clone()),
+            track: tags.and_then(|tags| tags.track),
+            cover_art: tags.filter(|tags| tags.has_cover).map(|_| id.clone()),
+            size,
+            content_type: Some(mime_guess::from_path(path).first_or_octet_stream().to_string()),
+            suffix: path.extension().map(|extension| extension.to_string_lossy().to_lowercase()),
+            duration: metadata
+                .as_ref()
+                .and_then(|metadata| metadata.duration)
+                .map(|duration| duration.round() as u64),
+            media_type: Some("music"),
+            id,
+        }
+    }
+}
+
+/// Up to [`MAX_SONGS`] files get stat'ed, which mustn't block the runtime thread.
+async fn songs(paths: Vec<PathBuf>) -> Vec<Child> {
+    let (paths, sizes) = compio::runtime::spawn_blocking(move || {
+        let sizes = paths
+            .iter()
+            .map(|path| std::fs::metadata(path).ok().map(|metadata| metadata.len()))
+            .collect::<Vec<_>>();
+        (paths, sizes)
+    })
+    .await
+    .unwrap();
+    paths.iter().zip(sizes).map(|(path, size)| Child::song(path, size)).collect()
+}
+
+fn id(path: &Path) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(path.to_string_lossy().as_bytes())
+}
+
+async fn roots() -> Vec<PathBuf> {
+    QUEUE.get().unwrap().enabled_roots().read_async().await.clone()
+}
+
+/// The path behind `id`, as long as it's in an enabled root and not hidden.
+async fn resolve(id: Option<&str>) -> Result<PathBuf, SubsonicError> {
+    let Some(id) = id else { return Err(SubsonicError::missing("id")) };
+    let path = BASE64_URL_SAFE_NO_PAD
+        .decode(id)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .map(PathBuf::from)
+        .ok_or(SubsonicError::NOT_FOUND)?;
+
+    let is_normal = path.is_absolute()
+        && path.components().all(|component| !matches!(component, Component::ParentDir));
+    if !is_normal {
+        return Err(SubsonicError::NOT_FOUND);
+    }
+    // Roots are canonical, so symlinks out of them have to be resolved for the prefix check.
+    let path = compio::runtime::spawn_blocking(move || path.canonicalize())
+        .await
+        .unwrap()
+        .map_err(|_| SubsonicError::NOT_FOUND)?;
+    let in_root = roots().await.iter().any(|root| path.starts_with(root));
+    if !in_root || STORE.get().unwrap().is_hidden(&path) {
+        return Err(SubsonicError::NOT_FOUND);
+    }
+    Ok(path)
+}
+
+/// 1-based, like the ids in `getMusicFolders`.
+async fn music_folder(id: Option<usize>) -> Result<Option<PathBuf>, SubsonicError> {
+    let Some(id) = id else { return Ok(None) };
+    let roots = roots().await;
+    let root = id.checked_sub(1).and_then(|index| roots.get(index));
+    root.cloned().map(Some).ok_or(SubsonicError::NOT_FOUND)
+}
+
+async fn music_folders() -> Value {
+    let folders = roots()
+        .await
+        .iter()
+        .enumerate()
+        .map(|(index, root)| json!({ "id": index + 1, "name": browse::file_name(root) }))
+        .collect::<Vec<_>>();
+    json!({ "musicFolders": { "musicFolder": folders } })
+}
+
+/// Subdirectories holding audio somewhere below them and the audio files directly in `dir`.
+async fn read_audio(dir: PathBuf) -> Result<(Vec<PathBuf>, Vec<PathBuf>), SubsonicError> {
+    let dir_clone = dir.clone();
+    let read = compio::runtime::spawn_blocking(move || browse::read_entries(&dir_clone))
+        .await
+        .unwrap();
+    let Ok((dirs, files)) = read else { return Err(SubsonicError::NOT_FOUND) };
+
+    let dirs = dirs.into_iter().map(|(dir, _)| dir).collect::<Vec<_>>();
+    let counts = browse::recursive_counts(&dirs);
+    let mut dirs = dirs
+        .into_iter()
+        .filter(|dir| counts.get(dir).is_some_and(|counts| counts.audio_count > 0))
+        .collect::<Vec<_>>();
+    let mut files = files
+        .into_iter()
+        .filter_map(|entry| match entry {
+            BrowseEntry::File { path, kind: FileKind::Audio, .. } => Some(path),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let by_name = |a: &PathBuf, b: &PathBuf| {
+        browse::compare_names(&browse::file_name(a), &browse::file_name(b))
+    };
+    dirs.sort_unstable_by(by_name);
+    files.sort_unstable_by(by_name);
+    Ok((dirs, files))
+}
+
+/// Top level directories of the roots, grouped by initial.
+async fn indexes(params: &Params) -> Result<Value, SubsonicError> {
+    let roots = match music_folder(params.music_folder_id).await? {
+        Some(root) => vec![root],
+        None => roots().await,
+    };
+
+    let mut index = BTreeMap::<String, Vec<Value>>::new();
+    let mut children = Vec::new();
+    for root in roots {
+        let Ok((dirs, files)) = read_audio(root).await else { continue };
+        for dir in dirs {
+            let name = browse::file_name(&dir);
+            let initial = name
+                .chars()
+                .next()
+                .filter(|c| c.is_alphabetic())
+                .map_or_else(|| "#".to_owned(), |c| c.to_uppercase().collect());
+            index.entry(initial).or_default().push(json!({ "id": id(&dir), "name": name }));
+        }
+        children.extend(songs(files).await);
+    }
+
+    let last_modified = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let index = index
+        .into_iter()
+        .map(|(name, artists)| json!({ "name": name, "artist": artists }))
+        .collect::<Vec<_>>();
+    Ok(json!({
+        "indexes": {
+            "lastModified": last_modified.as_millis() as u64,
+            "ignoredArticles": "",
+            "index": index,
+            "child": children,
+        }
+    }))
+}
+
+async fn music_directory(params: &Params) -> Result<Value, SubsonicError> {
+    let dir = resolve(params.id.as_deref()).await?;
+    if !dir.is_dir() {
+        return Err(SubsonicError::NOT_FOUND);
+    }
+
+    let is_root = roots().await.contains(&dir);
+    let parent = dir.parent().filter(|_| !is_root).map(id);
+    let (dirs, files) = read_audio(dir.clone()).await?;
+    let mut children = dirs.iter().map(|path| Child::dir(path, Some(id(&dir)))).collect::<Vec<_>>();
+    children.extend(songs(files).await);
+
+    let mut directory =
+        json!({ "id": id(&dir), "name": browse::file_name(&dir), "child": children });
+    if let Some(parent) = parent {
+        directory["parent"] = parent.into();
+    }
+    Ok(json!({ "directory": directory }))
+}
+
+/// Popped from the shared queue, so they count as played like anything from `/random`.
+async fn random_songs(params: &Params) -> Result<Value, SubsonicError> {
+    let size = params.size.unwrap_or(DEFAULT_RANDOM_SONGS).clamp(1, MAX_SONGS);
+    let dir = music_folder(params.music_folder_id).await?;
+    let kinds = FxHashSet::from_iter([FileKind::Audio]);
+    let queue = QUEUE.get().unwrap();
+
+    let mut paths = Vec::with_capacity(size);
+    for _ in 0..size {
+        let pop = queue.find_pop_async(Some(&kinds), None, dir.as_deref(), None, None);
+        let Ok(Some((path, _))) = compio::time::timeout(RANDOM_SONG_TIMEOUT, pop).await else {
+            break;
+        };
+        paths.push(path);
+    }
+    Ok(json!({ "randomSongs": { "song": songs(paths).await } }))
+}
+
+/// Only songs are searched, there are no artists or albums without ID3 browsing. An empty
+/// query lists every song, which is how some clients sync their offline library.
+async fn search3(params: &Params) -> Result<Value, SubsonicError> {
+    let count = params.song_count.unwrap_or(DEFAULT_SEARCH_SONGS).min(MAX_SONGS);
+    let offset = params.song_offset.unwrap_or(0);
+    let query = params.query.as_deref().unwrap_or_default().trim_matches('"');
+
+    let roots = roots().await;
+    let store = STORE.get().unwrap();
+    let filter = move |path: &Path| {
+        roots.iter().any(|root| path.starts_with(root)) && !store.is_hidden(path)
+    };
+
+    let paths = if query.trim().is_empty() {
+        let mut paths = LIBRARY
+            .files(|path| FileKind::from_path(path) == Some(FileKind::Audio) && filter(path));
+        paths.sort_unstable();
+        paths.into_iter().skip(offset).take(count).collect::<Vec<_>>()
+    } else {
+        let kinds = FxHashSet::from_iter([FileKind::Audio]);
+        let query = query.to_owned();
+        let results = compio::runtime::spawn_blocking(move || {
+            LIBRARY.search(&query, &kinds, offset + count, filter)
+        })
+        .await
+        .unwrap();
+        results.into_iter().skip(offset).map(|result| result.path).collect()
+    };
+
+    let songs = songs(paths).await;
+    Ok(json!({ "searchResult3": { "artist": [], "album": [], "song": songs } }))
+}
+
+/// The file itself through `serve_dir` when the client can play it, MP3 from ffmpeg otherwise.
+async fn stream(params: &Params, request: Request) -> Result<Response, SubsonicError> {
+    let path = resolve(params.id.as_deref()).await?;
+    if FileKind::from_path(&path) != Some(FileKind::Audio) {
+        return Err(SubsonicError::NOT_FOUND);
+    }
+
+    let bit_rate = params.max_bit_rate.filter(|&bit_rate| bit_rate > 0);
+    let transcode = match params.format.as_deref() {
+        Some("raw") => false,
+        Some("mp3") => true,
+        _ => bit_rate.is_some() || needs_audio_transcode(&path).await,
+    };
+    if !transcode {
+        let path = path.to_string_lossy().into_owned();
+        let query = Query(RenditionQuery::default());
+        return Ok(serve_dir::serve_dir(extract::Path(path), query, request).await.into_response());
+    }
+
+    let bit_rate = bit_rate.unwrap_or(DEFAULT_BIT_RATE).min(MAX_BIT_RATE);
+    let span = tracing::info_span!("transcode", path = %path.display(), bit_rate);
+    let mut child = span
+        .in_scope(|| spawn_audio_stream(&path, bit_rate, params.time_offset))
+        .map_err(|error| SubsonicError::generic(format!("Failed to start ffmpeg: {error}")))?;
+    let mut stdout = child.stdout.take().unwrap();
+    let ffmpeg = METRICS.track_ffmpeg(FfmpegJob::Stream);
+
+    let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+    std::thread::spawn(move || {
+        let _span = span.enter();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match stdout.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => {
+                    // The client hung up.
+                    if tx.blocking_send(Ok(buf[..len].to_vec())).is_err() {
+                        break;
+                    }
+                }
+                Err(error) => {
+                    _ = tx.blocking_send(Err::<Vec<u8>, _>(error));
+                    break;
+                }
+            }
+        }
+        _ = child.kill();
+        _ = child.wait();
+        drop(ffmpeg);
+        tracing::debug!("Stream finished");
+    });
+
+    let stream = futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx));
+    Ok(([(CONTENT_TYPE, "audio/mpeg")], Body::from_stream(stream)).into_response())
+}
+
+/// Cover art ids are song ids, only embedded covers are served.
+async fn cover_art(params: &Params) -> Result<Response, SubsonicError> {
+    let path = resolve(params.id.as_deref()).await?;
+    let path = path.to_string_lossy().into_owned();
+    Ok(meta::cover_handler(extract::Path(path)).await)
+}
@@ -0,0 +1,240 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use axum::extract;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Json, Response};
+use axum_extra::extract::Query;
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use triomphe::Arc;
+use utoipa::{IntoParams, ToSchema};
+
+use super::session::is_valid_id;
+use super::{PathResponse, SYNC_GROUPS};
+
+/// Milliseconds since the Unix epoch, what every timestamp in a sync group is measured in.
+pub fn server_time() -> f64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_secs_f64() * 1000.0
+}
+
+/// Where the leader is in the current item.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct Playback {
+    pub paused: bool,
+    /// Seconds into the item.
+    pub position: f64,
+    /// Server time `position` was reported at, followers extrapolate from here.
+    #[serde(default)]
+    pub at: f64,
+}
+
+/// Also sent as `sync` events on `/sse?group=`.
+#[derive(Debug, Default, Clone, Serialize, ToSchema)]
+pub struct SyncState {
+    /// Bumped on every change.
+    pub seq: u64,
+    pub leader: Option<String>,
+    pub item: Option<PathResponse>,
+    pub playback: Playback,
+}
+
+/// Clients playing the same items at the same time, one of them picks what's next.
+pub struct SyncGroup {
+    state: Mutex<SyncState>,
+    notify: z_sync::Notify16,
+    last_active: Mutex<Instant>,
+}
+
+impl SyncGroup {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(SyncState::default()),
+            notify: z_sync::Notify16::new(),
+            last_active: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn state(&self) -> SyncState {
+        self.state.lock().clone()
+    }
+
+    pub fn touch(&self) {
+        *self.last_active.lock() = Instant::now();
+    }
+
+    /// Notified on every state change.
+    pub fn observe(&self) -> z_sync::notify::NotifyListener<'_> {
+        self.notify.listener()
+    }
+
+    pub fn is_leader(&self, client: Option<&str>) -> bool {
+        client.is_some() && self.state.lock().leader.as_deref() == client
+    }
+
+    fn update<F>(&self, f: F) -> SyncState
+    where
+        F: FnOnce(&mut SyncState),
+    {
+        let state = {
+            let mut state = self.state.lock();
+            f(&mut state);
+            state.seq += 1;
+            state.clone()
+        };
+        self.touch();
+        self.notify.notify(usize::MAX);
+        state
+    }
+
+    /// Followers start `item` from the beginning, as of now.
+    pub fn publish_item(&self, item: PathResponse) {
+        self.update(|state| {
+            state.item = Some(item);
+            state.playback = Playback { paused: false, position: 0.0, at: server_time() };
+        });
+    }
+}
+
+pub struct SyncGroups {
+    groups: Mutex<FxHashMap<String, Arc<SyncGroup>>>,
+}
+
+impl SyncGroups {
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(6 * 60 * 60);
+
+    pub fn new() -> Self {
+        Self { groups: Mutex::new(FxHashMap::default()) }
+    }
+
+    /// Groups are created on first use, idle ones are dropped along the way.
+    pub fn get(&self, name: &str) -> Option<Arc<SyncGroup>> {
+        if !is_valid_id(name) {
+            return None;
+        }
+
+        let now = Instant::now();
+        let mut groups = self.groups.lock();
+        groups
+            .retain(|_, group| now.duration_since(*group.last_active.lock()) < Self::IDLE_TIMEOUT);
+        let group = groups.entry(name.to_owned()).or_insert_with(|| Arc::new(SyncGroup::new()));
+        group.touch();
+        Some(group.clone())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+struct ClockResponse {
+    server_time: f64,
+}
+
+/// One round of the clock offset handshake: offset = server_time - (sent + received) / 2,
+/// clients should keep the sample with the shortest round trip.
+#[utoipa::path(
+    get,
+    path = "/sync/clock",
+    tag = "sync",
+    responses((status = OK, body = ClockResponse)),
+)]
+pub async fn clock_handler() -> Response {
+    Json(ClockResponse { server_time: server_time() }).into_response()
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SyncQuery {
+    client: Option<String>,
+}
+
+fn group_or_404(name: &str) -> Result<Arc<SyncGroup>, Response> {
+    SYNC_GROUPS
+        .get(name)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Invalid group name").into_response())
+}
+
+/// The group `client` leads, for requests that pick items for it.
+pub fn leader_group(name: &str, client: Option<&str>) -> Result<Arc<SyncGroup>, Response> {
+    let group = group_or_404(name)?;
+    if !group.is_leader(client) {
+        return Err((StatusCode::CONFLICT, "Only the leader picks items").into_response());
+    }
+    Ok(group)
+}
+
+#[utoipa::path(
+    get,
+    path = "/sync/{group}",
+    tag = "sync",
+    params(("group" = String, Path)),
+    responses((status = OK, body = SyncState), (status = NOT_FOUND)),
+)]
+pub async fn get_state(extract::Path(name): extract::Path<String>) -> Response {
+    match group_or_404(&name) {
+        Ok(group) => Json(group.state()).into_response(),
+        Err(response) => response,
+    }
+}
+
+/// Makes `client` the one picking items, the previous leader becomes a follower.
+#[utoipa::path(
+    post,
+    path = "/sync/{group}/lead",
+    tag = "sync",
+    params(("group" = String, Path), SyncQuery),
+    responses(
+        (status = OK, body = SyncState),
+        (status = BAD_REQUEST, description = "Missing or invalid client id"),
+        (status = NOT_FOUND),
+    ),
+)]
+pub async fn lead_handler(
+    extract::Path(name): extract::Path<String>,
+    Query(SyncQuery { client }): Query<SyncQuery>,
+) -> Response {
+    let group = match group_or_404(&name) {
+        Ok(group) => group,
+        Err(response) => return response,
+    };
+    let Some(client) = client else {
+        return (StatusCode::BAD_REQUEST, "Missing client id").into_response();
+    };
+    // Sent to every follower, so held to the same rules as session ids.
+    if !is_valid_id(&client) {
+        return (StatusCode::BAD_REQUEST, "Invalid client id").into_response();
+    }
+
+    Json(group.update(|state| state.leader = Some(client))).into_response()
+}
+
+/// Play, pause and seek from the leader, stamped with the server time.
+#[utoipa::path(
+    post,
+    path = "/sync/{group}/playback",
+    tag = "sync",
+    params(("group" = String, Path), SyncQuery),
+    request_body = Playback,
+    responses(
+        (status = OK, body = SyncState),
+        (status = NOT_FOUND),
+        (status = CONFLICT, description = "Only the leader controls playback"),
+    ),
+)]
+pub async fn playback_handler(
+    extract::Path(name): extract::Path<String>,
+    Query(SyncQuery { client }): Query<SyncQuery>,
+    Json(playback): Json<Playback>,
+) -> Response {
+    let group = match group_or_404(&name) {
+        Ok(group) => group,
+        Err(response) => return response,
+    };
+    if !group.is_leader(client.as_deref()) {
+        return (StatusCode::CONFLICT, "Only the leader controls playback").into_response();
+    }
+
+    let state = group.update(|state| {
+        state.playback = Playback { at: server_time(), ..playback };
+    });
+    Json(state).into_response()
+}
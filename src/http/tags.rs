@@ -0,0 +1,154 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+use axum::extract::Path;
+use axum::response::{IntoResponse, Json, Response};
+use axum_extra::extract::Query;
+use camino::{Utf8Path, Utf8PathBuf};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use super::STORE;
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TagsQuery {
+    #[serde(default, rename = "tag")]
+    tags: Vec<String>,
+}
+
+impl TagsQuery {
+    fn normalized(self) -> Result<BTreeSet<String>, Response> {
+        self.tags
+            .into_iter()
+            .map(|tag| {
+                let tag = tag.trim();
+                if tag.is_empty() {
+                    Err((StatusCode::BAD_REQUEST, "Tags can't be empty").into_response())
+                } else {
+                    Ok(tag.to_owned())
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+struct TagsResponse {
+    path: String,
+    /// Set on the path itself.
+    tags: BTreeSet<String>,
+    /// Set on directories above it.
+    inherited: BTreeSet<String>,
+}
+
+impl TagsResponse {
+    fn new(path: Utf8PathBuf, tags: BTreeSet<String>) -> Self {
+        let inherited = STORE.get().unwrap().inherited_tags(path.as_std_path());
+        Self { path: path.into_string(), tags, inherited }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/tags",
+    tag = "library",
+    responses((status = OK, description = "Paths by tag", body = BTreeMap<String, Vec<String>>)),
+)]
+pub async fn list_tags() -> Json<BTreeMap<String, Vec<PathBuf>>> {
+    Json(STORE.get().unwrap().all_tags())
+}
+
+#[utoipa::path(
+    get,
+    path = "/tags/{path}",
+    tag = "library",
+    params(("path" = String, Path, description = "Absolute path without the leading slash")),
+    responses(
+        (status = OK, body = TagsResponse),
+        (status = NOT_FOUND, description = "Not in an enabled root"),
+    ),
+)]
+pub async fn get_tags(Path(path): Path<String>) -> Response {
+    let path = Utf8Path::new("/").join(path);
+    let tags = STORE.get().unwrap().tags(path.as_std_path());
+    Json(TagsResponse::new(path, tags)).into_response()
+}
+
+/// Replaces the tags on a path.
+#[utoipa::path(
+    put,
+    path = "/tags/{path}",
+    tag = "library",
+    params(("path" = String, Path, description = "Absolute path without the leading slash"), TagsQuery),
+    responses(
+        (status = OK, body = TagsResponse),
+        (status = BAD_REQUEST, description = "Empty tag"),
+        (status = NOT_FOUND, description = "Not in an enabled root"),
+    ),
+)]
+pub async fn put_tags(Path(path): Path<String>, Query(query): Query<TagsQuery>) -> Response {
+    let new_tags = match query.normalized() {
+        Ok(tags) => tags,
+        Err(response) => return response,
+    };
+
+    let path = Utf8Path::new("/").join(path);
+    let tags = STORE.get().unwrap().update_tags(path.as_std_path(), |tags| *tags = new_tags);
+    Json(TagsResponse::new(path, tags)).into_response()
+}
+
+#[utoipa::path(
+    post,
+    path = "/tags/{path}",
+    tag = "library",
+    params(("path" = String, Path, description = "Absolute path without the leading slash"), TagsQuery),
+    responses(
+        (status = OK, body = TagsResponse),
+        (status = BAD_REQUEST, description = "Empty tag"),
+        (status = NOT_FOUND, description = "Not in an enabled root"),
+    ),
+)]
+pub async fn add_tags(Path(path): Path<String>, Query(query): Query<TagsQuery>) -> Response {
+    let new_tags = match query.normalized() {
+        Ok(tags) => tags,
+        Err(response) => return response,
+    };
+
+    let path = Utf8Path::new("/").join(path);
+    let tags = STORE
+        .get()
+        .unwrap()
+        .update_tags(path.as_std_path(), |tags| tags.extend(new_tags));
+    Json(TagsResponse::new(path, tags)).into_response()
+}
+
+/// Removes the given tags, or all of them when none are given.
+#[utoipa::path(
+    delete,
+    path = "/tags/{path}",
+    tag = "library",
+    params(("path" = String, Path, description = "Absolute path without the leading slash"), TagsQuery),
+    responses(
+        (status = OK, body = TagsResponse),
+        (status = BAD_REQUEST, description = "Empty tag"),
+        (status = NOT_FOUND, description = "Not in an enabled root"),
+    ),
+)]
+pub async fn delete_tags(Path(path): Path<String>, Query(query): Query<TagsQuery>) -> Response {
+    let removed_tags = match query.normalized() {
+        Ok(tags) => tags,
+        Err(response) => return response,
+    };
+
+    let path = Utf8Path::new("/").join(path);
+    let tags = STORE.get().unwrap().update_tags(path.as_std_path(), |tags| {
+        if removed_tags.is_empty() {
+            tags.clear();
+        } else {
+            tags.retain(|tag| !removed_tags.contains(tag));
+        }
+    });
+    Json(TagsResponse::new(path, tags)).into_response()
+}
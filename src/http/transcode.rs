@@ -4,6 +4,7 @@ use std::path::{Path, PathBuf};
 
 use compio::BufResult;
 use nvml_wrapper::Nvml;
+use serde::Serialize;
 
 use crate::http::FileKind;
 
@@ -21,7 +22,7 @@ impl GpuMonitor {
         match Nvml::init() {
             Ok(nvml) => return Self::Nvidia(nvml),
             Err(error) => {
-                eprintln!("Failed to initialize NVML: {error}");
+                tracing::info!(%error, "Failed to initialize NVML");
             }
         }
 
@@ -37,7 +38,7 @@ impl GpuMonitor {
 
                     // If this device has AMD memory info nodes, we found our GPU.
                     if device_path.join("mem_info_vram_total").exists() {
-                        println!("Detected AMD GPU at {:?}", device_path);
+                        tracing::info!(device = %device_path.display(), "Detected AMD GPU");
                         return Self::Amd { device_path };
                     }
                 }
@@ -52,12 +53,12 @@ impl GpuMonitor {
             Self::Nvidia(nvml) => {
                 let device = nvml
                     .device_by_index(0)
-                    .inspect_err(|error| eprintln!("Failed to get device: {error}"))
+                    .inspect_err(|error| tracing::warn!(%error, "Failed to get device"))
                     .ok()?;
 
                 let memory_info = device
                     .memory_info()
-                    .inspect_err(|error| eprintln!("Failed to get memory info: {error}"))
+                    .inspect_err(|error| tracing::warn!(%error, "Failed to get memory info"))
                     .ok()?;
 
                 Some(memory_info.free / (1024 * 1024))
@@ -71,10 +72,10 @@ impl GpuMonitor {
 
                 let (total_bytes, used_bytes) = tokio::join!(total_bytes_future, used_bytes_future);
                 let total_bytes = total_bytes
-                    .inspect_err(|error| eprintln!("Failed to read total VRAM: {error}"))
+                    .inspect_err(|error| tracing::warn!(%error, "Failed to read total VRAM"))
                     .ok()?;
                 let used_bytes = used_bytes
-                    .inspect_err(|error| eprintln!("Failed to read used VRAM: {error}"))
+                    .inspect_err(|error| tracing::warn!(%error, "Failed to read used VRAM"))
                     .ok()?;
 
                 let free_bytes = total_bytes.saturating_sub(used_bytes);
@@ -85,6 +86,28 @@ impl GpuMonitor {
     }
 }
 
+/// Which GPU VRAM is checked on before transcoding.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum DetectedGpu {
+    Nvidia,
+    Amd { device_path: PathBuf },
+    Unknown,
+}
+
+pub fn detected_gpu() -> DetectedGpu {
+    match &*GPU_MONITOR {
+        GpuMonitor::Nvidia(_) => DetectedGpu::Nvidia,
+        GpuMonitor::Amd { device_path } => DetectedGpu::Amd { device_path: device_path.clone() },
+        GpuMonitor::Unknown => DetectedGpu::Unknown,
+    }
+}
+
+/// Free VRAM on the GPU ffmpeg transcodes on, `None` without one we can query.
+pub async fn available_vram_mb() -> Option<u64> {
+    GPU_MONITOR.available_vram_mb().await
+}
+
 async fn read_file_u64<P>(path: P) -> Result<u64, std::io::Error>
 where
     P: AsRef<Path>,
@@ -94,12 +117,65 @@ where
     let vram_bytes = str
         .trim()
         .parse::<u64>()
-        .inspect_err(|error| eprintln!("Failed to parse VRAM value: {error}"))
+        .inspect_err(|error| tracing::warn!(%error, "Failed to parse VRAM value"))
         .map_err(|error| std::io::Error::other(error))?;
     Ok(vram_bytes)
 }
 
-async fn estimate_vram_required_mb<P>(path: P) -> Result<Option<u64>, std::io::Error>
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct VideoProbe {
+    pub width: u64,
+    pub height: u64,
+    /// Clockwise rotation in degrees required to display the stream upright.
+    pub rotation: u16,
+    pub interlaced: bool,
+}
+
+impl VideoProbe {
+    pub fn from_stream_json(stream: &serde_json::Value) -> Option<Self> {
+        let width = stream.get("width").and_then(serde_json::Value::as_u64)?;
+        let height = stream.get("height").and_then(serde_json::Value::as_u64)?;
+
+        // The display matrix stores the counter-clockwise rotation, older muxers only set the
+        // clockwise "rotate" tag.
+        let side_data_rotation = stream
+            .get("side_data_list")
+            .and_then(serde_json::Value::as_array)
+            .and_then(|list| list.iter().find_map(|side_data| side_data.get("rotation")?.as_f64()))
+            .map(|rotation| -rotation);
+        let tag_rotation = stream
+            .get("tags")
+            .and_then(|tags| tags.get("rotate"))
+            .and_then(serde_json::Value::as_str)
+            .and_then(|rotate| rotate.parse::<f64>().ok());
+
+        let rotation = side_data_rotation.or(tag_rotation).unwrap_or(0.0);
+        // Snap to the nearest quarter turn.
+        let rotation = ((rotation / 90.0).round() as i64 * 90).rem_euclid(360) as u16;
+
+        let interlaced = matches!(
+            stream.get("field_order").and_then(serde_json::Value::as_str),
+            Some("tt" | "bb" | "tb" | "bt")
+        );
+
+        Some(Self { width, height, rotation, interlaced })
+    }
+
+    fn is_rotated(&self) -> bool {
+        self.rotation != 0
+    }
+
+    /// Width and height once rotated for display.
+    pub fn display_size(&self) -> (u64, u64) {
+        if self.rotation % 180 == 90 {
+            (self.height, self.width)
+        } else {
+            (self.width, self.height)
+        }
+    }
+}
+
+pub async fn probe_video<P>(path: P) -> Result<Option<VideoProbe>, std::io::Error>
 where
     P: AsRef<Path>,
 {
@@ -110,7 +186,7 @@ where
             "-select_streams",
             "v:0",
             "-show_entries",
-            "stream=width,height",
+            "stream=width,height,field_order:stream_side_data=rotation:stream_tags=rotate",
             "-of",
             "json",
         ])
@@ -125,36 +201,45 @@ where
     let stdout = String::from_utf8_lossy(&output.stdout);
     let json: serde_json::Value = serde_json::from_str(&stdout)?;
 
-    if let Some(stream) = json.get("streams").and_then(|v| v.get(0))
-        && let Some(width) = stream.get("width").and_then(serde_json::Value::as_u64)
-        && let Some(height) = stream.get("height").and_then(serde_json::Value::as_u64)
-    {
-        let pixels = width * height;
-
-        // Approx 12 bytes per pixel for 8-bit YUV420 buffers
-        // (1.5 bytes * ~8 frames of decode/encode/reference surfaces)
-        let surface_vram_bytes = pixels * 12;
+    let probe = json
+        .get("streams")
+        .and_then(|v| v.get(0))
+        .and_then(VideoProbe::from_stream_json);
+    Ok(probe)
+}
 
-        // Base context overhead
-        let base_overhead_bytes = match &*GPU_MONITOR {
-            GpuMonitor::Nvidia(_) => {
-                // ~40 MiB
-                40 * 1024 * 1024
-            }
-            GpuMonitor::Amd { .. } => {
-                // ~20 MiB
-                20 * 1024 * 1024
-            }
-            GpuMonitor::Unknown => 0,
-        };
+fn estimate_vram_required_mb(probe: &VideoProbe) -> u64 {
+    let pixels = probe.width * probe.height;
 
-        let total_bytes = base_overhead_bytes + surface_vram_bytes;
+    // Approx 12 bytes per pixel for 8-bit YUV420 buffers
+    // (1.5 bytes * ~8 frames of decode/encode/reference surfaces)
+    let mut surface_vram_bytes = pixels * 12;
 
-        // Add a 10% safety buffer and convert to MiB
-        Ok(Some((total_bytes as f64 * 1.10) as u64 / (1024 * 1024)))
-    } else {
-        Ok(None)
+    // Deinterlacing and transposing each need their own set of output surfaces.
+    if probe.interlaced {
+        surface_vram_bytes += pixels * 3;
+    }
+    if probe.is_rotated() {
+        surface_vram_bytes += pixels * 3;
     }
+
+    // Base context overhead
+    let base_overhead_bytes = match &*GPU_MONITOR {
+        GpuMonitor::Nvidia(_) => {
+            // ~40 MiB
+            40 * 1024 * 1024
+        }
+        GpuMonitor::Amd { .. } => {
+            // ~20 MiB
+            20 * 1024 * 1024
+        }
+        GpuMonitor::Unknown => 0,
+    };
+
+    let total_bytes = base_overhead_bytes + surface_vram_bytes;
+
+    // Add a 10% safety buffer and convert to MiB
+    (total_bytes as f64 * 1.10) as u64 / (1024 * 1024)
 }
 
 pub async fn should_transcode<P>(path: P) -> bool
@@ -165,25 +250,34 @@ where
     let Some(file_kind) = FileKind::from_path(path) else { return false };
     let is_gif = path.ends_with(".gif");
 
+    // Audio is transcoded on the CPU, so there's no VRAM to check.
+    if matches!(file_kind, FileKind::Audio) {
+        return needs_audio_transcode(path).await;
+    }
+
     if !matches!(file_kind, FileKind::Video) && !is_gif {
         return false;
     }
 
-    let required_vram = match estimate_vram_required_mb(path).await {
+    let probe = match probe_video(path).await {
         Ok(value) => value,
         Err(error) => {
-            eprintln!("Failed to estimate VRAM required: {error}");
+            tracing::warn!(path = %path.display(), %error, "Failed to estimate VRAM required");
             return false;
         }
     };
 
+    let required_vram = probe.as_ref().map(estimate_vram_required_mb);
+
     if let Some(required_vram) = required_vram
         && let Some(available_vram) = GPU_MONITOR.available_vram_mb().await
         && available_vram < required_vram
     {
-        eprintln!(
-            "Insufficient VRAM available for transcoding {}: {required_vram} MiB required, {available_vram} MiB available",
-            path.display()
+        tracing::warn!(
+            path = %path.display(),
+            required_mb = required_vram,
+            available_mb = available_vram,
+            "Insufficient VRAM available for transcoding"
         );
         return false;
     }
@@ -191,6 +285,61 @@ where
     true
 }
 
+/// Containers that every major browser can play from a plain `<audio>` element.
+const BROWSER_AUDIO_EXTENSIONS: &[&str] =
+    &["mp3", "mpga", "wav", "ogg", "oga", "opus", "m4a", "aac", "flac", "weba"];
+
+/// Codecs that every major browser can decode in the containers above.
+const BROWSER_AUDIO_CODECS: &[&str] =
+    &["mp3", "aac", "opus", "vorbis", "flac", "pcm_s16le", "pcm_s24le", "pcm_u8", "pcm_f32le"];
+
+pub async fn needs_audio_transcode(path: &Path) -> bool {
+    let is_browser_container = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase())
+        .is_some_and(|extension| BROWSER_AUDIO_EXTENSIONS.contains(&extension.as_str()));
+
+    if !is_browser_container {
+        return true;
+    }
+
+    // Some containers (e.g. ALAC in m4a) hold codecs browsers can't decode.
+    match probe_audio_codec(path).await {
+        Ok(Some(codec)) => !BROWSER_AUDIO_CODECS.contains(&codec.as_str()),
+        Ok(None) => false,
+        Err(error) => {
+            tracing::warn!(path = %path.display(), %error, "Failed to probe audio codec");
+            false
+        }
+    }
+}
+
+async fn probe_audio_codec(path: &Path) -> Result<Option<String>, std::io::Error> {
+    let output = compio::process::Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-select_streams",
+            "a:0",
+            "-show_entries",
+            "stream=codec_name",
+            "-of",
+            "default=noprint_wrappers=1:nokey=1",
+        ])
+        .arg(path)
+        .stdout(std::process::Stdio::piped())
+        .unwrap()
+        .stderr(std::process::Stdio::inherit())
+        .unwrap()
+        .output()
+        .await?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let codec = stdout.trim();
+    Ok(if codec.is_empty() { None } else { Some(codec.to_owned()) })
+}
+
 pub async fn get_video_duration<P>(path: P) -> Result<Option<f64>, std::io::Error>
 where
     P: AsRef<Path>,
@@ -220,13 +369,57 @@ pub const FRAMES_PER_SECOND: u32 = 30;
 pub const SEGMENT_SECS_U32: u32 = 2;
 pub const SEGMENT_SECS_F64: f64 = 2.0;
 
-fn vod_playlist_content(duration_sec: f64) -> String {
+pub const AUDIO_SAMPLE_RATE: u32 = 48_000;
+/// Samples per AAC frame. Audio segments can only be cut on frame boundaries.
+const AAC_FRAME_SAMPLES: u32 = 1024;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TranscodeKind {
+    Video(VideoProbe),
+    Audio,
+}
+
+/// The muxer cuts audio segments on the first AAC frame at or after each segment boundary, so
+/// they are slightly longer than [`SEGMENT_SECS_F64`].
+fn audio_segment_start_secs(segment: usize) -> f64 {
+    let frames_per_segment =
+        (SEGMENT_SECS_U32 * AUDIO_SAMPLE_RATE) as f64 / AAC_FRAME_SAMPLES as f64;
+    let frame = (segment as f64 * frames_per_segment).ceil();
+    frame * AAC_FRAME_SAMPLES as f64 / AUDIO_SAMPLE_RATE as f64
+}
+
+fn vod_playlist_header() -> String {
     let mut playlist = String::with_capacity(1024);
     playlist.push_str("#EXTM3U\n");
     playlist.push_str("#EXT-X-VERSION:7\n");
     writeln!(playlist, "#EXT-X-TARGETDURATION:{SEGMENT_SECS_U32}\n").unwrap();
     playlist.push_str("#EXT-X-PLAYLIST-TYPE:VOD\n");
     playlist.push_str("#EXT-X-MAP:URI=\"init.mp4\"\n"); // Required for fMP4 HLS
+    playlist
+}
+
+fn vod_audio_playlist_content(duration_sec: f64) -> String {
+    let mut playlist = vod_playlist_header();
+
+    let mut segment = 0;
+    let mut start = 0.0;
+    while start < duration_sec {
+        let end = audio_segment_start_secs(segment + 1).min(duration_sec);
+        let length = end - start;
+        // HLS spec requires max 6 decimal places
+        write!(&mut playlist, "#EXTINF:{length:.6},\n").unwrap();
+        write!(&mut playlist, "seg_{segment:03}.m4s\n").unwrap();
+
+        segment += 1;
+        start = end;
+    }
+
+    playlist.push_str("#EXT-X-ENDLIST\n");
+    playlist
+}
+
+fn vod_playlist_content(duration_sec: f64) -> String {
+    let mut playlist = vod_playlist_header();
 
     let full_segments = (duration_sec / SEGMENT_SECS_F64).floor() as usize;
     let remainder = duration_sec % SEGMENT_SECS_F64;
@@ -249,20 +442,80 @@ fn vod_playlist_content(duration_sec: f64) -> String {
 pub async fn create_vod_playlist<P>(
     playlist_path: P,
     duration_secs: f64,
+    kind: &TranscodeKind,
 ) -> Result<(), std::io::Error>
 where
     P: AsRef<Path>,
 {
-    let playlist_content = vod_playlist_content(duration_secs);
+    let playlist_content = match kind {
+        TranscodeKind::Video(_) => vod_playlist_content(duration_secs),
+        TranscodeKind::Audio => vod_audio_playlist_content(duration_secs),
+    };
     let BufResult(result, _) = compio::fs::write(playlist_path, playlist_content).await;
     result?;
     Ok(())
 }
 
-pub fn spawn_transcode_hls<P, OP>(
+pub fn spawn_transcode<P, OP>(
     path: P,
     output_path: OP,
     playlist_name: &str,
+    kind: &TranscodeKind,
+    start_segment: Option<u16>,
+) -> Result<std::process::Child, std::io::Error>
+where
+    P: AsRef<Path>,
+    OP: AsRef<Path>,
+{
+    match kind {
+        TranscodeKind::Video(probe) => {
+            spawn_transcode_hls(path, output_path, playlist_name, probe, start_segment)
+        }
+        TranscodeKind::Audio => {
+            spawn_transcode_hls_audio(path, output_path, playlist_name, start_segment)
+        }
+    }
+}
+
+fn video_filter_graph(probe: &VideoProbe) -> Option<String> {
+    let mut filters = Vec::with_capacity(3);
+
+    match &*GPU_MONITOR {
+        // Frames stay in VAAPI surfaces, so the filters have to as well.
+        GpuMonitor::Amd { .. } => {
+            if probe.interlaced {
+                filters.push("deinterlace_vaapi=rate=frame");
+            }
+            match probe.rotation {
+                90 => filters.push("transpose_vaapi=dir=clock"),
+                180 => filters.push("transpose_vaapi=dir=reversal"),
+                270 => filters.push("transpose_vaapi=dir=cclock"),
+                _ => (),
+            }
+            filters.push("scale_vaapi=format=nv12");
+        }
+        // CUDA decodes are downloaded to system memory, so the software filters work for both.
+        GpuMonitor::Nvidia(_) | GpuMonitor::Unknown => {
+            if probe.interlaced {
+                filters.push("bwdif=mode=send_frame:parity=auto:deint=interlaced");
+            }
+            match probe.rotation {
+                90 => filters.push("transpose=clock"),
+                180 => filters.push("hflip,vflip"),
+                270 => filters.push("transpose=cclock"),
+                _ => (),
+            }
+        }
+    }
+
+    if filters.is_empty() { None } else { Some(filters.join(",")) }
+}
+
+fn spawn_transcode_hls<P, OP>(
+    path: P,
+    output_path: OP,
+    playlist_name: &str,
+    probe: &VideoProbe,
     start_segment: Option<u16>,
 ) -> Result<std::process::Child, std::io::Error>
 where
@@ -290,6 +543,7 @@ where
         GpuMonitor::Unknown => (),
     }
 
+    // Rotation is applied explicitly in the filter graph, so it can stay on the GPU.
     command.arg("-noautorotate");
 
     if let Some(start_segment) = start_segment {
@@ -310,7 +564,7 @@ where
             command.args(["-c:v", "h264_nvenc", "-preset", "p3", "-no-scenecut", "1"]);
         }
         GpuMonitor::Amd { .. } => {
-            command.args(["-c:v", "h264_vaapi", "-vf", "scale_vaapi=format=nv12"]);
+            command.args(["-c:v", "h264_vaapi"]);
         }
         GpuMonitor::Unknown => {
             command.args([
@@ -323,6 +577,10 @@ where
         }
     }
 
+    if let Some(filter_graph) = video_filter_graph(probe) {
+        command.args(["-vf", &filter_graph]);
+    }
+
     let gop_size = (FRAMES_PER_SECOND * SEGMENT_SECS_U32).to_string();
 
     command.args([
@@ -337,11 +595,99 @@ where
         "16M",
     ]);
 
+    command.args(["-c:a", "aac", "-ac", "2"]);
+
+    spawn_hls_output(command, output_path.as_ref(), playlist_name, start_segment)
+}
+
+fn spawn_transcode_hls_audio<P, OP>(
+    path: P,
+    output_path: OP,
+    playlist_name: &str,
+    start_segment: Option<u16>,
+) -> Result<std::process::Child, std::io::Error>
+where
+    P: AsRef<Path>,
+    OP: AsRef<Path>,
+{
+    let mut command = std::process::Command::new("ffmpeg");
+
+    if let Some(start_segment) = start_segment {
+        let start_time_secs = audio_segment_start_secs(start_segment as usize);
+        command.args(["-ss", &start_time_secs.to_string()]);
+    }
+
+    command.arg("-i").arg(path.as_ref());
+
+    if start_segment.is_some() {
+        command.arg("-copyts");
+    }
+
     command.args([
+        "-map",
+        "0:a:0",
+        "-vn",
         "-c:a",
         "aac",
+        "-b:a",
+        "192k",
         "-ac",
         "2",
+        // A fixed sample rate keeps the segment boundaries in line with the VOD playlist.
+        "-ar",
+        &AUDIO_SAMPLE_RATE.to_string(),
+    ]);
+
+    spawn_hls_output(command, output_path.as_ref(), playlist_name, start_segment)
+}
+
+/// MP3 on stdout, for clients that want one continuous stream rather than HLS.
+pub fn spawn_audio_stream<P>(
+    path: P,
+    bitrate_kbps: u32,
+    start_secs: Option<u32>,
+) -> Result<std::process::Child, std::io::Error>
+where
+    P: AsRef<Path>,
+{
+    let mut command = std::process::Command::new("ffmpeg");
+
+    if let Some(start_secs) = start_secs {
+        command.args(["-ss", &start_secs.to_string()]);
+    }
+
+    command.arg("-i").arg(path.as_ref());
+    command.args([
+        "-map",
+        "0:a:0",
+        "-vn",
+        "-c:a",
+        "libmp3lame",
+        "-b:a",
+        &format!("{bitrate_kbps}k"),
+        "-ac",
+        "2",
+        "-f",
+        "mp3",
+        "pipe:1",
+    ]);
+
+    tracing::debug!(?command, "Spawning ffmpeg");
+    let child = command
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::inherit())
+        .spawn()?;
+
+    Ok(child)
+}
+
+fn spawn_hls_output(
+    mut command: std::process::Command,
+    output_path: &Path,
+    playlist_name: &str,
+    start_segment: Option<u16>,
+) -> Result<std::process::Child, std::io::Error> {
+    command.args([
         "-f",
         "hls",
         "-hls_time",
@@ -358,12 +704,12 @@ where
         command.args(["-start_number", &start_segment.to_string()]);
     }
 
-    let output_path = output_path.as_ref();
     command.arg("-hls_segment_filename").arg(output_path.join("seg_%03d.m4s"));
 
     let playlist_path = output_path.join(playlist_name);
     command.arg(&playlist_path);
 
+    tracing::debug!(?command, "Spawning ffmpeg");
     let child = command
         .stdout(std::process::Stdio::inherit())
         .stderr(std::process::Stdio::inherit())
@@ -86,6 +86,11 @@ impl INotify {
         })
     }
 
+    /// Directories being watched.
+    pub fn watch_count(&self) -> usize {
+        self.watching.read().len()
+    }
+
     pub fn add_watch(&self, path: PathBuf, flags: WatchFlags) -> Result<(), std::io::Error> {
         let wd = inotify::add_watch(self.watch_fd.as_fd(), &path, flags)?;
         self.watching.write().insert(wd, path);
@@ -2,6 +2,9 @@
 
 #[cfg(feature = "app")]
 pub mod app;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod exif;
 pub mod inotify;
 pub mod path_cache;
 #[cfg(feature = "app")]
@@ -11,7 +14,12 @@ pub mod playback_speed;
 pub mod random_files;
 #[cfg(feature = "immich")]
 pub mod random_files_immich;
+pub mod raw_preview;
+#[cfg(any(feature = "app", feature = "http", feature = "client"))]
+pub mod remote;
 pub mod storage_class;
+pub mod tags;
+pub mod tiff;
 #[cfg(feature = "app")]
 pub mod ui;
 pub mod walkdir;
@@ -0,0 +1,49 @@
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+const DEFAULT_FILTER: &str = "z_play=info";
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum LogFormat {
+    /// Human readable, for a terminal.
+    Text,
+    /// One object per line with the span fields included, for journald and log shippers.
+    Json,
+    /// `key=value` pairs on one line, for Loki and other logfmt parsers.
+    Logfmt,
+}
+
+/// Sets up the global subscriber. `RUST_LOG` filters as before, `Z_PLAY_LOG_FORMAT` picks
+/// between `text`, `json` and `logfmt`. Records from crates still using `log` are forwarded.
+pub fn init() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| DEFAULT_FILTER.into());
+    let requested = std::env::var("Z_PLAY_LOG_FORMAT").ok();
+    let format = match requested.as_deref() {
+        None | Some("text") => Some(LogFormat::Text),
+        Some("json") => Some(LogFormat::Json),
+        Some("logfmt") => Some(LogFormat::Logfmt),
+        Some(_) => None,
+    };
+
+    let registry = tracing_subscriber::registry().with(filter);
+    match format.unwrap_or(LogFormat::Text) {
+        LogFormat::Text => registry.with(tracing_subscriber::fmt::layer()).init(),
+        LogFormat::Json => registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_current_span(true)
+                    .with_span_list(true),
+            )
+            .init(),
+        LogFormat::Logfmt => registry.with(tracing_logfmt::layer()).init(),
+    }
+
+    if format.is_none() {
+        tracing::warn!(
+            format = requested.as_deref(),
+            "Unknown Z_PLAY_LOG_FORMAT, expected text, json or logfmt"
+        );
+    }
+}
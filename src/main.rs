@@ -3,6 +3,7 @@
 
 #[cfg(feature = "http")]
 mod http;
+mod logging;
 
 use std::path::PathBuf;
 
@@ -16,10 +17,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     #[cfg(feature = "dotenv")]
     dotenv::dotenv().ok();
 
-    if std::env::var_os("RUST_LOG").is_none() {
-        unsafe { std::env::set_var("RUST_LOG", "z_play=info") };
-    }
-    env_logger::init();
+    logging::init();
 
     let mut args = std::env::args_os().skip(1).peekable();
     let mut http_port = None;
@@ -49,7 +47,17 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             _temp_dir.as_ref().unwrap().path().to_owned()
         };
 
-        http::start_server(port, root_dirs, hls_dir);
+        let data_dir = std::env::var_os("Z_PLAY_DATA_DIR")
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("XDG_DATA_HOME").map(|dir| PathBuf::from(dir).join("z-play"))
+            })
+            .or_else(|| {
+                std::env::var_os("HOME").map(|dir| PathBuf::from(dir).join(".local/share/z-play"))
+            })
+            .unwrap_or_else(|| PathBuf::from("z-play-data"));
+
+        http::start_server(port, root_dirs, hls_dir, data_dir);
         return Ok(());
     }
 
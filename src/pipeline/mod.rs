@@ -14,11 +14,13 @@ use parking_lot::{MappedMutexGuard, Mutex, MutexGuard};
 use state::State;
 
 use crate::Error;
+use crate::exif::{self, Orientation};
 
 pub struct Pipeline {
     pipeline: worker::PipelineHandle,
     state: Arc<Mutex<State>>,
     path: PathBuf,
+    span: tracing::Span,
 }
 
 impl Pipeline {
@@ -28,10 +30,11 @@ impl Pipeline {
     {
         gstreamer::init().expect("Failed to initialize GStreamer");
 
+        let span = tracing::info_span!("pipeline", path = %path.display());
         let state = Arc::new(Mutex::new(State::default()));
-        let pipeline = create_pipeline(path.clone(), on_sample, state.clone())?;
+        let pipeline = create_pipeline(path.clone(), on_sample, state.clone(), span.clone())?;
         let pipeline = worker::PipelineHandle::new(pipeline);
-        Ok(Self { pipeline, state, path })
+        Ok(Self { pipeline, state, path, span })
     }
 
     pub fn event_rx(&self) -> &flume::Receiver<Event> {
@@ -42,6 +45,11 @@ impl Pipeline {
         &self.path
     }
 
+    /// Wraps everything logged about this file, enter it when handling its events.
+    pub fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
     pub fn frame(&self) -> MappedMutexGuard<'_, egui::ColorImage> {
         let lock = self.state.lock();
         MutexGuard::map(lock, |state| state.active_frame_mut())
@@ -93,6 +101,7 @@ fn create_pipeline<F>(
     path: PathBuf,
     mut on_sample: F,
     state: Arc<Mutex<State>>,
+    span: tracing::Span,
 ) -> Result<gstreamer::Pipeline, Error>
 where
     F: FnMut() + Send + 'static,
@@ -117,18 +126,19 @@ where
     let state_clone = state.clone();
 
     decode_bin.connect_pad_added(move |_decode_bin, src_pad| {
+        let _span = span.enter();
         let pad_name = src_pad.name();
-        log::info!("Decoder: New pad added: {pad_name} - {}", path.display());
+        tracing::info!(pad = %pad_name, "Decoder added a pad");
 
         let Some(pipeline) = pipeline_weak.upgrade() else { return };
 
         if pad_name.starts_with("video_") {
             if let Err(error) = pipeline.add(&video_bin) {
-                log::error!("Failed to add video bin: {error}");
+                tracing::error!(%error, "Failed to add video bin");
                 return;
             }
             if let Err(error) = video_bin.sync_state_with_parent() {
-                log::error!("Failed to sync video bin: {error}");
+                tracing::error!(%error, "Failed to sync video bin");
                 return;
             }
 
@@ -145,42 +155,43 @@ where
                 video_sink_pad = match add_image_elements(&pipeline, &video_bin) {
                     Ok(pad) => pad,
                     Err(error) => {
-                        log::error!("Failed to add image elements: {error}");
+                        tracing::error!(%error, "Failed to add image elements");
                         return;
                     }
-                }
+                };
+                apply_image_orientation(&video_bin, &path);
             }
 
             if video_sink_pad.is_linked() {
-                log::info!("Video pad already linked");
+                tracing::info!("Video pad already linked");
                 return;
             }
 
             if let Err(error) = src_pad.link(&video_sink_pad) {
-                log::error!("Failed to link video pad: {error}");
+                tracing::error!(%error, "Failed to link video pad");
             }
         } else if pad_name.starts_with("audio_") {
             let audio_sink_pad = audio_bin.static_pad("sink").unwrap();
 
             if audio_sink_pad.is_linked() {
-                log::info!("Audio pad already linked");
+                tracing::info!("Audio pad already linked");
                 return;
             }
 
             if let Err(error) = pipeline.add(&audio_bin) {
-                log::error!("Failed to add audio bin: {error}");
+                tracing::error!(%error, "Failed to add audio bin");
                 return;
             }
             if let Err(error) = audio_bin.sync_state_with_parent() {
-                log::error!("Failed to sync audio bin: {error}");
+                tracing::error!(%error, "Failed to sync audio bin");
                 return;
             }
 
             if let Err(error) = src_pad.link(&audio_sink_pad) {
-                log::error!("Failed to link audio pad: {error}");
+                tracing::error!(%error, "Failed to link audio pad");
             }
         } else {
-            log::info!("Unknown pad type: {pad_name}");
+            tracing::info!(pad = %pad_name, "Unknown pad type");
         }
     });
 
@@ -276,10 +287,53 @@ fn add_image_elements(
     Ok(convert.static_pad("sink").unwrap())
 }
 
+/// Decoders don't apply the EXIF orientation to still images, so set it on the flip element.
+fn apply_image_orientation(video_bin: &gstreamer::Bin, path: &Path) {
+    // HEIF stores its own transform, which the decoder applies.
+    let is_heif = path.extension().is_some_and(|extension| {
+        extension.eq_ignore_ascii_case("heic") || extension.eq_ignore_ascii_case("heif")
+    });
+    if is_heif {
+        return;
+    }
+
+    let exif = match exif::read_file(path) {
+        Ok(Some(exif)) => exif,
+        Ok(None) => return,
+        Err(error) => {
+            tracing::warn!(path = %path.display(), %error, "Failed to read EXIF");
+            return;
+        }
+    };
+
+    let direction = match exif.orientation {
+        Orientation::Normal => return,
+        Orientation::FlipHorizontal => "horiz",
+        Orientation::Rotate180 => "180",
+        Orientation::FlipVertical => "vert",
+        Orientation::Transpose => "ul-lr",
+        Orientation::Rotate90 => "90r",
+        Orientation::Transverse => "ur-ll",
+        Orientation::Rotate270 => "90l",
+    };
+
+    match video_bin.by_name("video_flip") {
+        Some(flip) => flip.set_property_from_str("video-direction", direction),
+        None => tracing::error!("Video bin has no flip element"),
+    }
+}
+
 fn create_video_bin() -> Result<(gstreamer::Bin, gstreamer_app::AppSink), Error> {
     let bin = gstreamer::Bin::builder().name("video_bin").build();
 
+    // Only deinterlaces buffers flagged as interlaced, progressive streams pass through.
+    let deinterlace = gstreamer::ElementFactory::make("deinterlace").build()?;
     let convert = gstreamer::ElementFactory::make("videoconvert").build()?;
+    // Follows the rotation from the stream's image-orientation tag.
+    let flip = gstreamer::ElementFactory::make("videoflip")
+        .name("video_flip")
+        .property_from_str("video-direction", "auto")
+        .build()?;
     let scale = gstreamer::ElementFactory::make("videoscale").build()?;
 
     let initial_caps = gstreamer::Caps::builder("video/x-raw")
@@ -296,10 +350,17 @@ fn create_video_bin() -> Result<(gstreamer::Bin, gstreamer_app::AppSink), Error>
         .caps(&initial_caps)
         .build();
 
-    bin.add_many([&convert, &scale, &caps, app_sink.upcast_ref()])?;
-    gstreamer::Element::link_many([&convert, &scale, &caps, app_sink.upcast_ref()])?;
-
-    let sink_pad = convert.static_pad("sink").expect("no videoconvert sink pad");
+    bin.add_many([&deinterlace, &convert, &flip, &scale, &caps, app_sink.upcast_ref()])?;
+    gstreamer::Element::link_many([
+        &deinterlace,
+        &convert,
+        &flip,
+        &scale,
+        &caps,
+        app_sink.upcast_ref(),
+    ])?;
+
+    let sink_pad = deinterlace.static_pad("sink").expect("no deinterlace sink pad");
     let ghost_pad = gstreamer::GhostPad::with_target(&sink_pad)?;
     bin.add_pad(&ghost_pad)?;
 
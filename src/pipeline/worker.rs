@@ -258,7 +258,7 @@ fn worker_thread(command_rx: flume::Receiver<Command>) {
                     let worker = map.get(&id).expect("Pipeline not found");
 
                     let Some(caps_filter) = worker.pipeline.by_name("video_caps") else {
-                        log::warn!("video_caps not found; ignoring resize to {width}x{height}");
+                        tracing::warn!(width, height, "video_caps not found, ignoring resize");
                         continue;
                     };
 
@@ -52,8 +52,8 @@ where
     while let Some(result) = futures.next().await {
         match result {
             Ok(Ok(result)) => search_result = reduce_scan_result(search_result, result, &mut rng),
-            Ok(Err(error)) => eprintln!("Error searching roots: {error:?}"),
-            Err(error) => eprintln!("Search roots task panicked: {error:?}"),
+            Ok(Err(error)) => tracing::error!(?error, "Error searching roots"),
+            Err(error) => tracing::error!(?error, "Search roots task panicked"),
         }
     }
 
@@ -118,7 +118,7 @@ where
                 compio::time::sleep(Duration::from_millis(50)).await;
             }
             Ok(Err(error)) => {
-                eprintln!("Error searching root ({root}): {error:?}");
+                tracing::error!(%root, ?error, "Error searching root");
                 if search_result.selected.is_none() {
                     compio::time::sleep(Duration::from_millis(50)).await;
                 } else {
@@ -0,0 +1,183 @@
+use std::ops::Range;
+
+use crate::tiff::{self, Tiff};
+
+const JPEG_SOI: [u8; 2] = [0xFF, 0xD8];
+pub const RAF_MAGIC: &[u8] = b"FUJIFILMCCD-RAW ";
+
+/// Finds the largest JPEG preview embedded in a camera RAW file.
+///
+/// Supports TIFF based formats (CR2, NEF, ARW, DNG, ORF, RW2, PEF, SRW), Fujifilm RAF and Canon
+/// CR3.
+pub fn find_embedded_preview(data: &[u8]) -> Option<&[u8]> {
+    let range = raf_preview(data).or_else(|| cr3_preview(data)).or_else(|| tiff_preview(data))?;
+    data.get(range)
+}
+
+fn is_jpeg(data: &[u8], range: &Range<usize>) -> bool {
+    range.len() > JPEG_SOI.len() && data.get(range.start..range.start + 2) == Some(&JPEG_SOI[..])
+}
+
+/// The preview a RAF header points at, which may lie past the end of `head`.
+pub fn raf_preview_range(head: &[u8]) -> Option<Range<usize>> {
+    if !head.starts_with(RAF_MAGIC) {
+        return None;
+    }
+    let offset = u32::from_be_bytes(head.get(84..88)?.try_into().ok()?) as usize;
+    let length = u32::from_be_bytes(head.get(88..92)?.try_into().ok()?) as usize;
+    Some(offset..offset.checked_add(length)?)
+}
+
+fn raf_preview(data: &[u8]) -> Option<Range<usize>> {
+    let range = raf_preview_range(data)?;
+    (range.end <= data.len() && is_jpeg(data, &range)).then_some(range)
+}
+
+fn cr3_preview(data: &[u8]) -> Option<Range<usize>> {
+    if data.get(4..12) != Some(&b"ftypcrx "[..]) {
+        return None;
+    }
+    // The full size preview lives in a `PRVW` box inside one of the `uuid` boxes.
+    let box_type_index = memchr::memmem::find(data, b"PRVW")?;
+    let box_start = box_type_index.checked_sub(4)?;
+    let box_size = u32::from_be_bytes(data.get(box_start..box_type_index)?.try_into().ok()?);
+    let box_end = box_start.checked_add(box_size as usize)?.min(data.len());
+
+    let search = data.get(box_type_index..box_end)?;
+    let jpeg_start = box_type_index + memchr::memmem::find(search, &[0xFF, 0xD8, 0xFF])?;
+    let range = jpeg_start..box_end;
+    is_jpeg(data, &range).then_some(range)
+}
+
+fn tiff_preview(data: &[u8]) -> Option<Range<usize>> {
+    let tiff = Tiff::parse(data)?;
+
+    let mut candidates = Vec::new();
+    for ifd in tiff.all_ifds() {
+        if let Some(offset) = ifd.get(tiff::TAG_JPEG_OFFSET)
+            && let Some(length) = ifd.get(tiff::TAG_JPEG_LENGTH)
+            && let Some(offset) = tiff.entry_u32(offset, 0)
+            && let Some(length) = tiff.entry_u32(length, 0)
+        {
+            candidates.push(offset as usize..offset as usize + length as usize);
+        }
+
+        // DNG and NEF keep previews as single strip, JPEG compressed, reduced resolution images.
+        let is_reduced_resolution = ifd
+            .get(tiff::TAG_NEW_SUBFILE_TYPE)
+            .and_then(|entry| tiff.entry_u32(entry, 0))
+            .is_some_and(|value| value & 1 == 1);
+        let is_jpeg_compressed = ifd
+            .get(tiff::TAG_COMPRESSION)
+            .and_then(|entry| tiff.entry_u32(entry, 0))
+            .is_some_and(|value| matches!(value, 6 | 7));
+
+        if is_reduced_resolution
+            && is_jpeg_compressed
+            && let Some(offsets) = ifd.get(tiff::TAG_STRIP_OFFSETS)
+            && let Some(lengths) = ifd.get(tiff::TAG_STRIP_BYTE_COUNTS)
+            && offsets.count == 1
+            && let Some(offset) = tiff.entry_u32(offsets, 0)
+            && let Some(length) = tiff.entry_u32(lengths, 0)
+        {
+            candidates.push(offset as usize..offset as usize + length as usize);
+        }
+    }
+
+    candidates
+        .into_iter()
+        .filter(|range| range.end <= data.len() && is_jpeg(data, range))
+        .max_by_key(|range| range.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiff::tests::IfdBuilder;
+
+    const JPEG: &[u8] = &[0xFF, 0xD8, 0xFF, 0xDB, 0, 4, 1, 2, 0xFF, 0xD9];
+
+    fn raf(offset: u32, length: u32) -> Vec<u8> {
+        let mut data = RAF_MAGIC.to_vec();
+        data.resize(84, 0);
+        data.extend(offset.to_be_bytes());
+        data.extend(length.to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn finds_raf_preview() {
+        let mut data = raf(100, JPEG.len() as u32);
+        data.resize(100, 0);
+        data.extend(JPEG);
+        assert_eq!(find_embedded_preview(&data), Some(JPEG));
+    }
+
+    #[test]
+    fn raf_preview_past_the_end() {
+        let data = raf(1 << 20, 4096);
+        assert_eq!(raf_preview_range(&data), Some((1 << 20)..(1 << 20) + 4096));
+        assert_eq!(find_embedded_preview(&data), None);
+
+        assert_eq!(raf_preview_range(&data[..88]), None);
+    }
+
+    #[test]
+    fn rejects_raf_preview_that_isnt_jpeg() {
+        let mut data = raf(100, 8);
+        data.resize(108, 0xAB);
+        assert_eq!(find_embedded_preview(&data), None);
+    }
+
+    #[test]
+    fn finds_cr2_preview() {
+        for big_endian in [false, true] {
+            let mut data = IfdBuilder::default()
+                .long(tiff::TAG_JPEG_OFFSET, &[0x200])
+                .long(tiff::TAG_JPEG_LENGTH, &[JPEG.len() as u32])
+                .build(big_endian);
+            data.resize(0x200, 0);
+            data.extend(JPEG);
+            assert_eq!(find_embedded_preview(&data), Some(JPEG));
+
+            // Truncated before the end of the preview.
+            data.pop();
+            assert_eq!(find_embedded_preview(&data), None);
+        }
+    }
+
+    #[test]
+    fn finds_nef_preview_in_sub_ifd() {
+        let mut large = JPEG[..2].to_vec();
+        large.resize(64, 0);
+        large.extend(&JPEG[2..]);
+
+        let preview = |offset, length| {
+            IfdBuilder::default()
+                .long(tiff::TAG_NEW_SUBFILE_TYPE, &[1])
+                .short(tiff::TAG_COMPRESSION, &[6])
+                .long(tiff::TAG_STRIP_OFFSETS, &[offset])
+                .long(tiff::TAG_STRIP_BYTE_COUNTS, &[length])
+        };
+        let mut data = IfdBuilder::default()
+            .long(tiff::TAG_JPEG_OFFSET, &[0x200])
+            .long(tiff::TAG_JPEG_LENGTH, &[JPEG.len() as u32])
+            .ifd(tiff::TAG_SUB_IFDS, preview(0x300, large.len() as u32))
+            .build(true);
+        data.resize(0x200, 0);
+        data.extend(JPEG);
+        data.resize(0x300, 0);
+        data.extend(&large);
+
+        // The largest preview wins.
+        assert_eq!(find_embedded_preview(&data), Some(&large[..]));
+    }
+
+    #[test]
+    fn ignores_garbage() {
+        assert_eq!(find_embedded_preview(b""), None);
+        assert_eq!(find_embedded_preview(&[0; 64]), None);
+        assert_eq!(find_embedded_preview(RAF_MAGIC), None);
+        assert_eq!(find_embedded_preview(b"\0\0\0\x18ftypcrx PRVW"), None);
+    }
+}
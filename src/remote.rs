@@ -0,0 +1,265 @@
+//! Messages exchanged over the `/remote` WebSocket, where players register and controllers
+//! drive them.
+//!
+//! Every message is a JSON object tagged by `type`, commands are tagged by `action`.
+
+use serde::{Deserialize, Serialize};
+
+/// Sent by players and controllers alike, a connection becomes a player by registering, a
+/// browser registers each of its players on the same connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// Registering an id that's already taken replaces the previous player.
+    Register {
+        id: String,
+        #[serde(default)]
+        name: Option<String>,
+    },
+    Leave {
+        id: String,
+    },
+    /// Sent by a player whenever its state changes.
+    State {
+        id: String,
+        state: PlayerState,
+    },
+    /// Asks for [`ServerMessage::Players`].
+    List,
+    Command {
+        target: String,
+        command: Command,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Players {
+        players: Vec<PlayerInfo>,
+    },
+    /// Delivered to the connection of the player a controller addressed.
+    Command {
+        id: String,
+        command: Command,
+    },
+    /// Broadcast whenever a player registers or reports a new state.
+    State {
+        id: String,
+        state: PlayerState,
+    },
+    Left {
+        id: String,
+    },
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "http", derive(utoipa::ToSchema))]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum Command {
+    Next,
+    Previous,
+    /// Toggles when `paused` is left out.
+    Pause {
+        #[serde(default)]
+        paused: Option<bool>,
+    },
+    /// Seconds into the current item.
+    Seek {
+        position: f64,
+    },
+    /// Kinds to play, `video`, `audio` or `image`, empty plays everything.
+    SetFilter {
+        kinds: Vec<String>,
+    },
+    /// Toggles when `enabled` is left out.
+    Fullscreen {
+        #[serde(default)]
+        enabled: Option<bool>,
+    },
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "http", derive(utoipa::ToSchema))]
+pub struct PlayerState {
+    pub path: Option<String>,
+    pub paused: bool,
+    /// Seconds.
+    pub position: Option<f64>,
+    pub duration: Option<f64>,
+    pub fullscreen: bool,
+    pub kinds: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "http", derive(utoipa::ToSchema))]
+pub struct PlayerInfo {
+    pub id: String,
+    pub name: Option<String>,
+    pub state: PlayerState,
+}
+
+#[cfg(feature = "app")]
+pub use self::client::RemoteClient;
+
+#[cfg(feature = "app")]
+mod client {
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::time::Duration;
+
+    use parking_lot::Mutex;
+    use tungstenite::client::{IntoClientRequest, uri_mode};
+    use tungstenite::error::UrlError;
+    use tungstenite::stream::{MaybeTlsStream, Mode};
+    use tungstenite::{HandshakeError, Message, WebSocket};
+
+    use super::{ClientMessage, Command, PlayerState, ServerMessage};
+
+    const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+    // How often the connection thread checks for state changes while waiting for commands.
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    /// Joins a server's `/remote` channel as a player, reconnecting in the background.
+    pub struct RemoteClient {
+        commands: Receiver<Command>,
+        state: Arc<Mutex<PlayerState>>,
+    }
+
+    impl RemoteClient {
+        /// `url` is the WebSocket URL, e.g. `ws://tv.local:8080/remote`, `on_command` is called
+        /// whenever a command is waiting in [`RemoteClient::try_recv`].
+        pub fn connect<F>(url: String, id: String, name: Option<String>, on_command: F) -> Self
+        where
+            F: Fn() + Send + 'static,
+        {
+            let (commands_tx, commands) = mpsc::channel();
+            let state = Arc::new(Mutex::new(PlayerState::default()));
+
+            let state_clone = state.clone();
+            std::thread::spawn(move || {
+                // Also checked between retries, an unreachable server mustn't outlive the app.
+                while !is_app_gone(&state_clone) {
+                    let result =
+                        run(&url, &id, name.as_deref(), &commands_tx, &on_command, &state_clone);
+                    match result {
+                        Ok(()) => break,
+                        Err(error) => tracing::error!(url, %error, "Remote connection failed"),
+                    }
+                    std::thread::sleep(RECONNECT_DELAY);
+                }
+                tracing::info!(url, "App dropped, leaving");
+            });
+
+            Self { commands, state }
+        }
+
+        pub fn try_recv(&self) -> Option<Command> {
+            self.commands.try_recv().ok()
+        }
+
+        /// Reported to controllers the next time the connection thread wakes up.
+        pub fn set_state(&self, state: PlayerState) {
+            *self.state.lock() = state;
+        }
+    }
+
+    /// The [`RemoteClient`] holds the only other reference to its state.
+    fn is_app_gone(state: &Arc<Mutex<PlayerState>>) -> bool {
+        Arc::strong_count(state) == 1
+    }
+
+    /// Returns `Ok` once the app is gone and there's nobody left to send commands to.
+    fn run(
+        url: &str,
+        id: &str,
+        name: Option<&str>,
+        commands: &Sender<Command>,
+        on_command: &dyn Fn(),
+        state: &Arc<Mutex<PlayerState>>,
+    ) -> Result<(), tungstenite::Error> {
+        let mut socket = connect(url)?;
+        tracing::info!(url, id, "Joined");
+
+        let register = ClientMessage::Register { id: id.to_owned(), name: name.map(Into::into) };
+        send(&mut socket, &register)?;
+
+        let mut sent_state = None;
+        loop {
+            if is_app_gone(state) {
+                return Ok(());
+            }
+
+            let current = state.lock().clone();
+            if sent_state.as_ref() != Some(&current) {
+                let message = ClientMessage::State { id: id.to_owned(), state: current.clone() };
+                send(&mut socket, &message)?;
+                sent_state = Some(current);
+            }
+
+            let message = match socket.read() {
+                Ok(message) => message,
+                Err(tungstenite::Error::Io(error))
+                    if matches!(
+                        error.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue;
+                }
+                Err(error) => return Err(error),
+            };
+
+            let Message::Text(text) = message else { continue };
+            match serde_json::from_str::<ServerMessage>(&text) {
+                Ok(ServerMessage::Command { id: _, command }) => {
+                    if commands.send(command).is_err() {
+                        return Ok(());
+                    }
+                    on_command();
+                }
+                Ok(ServerMessage::Error { message }) => tracing::warn!(message, "Remote error"),
+                Ok(_) => (),
+                Err(error) => tracing::warn!(%error, "Invalid remote message"),
+            }
+        }
+    }
+
+    /// Like [`tungstenite::connect`], but reads time out after [`POLL_INTERVAL`] so state
+    /// changes get sent while no commands come in, whether or not the socket is wrapped in TLS.
+    fn connect(url: &str) -> Result<WebSocket<MaybeTlsStream<TcpStream>>, tungstenite::Error> {
+        let request = url.into_client_request()?;
+        let uri = request.uri();
+        let mode = uri_mode(uri)?;
+        let host = uri.host().ok_or(tungstenite::Error::Url(UrlError::NoHostName))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let port = uri.port_u16().unwrap_or(match mode {
+            Mode::Plain => 80,
+            Mode::Tls => 443,
+        });
+
+        let stream = TcpStream::connect((host, port))?;
+        // Shares the socket, the timeout applies underneath TLS too.
+        let timeout_stream = stream.try_clone()?;
+        let (socket, _) =
+            tungstenite::client_tls(request, stream).map_err(|error| match error {
+                HandshakeError::Failure(error) => error,
+                // Only non-blocking streams get interrupted.
+                HandshakeError::Interrupted(_) => unreachable!(),
+            })?;
+        timeout_stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        Ok(socket)
+    }
+
+    fn send(
+        socket: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+        message: &ClientMessage,
+    ) -> Result<(), tungstenite::Error> {
+        let json = serde_json::to_string(message).unwrap();
+        socket.send(Message::text(json))
+    }
+}
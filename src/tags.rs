@@ -0,0 +1,929 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use base64::Engine;
+
+// Nothing legitimate comes close, guards against reading whole files for corrupt sizes.
+const MAX_TAG_SIZE: u64 = 64 * 1024 * 1024;
+
+/// ID3v2 picture type and FLAC picture type of the front cover.
+const PICTURE_FRONT_COVER: u32 = 3;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Picture {
+    pub mime_type: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track: Option<u32>,
+    pub track_total: Option<u32>,
+    /// Set when the file has embedded cover art, even if `cover` was dropped.
+    pub has_cover: bool,
+    pub cover: Option<Picture>,
+}
+
+impl Tags {
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none()
+            && self.artist.is_none()
+            && self.album.is_none()
+            && self.album_artist.is_none()
+            && self.track.is_none()
+            && !self.has_cover
+    }
+
+    fn set_text(&mut self, field: Field, value: &str) {
+        let value = value.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+        if value.is_empty() {
+            return;
+        }
+
+        let slot = match field {
+            Field::Title => &mut self.title,
+            Field::Artist => &mut self.artist,
+            Field::Album => &mut self.album,
+            Field::AlbumArtist => &mut self.album_artist,
+            Field::Track => {
+                // "3" or "3/12".
+                let (track, total) = value.split_once('/').unwrap_or((value, ""));
+                self.track = self.track.or_else(|| track.trim().parse().ok());
+                self.track_total = self.track_total.or_else(|| total.trim().parse().ok());
+                return;
+            }
+            Field::TrackTotal => {
+                self.track_total = self.track_total.or_else(|| value.parse().ok());
+                return;
+            }
+        };
+        // The first value wins, later ones are usually additional artists.
+        if slot.is_none() {
+            *slot = Some(value.to_owned());
+        }
+    }
+
+    fn set_picture(&mut self, picture_type: u32, mime_type: String, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        self.has_cover = true;
+        // Prefer the front cover, otherwise keep whatever came first.
+        if self.cover.is_none() || picture_type == PICTURE_FRONT_COVER {
+            let mime_type = if mime_type.is_empty() || !mime_type.contains('/') {
+                sniff_image_mime(data).to_owned()
+            } else {
+                mime_type
+            };
+            self.cover = Some(Picture { mime_type, data: data.to_vec() });
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Field {
+    Title,
+    Artist,
+    Album,
+    AlbumArtist,
+    Track,
+    TrackTotal,
+}
+
+/// Whether `path` is an audio file in a container [`read_file`] understands.
+///
+/// MP4 and Ogg video share the tag formats, but reading them can mean tens of megabytes before
+/// the tags turn up.
+pub fn is_audio(path: &Path) -> bool {
+    const EXTENSIONS: &[&str] = &["mp3", "flac", "ogg", "oga", "opus", "m4a", "aac", "wav"];
+
+    let Some(extension) = path.extension().and_then(|extension| extension.to_str()) else {
+        return false;
+    };
+    EXTENSIONS.iter().any(|candidate| extension.eq_ignore_ascii_case(candidate))
+}
+
+/// Reads ID3v2 (and ID3v1), FLAC, Ogg Vorbis/Opus and MP4 tags.
+pub fn read_file(path: &Path) -> std::io::Result<Option<Tags>> {
+    let mut file = std::fs::File::open(path)?;
+
+    let mut magic = [0; 12];
+    let len = read_up_to(&mut file, &mut magic)?;
+    let magic = &magic[..len];
+    file.seek(SeekFrom::Start(0))?;
+
+    let mut tags = Tags::default();
+    if magic.starts_with(b"ID3") {
+        read_id3v2(&mut file, &mut tags)?;
+        // FLAC files sometimes have an ID3 tag in front.
+        let mut flac_magic = [0; 4];
+        if read_up_to(&mut file, &mut flac_magic)? == 4 && &flac_magic == b"fLaC" {
+            read_flac(&mut file, &mut tags)?;
+        }
+    } else if magic.starts_with(b"fLaC") {
+        file.seek(SeekFrom::Start(4))?;
+        read_flac(&mut file, &mut tags)?;
+    } else if magic.starts_with(b"OggS") {
+        read_ogg(&mut file, &mut tags)?;
+    } else if magic.get(4..8) == Some(&b"ftyp"[..]) {
+        read_mp4(&mut file, &mut tags)?;
+    }
+
+    if tags.is_empty() {
+        read_id3v1(&mut file, &mut tags)?;
+    }
+
+    Ok(if tags.is_empty() { None } else { Some(tags) })
+}
+
+fn read_up_to<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+fn read_exact_vec<R: Read>(reader: &mut R, len: u64) -> std::io::Result<Vec<u8>> {
+    if len > MAX_TAG_SIZE {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "tag too large"));
+    }
+    let mut data = vec![0; len as usize];
+    reader.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn sniff_image_mime(data: &[u8]) -> &'static str {
+    if data.starts_with(&[0x89, b'P', b'N', b'G']) {
+        "image/png"
+    } else if data.starts_with(b"GIF8") {
+        "image/gif"
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(&b"WEBP"[..]) {
+        "image/webp"
+    } else {
+        "image/jpeg"
+    }
+}
+
+// ID3v2
+
+fn syncsafe(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |value, &byte| (value << 7) | (byte & 0x7F) as u32)
+}
+
+/// Reverses ID3 unsynchronisation, which inserts a zero after every 0xFF.
+fn remove_unsync(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    let mut previous_ff = false;
+    for &byte in data {
+        if !(previous_ff && byte == 0) {
+            output.push(byte);
+        }
+        previous_ff = byte == 0xFF;
+    }
+    output
+}
+
+fn read_id3v2<R: Read>(reader: &mut R, tags: &mut Tags) -> std::io::Result<()> {
+    let mut header = [0; 10];
+    reader.read_exact(&mut header)?;
+    let version = header[3];
+    let flags = header[5];
+    let size = syncsafe(&header[6..10]) as u64;
+
+    let mut data = read_exact_vec(reader, size)?;
+    // Footer, present in some v2.4 tags.
+    if version == 4 && flags & 0x10 != 0 {
+        let mut footer = [0; 10];
+        reader.read_exact(&mut footer)?;
+    }
+
+    if !(2..=4).contains(&version) {
+        return Ok(());
+    }
+    if version < 4 && flags & 0x80 != 0 {
+        data = remove_unsync(&data);
+    }
+
+    let mut offset = 0;
+    if version >= 3 && flags & 0x40 != 0 {
+        // Skip the extended header. v2.3 doesn't count the size field itself.
+        let Some(size) = data.get(0..4) else { return Ok(()) };
+        offset = if version == 4 {
+            syncsafe(size) as usize
+        } else {
+            u32::from_be_bytes(size.try_into().unwrap()) as usize + 4
+        };
+    }
+
+    let (id_len, header_len) = if version == 2 { (3, 6) } else { (4, 10) };
+
+    while offset + header_len <= data.len() {
+        let frame_header = &data[offset..offset + header_len];
+        // Padding.
+        if frame_header[0] == 0 {
+            break;
+        }
+
+        let id = &frame_header[..id_len];
+        let size = match version {
+            2 => u32::from_be_bytes([0, frame_header[3], frame_header[4], frame_header[5]]),
+            3 => u32::from_be_bytes(frame_header[4..8].try_into().unwrap()),
+            _ => syncsafe(&frame_header[4..8]),
+        } as usize;
+        let frame_flags = if version == 2 { 0 } else { frame_header[9] };
+
+        let start = offset + header_len;
+        let Some(frame) = data.get(start..start + size) else { break };
+        offset = start + size;
+
+        // Compressed and encrypted frames aren't worth supporting.
+        let unsupported = match version {
+            3 => frame_flags & 0xC0 != 0,
+            4 => frame_flags & 0x0C != 0,
+            _ => false,
+        };
+        if unsupported {
+            continue;
+        }
+
+        let mut frame = std::borrow::Cow::Borrowed(frame);
+        if version == 4 {
+            // Data length indicator.
+            if frame_flags & 0x01 != 0 {
+                frame = std::borrow::Cow::Owned(frame.get(4..).unwrap_or_default().to_vec());
+            }
+            if frame_flags & 0x02 != 0 {
+                frame = std::borrow::Cow::Owned(remove_unsync(&frame));
+            }
+        }
+
+        match id {
+            b"TIT2" | b"TT2" => tags.set_text(Field::Title, &id3_text(&frame)),
+            b"TPE1" | b"TP1" => tags.set_text(Field::Artist, &id3_text(&frame)),
+            b"TALB" | b"TAL" => tags.set_text(Field::Album, &id3_text(&frame)),
+            b"TPE2" | b"TP2" => tags.set_text(Field::AlbumArtist, &id3_text(&frame)),
+            b"TRCK" | b"TRK" => tags.set_text(Field::Track, &id3_text(&frame)),
+            b"APIC" => read_apic(&frame, tags),
+            b"PIC" => read_pic(&frame, tags),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn decode_id3_string(encoding: u8, data: &[u8]) -> String {
+    match encoding {
+        // ISO-8859-1 maps directly to the first 256 code points.
+        0 => data.iter().map(|&b| b as char).collect(),
+        1 | 2 => {
+            let (big_endian, data) = match data {
+                [0xFE, 0xFF, rest @ ..] => (true, rest),
+                [0xFF, 0xFE, rest @ ..] => (false, rest),
+                _ => (encoding == 2, data),
+            };
+            let units = data.chunks_exact(2).map(|pair| {
+                if big_endian {
+                    u16::from_be_bytes([pair[0], pair[1]])
+                } else {
+                    u16::from_le_bytes([pair[0], pair[1]])
+                }
+            });
+            char::decode_utf16(units)
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect()
+        }
+        _ => String::from_utf8_lossy(data).into_owned(),
+    }
+}
+
+/// Splits off a terminated string, returning it and the remaining data.
+fn split_id3_string(encoding: u8, data: &[u8]) -> (&[u8], &[u8]) {
+    if matches!(encoding, 1 | 2) {
+        let end = data.chunks_exact(2).position(|pair| pair == [0, 0]).map(|index| index * 2);
+        match end {
+            Some(end) => (&data[..end], &data[end + 2..]),
+            None => (data, &[]),
+        }
+    } else {
+        match memchr::memchr(0, data) {
+            Some(end) => (&data[..end], &data[end + 1..]),
+            None => (data, &[]),
+        }
+    }
+}
+
+fn id3_text(frame: &[u8]) -> String {
+    let Some((&encoding, text)) = frame.split_first() else { return String::new() };
+    // Multiple values are separated by nulls, keep the first.
+    let (text, _) = split_id3_string(encoding, text);
+    decode_id3_string(encoding, text)
+}
+
+fn read_apic(frame: &[u8], tags: &mut Tags) {
+    let Some((&encoding, rest)) = frame.split_first() else { return };
+    let (mime_type, rest) = split_id3_string(0, rest);
+    let Some((&picture_type, rest)) = rest.split_first() else { return };
+    let (_description, data) = split_id3_string(encoding, rest);
+    tags.set_picture(picture_type as u32, decode_id3_string(0, mime_type), data);
+}
+
+fn read_pic(frame: &[u8], tags: &mut Tags) {
+    // Encoding, 3 character image format, picture type.
+    let Some(&encoding) = frame.first() else { return };
+    let Some(format) = frame.get(1..4) else { return };
+    let Some(&picture_type) = frame.get(4) else { return };
+    let (_description, data) = split_id3_string(encoding, frame.get(5..).unwrap_or_default());
+    let mime_type = match format {
+        b"PNG" => "image/png",
+        _ => "image/jpeg",
+    };
+    tags.set_picture(picture_type as u32, mime_type.to_owned(), data);
+}
+
+fn read_id3v1<R: Read + Seek>(reader: &mut R, tags: &mut Tags) -> std::io::Result<()> {
+    let Ok(_) = reader.seek(SeekFrom::End(-128)) else { return Ok(()) };
+    let mut tag = [0; 128];
+    if read_up_to(reader, &mut tag)? != 128 || !tag.starts_with(b"TAG") {
+        return Ok(());
+    }
+
+    let text = |range: std::ops::Range<usize>| decode_id3_string(0, &tag[range]);
+    tags.set_text(Field::Title, &text(3..33));
+    tags.set_text(Field::Artist, &text(33..63));
+    tags.set_text(Field::Album, &text(63..93));
+    // ID3v1.1 stores the track in the last byte of the comment.
+    if tag[125] == 0 && tag[126] != 0 {
+        tags.track = Some(tag[126] as u32);
+    }
+
+    Ok(())
+}
+
+// FLAC and Vorbis comments
+
+const FLAC_VORBIS_COMMENT: u8 = 4;
+const FLAC_PICTURE: u8 = 6;
+
+/// Reads metadata blocks, the reader must be positioned just after `fLaC`.
+fn read_flac<R: Read + Seek>(reader: &mut R, tags: &mut Tags) -> std::io::Result<()> {
+    loop {
+        let mut header = [0; 4];
+        reader.read_exact(&mut header)?;
+        let is_last = header[0] & 0x80 != 0;
+        let block_type = header[0] & 0x7F;
+        let size = u32::from_be_bytes([0, header[1], header[2], header[3]]) as u64;
+
+        match block_type {
+            FLAC_VORBIS_COMMENT => read_vorbis_comments(&read_exact_vec(reader, size)?, tags),
+            FLAC_PICTURE => read_flac_picture(&read_exact_vec(reader, size)?, tags),
+            _ => {
+                reader.seek(SeekFrom::Current(size as i64))?;
+            }
+        }
+
+        if is_last {
+            return Ok(());
+        }
+    }
+}
+
+fn read_vorbis_comments(data: &[u8], tags: &mut Tags) {
+    let mut reader = LeReader { data, offset: 0 };
+    let Some(vendor_len) = reader.u32() else { return };
+    if reader.bytes(vendor_len as usize).is_none() {
+        return;
+    }
+    let Some(count) = reader.u32() else { return };
+
+    for _ in 0..count {
+        let Some(len) = reader.u32() else { return };
+        let Some(comment) = reader.bytes(len as usize) else { return };
+        let comment = String::from_utf8_lossy(comment);
+        let Some((key, value)) = comment.split_once('=') else { continue };
+
+        let field = match key.to_ascii_uppercase().as_str() {
+            "TITLE" => Field::Title,
+            "ARTIST" => Field::Artist,
+            "ALBUM" => Field::Album,
+            "ALBUMARTIST" | "ALBUM ARTIST" => Field::AlbumArtist,
+            "TRACKNUMBER" => Field::Track,
+            "TRACKTOTAL" | "TOTALTRACKS" => Field::TrackTotal,
+            "METADATA_BLOCK_PICTURE" => {
+                let engine = base64::engine::general_purpose::STANDARD;
+                if let Ok(picture) = engine.decode(value.trim()) {
+                    read_flac_picture(&picture, tags);
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        tags.set_text(field, value);
+    }
+}
+
+fn read_flac_picture(data: &[u8], tags: &mut Tags) {
+    if let Some((picture_type, mime_type, data)) = parse_flac_picture(data) {
+        tags.set_picture(picture_type, mime_type, data);
+    }
+}
+
+fn parse_flac_picture(data: &[u8]) -> Option<(u32, String, &[u8])> {
+    let mut reader = BeReader { data, offset: 0 };
+    let picture_type = reader.u32()?;
+    let mime_len = reader.u32()? as usize;
+    let mime_type = String::from_utf8_lossy(reader.bytes(mime_len)?).into_owned();
+    let description_len = reader.u32()? as usize;
+    reader.bytes(description_len)?;
+    // Width, height, colour depth and palette size.
+    reader.bytes(16)?;
+    let data_len = reader.u32()? as usize;
+    Some((picture_type, mime_type, reader.bytes(data_len)?))
+}
+
+struct LeReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> LeReader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.offset..self.offset.checked_add(len)?)?;
+        self.offset += len;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+}
+
+struct BeReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> BeReader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.offset..self.offset.checked_add(len)?)?;
+        self.offset += len;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+}
+
+// Ogg
+
+/// Reads the comment header, the second packet of the first logical stream.
+fn read_ogg<R: Read>(reader: &mut R, tags: &mut Tags) -> std::io::Result<()> {
+    let mut packet = Vec::new();
+    let mut packet_index = 0;
+    let mut serial = None;
+
+    loop {
+        let mut header = [0; 27];
+        if read_up_to(reader, &mut header)? != 27 || !header.starts_with(b"OggS") {
+            return Ok(());
+        }
+        let page_serial = u32::from_le_bytes(header[14..18].try_into().unwrap());
+        let segment_count = header[26] as usize;
+        let mut lacing = vec![0; segment_count];
+        reader.read_exact(&mut lacing)?;
+        let page_size: u64 = lacing.iter().map(|&len| len as u64).sum();
+        let page = read_exact_vec(reader, page_size)?;
+
+        // Skip pages of other multiplexed streams.
+        if *serial.get_or_insert(page_serial) != page_serial {
+            continue;
+        }
+
+        let mut offset = 0;
+        for &len in &lacing {
+            let len = len as usize;
+            if packet_index == 1 {
+                packet.extend_from_slice(&page[offset..offset + len]);
+                if packet.len() as u64 > MAX_TAG_SIZE {
+                    return Ok(());
+                }
+            }
+            offset += len;
+
+            // A lacing value under 255 ends the packet.
+            if len < 255 {
+                if packet_index == 1 {
+                    if let Some(comments) = packet
+                        .strip_prefix(b"\x03vorbis")
+                        .or_else(|| packet.strip_prefix(b"OpusTags"))
+                    {
+                        read_vorbis_comments(comments, tags);
+                    }
+                    return Ok(());
+                }
+                packet_index += 1;
+            }
+        }
+    }
+}
+
+// MP4
+
+fn read_box_header<R: Read + Seek>(reader: &mut R) -> std::io::Result<Option<([u8; 4], u64)>> {
+    let mut header = [0; 8];
+    if read_up_to(reader, &mut header)? != 8 {
+        return Ok(None);
+    }
+    let size = u32::from_be_bytes(header[0..4].try_into().unwrap()) as u64;
+    let box_type: [u8; 4] = header[4..8].try_into().unwrap();
+
+    let content_size = match size {
+        // Extends to the end of the file.
+        0 => {
+            let position = reader.stream_position()?;
+            let end = reader.seek(SeekFrom::End(0))?;
+            reader.seek(SeekFrom::Start(position))?;
+            end - position
+        }
+        1 => {
+            let mut large_size = [0; 8];
+            reader.read_exact(&mut large_size)?;
+            u64::from_be_bytes(large_size).saturating_sub(16)
+        }
+        size => size.saturating_sub(8),
+    };
+
+    Ok(Some((box_type, content_size)))
+}
+
+fn read_mp4<R: Read + Seek>(reader: &mut R, tags: &mut Tags) -> std::io::Result<()> {
+    // `moov` can be at either end, skip over `mdat` without reading it.
+    while let Some((box_type, size)) = read_box_header(reader)? {
+        if &box_type == b"moov" {
+            let moov = read_exact_vec(reader, size)?;
+            if let Some(ilst) = find_box_path(&moov, &[b"udta", b"meta", b"ilst"]) {
+                read_ilst(ilst, tags);
+            }
+            return Ok(());
+        }
+        reader.seek(SeekFrom::Current(size as i64))?;
+    }
+    Ok(())
+}
+
+/// Iterates over the boxes in `data` as `(type, content)`.
+fn boxes(data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let size = u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?) as usize;
+        let box_type = data.get(offset + 4..offset + 8)?;
+        let end = if size == 0 { data.len() } else { offset.checked_add(size)? };
+        let content = data.get(offset + 8..end)?;
+        offset = end.max(offset + 8);
+        Some((box_type, content))
+    })
+}
+
+fn find_box_path<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    let (first, rest) = path.split_first()?;
+    let (_, mut content) = boxes(data).find(|(box_type, _)| box_type == first)?;
+    // `meta` is a full box with version and flags before its children.
+    if *first == b"meta" {
+        content = content.get(4..)?;
+    }
+    if rest.is_empty() { Some(content) } else { find_box_path(content, rest) }
+}
+
+fn read_ilst(ilst: &[u8], tags: &mut Tags) {
+    for (item_type, item) in boxes(ilst) {
+        let Some((_, data)) = boxes(item).find(|(box_type, _)| *box_type == b"data") else {
+            continue;
+        };
+        // Type indicator and locale.
+        let Some(type_indicator) = data.get(0..4) else { continue };
+        let type_indicator = u32::from_be_bytes(type_indicator.try_into().unwrap()) & 0xFFFFFF;
+        let Some(value) = data.get(8..) else { continue };
+
+        let field = match item_type {
+            b"\xA9nam" => Field::Title,
+            b"\xA9ART" => Field::Artist,
+            b"\xA9alb" => Field::Album,
+            b"aART" => Field::AlbumArtist,
+            b"trkn" => {
+                // Reserved, track number, total.
+                if let Some(track) = value.get(2..4) {
+                    let track = u16::from_be_bytes(track.try_into().unwrap());
+                    tags.track = (track > 0).then_some(track as u32);
+                }
+                if let Some(total) = value.get(4..6) {
+                    let total = u16::from_be_bytes(total.try_into().unwrap());
+                    tags.track_total = (total > 0).then_some(total as u32);
+                }
+                continue;
+            }
+            b"covr" => {
+                let mime_type = match type_indicator {
+                    14 => "image/png",
+                    27 => "image/bmp",
+                    _ => sniff_image_mime(value),
+                };
+                tags.set_picture(PICTURE_FRONT_COVER, mime_type.to_owned(), value);
+                continue;
+            }
+            _ => continue,
+        };
+        tags.set_text(field, &String::from_utf8_lossy(value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn id3v2(version: u8, flags: u8, frames: &[u8]) -> Vec<u8> {
+        let size = frames.len() as u32;
+        let mut data = vec![b'I', b'D', b'3', version, 0, flags];
+        data.extend((0..4).rev().map(|shift| ((size >> (shift * 7)) & 0x7F) as u8));
+        data.extend(frames);
+        data
+    }
+
+    fn frame(version: u8, id: &[u8; 4], content: &[u8]) -> Vec<u8> {
+        let size = content.len() as u32;
+        let mut frame = id.to_vec();
+        if version == 4 {
+            frame.extend((0..4).rev().map(|shift| ((size >> (shift * 7)) & 0x7F) as u8));
+        } else {
+            frame.extend(size.to_be_bytes());
+        }
+        frame.extend([0, 0]);
+        frame.extend(content);
+        frame
+    }
+
+    fn text_frame(version: u8, id: &[u8; 4], encoding: u8, text: &[u8]) -> Vec<u8> {
+        let mut content = vec![encoding];
+        content.extend(text);
+        frame(version, id, &content)
+    }
+
+    fn read_id3(data: &[u8]) -> std::io::Result<Tags> {
+        let mut tags = Tags::default();
+        read_id3v2(&mut Cursor::new(data), &mut tags)?;
+        Ok(tags)
+    }
+
+    #[test]
+    fn decodes_syncsafe_sizes() {
+        assert_eq!(syncsafe(&[0, 0, 0, 0x7F]), 0x7F);
+        assert_eq!(syncsafe(&[0, 0, 0x02, 0x01]), 0x101);
+        assert_eq!(syncsafe(&[0x7F, 0x7F, 0x7F, 0x7F]), 0x0FFF_FFFF);
+        // The high bit of each byte is always clear, ignore it if it isn't.
+        assert_eq!(syncsafe(&[0x80, 0x80, 0x81, 0x80]), 0x80);
+    }
+
+    #[test]
+    fn reads_id3v23_frames() {
+        // Over 127 bytes, so the plain big-endian size differs from a syncsafe reading.
+        let album = "A".repeat(200);
+        let mut frames = text_frame(3, b"TIT2", 0, b"Caf\xE9");
+        // UTF-16 with a byte order mark.
+        frames.extend(text_frame(3, b"TPE1", 1, b"\xFF\xFEA\0r\0t\0\0\0Other\0"));
+        frames.extend(text_frame(3, b"TALB", 0, album.as_bytes()));
+        frames.extend(text_frame(3, b"TRCK", 0, b"3/12"));
+        frames.extend([0; 16]);
+
+        let tags = read_id3(&id3v2(3, 0, &frames)).unwrap();
+        assert_eq!(tags.title.as_deref(), Some("Café"));
+        assert_eq!(tags.artist.as_deref(), Some("Art"));
+        assert_eq!(tags.album, Some(album));
+        assert_eq!((tags.track, tags.track_total), (Some(3), Some(12)));
+    }
+
+    #[test]
+    fn reads_id3v24_frames() {
+        let title = "Tïtle ".repeat(40);
+        let mut frames = text_frame(4, b"TIT2", 3, title.as_bytes());
+        // UTF-16BE without a byte order mark.
+        frames.extend(text_frame(4, b"TPE2", 2, b"\0B\0a\0n\0d"));
+
+        let mut apic = b"\0image/png\0\x03cover\0".to_vec();
+        apic.extend(b"\x89PNG");
+        frames.extend(frame(4, b"APIC", &apic));
+
+        let tags = read_id3(&id3v2(4, 0, &frames)).unwrap();
+        assert_eq!(tags.title.as_deref(), Some(title.trim()));
+        assert_eq!(tags.album_artist.as_deref(), Some("Band"));
+        assert!(tags.has_cover);
+        let cover = tags.cover.unwrap();
+        assert_eq!(cover.mime_type, "image/png");
+        assert_eq!(cover.data, b"\x89PNG");
+    }
+
+    #[test]
+    fn reverses_id3v23_unsynchronisation() {
+        let mut apic = b"\0\0\x03\0".to_vec();
+        apic.extend([0xFF, 0xD8, 0xFF, 0xE0]);
+        let frames = frame(3, b"APIC", &apic);
+        let unsynced = frames
+            .iter()
+            .flat_map(|&byte| if byte == 0xFF { vec![0xFF, 0] } else { vec![byte] })
+            .collect::<Vec<_>>();
+
+        let cover = read_id3(&id3v2(3, 0x80, &unsynced)).unwrap().cover.unwrap();
+        assert_eq!(cover.mime_type, "image/jpeg");
+        assert_eq!(cover.data, [0xFF, 0xD8, 0xFF, 0xE0]);
+    }
+
+    #[test]
+    fn handles_truncated_id3v2() {
+        let frames = text_frame(3, b"TIT2", 0, b"Title");
+        let data = id3v2(3, 0, &frames);
+        assert!(read_id3(&data[..data.len() - 1]).is_err());
+        assert!(read_id3(&data[..6]).is_err());
+
+        // A frame claiming more than the tag holds ends parsing, earlier frames are kept.
+        let mut frames = text_frame(3, b"TIT2", 0, b"Title");
+        let mut oversized = text_frame(3, b"TPE1", 0, b"Artist");
+        oversized[4..8].copy_from_slice(&1000u32.to_be_bytes());
+        frames.extend(oversized);
+        let tags = read_id3(&id3v2(3, 0, &frames)).unwrap();
+        assert_eq!(tags.title.as_deref(), Some("Title"));
+        assert_eq!(tags.artist, None);
+
+        // A corrupt tag size is refused rather than allocated.
+        let mut data = id3v2(3, 0, &[]);
+        data[6..10].copy_from_slice(&[0x7F; 4]);
+        assert!(read_id3(&data).is_err());
+    }
+
+    fn vorbis_comments(comments: &[&str]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(6u32.to_le_bytes());
+        data.extend(b"vendor");
+        data.extend((comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            data.extend((comment.len() as u32).to_le_bytes());
+            data.extend(comment.as_bytes());
+        }
+        data
+    }
+
+    /// An Ogg page holding `segments`, each `(data, ends_packet)`.
+    fn ogg_page(serial: u32, segments: &[(&[u8], bool)]) -> Vec<u8> {
+        let mut lacing = Vec::new();
+        let mut body = Vec::<u8>::new();
+        for &(data, ends_packet) in segments {
+            let mut remaining = data.len();
+            loop {
+                let len = remaining.min(255);
+                lacing.push(len as u8);
+                remaining -= len;
+                if remaining == 0 && (len < 255 || !ends_packet) {
+                    break;
+                }
+            }
+            body.extend(data);
+        }
+
+        let mut page = b"OggS\0\0".to_vec();
+        page.extend([0; 8]);
+        page.extend(serial.to_le_bytes());
+        page.extend([0; 8]);
+        page.push(lacing.len() as u8);
+        page.extend(lacing);
+        page.extend(body);
+        page
+    }
+
+    #[test]
+    fn reads_vorbis_comments_split_across_ogg_pages() {
+        let title = "T".repeat(300);
+        let mut packet = b"\x03vorbis".to_vec();
+        packet.extend(vorbis_comments(&[
+            &format!("TITLE={title}"),
+            "artist=Someone",
+            "TRACKNUMBER=4",
+            "TRACKTOTAL=9",
+        ]));
+        let (first, rest) = packet.split_at(255);
+
+        let mut data = ogg_page(1, &[(b"\x01vorbis identification", true), (first, false)]);
+        // Another stream's page in between is skipped.
+        data.extend(ogg_page(2, &[(b"\x01video", true)]));
+        data.extend(ogg_page(1, &[(rest, true)]));
+
+        let mut tags = Tags::default();
+        read_ogg(&mut Cursor::new(&data), &mut tags).unwrap();
+        assert_eq!(tags.title, Some(title));
+        assert_eq!(tags.artist.as_deref(), Some("Someone"));
+        assert_eq!((tags.track, tags.track_total), (Some(4), Some(9)));
+    }
+
+    #[test]
+    fn reads_opus_tags() {
+        let mut packet = b"OpusTags".to_vec();
+        packet.extend(vorbis_comments(&["ALBUM=Record", "ALBUMARTIST=Band"]));
+        let data = ogg_page(7, &[(b"OpusHead", true), (&packet, true)]);
+
+        let mut tags = Tags::default();
+        read_ogg(&mut Cursor::new(&data), &mut tags).unwrap();
+        assert_eq!(tags.album.as_deref(), Some("Record"));
+        assert_eq!(tags.album_artist.as_deref(), Some("Band"));
+    }
+
+    #[test]
+    fn stops_at_truncated_ogg() {
+        let packet = vorbis_comments(&["TITLE=Title"]);
+        let data = ogg_page(1, &[(b"\x01vorbis", true), (&packet, true)]);
+
+        let mut tags = Tags::default();
+        assert!(read_ogg(&mut Cursor::new(&data[..data.len() - 4]), &mut tags).is_err());
+        assert!(read_ogg(&mut Cursor::new(&data[..20]), &mut tags).is_ok());
+        assert!(tags.is_empty());
+    }
+
+    #[test]
+    fn reads_flac_blocks() {
+        let mut data = vec![0, 0, 0, 34];
+        data.extend([0; 34]);
+        let comments = vorbis_comments(&["TITLE=Song", "ARTIST=Singer"]);
+        data.push(0x80 | FLAC_VORBIS_COMMENT);
+        data.extend(&(comments.len() as u32).to_be_bytes()[1..]);
+        data.extend(comments);
+
+        let mut tags = Tags::default();
+        read_flac(&mut Cursor::new(&data), &mut tags).unwrap();
+        assert_eq!(tags.title.as_deref(), Some("Song"));
+        assert_eq!(tags.artist.as_deref(), Some("Singer"));
+
+        // No block marked last before the data ends.
+        data[4 + 34] &= 0x7F;
+        assert!(read_flac(&mut Cursor::new(&data), &mut Tags::default()).is_err());
+    }
+
+    fn mp4_box(box_type: &[u8; 4], content: &[u8]) -> Vec<u8> {
+        let mut data = ((content.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend(box_type);
+        data.extend(content);
+        data
+    }
+
+    fn ilst_item(item_type: &[u8; 4], type_indicator: u32, value: &[u8]) -> Vec<u8> {
+        let mut data = type_indicator.to_be_bytes().to_vec();
+        data.extend([0; 4]);
+        data.extend(value);
+        mp4_box(item_type, &mp4_box(b"data", &data))
+    }
+
+    #[test]
+    fn reads_mp4_ilst() {
+        let mut ilst = ilst_item(b"\xA9nam", 1, b"Title");
+        ilst.extend(ilst_item(b"\xA9ART", 1, b"Artist"));
+        ilst.extend(ilst_item(b"trkn", 0, &[0, 0, 0, 5, 0, 10, 0, 0]));
+        ilst.extend(ilst_item(b"covr", 14, b"\x89PNG"));
+
+        let mut meta = vec![0; 4];
+        meta.extend(mp4_box(b"hdlr", &[0; 25]));
+        meta.extend(mp4_box(b"ilst", &ilst));
+        let moov = mp4_box(b"moov", &mp4_box(b"udta", &mp4_box(b"meta", &meta)));
+
+        let mut data = mp4_box(b"ftyp", b"M4A \0\0\0\0");
+        // `moov` after the media data, which is skipped over.
+        data.extend(mp4_box(b"mdat", &[0xAB; 64]));
+        data.extend(&moov);
+
+        let mut tags = Tags::default();
+        read_mp4(&mut Cursor::new(&data), &mut tags).unwrap();
+        assert_eq!(tags.title.as_deref(), Some("Title"));
+        assert_eq!(tags.artist.as_deref(), Some("Artist"));
+        assert_eq!((tags.track, tags.track_total), (Some(5), Some(10)));
+        assert_eq!(tags.cover.unwrap().mime_type, "image/png");
+
+        // A `moov` cut short fails to read instead of parsing garbage.
+        assert!(read_mp4(&mut Cursor::new(&data[..data.len() - 1]), &mut Tags::default()).is_err());
+    }
+
+    #[test]
+    fn ignores_malformed_mp4_boxes() {
+        // A child claiming more than its parent holds.
+        let mut meta = vec![0; 4];
+        let mut ilst = mp4_box(b"ilst", &ilst_item(b"\xA9nam", 1, b"Title"));
+        ilst[0..4].copy_from_slice(&1000u32.to_be_bytes());
+        meta.extend(ilst);
+        let moov = mp4_box(b"udta", &mp4_box(b"meta", &meta));
+        assert_eq!(find_box_path(&moov, &[b"udta", b"meta", b"ilst"]), None);
+        assert_eq!(boxes(&[0, 0, 0]).count(), 0);
+    }
+}
@@ -0,0 +1,437 @@
+use rustc_hash::FxHashSet;
+
+pub const TAG_NEW_SUBFILE_TYPE: u16 = 0x00FE;
+pub const TAG_COMPRESSION: u16 = 0x0103;
+pub const TAG_STRIP_OFFSETS: u16 = 0x0111;
+pub const TAG_STRIP_BYTE_COUNTS: u16 = 0x0117;
+pub const TAG_SUB_IFDS: u16 = 0x014A;
+pub const TAG_JPEG_OFFSET: u16 = 0x0201;
+pub const TAG_JPEG_LENGTH: u16 = 0x0202;
+
+const TYPE_BYTE: u16 = 1;
+const TYPE_ASCII: u16 = 2;
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+const TYPE_RATIONAL: u16 = 5;
+const TYPE_UNDEFINED: u16 = 7;
+const TYPE_SRATIONAL: u16 = 10;
+const TYPE_IFD: u16 = 13;
+
+// Guards against reference loops in malformed files.
+const MAX_IFDS: usize = 32;
+
+/// A TIFF structure, as used by TIFF images, most camera RAW formats and EXIF blocks.
+///
+/// All offsets are relative to the start of `data`, which must begin with the byte order mark.
+#[derive(Debug, Copy, Clone)]
+pub struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+    first_ifd: usize,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Entry {
+    pub tag: u16,
+    pub field_type: u16,
+    pub count: u32,
+    value_offset: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Ifd {
+    pub offset: usize,
+    entries: Vec<Entry>,
+    next: Option<usize>,
+}
+
+impl Ifd {
+    pub fn get(&self, tag: u16) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.tag == tag)
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn next_offset(&self) -> Option<usize> {
+        self.next
+    }
+}
+
+impl<'a> Tiff<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        let big_endian = match data.get(0..2)? {
+            b"II" => false,
+            b"MM" => true,
+            _ => return None,
+        };
+
+        let mut tiff = Self { data, big_endian, first_ifd: 0 };
+        // Olympus (ORF) and Panasonic (RW2) replace the usual 42 with their own magic numbers.
+        if !matches!(tiff.u16_at(2)?, 42 | 0x4F52 | 0x5352 | 0x0055) {
+            return None;
+        }
+        tiff.first_ifd = tiff.u32_at(4)? as usize;
+        Some(tiff)
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn u16_at(&self, offset: usize) -> Option<u16> {
+        let bytes: [u8; 2] = self.data.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    pub fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    pub fn first_ifd(&self) -> Option<Ifd> {
+        self.ifd_at(self.first_ifd)
+    }
+
+    pub fn ifd_at(&self, offset: usize) -> Option<Ifd> {
+        let count = self.u16_at(offset)? as usize;
+        let mut entries = Vec::with_capacity(count);
+
+        for index in 0..count {
+            let entry_offset = offset + 2 + index * 12;
+            let tag = self.u16_at(entry_offset)?;
+            let field_type = self.u16_at(entry_offset + 2)?;
+            let count = self.u32_at(entry_offset + 4)?;
+
+            let size = type_size(field_type).saturating_mul(count as usize);
+            let value_offset = if size <= 4 {
+                entry_offset + 8
+            } else {
+                self.u32_at(entry_offset + 8)? as usize
+            };
+
+            entries.push(Entry { tag, field_type, count, value_offset });
+        }
+
+        let next = self.u32_at(offset + 2 + count * 12).filter(|&next| next != 0);
+        Some(Ifd { offset, entries, next: next.map(|next| next as usize) })
+    }
+
+    /// Every IFD in the main chain plus any linked through `SubIFDs`.
+    pub fn all_ifds(&self) -> Vec<Ifd> {
+        let mut ifds = Vec::new();
+        let mut visited = FxHashSet::default();
+        let mut pending = vec![self.first_ifd];
+
+        while let Some(offset) = pending.pop() {
+            if ifds.len() >= MAX_IFDS || offset == 0 || !visited.insert(offset) {
+                continue;
+            }
+            let Some(ifd) = self.ifd_at(offset) else { continue };
+
+            if let Some(next) = ifd.next {
+                pending.push(next);
+            }
+            if let Some(sub_ifds) = ifd.get(TAG_SUB_IFDS) {
+                for index in 0..sub_ifds.count as usize {
+                    if let Some(offset) = self.entry_u32(sub_ifds, index) {
+                        pending.push(offset as usize);
+                    }
+                }
+            }
+
+            ifds.push(ifd);
+        }
+
+        ifds
+    }
+
+    /// Reads an unsigned integer value, widening `BYTE` and `SHORT` values.
+    pub fn entry_u32(&self, entry: &Entry, index: usize) -> Option<u32> {
+        if index >= entry.count as usize {
+            return None;
+        }
+        match entry.field_type {
+            TYPE_BYTE | TYPE_UNDEFINED => {
+                self.data.get(entry.value_offset + index).map(|&b| b as u32)
+            }
+            TYPE_SHORT => self.u16_at(entry.value_offset + index * 2).map(u32::from),
+            TYPE_LONG | TYPE_IFD => self.u32_at(entry.value_offset + index * 4),
+            _ => None,
+        }
+    }
+
+    pub fn entry_rational(&self, entry: &Entry, index: usize) -> Option<f64> {
+        if index >= entry.count as usize {
+            return None;
+        }
+        let offset = entry.value_offset + index * 8;
+        let numerator = self.u32_at(offset)?;
+        let denominator = self.u32_at(offset + 4)?;
+        if denominator == 0 {
+            return None;
+        }
+        match entry.field_type {
+            TYPE_RATIONAL => Some(numerator as f64 / denominator as f64),
+            TYPE_SRATIONAL => Some(numerator as i32 as f64 / denominator as i32 as f64),
+            _ => None,
+        }
+    }
+
+    pub fn entry_ascii(&self, entry: &Entry) -> Option<&'a str> {
+        if entry.field_type != TYPE_ASCII {
+            return None;
+        }
+        let bytes = self.entry_bytes(entry)?;
+        let end = memchr::memchr(0, bytes).unwrap_or(bytes.len());
+        let value = std::str::from_utf8(&bytes[..end]).ok()?.trim();
+        if value.is_empty() { None } else { Some(value) }
+    }
+
+    pub fn entry_bytes(&self, entry: &Entry) -> Option<&'a [u8]> {
+        let size = type_size(entry.field_type).checked_mul(entry.count as usize)?;
+        self.data.get(entry.value_offset..entry.value_offset.checked_add(size)?)
+    }
+}
+
+fn type_size(field_type: u16) -> usize {
+    match field_type {
+        TYPE_BYTE | TYPE_ASCII | 6 | TYPE_UNDEFINED => 1,
+        TYPE_SHORT | 8 => 2,
+        TYPE_LONG | 9 | 11 | TYPE_IFD => 4,
+        TYPE_RATIONAL | TYPE_SRATIONAL | 12 => 8,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    enum Value {
+        Shorts(Vec<u16>),
+        Longs(Vec<u32>),
+        Ascii(String),
+        Rationals(Vec<(u32, u32)>),
+        Ifd(IfdBuilder),
+    }
+
+    /// Lays out an IFD and everything it points to, for building fixtures.
+    #[derive(Default)]
+    pub(crate) struct IfdBuilder {
+        entries: Vec<(u16, Value)>,
+    }
+
+    impl IfdBuilder {
+        pub(crate) fn short(mut self, tag: u16, values: &[u16]) -> Self {
+            self.entries.push((tag, Value::Shorts(values.to_vec())));
+            self
+        }
+
+        pub(crate) fn long(mut self, tag: u16, values: &[u32]) -> Self {
+            self.entries.push((tag, Value::Longs(values.to_vec())));
+            self
+        }
+
+        pub(crate) fn ascii(mut self, tag: u16, value: &str) -> Self {
+            self.entries.push((tag, Value::Ascii(value.to_owned())));
+            self
+        }
+
+        pub(crate) fn rational(mut self, tag: u16, values: &[(u32, u32)]) -> Self {
+            self.entries.push((tag, Value::Rationals(values.to_vec())));
+            self
+        }
+
+        pub(crate) fn ifd(mut self, tag: u16, ifd: IfdBuilder) -> Self {
+            self.entries.push((tag, Value::Ifd(ifd)));
+            self
+        }
+
+        /// A TIFF with this as its only top-level IFD.
+        pub(crate) fn build(self, big_endian: bool) -> Vec<u8> {
+            let mut out = if big_endian { b"MM\0\x2A".to_vec() } else { b"II\x2A\0".to_vec() };
+            out.extend(encode_u32(big_endian, 8));
+            self.write(big_endian, &mut out);
+            out
+        }
+
+        fn write(self, big_endian: bool, out: &mut Vec<u8>) -> u32 {
+            let offset = out.len();
+            let data_start = offset + 2 + self.entries.len() * 12 + 4;
+            let mut data = Vec::new();
+            let mut children = Vec::new();
+
+            out.extend(encode_u16(big_endian, self.entries.len() as u16));
+            for (tag, value) in self.entries {
+                let (field_type, count, bytes) = match value {
+                    Value::Shorts(values) => (
+                        TYPE_SHORT,
+                        values.len(),
+                        values.iter().flat_map(|&v| encode_u16(big_endian, v)).collect(),
+                    ),
+                    Value::Longs(values) => (
+                        TYPE_LONG,
+                        values.len(),
+                        values.iter().flat_map(|&v| encode_u32(big_endian, v)).collect(),
+                    ),
+                    Value::Ascii(value) => {
+                        let mut bytes = value.into_bytes();
+                        bytes.push(0);
+                        (TYPE_ASCII, bytes.len(), bytes)
+                    }
+                    Value::Rationals(values) => (
+                        TYPE_RATIONAL,
+                        values.len(),
+                        values
+                            .iter()
+                            .flat_map(|&(n, d)| {
+                                encode_u32(big_endian, n)
+                                    .into_iter()
+                                    .chain(encode_u32(big_endian, d))
+                            })
+                            .collect(),
+                    ),
+                    Value::Ifd(ifd) => {
+                        children.push((out.len() + 8, ifd));
+                        (TYPE_LONG, 1, vec![0; 4])
+                    }
+                };
+
+                out.extend(encode_u16(big_endian, tag));
+                out.extend(encode_u16(big_endian, field_type));
+                out.extend(encode_u32(big_endian, count as u32));
+                if bytes.len() <= 4 {
+                    out.extend(&bytes);
+                    out.resize(out.len() + 4 - bytes.len(), 0);
+                } else {
+                    out.extend(encode_u32(big_endian, (data_start + data.len()) as u32));
+                    data.extend(bytes);
+                }
+            }
+            // No next IFD.
+            out.extend([0; 4]);
+            out.extend(data);
+
+            for (pointer, ifd) in children {
+                let child = ifd.write(big_endian, out);
+                out[pointer..pointer + 4].copy_from_slice(&encode_u32(big_endian, child));
+            }
+
+            offset as u32
+        }
+    }
+
+    fn encode_u16(big_endian: bool, value: u16) -> [u8; 2] {
+        if big_endian { value.to_be_bytes() } else { value.to_le_bytes() }
+    }
+
+    fn encode_u32(big_endian: bool, value: u32) -> [u8; 4] {
+        if big_endian { value.to_be_bytes() } else { value.to_le_bytes() }
+    }
+
+    fn sample(big_endian: bool) -> Vec<u8> {
+        IfdBuilder::default()
+            .short(0x0112, &[6])
+            .long(0x0100, &[4000])
+            .short(0x0102, &[8, 8, 8])
+            .ascii(0x010F, "Canon")
+            .rational(0x011A, &[(300, 1)])
+            .build(big_endian)
+    }
+
+    #[test]
+    fn reads_both_byte_orders() {
+        for big_endian in [false, true] {
+            let data = sample(big_endian);
+            let tiff = Tiff::parse(&data).unwrap();
+            let ifd = tiff.first_ifd().unwrap();
+
+            assert_eq!(ifd.entries().len(), 5);
+            assert_eq!(tiff.entry_u32(ifd.get(0x0112).unwrap(), 0), Some(6));
+            assert_eq!(tiff.entry_u32(ifd.get(0x0100).unwrap(), 0), Some(4000));
+            // Six bytes, so stored out of line.
+            let bits = ifd.get(0x0102).unwrap();
+            assert_eq!((0..3).map(|i| tiff.entry_u32(bits, i)).collect::<Vec<_>>(), [Some(8); 3]);
+            assert_eq!(tiff.entry_u32(bits, 3), None);
+            assert_eq!(tiff.entry_ascii(ifd.get(0x010F).unwrap()), Some("Canon"));
+            assert_eq!(tiff.entry_rational(ifd.get(0x011A).unwrap(), 0), Some(300.0));
+            assert_eq!(ifd.next_offset(), None);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        assert!(Tiff::parse(b"").is_none());
+        assert!(Tiff::parse(b"II").is_none());
+        assert!(Tiff::parse(b"XX\x2A\0\x08\0\0\0").is_none());
+        assert!(Tiff::parse(b"II\x2B\0\x08\0\0\0").is_none());
+        // The magic number is read in the declared byte order.
+        assert!(Tiff::parse(b"MM\x2A\0\0\0\0\x08").is_none());
+        assert!(Tiff::parse(b"II\x2A\0\x08\0").is_none());
+    }
+
+    #[test]
+    fn handles_truncated_data() {
+        let data = sample(false);
+        // Cut inside the entries, the IFD is unreadable.
+        let tiff = Tiff::parse(&data[..20]).unwrap();
+        assert!(tiff.first_ifd().is_none());
+        assert!(tiff.all_ifds().is_empty());
+
+        // Cut inside the out of line values, the entries still parse but their values don't.
+        let end = 8 + 2 + 5 * 12 + 4 + 2;
+        let tiff = Tiff::parse(&data[..end]).unwrap();
+        let ifd = tiff.first_ifd().unwrap();
+        assert_eq!(tiff.entry_u32(ifd.get(0x0112).unwrap(), 0), Some(6));
+        assert_eq!(tiff.entry_u32(ifd.get(0x0102).unwrap(), 2), None);
+        assert_eq!(tiff.entry_ascii(ifd.get(0x010F).unwrap()), None);
+        assert_eq!(tiff.entry_rational(ifd.get(0x011A).unwrap(), 0), None);
+    }
+
+    #[test]
+    fn rejects_wrong_types_and_zero_denominators() {
+        let data = IfdBuilder::default()
+            .ascii(0x010F, "Canon")
+            .rational(0x011A, &[(300, 0)])
+            .short(0x0112, &[1])
+            .build(true);
+        let tiff = Tiff::parse(&data).unwrap();
+        let ifd = tiff.first_ifd().unwrap();
+        assert_eq!(tiff.entry_u32(ifd.get(0x010F).unwrap(), 0), None);
+        assert_eq!(tiff.entry_rational(ifd.get(0x011A).unwrap(), 0), None);
+        assert_eq!(tiff.entry_ascii(ifd.get(0x0112).unwrap()), None);
+    }
+
+    #[test]
+    fn follows_sub_ifds() {
+        let data = IfdBuilder::default()
+            .ifd(TAG_SUB_IFDS, IfdBuilder::default().short(TAG_COMPRESSION, &[6]))
+            .build(false);
+        let tiff = Tiff::parse(&data).unwrap();
+        let ifds = tiff.all_ifds();
+        assert_eq!(ifds.len(), 2);
+        assert!(ifds[1].get(TAG_COMPRESSION).is_some());
+    }
+
+    #[test]
+    fn stops_at_reference_loops() {
+        let mut data = IfdBuilder::default().short(0x0112, &[1]).build(false);
+        // Point the next IFD back at the first.
+        let next = 8 + 2 + 12;
+        data[next..next + 4].copy_from_slice(&8u32.to_le_bytes());
+
+        let tiff = Tiff::parse(&data).unwrap();
+        assert_eq!(tiff.first_ifd().unwrap().next_offset(), Some(8));
+        assert_eq!(tiff.all_ifds().len(), 1);
+    }
+}
@@ -1,8 +1,12 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
 use eframe::egui;
 use eframe::egui::Widget;
+use rustc_hash::FxHashMap;
 
-use crate::Error;
 use crate::pipeline::{Event, Pipeline};
+use crate::{Error, tags};
 
 #[derive(Debug)]
 pub struct Response {
@@ -45,9 +49,14 @@ pub struct PlayerUi {
     new_target_video_size: Option<(i32, i32)>,
     new_target_video_size_changed_at: f64,
     playing_text: String,
+    // Tags are read on a separate thread, a sleeping disk would otherwise freeze the UI.
+    playing_text_rx: Option<flume::Receiver<(PathBuf, String)>>,
+    playing_texts: FxHashMap<PathBuf, String>,
 }
 
 impl PlayerUi {
+    const MAX_PLAYING_TEXTS: usize = 256;
+
     pub fn rate(&self) -> f64 {
         self.rate
     }
@@ -76,7 +85,7 @@ impl PlayerUi {
     {
         let pipeline = pipeline.into();
         if let Some(pipeline) = &pipeline {
-            self.playing_text = format!("Playing: {}", pipeline.path().display());
+            self.set_playing_text(pipeline.path());
             self.last_target_video_size = None;
             self.new_target_video_size = None;
             self.new_target_video_size_changed_at = 0.0;
@@ -84,6 +93,46 @@ impl PlayerUi {
         std::mem::replace(&mut self.pipeline, pipeline)
     }
 
+    fn set_playing_text(&mut self, path: &Path) {
+        self.playing_text_rx = None;
+        if let Some(text) = self.playing_texts.get(path) {
+            self.playing_text = text.clone();
+            return;
+        }
+
+        self.playing_text = format!("Playing: {}", path.display());
+        if !tags::is_audio(path) {
+            return;
+        }
+
+        let (tx, rx) = flume::bounded(1);
+        let path = path.to_owned();
+        std::thread::spawn(move || {
+            let text = playing_text(&path);
+            _ = tx.send((path, text));
+        });
+        self.playing_text_rx = Some(rx);
+    }
+
+    fn receive_playing_text(&mut self, ctx: &egui::Context) {
+        let Some(rx) = &self.playing_text_rx else { return };
+        let Ok((path, text)) = rx.try_recv() else {
+            if !rx.is_disconnected() {
+                ctx.request_repaint_after(Duration::from_millis(100));
+            }
+            return;
+        };
+        self.playing_text_rx = None;
+
+        if self.pipeline.as_ref().is_some_and(|pipeline| pipeline.path() == path) {
+            self.playing_text = text.clone();
+        }
+        if self.playing_texts.len() >= Self::MAX_PLAYING_TEXTS {
+            self.playing_texts.clear();
+        }
+        self.playing_texts.insert(path, text);
+    }
+
     pub fn clear(&mut self) {
         self.pipeline = None;
         self.last_target_video_size = None;
@@ -94,9 +143,11 @@ impl PlayerUi {
     pub fn ui(&mut self, ui: &mut egui::Ui, fullscreen: bool) -> Response {
         let mut response = Response { finished: false, error: None, pipeline_rx: None };
 
+        self.receive_playing_text(ui.ctx());
         let Some(pipeline) = &self.pipeline else { return response };
 
         {
+            let _span = pipeline.span().enter();
             let event_rx = pipeline.event_rx().clone();
             for event in event_rx.try_iter() {
                 match event {
@@ -104,16 +155,13 @@ impl PlayerUi {
                         response.finished = true;
                     }
                     Event::Error(error) => {
-                        log::error!("Error in pipeline: {error}");
+                        tracing::error!(%error, "Error in pipeline");
                         response.error = Some(Error::Any(error));
                         response.finished = true;
                     }
                     Event::StateChanged { from, to } => {
                         // TODO: Play/pause text.
-                        log::info!(
-                            "Pipeline state changed {} - {from:?} -> {to:?}",
-                            pipeline.path().display()
-                        );
+                        tracing::info!(?from, ?to, "Pipeline state changed");
                     }
                 }
             }
@@ -319,3 +367,26 @@ fn display_clocktime(time: gstreamer::ClockTime, show_hours: bool) -> String {
         format!("{minutes:02}:{seconds:02}")
     }
 }
+
+fn playing_text(path: &Path) -> String {
+    let tags = match tags::read_file(path) {
+        Ok(tags) => tags,
+        Err(error) => {
+            tracing::warn!(path = %path.display(), %error, "Failed to read tags");
+            None
+        }
+    };
+
+    let Some(tags::Tags { title: Some(title), artist, album, .. }) = tags else {
+        return format!("Playing: {}", path.display());
+    };
+
+    let mut text = match artist {
+        Some(artist) => format!("Playing: {artist} - {title}"),
+        None => format!("Playing: {title}"),
+    };
+    if let Some(album) = album {
+        text.push_str(&format!(" ({album})"));
+    }
+    text
+}
@@ -4,11 +4,14 @@ use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::Instant;
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
+use parking_lot::Mutex;
 use rustc_hash::FxHashMap;
 use rustix::fs::{AtFlags, Mode, OFlags};
+use tracing::Instrument;
 use triomphe::Arc;
 use z_sync::Notify;
 
@@ -21,6 +24,64 @@ type InternalPathSender = z_queue::defaults::UnboundedSender<(PathBuf, Option<Ow
 
 const BOUND: NonZeroUsize = NonZeroUsize::new(1000).unwrap();
 
+/// Work done by every walk since startup on one kind of storage.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Throughput {
+    pub dirs: u64,
+    pub entries: u64,
+    /// Time spent reading directories, entries over this is how fast the storage is walked.
+    pub busy: Duration,
+}
+
+static THROUGHPUT: LazyLock<Mutex<FxHashMap<StorageClass, Throughput>>> =
+    LazyLock::new(|| Mutex::new(FxHashMap::default()));
+
+pub fn throughput() -> Vec<(StorageClass, Throughput)> {
+    THROUGHPUT
+        .lock()
+        .iter()
+        .map(|(class, throughput)| (*class, *throughput))
+        .collect()
+}
+
+/// A walk that hasn't finished yet.
+#[derive(Debug, Clone)]
+pub struct WalkProgress {
+    pub roots: Vec<PathBuf>,
+    pub started: Instant,
+    pub dirs: u64,
+    pub entries: u64,
+    /// Directories found but not read yet, as of the last one read.
+    pub pending: usize,
+}
+
+static NEXT_WALK_ID: AtomicU64 = AtomicU64::new(0);
+
+static WALKS: LazyLock<Mutex<FxHashMap<u64, WalkProgress>>> =
+    LazyLock::new(|| Mutex::new(FxHashMap::default()));
+
+// Every device a walk has crossed, a walk's own map is dropped with it.
+static DEVICE_CLASSES: LazyLock<Mutex<FxHashMap<u64, StorageClass>>> =
+    LazyLock::new(|| Mutex::new(FxHashMap::default()));
+
+/// Walks still running, oldest first.
+pub fn walks() -> Vec<WalkProgress> {
+    let mut walks = WALKS.lock().values().cloned().collect::<Vec<_>>();
+    walks.sort_unstable_by_key(|walk| walk.started);
+    walks
+}
+
+/// The storage class of every device walked so far, by `st_dev`.
+pub fn device_classes() -> Vec<(u64, StorageClass)> {
+    let mut devices = DEVICE_CLASSES
+        .lock()
+        .iter()
+        .map(|(device, class)| (*device, *class))
+        .collect::<Vec<_>>();
+    devices.sort_unstable_by_key(|(device, _)| *device);
+    devices
+}
+
 pub async fn walk_dir(
     path: PathBuf,
     deadline: Option<Instant>,
@@ -36,12 +97,13 @@ pub async fn walk_dir_filter<F>(
 where
     F: Fn(&Path, bool) -> bool + Send + Sync + 'static,
 {
-    let (walk, rx) = Walk::new(deadline, filter);
-    walk.walk(path).await?;
+    let (walk, rx) = Walk::new(std::slice::from_ref(&path), deadline, filter);
+    // Let the walk finish even when the root is gone, or it's reported as running forever.
+    let result = walk.walk(path).await;
     if walk.active.0.fetch_sub(1, Ordering::AcqRel) == 1 {
         walk.active.1.notify(usize::MAX);
     }
-    Ok(rx)
+    result.map(|()| rx)
 }
 
 pub async fn walk_roots(
@@ -59,15 +121,19 @@ pub async fn walk_roots_filter<F>(
 where
     F: Fn(&Path, bool) -> bool + Send + Sync + 'static,
 {
-    let (walk, rx) = Walk::new(deadline, filter);
+    let (walk, rx) = Walk::new(paths, deadline, filter);
 
+    let mut result = Ok(());
     for path in paths {
-        walk.walk(path.clone()).await?;
+        result = walk.walk(path.clone()).await;
+        if result.is_err() {
+            break;
+        }
     }
     if walk.active.0.fetch_sub(1, Ordering::AcqRel) == 1 {
         walk.active.1.notify(usize::MAX);
     }
-    Ok(rx)
+    result.map(|()| rx)
 }
 
 type RequestWorkerSender =
@@ -82,13 +148,19 @@ struct Walk<F> {
     active: (AtomicUsize, z_sync::Notify16),
     request_worker_tx: RequestWorkerSender,
     filter: F,
+    id: u64,
+    span: tracing::Span,
 }
 
 impl<F> Walk<F>
 where
     F: Fn(&Path, bool) -> bool + Send + Sync + 'static,
 {
-    fn new(deadline: Option<Instant>, filter: F) -> (std::sync::Arc<Self>, PathReceiver) {
+    fn new(
+        roots: &[PathBuf],
+        deadline: Option<Instant>,
+        filter: F,
+    ) -> (std::sync::Arc<Self>, PathReceiver) {
         let (tx, rx) = z_queue::bounded(BOUND);
         let device_class_map = z_sync::Lock::new(FxHashMap::default());
         let storage_map = z_sync::Lock::new(FxHashMap::default());
@@ -96,6 +168,17 @@ where
 
         let (request_worker_tx, request_worker_rx) = z_queue::bounded(NonZeroUsize::MIN);
 
+        let id = NEXT_WALK_ID.fetch_add(1, Ordering::Relaxed);
+        let progress = WalkProgress {
+            roots: roots.to_vec(),
+            started: Instant::now(),
+            dirs: 0,
+            entries: 0,
+            pending: 0,
+        };
+        WALKS.lock().insert(id, progress);
+        let span = tracing::info_span!("walk", id, ?roots);
+
         let this = std::sync::Arc::new(Self {
             result_tx: tx,
             device_class_map,
@@ -104,18 +187,23 @@ where
             active,
             request_worker_tx,
             filter,
+            id,
+            span,
         });
 
         let this_clone = this.clone();
-        compio::runtime::spawn(async move {
+        let finished = async move {
             let mut listener = this_clone.active.1.listener();
             while this_clone.active.0.load(Ordering::Acquire) != 0 {
                 listener.await;
                 listener = this_clone.active.1.listener();
             }
             this_clone.storage_map.write_async().await.clear();
-        })
-        .detach();
+            WALKS.lock().remove(&this_clone.id);
+            tracing::debug!("Walk finished");
+        }
+        .instrument(this.span.clone());
+        compio::runtime::spawn(finished).detach();
 
         let this_weak = std::sync::Arc::downgrade(&this);
         compio::runtime::spawn(async move {
@@ -136,14 +224,16 @@ where
                     for _ in 0..worker_count {
                         let this = this.clone();
                         let rx = rx.clone();
+                        let span = this.span.clone();
 
-                        compio::runtime::spawn(async move {
+                        let worker = async move {
                             while let Ok((path, dir_fd)) = rx.recv_async().await {
                                 // Reduce CPU usage by taking a small break.
                                 compio::time::sleep(std::time::Duration::from_millis(5)).await;
 
                                 let this_clone = this.clone();
                                 let result = compio::runtime::spawn_blocking(move || {
+                                    let _span = this_clone.span.enter();
                                     read_dir(path, &this_clone, device, dir_fd)
                                 })
                                 .await;
@@ -151,10 +241,10 @@ where
                                 match result {
                                     Ok(Ok(())) => (),
                                     Ok(Err(error)) => {
-                                        eprintln!("Error reading directory: {error:?}")
+                                        tracing::warn!(%error, "Error reading directory")
                                     }
                                     Err(error) => {
-                                        eprintln!("Panicked reading directory: {error:?}")
+                                        tracing::error!(?error, "Panicked reading directory")
                                     }
                                 }
 
@@ -162,8 +252,9 @@ where
                                     this.active.1.notify(usize::MAX);
                                 }
                             }
-                        })
-                        .detach();
+                        }
+                        .instrument(span);
+                        compio::runtime::spawn(worker).detach();
                     }
                 }
                 notify.notify(usize::MAX);
@@ -190,6 +281,7 @@ where
 
         let class = StorageClass::from_compio_metadata(metadata).await?;
         self.device_class_map.write_async().await.insert(device, class);
+        DEVICE_CLASSES.lock().insert(device, class);
         Ok(class)
     }
 
@@ -203,6 +295,7 @@ where
 
         let class = StorageClass::from_stat(stat)?;
         self.device_class_map.write().insert(stat.st_dev, class);
+        DEVICE_CLASSES.lock().insert(stat.st_dev, class);
         Ok(class)
     }
 
@@ -321,6 +414,22 @@ where
     let mut buffer = Vec::with_capacity(64 * 1024);
     let mut iter = rustix::fs::RawDir::new(dir_fd.as_fd(), buffer.spare_capacity_mut());
 
+    let started = Instant::now();
+    let mut entries = scopeguard::guard(0, |entries| {
+        let mut classes = THROUGHPUT.lock();
+        let throughput = classes.entry(device.1).or_default();
+        throughput.dirs += 1;
+        throughput.entries += entries;
+        throughput.busy += started.elapsed();
+        drop(classes);
+
+        if let Some(progress) = WALKS.lock().get_mut(&walk.id) {
+            progress.dirs += 1;
+            progress.entries += entries;
+            progress.pending = walk.active.0.load(Ordering::Relaxed);
+        }
+    });
+
     let mut counter: usize = 0;
 
     // Iterate over the entries.
@@ -344,6 +453,7 @@ where
         if matches!(name_bytes, b"." | b"..") {
             continue;
         }
+        *entries += 1;
         let file_name = OsStr::from_bytes(name_bytes);
         let mut file_type = entry.file_type();
         let mut stat = None;
@@ -385,7 +495,7 @@ where
                 Some(device),
             )
         {
-            eprintln!("Error walking directory: {error:?}");
+            tracing::warn!(path = %path.display(), %error, "Error walking directory");
         }
 
         if walk.result_tx.is_disconnected() {